@@ -10,6 +10,7 @@ use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
+use url::Url;
 
 use darkfi::{
     blockchain::{rocks::columns, Rocks, RocksColumn},
@@ -20,9 +21,10 @@ use darkfi::{
         token_id::generate_id2,
         types::DrkTokenId,
     },
+    net::{NetAddr, Settings},
     node::{
-        client::Client,
-        state::State,
+        client::{Client, GatewaySettings},
+        state::{State, MERKLE_ANCHOR_WINDOW},
         wallet::{cashierdb::CashierDb, walletdb::WalletDb},
     },
     rpc::{
@@ -68,6 +70,34 @@ pub struct CashierdConfig {
     pub gateway_protocol_url: String,
     /// The endpoint to a gatewayd publisher API
     pub gateway_publisher_url: String,
+    /// The gatewayd public key, used to authenticate the gateway
+    /// (Used if use_p2p=false)
+    #[serde(default)]
+    pub gateway_public_key: String,
+    /// Additional gatewayd protocol and publisher endpoints with their
+    /// public keys, used for failover and cross-checking slabs
+    #[serde(default)]
+    pub backup_gateway_urls: Vec<(String, String, String)>,
+    /// Sync slabs over the P2P network instead of ZeroMQ gateways
+    #[serde(default)]
+    pub use_p2p: bool,
+    /// P2P peers to connect to (Used if use_p2p=true)
+    #[serde(default)]
+    pub p2p_peers: Vec<NetAddr>,
+    /// P2P seed nodes (Used if use_p2p=true)
+    #[serde(default)]
+    pub p2p_seeds: Vec<NetAddr>,
+    /// SOCKS5 proxy for outbound P2P connections (Used if use_p2p=true)
+    pub p2p_socks5_proxy: Option<String>,
+    /// Path of the P2P host database (Used if use_p2p=true)
+    pub p2p_hosts_path: Option<String>,
+    /// Public keys of the consensus participants certifying the slabs
+    /// (Used if use_p2p=true)
+    #[serde(default)]
+    pub consensus_participants: Vec<String>,
+    /// Number of slabs a merkle root can be used as an anchor
+    #[serde(default = "default_merkle_anchor_window")]
+    pub merkle_anchor_window: u64,
     /// Path to cashierd wallet
     pub cashier_wallet_path: String,
     /// Password for cashierd wallet
@@ -86,6 +116,10 @@ pub struct CashierdConfig {
     pub networks: Vec<FeatureNetwork>,
 }
 
+fn default_merkle_anchor_window() -> u64 {
+    MERKLE_ANCHOR_WINDOW
+}
+
 /// Cashierd cli
 #[derive(Parser)]
 #[clap(name = "cashierd")]
@@ -243,7 +277,7 @@ impl Cashierd {
             }
         }
 
-        client.start().await?;
+        client.start(executor.clone()).await?;
 
         let (notify, recv_coin) = async_channel::unbounded::<(PublicKey, u64)>();
 
@@ -682,12 +716,54 @@ async fn start(
     let spend_vk = VerifyingKey::build(11, &SpendContract::default());
 
     // new Client
-    let gateway_urls =
-        (config.gateway_protocol_url.parse()?, config.gateway_publisher_url.parse()?);
-    let client = Client::new(rocks.clone(), gateway_urls, client_wallet.clone()).await?;
+    let gateway_settings = if config.use_p2p {
+        let socks5_proxy = match &config.p2p_socks5_proxy {
+            Some(proxy) => Some(Url::parse(proxy)?),
+            None => None,
+        };
+
+        let hosts_path = match &config.p2p_hosts_path {
+            Some(path) => Some(expand_path(path)?),
+            None => None,
+        };
+
+        let mut participants = vec![];
+        for public_key in config.consensus_participants.iter() {
+            participants.push(PublicKey::try_from(Address::from_str(public_key)?)?);
+        }
+
+        GatewaySettings::P2p(
+            Settings {
+                peers: config.p2p_peers.clone(),
+                seeds: config.p2p_seeds.clone(),
+                socks5_proxy,
+                hosts_path,
+                ..Default::default()
+            },
+            participants,
+        )
+    } else {
+        let mut gateway_urls = vec![(
+            config.gateway_protocol_url.parse()?,
+            config.gateway_publisher_url.parse()?,
+            PublicKey::try_from(Address::from_str(&config.gateway_public_key)?)?,
+        )];
+        for (protocol_url, publisher_url, public_key) in config.backup_gateway_urls.iter() {
+            gateway_urls.push((
+                protocol_url.parse()?,
+                publisher_url.parse()?,
+                PublicKey::try_from(Address::from_str(public_key)?)?,
+            ));
+        }
+
+        GatewaySettings::Zmq(gateway_urls)
+    };
+
+    let client = Client::new(rocks.clone(), gateway_settings, client_wallet.clone()).await?;
 
     let tree = client.get_tree().await?;
     let merkle_roots = RocksColumn::<columns::MerkleRoots>::new(rocks.clone());
+    let last_slab_index = client.get_last_slab_index(&merkle_roots).await?;
     let nullifiers = RocksColumn::<columns::Nullifiers>::new(rocks);
 
     // get cashier public key
@@ -707,6 +783,8 @@ async fn start(
     let state = Arc::new(Mutex::new(State {
         tree,
         merkle_roots,
+        merkle_anchor_window: config.merkle_anchor_window,
+        last_slab_index,
         nullifiers,
         public_keys,
         mint_vk,
@@ -763,7 +841,9 @@ async fn main() -> Result<()> {
             format!("sqlite://{}", expand_path(&config.client_wallet_path)?.to_str().unwrap());
         let client_wallet =
             WalletDb::new(&client_wallet_path, &config.client_wallet_password).await?;
+        client_wallet.init_db().await?;
         client_wallet.remove_own_coins().await?;
+        client_wallet.reset_tree().await?;
 
         // refresh cashier wallet
         let wallet_path =
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr};
 
 use async_executor::Executor;
 use async_std::sync::{Arc, Mutex};
@@ -21,9 +21,10 @@ use darkfi::{
         token_list::{assign_id, DrkTokenList, TokenList},
         types::DrkTokenId,
     },
+    net::{NetAddr, Settings},
     node::{
-        client::Client,
-        state::{ProgramState, State},
+        client::{Client, GatewaySettings},
+        state::{ProgramState, State, MERKLE_ANCHOR_WINDOW},
         wallet::walletdb::WalletDb,
     },
     rpc::{
@@ -51,6 +52,16 @@ pub struct CashierC {
     pub rpc_url: UrlConfig,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayC {
+    /// The endpoint to a gatewayd protocol API
+    pub url: UrlConfig,
+    /// The endpoint to a gatewayd publisher API
+    pub pub_url: UrlConfig,
+    /// The gatewayd public key
+    pub public_key: String,
+}
+
 /// The configuration for darkfid
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct DarkfidConfig {
@@ -70,10 +81,46 @@ pub struct DarkfidConfig {
     pub gateway_url: UrlConfig,
     /// The endpoint to a gatewayd publisher API
     pub gateway_pub_url: UrlConfig,
+    /// The gatewayd public key, used to authenticate the gateway
+    /// (Used if use_p2p=false)
+    #[serde(default)]
+    pub gateway_public_key: String,
+    /// Additional gateways used for failover and cross-checking slabs
+    #[serde(default)]
+    pub backup_gateways: Vec<GatewayC>,
+    /// Sync slabs over the P2P network instead of ZeroMQ gateways
+    #[serde(default)]
+    pub use_p2p: bool,
+    /// P2P peers to connect to (Used if use_p2p=true)
+    #[serde(default)]
+    pub p2p_peers: Vec<NetAddr>,
+    /// P2P seed nodes (Used if use_p2p=true)
+    #[serde(default)]
+    pub p2p_seeds: Vec<NetAddr>,
+    /// SOCKS5 proxy for outbound P2P connections (Used if use_p2p=true)
+    pub p2p_socks5_proxy: Option<String>,
+    /// Path of the P2P host database (Used if use_p2p=true)
+    pub p2p_hosts_path: Option<String>,
+    /// Address serving the P2P metrics over HTTP (Used if use_p2p=true)
+    pub p2p_metrics_address: Option<SocketAddr>,
+    /// Public keys of the consensus participants certifying the slabs
+    /// (Used if use_p2p=true)
+    #[serde(default)]
+    pub consensus_participants: Vec<String>,
+    /// Number of slabs a merkle root can be used as an anchor
+    #[serde(default = "default_merkle_anchor_window")]
+    pub merkle_anchor_window: u64,
+    /// Scan the chain using compact slabs instead of full slabs
+    #[serde(default)]
+    pub compact_sync: bool,
     /// The configured cashiers to use
     pub cashiers: Vec<CashierC>,
 }
 
+fn default_merkle_anchor_window() -> u64 {
+    MERKLE_ANCHOR_WINDOW
+}
+
 /// Darkfid cli
 #[derive(Parser)]
 #[clap(name = "darkfid")]
@@ -112,6 +159,7 @@ struct Darkfid {
     drk_tokenlist: DrkTokenList,
     cashiers: Vec<Cashier>,
     socks_url: Url,
+    compact_sync: bool,
 }
 
 #[async_trait]
@@ -143,6 +191,9 @@ impl RequestHandler for Darkfid {
                 return self.set_default_address(req.id, req.params).await
             }
             Some("get_balances") => return self.get_balances(req.id, req.params).await,
+            Some("get_gateway_metrics") => {
+                return self.get_gateway_metrics(req.id, req.params).await
+            }
             Some("get_token_id") => return self.get_token_id(req.id, req.params).await,
             Some("features") => return self.features(req.id, req.params).await,
             Some("deposit") => return self.deposit(req.id, req.params).await,
@@ -159,6 +210,7 @@ impl Darkfid {
         state: Arc<Mutex<State>>,
         cashiers: Vec<Cashier>,
         socks_url: Url,
+        compact_sync: bool,
     ) -> Result<Self> {
         let sol_tokenlist =
             TokenList::new(include_bytes!("../../../contrib/token/solana_token_list.json"))?;
@@ -177,11 +229,16 @@ impl Darkfid {
             drk_tokenlist,
             cashiers,
             socks_url,
+            compact_sync,
         })
     }
 
     async fn start(&mut self, executor: Arc<Executor<'_>>) -> Result<()> {
-        self.client.lock().await.start().await?;
+        if self.compact_sync {
+            self.client.lock().await.start_compact(self.state.clone(), executor.clone()).await?;
+        } else {
+            self.client.lock().await.start(executor.clone()).await?;
+        }
         self.client.lock().await.connect_to_subscriber(self.state.clone(), executor).await?;
 
         Ok(())
@@ -431,6 +488,34 @@ impl Darkfid {
         }
     }
 
+    // RPCAPI:
+    // Returns the request statistics of each configured gateway, latencies are in milliseconds.
+    // Nodes syncing over P2P return an empty list.
+    // --> {"jsonrpc": "2.0", "method": "get_gateway_metrics", "params": [], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": [{"address": "127.0.0.1:3333", ...}], "id": 1}
+    async fn get_gateway_metrics(&self, id: Value, _params: Value) -> JsonResult {
+        let metrics: Vec<Value> = self
+            .client
+            .lock()
+            .await
+            .gateway_metrics()
+            .iter()
+            .map(|(addr, metrics)| {
+                json!({
+                    "address": addr.to_string(),
+                    "replies": metrics.replies,
+                    "failures": metrics.failures,
+                    "timeouts": metrics.timeouts,
+                    "reconnects": metrics.reconnects,
+                    "last_latency": metrics.last_latency.as_millis() as u64,
+                    "average_latency": metrics.average_latency().as_millis() as u64,
+                })
+            })
+            .collect();
+
+        JsonResult::Resp(jsonresp(json!(metrics), id))
+    }
+
     // RPCAPI:
     // Generates the internal token ID for a given `network` and token ticker or address.
     // Returns the internal representation of the token ID.
@@ -826,20 +911,57 @@ async fn start(
         }
     }
 
-    let client = Client::new(
-        rocks.clone(),
-        (
+    let gateway_settings = if config.use_p2p {
+        let socks5_proxy = match &config.p2p_socks5_proxy {
+            Some(proxy) => Some(Url::parse(proxy)?),
+            None => None,
+        };
+
+        let hosts_path = match &config.p2p_hosts_path {
+            Some(path) => Some(expand_path(path)?),
+            None => None,
+        };
+
+        let mut participants = vec![];
+        for public_key in config.consensus_participants.iter() {
+            participants.push(PublicKey::try_from(Address::from_str(public_key)?)?);
+        }
+
+        GatewaySettings::P2p(
+            Settings {
+                peers: config.p2p_peers.clone(),
+                seeds: config.p2p_seeds.clone(),
+                socks5_proxy,
+                hosts_path,
+                metrics_addr: config.p2p_metrics_address,
+                ..Default::default()
+            },
+            participants,
+        )
+    } else {
+        let mut gateway_addrs = vec![(
             Url::try_from(config.gateway_url.clone())?,
             Url::try_from(config.gateway_pub_url.clone())?,
-        ),
-        wallet.clone(),
-    )
-    .await?;
+            PublicKey::try_from(Address::from_str(&config.gateway_public_key)?)?,
+        )];
+        for gateway in config.backup_gateways.clone() {
+            gateway_addrs.push((
+                Url::try_from(gateway.url)?,
+                Url::try_from(gateway.pub_url)?,
+                PublicKey::try_from(Address::from_str(&gateway.public_key)?)?,
+            ));
+        }
+
+        GatewaySettings::Zmq(gateway_addrs)
+    };
+
+    let client = Client::new(rocks.clone(), gateway_settings, wallet.clone()).await?;
 
     let client = Arc::new(Mutex::new(client));
 
     let tree = client.lock().await.get_tree().await?;
     let merkle_roots = RocksColumn::<columns::MerkleRoots>::new(rocks.clone());
+    let last_slab_index = client.lock().await.get_last_slab_index(&merkle_roots).await?;
     let nullifiers = RocksColumn::<columns::Nullifiers>::new(rocks);
 
     info!("Building verifying key for the mint contract...");
@@ -850,14 +972,22 @@ async fn start(
     let state = Arc::new(Mutex::new(State {
         tree,
         merkle_roots,
+        merkle_anchor_window: config.merkle_anchor_window,
+        last_slab_index,
         nullifiers,
         mint_vk,
         spend_vk,
         public_keys: cashier_keys,
     }));
 
-    let mut darkfid =
-        Darkfid::new(client, state, cashiers, Url::try_from(config.socks_url.clone())?).await?;
+    let mut darkfid = Darkfid::new(
+        client,
+        state,
+        cashiers,
+        Url::try_from(config.socks_url.clone())?,
+        config.compact_sync,
+    )
+    .await?;
 
     // TODO fix this
     let server_config = RpcServerConfig {
@@ -899,7 +1029,9 @@ async fn main() -> Result<()> {
             format!("sqlite://{}", expand_path(&config.wallet_path)?.to_str().unwrap());
         let wallet = WalletDb::new(&wallet_path, &config.wallet_password).await?;
 
+        wallet.init_db().await?;
         wallet.remove_own_coins().await?;
+        wallet.reset_tree().await?;
 
         if let Some(path) = expand_path(&config.database_path)?.to_str() {
             info!(target: "DARKFI DAEMON", "Remove database: {}", path);
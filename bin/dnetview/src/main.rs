@@ -30,7 +30,10 @@ use url::Url;
 
 use dnetview::{
     config::{DnvConfig, CONFIG_FILE_CONTENTS},
-    model::{Channel, IdList, InboundInfo, InfoList, ManualInfo, NodeInfo, OutboundInfo, Slot},
+    model::{
+        Channel, CrawlerInfo, IdList, InboundInfo, InfoList, ManualInfo, NodeInfo, OutboundInfo,
+        Slot,
+    },
     options::ProgramOptions,
     ui,
     view::{IdListView, InfoListView},
@@ -154,7 +157,7 @@ async fn poll(client: Map, model: Arc<Model>) -> Result<()> {
         if reply.as_object().is_some() && !reply.as_object().unwrap().is_empty() {
             //debug!("reply: {:?}", reply);
             // TODO: we are ignoring this value for now
-            let _ext_addr = reply.as_object().unwrap().get("external_addr");
+            let _ext_addrs = reply.as_object().unwrap().get("external_addrs");
 
             let inbound_obj = &reply.as_object().unwrap()["session_inbound"];
             let manual_obj = &reply.as_object().unwrap()["session_manual"];
@@ -227,7 +230,11 @@ async fn poll(client: Map, model: Arc<Model>) -> Result<()> {
             }
 
             debug!("OCONNECTS: {:?}", oconnects);
-            let infos = NodeInfo { outbound: oconnects, manual: mconnects, inbound: iconnects };
+            // parse crawl stats, only seed nodes have them
+            let crawler: Option<CrawlerInfo> = serde_json::from_value(reply["crawler"].clone())?;
+
+            let infos =
+                NodeInfo { outbound: oconnects, manual: mconnects, inbound: iconnects, crawler };
             let mut node_info = HashMap::new();
 
             // TODO: here we are setting the RPC url as the node_id.
@@ -46,14 +46,25 @@ pub struct NodeInfo {
     pub outbound: Vec<OutboundInfo>,
     pub manual: Vec<ManualInfo>,
     pub inbound: Vec<InboundInfo>,
+    pub crawler: Option<CrawlerInfo>,
 }
 
 impl NodeInfo {
     pub fn new() -> NodeInfo {
-        NodeInfo { outbound: Vec::new(), manual: Vec::new(), inbound: Vec::new() }
+        NodeInfo { outbound: Vec::new(), manual: Vec::new(), inbound: Vec::new(), crawler: None }
     }
 }
 
+// Crawl stats of seed nodes
+#[derive(Clone, Debug, PartialEq, Deserialize, Eq, Hash)]
+pub struct CrawlerInfo {
+    pub rounds: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub hosts: u64,
+    pub reachable: u64,
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Eq, Hash)]
 pub struct ManualInfo {
     pub key: u64,
@@ -110,7 +110,13 @@ pub fn ui<B: Backend>(f: &mut Frame<'_, B>, mut view: View) {
 
     for id in &view.id_list.node_id {
         let mut lines = vec![Spans::from(id.to_string())];
-        for _i in 1..prev_len {
+        if let Some(crawler) = view.info_list.infos.get(id).and_then(|info| info.crawler.as_ref()) {
+            lines.push(Spans::from(format!(
+                "Seed: {}/{} hosts reachable, {} crawls",
+                crawler.reachable, crawler.hosts, crawler.rounds
+            )));
+        }
+        for _i in lines.len()..prev_len {
             lines.push(Spans::from(""));
         }
         let ids = ListItem::new(lines).style(Style::default());
@@ -1,20 +1,51 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{
+    convert::TryInto,
+    fs,
+    future::Future,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use async_executor::Executor;
+use async_std::sync::{Arc, Mutex};
 use clap::{IntoApp, Parser};
 use easy_parallel::Parallel;
-use log::debug;
+use incrementalmerkletree::bridgetree::BridgeTree;
+use log::{debug, info, warn};
+use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
+use smol::stream::StreamExt;
+use url::Url;
 
 use darkfi::{
     blockchain::{rocks::columns, Rocks, RocksColumn},
-    node::service::gateway::GatewayService,
+    consensus::{ParticipantSettings, DELTA},
+    crypto::{
+        address::Address,
+        keypair::{PublicKey, SecretKey},
+        merkle_node::MerkleNode,
+        proof::VerifyingKey,
+    },
+    net::{NatPmp, NetAddr, Settings},
+    node::{
+        service::{
+            gateway::GatewayService,
+            gateway_p2p::{ConsensusSettings, Gateway},
+        },
+        state::{State, MERKLE_ANCHOR_WINDOW},
+    },
     util::{
         cli::{log_config, spawn_config, Config},
         expand_path, join_config_path,
     },
-    Result,
+    zk::circuit::{MintContract, SpendContract},
+    Error, Result,
 };
 
 /// The configuration for gatewayd
@@ -32,6 +63,65 @@ pub struct GatewaydConfig {
     pub tls_identity_password: String,
     /// Path to the database
     pub database_path: String,
+    /// Path to the gateway's secret key, created on first run
+    pub secret_key_path: String,
+    /// Cashier public keys allowed to sign clear inputs
+    #[serde(default)]
+    pub cashier_public_keys: Vec<String>,
+    /// Number of slabs a merkle root can be used as an anchor
+    #[serde(default = "default_merkle_anchor_window")]
+    pub merkle_anchor_window: u64,
+    /// Protocol endpoint of the primary gateway, when running as a follower
+    pub upstream_gateway_address: Option<SocketAddr>,
+    /// Public key of the primary gateway (Used if upstream_gateway_address
+    /// is set)
+    pub upstream_gateway_public_key: Option<String>,
+    /// Serve slabs over the P2P network instead of ZeroMQ
+    #[serde(default)]
+    pub use_p2p: bool,
+    /// P2P accept addresses, eg. an IPv4 and an IPv6 address (Used if
+    /// use_p2p=true)
+    #[serde(default)]
+    pub p2p_accept_addresses: Vec<SocketAddr>,
+    /// P2P addresses advertised to peers, eg. an onion address. Defaults
+    /// to the accept addresses, or to the discovered addresses when a
+    /// NAT-PMP gateway is set (Used if use_p2p=true)
+    #[serde(default)]
+    pub p2p_external_addresses: Vec<NetAddr>,
+    /// Router the P2P accept ports are mapped on with NAT-PMP (Used if
+    /// use_p2p=true)
+    pub p2p_nat_pmp_gateway: Option<IpAddr>,
+    /// P2P peers to connect to (Used if use_p2p=true)
+    #[serde(default)]
+    pub p2p_peers: Vec<NetAddr>,
+    /// P2P seed nodes (Used if use_p2p=true)
+    #[serde(default)]
+    pub p2p_seeds: Vec<NetAddr>,
+    /// SOCKS5 proxy for outbound P2P connections (Used if use_p2p=true)
+    pub p2p_socks5_proxy: Option<String>,
+    /// Path of the P2P host database (Used if use_p2p=true)
+    pub p2p_hosts_path: Option<String>,
+    /// Address serving the P2P metrics over HTTP (Used if use_p2p=true)
+    pub p2p_metrics_address: Option<SocketAddr>,
+    /// Public keys of the consensus participants ordering the slabs. We take
+    /// part when our own key is listed (Used if use_p2p=true)
+    #[serde(default)]
+    pub consensus_participants: Vec<String>,
+    /// Unix timestamp of the consensus genesis epoch (Used if use_p2p=true)
+    #[serde(default)]
+    pub consensus_genesis_time: u64,
+    /// Upper bound of the network delay in seconds, used by the consensus
+    /// participants (Used if use_p2p=true)
+    #[serde(default = "default_consensus_delta")]
+    pub consensus_delta: u64,
+}
+
+fn default_consensus_delta() -> u64 {
+    DELTA
+}
+
+fn default_merkle_anchor_window() -> u64 {
+    MERKLE_ANCHOR_WINDOW
 }
 
 /// Gatewayd cli
@@ -48,16 +138,169 @@ pub struct CliGatewayd {
 
 const CONFIG_FILE_CONTENTS: &[u8] = include_bytes!("../gatewayd_config.toml");
 
+/// Load the gateway's secret key, or create a new one if the file doesn't
+/// exist yet. Clients pin the matching public key in their config. The
+/// file is only readable by its owner.
+fn load_secret_key(path: &Path) -> Result<SecretKey> {
+    if path.exists() {
+        let mut permissions = fs::metadata(path)?.permissions();
+        if permissions.mode() & 0o077 != 0 {
+            warn!("Gateway secret key {:?} was readable by others, restricting it", path);
+            permissions.set_mode(0o600);
+            fs::set_permissions(path, permissions)?;
+        }
+
+        let bytes: [u8; 32] = fs::read(path)?
+            .try_into()
+            .map_err(|_| Error::ParseFailed("Invalid gateway secret key"))?;
+        return SecretKey::from_bytes(bytes)
+    }
+
+    let secret = SecretKey::random(&mut OsRng);
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(&secret.to_bytes())?;
+    info!("Created a new gateway secret key in {:?}", path);
+    Ok(secret)
+}
+
+/// Run `stop` once SIGINT or SIGTERM is received, to shut the gateway
+/// down gracefully.
+fn handle_signals<F>(executor: &Executor<'_>, stop: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut signals = Signals::new(&[SIGINT, SIGTERM])?;
+    executor
+        .spawn(async move {
+            if signals.next().await.is_some() {
+                info!("Caught termination signal, shutting down");
+                stop.await;
+            }
+        })
+        .detach();
+    Ok(())
+}
+
+/// Build the state transactions are verified against. The tree and the
+/// last slab index are rebuilt from the stored slabs when the gateway
+/// starts.
+fn build_state(config: &GatewaydConfig, rocks: &Arc<Rocks>) -> Result<Arc<Mutex<State>>> {
+    let mut cashier_keys = vec![];
+    for public_key in config.cashier_public_keys.iter() {
+        cashier_keys.push(PublicKey::try_from(Address::from_str(public_key)?)?);
+    }
+
+    info!("Building verifying key for the mint contract...");
+    let mint_vk = VerifyingKey::build(11, &MintContract::default());
+    info!("Building verifying key for the spend contract...");
+    let spend_vk = VerifyingKey::build(11, &SpendContract::default());
+
+    Ok(Arc::new(Mutex::new(State {
+        tree: BridgeTree::<MerkleNode, 32>::new(100),
+        merkle_roots: RocksColumn::<columns::MerkleRoots>::new(rocks.clone()),
+        merkle_anchor_window: config.merkle_anchor_window,
+        last_slab_index: 0,
+        nullifiers: RocksColumn::<columns::Nullifiers>::new(rocks.clone()),
+        mint_vk,
+        spend_vk,
+        public_keys: cashier_keys,
+    })))
+}
+
 async fn start(executor: Arc<Executor<'_>>, config: &GatewaydConfig) -> Result<()> {
     let rocks = Rocks::new(&expand_path(&config.database_path)?)?;
-    let rocks_slabstore_column = RocksColumn::<columns::Slabs>::new(rocks);
+
+    if config.use_p2p {
+        let external_addrs =
+            if config.p2p_external_addresses.is_empty() && config.p2p_nat_pmp_gateway.is_none() {
+                // Nodes listening on all interfaces discover their address
+                config
+                    .p2p_accept_addresses
+                    .iter()
+                    .filter(|addr| !addr.ip().is_unspecified())
+                    .map(|addr| NetAddr::from(*addr))
+                    .collect()
+            } else {
+                config.p2p_external_addresses.clone()
+            };
+        let socks5_proxy = match &config.p2p_socks5_proxy {
+            Some(proxy) => Some(Url::parse(proxy)?),
+            None => None,
+        };
+
+        let hosts_path = match &config.p2p_hosts_path {
+            Some(path) => Some(expand_path(path)?),
+            None => None,
+        };
+
+        let settings = Settings {
+            inbound: config.p2p_accept_addresses.clone(),
+            external_addrs,
+            port_mapper: config.p2p_nat_pmp_gateway.map(|gateway| NatPmp::new(gateway) as _),
+            peers: config.p2p_peers.clone(),
+            seeds: config.p2p_seeds.clone(),
+            socks5_proxy,
+            hosts_path,
+            metrics_addr: config.p2p_metrics_address,
+            ..Default::default()
+        };
+
+        let mut participants = vec![];
+        for public_key in config.consensus_participants.iter() {
+            participants.push(PublicKey::try_from(Address::from_str(public_key)?)?);
+        }
+
+        // The gateway's secret key is its participant identity
+        let secret = load_secret_key(&expand_path(&config.secret_key_path)?)?;
+        let public = PublicKey::from_secret(secret);
+        info!("Gateway public key: {}", Address::from(public));
+
+        let participant = if participants.contains(&public) {
+            Some(ParticipantSettings {
+                secret,
+                genesis_time: config.consensus_genesis_time,
+                delta: config.consensus_delta,
+                state: build_state(config, &rocks)?,
+            })
+        } else {
+            None
+        };
+        let consensus = ConsensusSettings { participants, participant };
+
+        let gateway = Gateway::new(settings, rocks, consensus).await?;
+        let gateway2 = gateway.clone();
+        handle_signals(&executor, async move { gateway2.stop().await })?;
+        return gateway.start(executor.clone()).await
+    }
+
+    let secret = load_secret_key(&expand_path(&config.secret_key_path)?)?;
+    info!("Gateway public key: {}", Address::from(PublicKey::from_secret(secret)));
+
+    let state = build_state(config, &rocks)?;
+
+    let upstream = match (config.upstream_gateway_address, &config.upstream_gateway_public_key) {
+        (Some(addr), Some(public_key)) => {
+            Some((addr, PublicKey::try_from(Address::from_str(public_key)?)?))
+        }
+        (Some(_), None) => return Err(Error::ParseFailed("Missing upstream_gateway_public_key")),
+        (None, _) => None,
+    };
+
+    let rocks_slabstore_column = RocksColumn::<columns::Slabs>::new(rocks.clone());
+    let rocks_nullifier_filters_column = RocksColumn::<columns::NullifierFilters>::new(rocks);
 
     let gateway = GatewayService::new(
         config.protocol_listen_address,
         config.publisher_listen_address,
+        secret,
         rocks_slabstore_column,
+        rocks_nullifier_filters_column,
+        state,
+        upstream,
     )?;
 
+    let gateway2 = gateway.clone();
+    handle_signals(&executor, async move { gateway2.stop().await })?;
     Ok(gateway.start(executor.clone()).await?)
 }
 
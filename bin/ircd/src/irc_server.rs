@@ -6,7 +6,7 @@ use std::net::TcpStream;
 
 use darkfi::{net, Error, Result};
 
-use crate::privmsg::{PrivMsg, SeenPrivMsgIdsPtr};
+use crate::privmsg::PrivMsg;
 
 /*
 NICK fifififif
@@ -27,7 +27,7 @@ PRIVMSG #dev hihi
 
 pub struct IrcServerConnection {
     write_stream: WriteHalf<Async<TcpStream>>,
-    seen_privmsg_ids: SeenPrivMsgIdsPtr,
+    gossip: net::GossipPtr<PrivMsg>,
     is_nick_init: bool,
     is_user_init: bool,
     is_registered: bool,
@@ -36,13 +36,10 @@ pub struct IrcServerConnection {
 }
 
 impl IrcServerConnection {
-    pub fn new(
-        write_stream: WriteHalf<Async<TcpStream>>,
-        seen_privmsg_ids: SeenPrivMsgIdsPtr,
-    ) -> Self {
+    pub fn new(write_stream: WriteHalf<Async<TcpStream>>, gossip: net::GossipPtr<PrivMsg>) -> Self {
         Self {
             write_stream,
-            seen_privmsg_ids,
+            gossip,
             is_nick_init: false,
             is_user_init: false,
             is_registered: false,
@@ -51,7 +48,7 @@ impl IrcServerConnection {
         }
     }
 
-    pub async fn update(&mut self, line: String, p2p: net::P2pPtr) -> Result<()> {
+    pub async fn update(&mut self, line: String) -> Result<()> {
         let mut tokens = line.split_ascii_whitespace();
         // Commands can begin with :garbage but we will reject clients doing that for now
         // to keep the protocol simple and focused.
@@ -101,16 +98,13 @@ impl IrcServerConnection {
                 let message = &line[substr_idx + 1..];
                 info!("Message {}: {}", channel, message);
 
-                let random_id = OsRng.next_u32();
-                self.seen_privmsg_ids.add_seen(random_id).await;
-
                 let protocol_msg = PrivMsg {
-                    id: random_id,
+                    id: OsRng.next_u32(),
                     nickname: self.nickname.clone(),
                     channel: channel.to_string(),
                     message: message.to_string(),
                 };
-                p2p.broadcast(protocol_msg).await?;
+                self.gossip.broadcast(protocol_msg).await?;
             }
             "QUIT" => {
                 // Close the connection
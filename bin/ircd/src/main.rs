@@ -11,8 +11,10 @@ use easy_parallel::Parallel;
 use futures::{AsyncBufReadExt, AsyncReadExt, FutureExt};
 use log::{debug, error, info, warn};
 use serde_json::{json, Value};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
-use smol::Async;
+use smol::{stream::StreamExt, Async};
 
 use darkfi::{
     net,
@@ -27,27 +29,24 @@ use darkfi::{
 mod irc_server;
 mod privmsg;
 mod program_options;
-mod protocol_privmsg;
 
 use crate::{
     irc_server::IrcServerConnection,
-    privmsg::{PrivMsg, SeenPrivMsgIds, SeenPrivMsgIdsPtr},
+    privmsg::{PrivMsg, SERVICE_PRIVMSG},
     program_options::ProgramOptions,
-    protocol_privmsg::ProtocolPrivMsg,
 };
 
 async fn process(
     recvr: async_channel::Receiver<Arc<PrivMsg>>,
     stream: Async<TcpStream>,
     peer_addr: SocketAddr,
-    p2p: net::P2pPtr,
-    seen_privmsg_ids: SeenPrivMsgIdsPtr,
+    gossip: net::GossipPtr<PrivMsg>,
     _executor: Arc<Executor<'_>>,
 ) -> Result<()> {
     let (reader, writer) = stream.split();
 
     let mut reader = BufReader::new(reader);
-    let mut connection = IrcServerConnection::new(writer, seen_privmsg_ids);
+    let mut connection = IrcServerConnection::new(writer, gossip);
 
     loop {
         let mut line = String::new();
@@ -69,7 +68,7 @@ async fn process(
                     warn!("Read line error. Closing stream for {}: {}", peer_addr, err);
                     return Ok(())
                 }
-                process_user_input(line, peer_addr, &mut connection, p2p.clone()).await?;
+                process_user_input(line, peer_addr, &mut connection).await?;
             }
         };
     }
@@ -79,7 +78,6 @@ async fn process_user_input(
     mut line: String,
     peer_addr: SocketAddr,
     connection: &mut IrcServerConnection,
-    p2p: net::P2pPtr,
 ) -> Result<()> {
     if line.is_empty() {
         warn!("Received empty line from {}. Closing connection.", peer_addr);
@@ -91,7 +89,7 @@ async fn process_user_input(
 
     debug!("Received '{}' from {}", line, peer_addr);
 
-    if let Err(err) = connection.update(line, p2p.clone()).await {
+    if let Err(err) = connection.update(line).await {
         warn!("Connection error: {} for {}", err, peer_addr);
         return Err(Error::ChannelStopped)
     }
@@ -124,24 +122,29 @@ async fn start(executor: Arc<Executor<'_>>, options: ProgramOptions) -> Result<(
         identity_pass: Default::default(),
     };
 
-    let seen_privmsg_ids = SeenPrivMsgIds::new();
-
     //
-    // PrivMsg protocol
+    // PrivMsg gossip
     //
     let p2p = net::P2p::new(options.network_settings).await;
-    let registry = p2p.protocol_registry();
-
+    let gossip = net::Gossip::<PrivMsg>::new(
+        p2p.clone(),
+        !net::SESSION_SEED,
+        SERVICE_PRIVMSG,
+        net::GossipSettings::default(),
+    )
+    .await;
+
+    // Queue the messages received from the network for the IRC clients
     let (sender, recvr) = async_channel::unbounded();
-    let seen_privmsg_ids2 = seen_privmsg_ids.clone();
-    let sender2 = sender.clone();
-    registry
-        .register(!net::SESSION_SEED, move |channel, p2p| {
-            let sender = sender2.clone();
-            let seen_privmsg_ids = seen_privmsg_ids2.clone();
-            async move { ProtocolPrivMsg::new(channel, sender, seen_privmsg_ids, p2p).await }
+    let privmsg_sub = gossip.subscribe().await;
+    executor
+        .spawn(async move {
+            loop {
+                let privmsg = privmsg_sub.receive().await;
+                sender.send(privmsg).await.expect("notify_queue_sender send failed!");
+            }
         })
-        .await;
+        .detach();
 
     //
     // p2p network main instance
@@ -149,15 +152,7 @@ async fn start(executor: Arc<Executor<'_>>, options: ProgramOptions) -> Result<(
     // Performs seed session
     p2p.clone().start(executor.clone()).await?;
     // Actual main p2p session
-    let ex2 = executor.clone();
-    let p2p2 = p2p.clone();
-    executor
-        .spawn(async move {
-            if let Err(err) = p2p2.run(ex2).await {
-                error!("Error: p2p run failed {}", err);
-            }
-        })
-        .detach();
+    let p2p_task = executor.spawn(p2p.clone().run(executor.clone()));
 
     //
     // RPC interface
@@ -173,22 +168,38 @@ async fn start(executor: Arc<Executor<'_>>, options: ProgramOptions) -> Result<(
     //
     // IRC instance
     //
-    loop {
-        let (stream, peer_addr) = match listener.accept().await {
-            Ok((s, a)) => (s, a),
-            Err(err) => {
-                error!("Error listening for connections: {}", err);
-                return Err(Error::ServiceStopped)
-            }
-        };
-        info!("Accepted client: {}", peer_addr);
+    let accept_loop = async {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok((s, a)) => (s, a),
+                Err(err) => {
+                    error!("Error listening for connections: {}", err);
+                    return Err(Error::ServiceStopped)
+                }
+            };
+            info!("Accepted client: {}", peer_addr);
 
-        let p2p2 = p2p.clone();
-        let ex2 = executor.clone();
-        executor
-            .spawn(process(recvr.clone(), stream, peer_addr, p2p2, seen_privmsg_ids.clone(), ex2))
-            .detach();
+            let ex2 = executor.clone();
+            executor.spawn(process(recvr.clone(), stream, peer_addr, gossip.clone(), ex2)).detach();
+        }
+    };
+
+    // Serve the IRC clients until SIGINT or SIGTERM is received
+    let mut signals = Signals::new(&[SIGINT, SIGTERM])?;
+    let result = smol::future::or(accept_loop, async {
+        signals.next().await;
+        info!("Caught termination signal, shutting down");
+        Ok::<(), Error>(())
+    })
+    .await;
+
+    // Shut down the network gracefully
+    p2p.stop().await;
+    if let Err(err) = p2p_task.await {
+        error!("Error: p2p run failed {}", err);
     }
+
+    result
 }
 
 struct JsonRpcInterface {
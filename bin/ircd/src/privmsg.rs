@@ -1,6 +1,4 @@
-use std::{collections::HashSet, io, sync::Arc};
-
-use async_std::sync::Mutex;
+use std::io;
 
 use darkfi::{
     net,
@@ -8,10 +6,15 @@ use darkfi::{
     Result,
 };
 
+/// Service flag of nodes relaying private messages.
+pub const SERVICE_PRIVMSG: net::ServiceBitflag = net::SERVICE_APP_START;
+
 pub type PrivMsgId = u32;
 
 #[derive(Debug, Clone)]
 pub struct PrivMsg {
+    /// Random nonce, so the same text sent twice gets a different
+    /// gossip id
     pub id: PrivMsgId,
     pub nickname: String,
     pub channel: String,
@@ -22,6 +25,10 @@ impl net::Message for PrivMsg {
     fn name() -> &'static str {
         "privmsg"
     }
+
+    fn max_size() -> usize {
+        8192
+    }
 }
 
 impl Encodable for PrivMsg {
@@ -45,23 +52,3 @@ impl Decodable for PrivMsg {
         })
     }
 }
-
-pub struct SeenPrivMsgIds {
-    privmsg_ids: Mutex<HashSet<PrivMsgId>>,
-}
-
-pub type SeenPrivMsgIdsPtr = Arc<SeenPrivMsgIds>;
-
-impl SeenPrivMsgIds {
-    pub fn new() -> Arc<Self> {
-        Arc::new(Self { privmsg_ids: Mutex::new(HashSet::new()) })
-    }
-
-    pub async fn add_seen(&self, id: u32) {
-        self.privmsg_ids.lock().await.insert(id);
-    }
-
-    pub async fn is_seen(&self, id: u32) -> bool {
-        self.privmsg_ids.lock().await.contains(&id)
-    }
-}
@@ -1,8 +1,11 @@
 use clap::{App, Arg, ArgMatches};
 use std::net::SocketAddr;
+use url::Url;
 
 use darkfi::{net, Result};
 
+use crate::privmsg::SERVICE_PRIVMSG;
+
 pub struct ProgramOptions {
     pub network_settings: net::Settings,
     pub log_path: Box<std::path::PathBuf>,
@@ -22,7 +25,8 @@ impl ProgramOptions {
                     .short('a')
                     .long("accept")
                     .value_name("ACCEPT")
-                    .help("Accept address")
+                    .help("Accept address, can be given several times (eg. IPv4 and IPv6)")
+                    .multiple_occurrences(true)
                     .takes_value(true),
             )
             .arg(
@@ -53,7 +57,29 @@ impl ProgramOptions {
                     .short('e')
                     .long("external")
                     .value_name("EXTERNAL_ADDR")
-                    .help("External address")
+                    .help("External address, can be given several times (eg. IPv4 and onion)")
+                    .multiple_occurrences(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("NAT_PMP")
+                    .long("nat-pmp")
+                    .value_name("GATEWAY")
+                    .help("Map the accept ports on this router with NAT-PMP (eg. 192.168.1.1)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("SOCKS5_PROXY")
+                    .long("socks5-proxy")
+                    .value_name("SOCKS5_PROXY")
+                    .help("SOCKS5 proxy for outbound connections (eg. socks5://127.0.0.1:9050)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("HOSTS_PATH")
+                    .long("hosts")
+                    .value_name("HOSTS_PATH")
+                    .help("Host database path")
                     .takes_value(true),
             )
             .arg(
@@ -78,6 +104,18 @@ impl ProgramOptions {
                     .help("RPC listen address")
                     .takes_value(true),
             )
+            .arg(
+                Arg::new("METRICS_LISTEN")
+                    .long("metrics")
+                    .value_name("METRICS_LISTEN")
+                    .help("Serve Prometheus metrics over HTTP on this address")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("REQUIRE_ENCRYPTION")
+                    .long("require-encryption")
+                    .help("Refuse peers that don't support channel encryption"),
+            )
             .arg(
                 Arg::new("verbose")
                     .short('v')
@@ -87,20 +125,21 @@ impl ProgramOptions {
             )
             .get_matches();
 
-        let accept_addr = if let Some(accept_addr) = app.value_of("ACCEPT") {
-            Some(accept_addr.parse()?)
-        } else {
-            None
-        };
+        let mut accept_addrs: Vec<SocketAddr> = vec![];
+        if let Some(addrs) = app.values_of("ACCEPT") {
+            for addr in addrs {
+                accept_addrs.push(addr.parse()?);
+            }
+        }
 
-        let mut seed_addrs: Vec<SocketAddr> = vec![];
+        let mut seed_addrs: Vec<net::NetAddr> = vec![];
         if let Some(seeds) = app.values_of("SEED_NODES") {
             for seed in seeds {
                 seed_addrs.push(seed.parse()?);
             }
         }
 
-        let mut manual_connects: Vec<SocketAddr> = vec![];
+        let mut manual_connects: Vec<net::NetAddr> = vec![];
         if let Some(connections) = app.values_of("CONNECTS") {
             for connect in connections {
                 manual_connects.push(connect.parse()?);
@@ -113,12 +152,28 @@ impl ProgramOptions {
             0
         };
 
-        let external_addr = if let Some(external_addr) = app.value_of("EXTERNAL_ADDR") {
-            Some(external_addr.parse()?)
+        let mut external_addrs: Vec<net::NetAddr> = vec![];
+        if let Some(addrs) = app.values_of("EXTERNAL_ADDR") {
+            for addr in addrs {
+                external_addrs.push(addr.parse()?);
+            }
+        }
+
+        let port_mapper: Option<net::PortMapperPtr> = if let Some(gateway) = app.value_of("NAT_PMP")
+        {
+            Some(net::NatPmp::new(gateway.parse()?))
         } else {
             None
         };
 
+        let socks5_proxy = if let Some(socks5_proxy) = app.value_of("SOCKS5_PROXY") {
+            Some(Url::parse(socks5_proxy)?)
+        } else {
+            None
+        };
+
+        let hosts_path = app.value_of("HOSTS_PATH").map(std::path::PathBuf::from);
+
         let log_path = Box::new(
             if let Some(log_path) = app.value_of("LOG_PATH") {
                 std::path::Path::new(log_path)
@@ -140,13 +195,25 @@ impl ProgramOptions {
             ([127, 0, 0, 1], 8000).into()
         };
 
+        let metrics_addr = if let Some(metrics_addr) = app.value_of("METRICS_LISTEN") {
+            Some(metrics_addr.parse()?)
+        } else {
+            None
+        };
+
         Ok(ProgramOptions {
             network_settings: net::Settings {
-                inbound: accept_addr,
+                inbound: accept_addrs,
                 outbound_connections: connection_slots,
-                external_addr,
+                external_addrs,
+                port_mapper,
                 peers: manual_connects,
                 seeds: seed_addrs,
+                socks5_proxy,
+                hosts_path,
+                require_encryption: app.is_present("REQUIRE_ENCRYPTION"),
+                services: net::SERVICE_RELAY | SERVICE_PRIVMSG,
+                metrics_addr,
                 ..Default::default()
             },
             log_path,
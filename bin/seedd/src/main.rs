@@ -0,0 +1,206 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use async_executor::Executor;
+use async_trait::async_trait;
+use clap::{IntoApp, Parser};
+use easy_parallel::Parallel;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
+use simplelog::{ColorChoice, TermLogger, TerminalMode};
+use smol::stream::StreamExt;
+use url::Url;
+
+use darkfi::{
+    net,
+    rpc::{
+        jsonrpc::{error as jsonerr, response as jsonresp, ErrorCode::*, JsonRequest, JsonResult},
+        rpcserver::{listen_and_serve, RequestHandler, RpcServerConfig},
+    },
+    util::{
+        cli::{log_config, spawn_config, Config},
+        expand_path, join_config_path,
+    },
+    Result,
+};
+
+/// The configuration for seedd
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SeeddConfig {
+    /// Addresses nodes connect to the seed on
+    pub accept_addresses: Vec<SocketAddr>,
+    /// Addresses advertised to peers
+    #[serde(default)]
+    pub external_addresses: Vec<net::NetAddr>,
+    /// Other seed nodes the host database is bootstrapped from
+    #[serde(default)]
+    pub seeds: Vec<net::NetAddr>,
+    /// Path of the host database
+    pub hosts_path: String,
+    /// Interval in seconds between two crawls of the known hosts
+    pub crawl_interval: u32,
+    /// SOCKS5 proxy for the crawler connections
+    pub socks5_proxy: Option<String>,
+    /// JSON-RPC listen address
+    pub rpc_listen_address: SocketAddr,
+    /// Address serving the P2P metrics over HTTP
+    pub metrics_address: Option<SocketAddr>,
+}
+
+/// Seedd cli
+#[derive(Parser)]
+#[clap(name = "seedd")]
+pub struct CliSeedd {
+    /// Sets a custom config file
+    #[clap(short, long)]
+    pub config: Option<String>,
+    /// Increase verbosity
+    #[clap(short, parse(from_occurrences))]
+    pub verbose: u8,
+}
+
+const CONFIG_FILE_CONTENTS: &[u8] = include_bytes!("../seedd_config.toml");
+
+async fn start(executor: Arc<Executor<'_>>, config: &SeeddConfig) -> Result<()> {
+    let socks5_proxy = match &config.socks5_proxy {
+        Some(proxy) => Some(Url::parse(proxy)?),
+        None => None,
+    };
+
+    let settings = net::Settings {
+        inbound: config.accept_addresses.clone(),
+        external_addrs: config.external_addresses.clone(),
+        seeds: config.seeds.clone(),
+        socks5_proxy,
+        hosts_path: Some(expand_path(&config.hosts_path)?),
+        metrics_addr: config.metrics_address,
+        seed_mode: true,
+        crawl_interval_seconds: config.crawl_interval,
+        ..Default::default()
+    };
+
+    let p2p = net::P2p::new(settings).await;
+
+    // The saved hosts are enough to keep crawling when the other seeds
+    // can't be reached
+    if let Err(err) = p2p.clone().start(executor.clone()).await {
+        warn!("Unable to query the seeds: {}", err);
+    }
+
+    let server_config = RpcServerConfig {
+        socket_addr: config.rpc_listen_address,
+        use_tls: false,
+        // this is all random filler that is meaningless bc tls is disabled
+        identity_path: Default::default(),
+        identity_pass: Default::default(),
+    };
+    let rpc_interface = Arc::new(JsonRpcInterface { p2p: p2p.clone() });
+    let ex2 = executor.clone();
+    executor
+        .spawn(async move {
+            if let Err(err) = listen_and_serve(server_config, rpc_interface, ex2).await {
+                error!("JSON-RPC server stopped: {}", err);
+            }
+        })
+        .detach();
+
+    // Shut down the network gracefully on SIGINT or SIGTERM
+    let mut signals = Signals::new(&[SIGINT, SIGTERM])?;
+    let p2p2 = p2p.clone();
+    executor
+        .spawn(async move {
+            if signals.next().await.is_some() {
+                info!("Caught termination signal, shutting down");
+                p2p2.stop().await;
+            }
+        })
+        .detach();
+
+    p2p.run(executor).await
+}
+
+struct JsonRpcInterface {
+    p2p: net::P2pPtr,
+}
+
+#[async_trait]
+impl RequestHandler for JsonRpcInterface {
+    async fn handle_request(&self, req: JsonRequest, _executor: Arc<Executor<'_>>) -> JsonResult {
+        if req.params.as_array().is_none() {
+            return JsonResult::Err(jsonerr(InvalidParams, None, req.id))
+        }
+
+        debug!(target: "RPC", "--> {}", serde_json::to_string(&req).unwrap());
+
+        match req.method.as_str() {
+            Some("ping") => self.pong(req.id, req.params).await,
+            Some("get_info") => self.get_info(req.id, req.params).await,
+            Some("get_crawl_stats") => self.get_crawl_stats(req.id, req.params).await,
+            Some(_) | None => JsonResult::Err(jsonerr(MethodNotFound, None, req.id)),
+        }
+    }
+}
+
+impl JsonRpcInterface {
+    // --> {"jsonrpc": "2.0", "method": "ping", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "pong", "id": 42}
+    async fn pong(&self, id: Value, _params: Value) -> JsonResult {
+        JsonResult::Resp(jsonresp(json!("pong"), id))
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_info", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"crawler": {...}, "session_inbound": {...}, ...}, "id": 42}
+    async fn get_info(&self, id: Value, _params: Value) -> JsonResult {
+        let resp = self.p2p.get_info().await;
+        JsonResult::Resp(jsonresp(resp, id))
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_crawl_stats", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"rounds": 3, "reachable": 120, ...}, "id": 42}
+    async fn get_crawl_stats(&self, id: Value, _params: Value) -> JsonResult {
+        let resp = self.p2p.crawler().await.get_info().await;
+        JsonResult::Resp(jsonresp(resp, id))
+    }
+}
+
+fn main() -> Result<()> {
+    let args = CliSeedd::parse();
+    let matches = CliSeedd::into_app().get_matches();
+
+    let config_path = match args.config {
+        Some(path) => expand_path(&path)?,
+        None => join_config_path(&PathBuf::from("seedd.toml"))?,
+    };
+
+    // Spawn config file if it's not in place already.
+    spawn_config(&config_path, CONFIG_FILE_CONTENTS)?;
+
+    let verbosity_level = matches.occurrences_of("verbose");
+    let (lvl, conf) = log_config(verbosity_level)?;
+    TermLogger::init(lvl, conf, TerminalMode::Mixed, ColorChoice::Auto)?;
+
+    let config: SeeddConfig = Config::<SeeddConfig>::load(config_path)?;
+
+    let ex = Arc::new(Executor::new());
+    let (signal, shutdown) = async_channel::unbounded::<()>();
+
+    let ex2 = ex.clone();
+
+    let nthreads = num_cpus::get();
+    debug!(target: "SEED DAEMON", "Run {} executor threads", nthreads);
+
+    let (_, result) = Parallel::new()
+        .each(0..nthreads, |_| smol::future::block_on(ex.run(shutdown.recv())))
+        // Run the main future on the current thread.
+        .finish(|| {
+            smol::future::block_on(async move {
+                start(ex2, &config).await?;
+                drop(signal);
+                Ok::<(), darkfi::Error>(())
+            })
+        });
+
+    result
+}
@@ -46,20 +46,20 @@ impl ProgramOptions {
     fn load() -> Result<ProgramOptions> {
         let programcli = DarkCli::parse();
 
-        let accept_addr = if let Some(accept_addr) = programcli.accept {
+        let accept_addr: Option<SocketAddr> = if let Some(accept_addr) = programcli.accept {
             Some(accept_addr.parse()?)
         } else {
             None
         };
 
-        let mut seed_addrs: Vec<SocketAddr> = vec![];
+        let mut seed_addrs: Vec<net::NetAddr> = vec![];
         if let Some(seeds) = programcli.seeds {
             for seed in seeds {
                 seed_addrs.push(seed.parse()?);
             }
         }
 
-        let mut manual_connects: Vec<SocketAddr> = vec![];
+        let mut manual_connects: Vec<net::NetAddr> = vec![];
         if let Some(connections) = programcli.connect {
             for connect in connections {
                 manual_connects.push(connect.parse()?);
@@ -80,9 +80,9 @@ impl ProgramOptions {
 
         Ok(ProgramOptions {
             network_settings: net::Settings {
-                inbound: accept_addr,
+                inbound: accept_addr.into_iter().collect(),
                 outbound_connections: connection_slots,
-                external_addr: accept_addr,
+                external_addrs: accept_addr.into_iter().map(net::NetAddr::from).collect(),
                 peers: manual_connects,
                 seeds: seed_addrs,
                 ..Default::default()
@@ -50,6 +50,11 @@ impl ProgramState for MemoryState {
         self.merkle_roots.iter().any(|m| m == merkle_root)
     }
 
+    fn is_stale_merkle(&self, _merkle_root: &MerkleNode) -> bool {
+        // All the roots are kept in memory for this example
+        false
+    }
+
     fn nullifier_exists(&self, nullifier: &Nullifier) -> bool {
         self.nullifiers.iter().any(|n| n == nullifier)
     }
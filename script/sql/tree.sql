@@ -2,3 +2,6 @@ CREATE TABLE IF NOT EXISTS tree(
 	tree BLOB NOT NULL
 );
 
+CREATE TABLE IF NOT EXISTS tree_index(
+	last_slab_index INTEGER NOT NULL
+);
@@ -21,6 +21,9 @@ pub mod columns {
     pub struct Slabs;
     pub struct Nullifiers;
     pub struct MerkleRoots;
+    pub struct NullifierFilters;
+    pub struct Blocks;
+    pub struct SlabCertificates;
 }
 
 impl Column for columns::Slabs {
@@ -35,6 +38,18 @@ impl Column for columns::MerkleRoots {
     const NAME: &'static str = "merkleroots";
 }
 
+impl Column for columns::NullifierFilters {
+    const NAME: &'static str = "nullifierfilters";
+}
+
+impl Column for columns::Blocks {
+    const NAME: &'static str = "blocks";
+}
+
+impl Column for columns::SlabCertificates {
+    const NAME: &'static str = "slabcertificates";
+}
+
 pub struct Rocks {
     db: DB,
 }
@@ -52,10 +67,27 @@ impl Rocks {
         // nullifiers column family
         let nullifiers_cf = ColumnFamilyDescriptor::new(columns::Nullifiers::NAME, cf_opts.clone());
         // merkleroots column family
-        let merkleroots_cf = ColumnFamilyDescriptor::new(columns::MerkleRoots::NAME, cf_opts);
+        let merkleroots_cf =
+            ColumnFamilyDescriptor::new(columns::MerkleRoots::NAME, cf_opts.clone());
+        // nullifier filters column family
+        let nullifierfilters_cf =
+            ColumnFamilyDescriptor::new(columns::NullifierFilters::NAME, cf_opts.clone());
+        // blocks column family
+        let blocks_cf = ColumnFamilyDescriptor::new(columns::Blocks::NAME, cf_opts.clone());
+        // slab certificates column family
+        let slabcertificates_cf =
+            ColumnFamilyDescriptor::new(columns::SlabCertificates::NAME, cf_opts);
 
         // column families
-        let cfs = vec![default_cf, slab_cf, nullifiers_cf, merkleroots_cf];
+        let cfs = vec![
+            default_cf,
+            slab_cf,
+            nullifiers_cf,
+            merkleroots_cf,
+            nullifierfilters_cf,
+            blocks_cf,
+            slabcertificates_cf,
+        ];
 
         // database options
         let mut opt = Options::default();
@@ -85,6 +117,11 @@ impl Rocks {
         Ok(val)
     }
 
+    pub fn delete_cf(&self, cf: &ColumnFamily, key: Vec<u8>) -> Result<()> {
+        self.db.delete_cf(cf, key)?;
+        Ok(())
+    }
+
     pub fn key_exist_cf(&self, cf: &ColumnFamily, key: Vec<u8>) -> Result<bool> {
         let val = self.db.get_cf(cf, key)?;
         Ok(val.is_some())
@@ -143,6 +180,13 @@ impl<T: Column> RocksColumn<T> {
         }
     }
 
+    pub fn delete(&self, key: impl Encodable) -> Result<()> {
+        let key = serialize(&key);
+        let cf = self.cf_handle()?;
+        self.rocks.delete_cf(cf, key)?;
+        Ok(())
+    }
+
     pub fn key_exist(&self, key: impl Encodable) -> Result<bool> {
         let key = serialize(&key);
         let cf = self.cf_handle()?;
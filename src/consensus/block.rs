@@ -0,0 +1,210 @@
+use std::io;
+
+use blake2b_simd::Params;
+
+use crate::{
+    crypto::{
+        keypair::SecretKey,
+        schnorr::{SchnorrSecret, Signature},
+    },
+    net,
+    tx::Transaction,
+    util::serial::{Decodable, Encodable},
+    Result,
+};
+
+use super::vote::Vote;
+
+const BLOCK_HASH_PERSONALIZATION: &[u8; 16] = b"DarkFi_BlockHash";
+
+/// Hash of the genesis block parent.
+pub const GENESIS_PARENT: [u8; 32] = [0; 32];
+
+/// Block proposed by the leader of epoch `sl`, extending the block whose
+/// hash is `st`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Previous block hash
+    pub st: [u8; 32],
+    /// Epoch the block was proposed in
+    pub sl: u64,
+    /// Transactions payload
+    pub txs: Vec<Transaction>,
+    /// Additional block information used by the consensus protocol
+    pub metadata: StreamletMetadata,
+}
+
+impl Block {
+    pub fn new(st: [u8; 32], sl: u64, txs: Vec<Transaction>) -> Self {
+        Self { st, sl, txs, metadata: StreamletMetadata::new() }
+    }
+
+    /// The genesis block is notarized and finalized by definition.
+    pub fn genesis_block() -> Self {
+        let mut block = Self::new(GENESIS_PARENT, 0, vec![]);
+        block.metadata.notarized = true;
+        block.metadata.finalized = true;
+        block
+    }
+
+    /// Hash of the block header and payload. Metadata is left out, since
+    /// it differs between nodes.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut encoded = vec![];
+        self.st.encode(&mut encoded).unwrap();
+        self.sl.encode(&mut encoded).unwrap();
+        self.txs.encode(&mut encoded).unwrap();
+
+        let hash = Params::new()
+            .hash_length(32)
+            .personal(BLOCK_HASH_PERSONALIZATION)
+            .to_state()
+            .update(&encoded)
+            .finalize();
+
+        let mut ret = [0u8; 32];
+        ret.copy_from_slice(hash.as_bytes());
+        ret
+    }
+}
+
+/// Votes and flags of a block, local to each node and left out of the
+/// block hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamletMetadata {
+    /// Epoch votes
+    pub votes: Vec<Vote>,
+    /// Block notarization flag
+    pub notarized: bool,
+    /// Block finalization flag
+    pub finalized: bool,
+}
+
+impl StreamletMetadata {
+    pub fn new() -> Self {
+        Self { votes: vec![], notarized: false, finalized: false }
+    }
+}
+
+impl Default for StreamletMetadata {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Block proposed by the epoch leader, signed with the leader secret key.
+#[derive(Debug, Clone)]
+pub struct BlockProposal {
+    /// Leader signature over the block hash
+    pub signature: Signature,
+    /// Leader index in the participants list
+    pub id: u64,
+    /// Proposed block
+    pub block: Block,
+}
+
+impl BlockProposal {
+    pub fn new(secret: &SecretKey, id: u64, block: Block) -> Self {
+        let signature = secret.sign(&block.hash());
+        Self { signature, id, block }
+    }
+}
+
+impl net::Message for BlockProposal {
+    fn name() -> &'static str {
+        "proposal"
+    }
+
+    fn max_size() -> usize {
+        4 * 1024 * 1024
+    }
+}
+
+// Transactions are relayed between participants, so the epoch leader can
+// include them in its proposal.
+impl net::Message for Transaction {
+    fn name() -> &'static str {
+        "tx"
+    }
+}
+
+impl Encodable for Block {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.st.encode(&mut s)?;
+        len += self.sl.encode(&mut s)?;
+        len += self.txs.encode(&mut s)?;
+        len += self.metadata.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Block {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            st: Decodable::decode(&mut d)?,
+            sl: Decodable::decode(&mut d)?,
+            txs: Decodable::decode(&mut d)?,
+            metadata: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl Encodable for StreamletMetadata {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.votes.encode(&mut s)?;
+        len += self.notarized.encode(&mut s)?;
+        len += self.finalized.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for StreamletMetadata {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            votes: Decodable::decode(&mut d)?,
+            notarized: Decodable::decode(&mut d)?,
+            finalized: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl Encodable for BlockProposal {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.signature.encode(&mut s)?;
+        len += self.id.encode(&mut s)?;
+        len += self.block.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for BlockProposal {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            signature: Decodable::decode(&mut d)?,
+            id: Decodable::decode(&mut d)?,
+            block: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::serial::{deserialize, serialize};
+
+    #[test]
+    fn block_hash_ignores_metadata() {
+        let genesis = Block::genesis_block();
+        let mut block = Block::new(genesis.hash(), 1, vec![]);
+        let hash = block.hash();
+
+        block.metadata.notarized = true;
+        assert_eq!(block.hash(), hash);
+
+        let block2: Block = deserialize(&serialize(&block)).unwrap();
+        assert_eq!(block, block2);
+    }
+}
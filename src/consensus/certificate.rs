@@ -0,0 +1,186 @@
+use std::io;
+
+use blake2b_simd::Params;
+
+use crate::{
+    blockchain::Slab,
+    crypto::{
+        keypair::{PublicKey, SecretKey},
+        schnorr::{SchnorrPublic, SchnorrSecret, Signature},
+    },
+    impl_vec, net,
+    util::serial::{serialize, Decodable, Encodable, VarInt},
+    Result,
+};
+
+const SLAB_HASH_PERSONALIZATION: &[u8; 16] = b"DarkFi_SlabHash_";
+
+/// Hash of a slab, covering its index and payload.
+pub fn slab_hash(slab: &Slab) -> [u8; 32] {
+    let hash = Params::new()
+        .hash_length(32)
+        .personal(SLAB_HASH_PERSONALIZATION)
+        .to_state()
+        .update(&serialize(slab))
+        .finalize();
+
+    let mut ret = [0u8; 32];
+    ret.copy_from_slice(hash.as_bytes());
+    ret
+}
+
+/// Signature of a participant over a slab appended from a finalized block.
+/// Every participant appends the same slabs, so they all sign the same
+/// hashes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlabVote {
+    /// Signature over the slab hash
+    pub signature: Signature,
+    /// Hash of the signed slab
+    pub slab: [u8; 32],
+    /// Index of the signed slab
+    pub index: u64,
+    /// Signer index in the participants list
+    pub id: u64,
+}
+
+impl SlabVote {
+    pub fn new(secret: &SecretKey, slab: &Slab, id: u64) -> Self {
+        let hash = slab_hash(slab);
+        let signature = secret.sign(&hash);
+        Self { signature, slab: hash, index: slab.get_index(), id }
+    }
+
+    pub fn verify(&self, public: &PublicKey) -> bool {
+        public.verify(&self.slab, &self.signature)
+    }
+}
+
+/// Signatures of more than 2n/3 participants over a slab. Nodes which
+/// don't take part in the consensus only accept slabs carrying one, so
+/// no single peer can make them store a slab the participants didn't
+/// order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlabCertificate {
+    pub votes: Vec<SlabVote>,
+}
+
+impl SlabCertificate {
+    /// Check the certificate holds valid signatures of more than 2n/3
+    /// distinct participants over the slab. Certificates with more votes
+    /// than participants, or several votes of the same participant, are
+    /// rejected before checking any signature.
+    pub fn verify(&self, slab: &Slab, participants: &[PublicKey]) -> bool {
+        if self.votes.len() > participants.len() {
+            return false
+        }
+
+        let mut ids: Vec<u64> = self.votes.iter().map(|vote| vote.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.len() != self.votes.len() {
+            return false
+        }
+
+        let hash = slab_hash(slab);
+        let mut signers = 0;
+        for vote in self.votes.iter() {
+            if vote.slab != hash || vote.index != slab.get_index() {
+                continue
+            }
+
+            match participants.get(vote.id as usize) {
+                Some(public) if vote.verify(public) => signers += 1,
+                _ => {}
+            }
+        }
+
+        signers > 2 * participants.len() / 3
+    }
+}
+
+impl net::Message for SlabVote {
+    fn name() -> &'static str {
+        "slabvote"
+    }
+}
+
+impl Encodable for SlabVote {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.signature.encode(&mut s)?;
+        len += self.slab.encode(&mut s)?;
+        len += self.index.encode(&mut s)?;
+        len += self.id.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SlabVote {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            signature: Decodable::decode(&mut d)?,
+            slab: Decodable::decode(&mut d)?,
+            index: Decodable::decode(&mut d)?,
+            id: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl_vec!(SlabVote);
+
+impl Encodable for SlabCertificate {
+    fn encode<S: io::Write>(&self, s: S) -> Result<usize> {
+        self.votes.encode(s)
+    }
+}
+
+impl Decodable for SlabCertificate {
+    fn decode<D: io::Read>(d: D) -> Result<Self> {
+        Ok(Self { votes: Decodable::decode(d)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn certificate_threshold() {
+        let secrets: Vec<SecretKey> = (0..4).map(|_| SecretKey::random(&mut OsRng)).collect();
+        let participants: Vec<PublicKey> =
+            secrets.iter().map(|secret| PublicKey::from_secret(*secret)).collect();
+
+        let mut slab = Slab::new(vec![1, 2, 3]);
+        slab.set_index(1);
+        let sign = |id: u64| SlabVote::new(&secrets[id as usize], &slab, id);
+
+        // Two votes out of four, or the same vote twice, aren't enough
+        let certificate = SlabCertificate { votes: vec![sign(0), sign(1)] };
+        assert!(!certificate.verify(&slab, &participants));
+        let certificate = SlabCertificate { votes: vec![sign(0), sign(1), sign(1)] };
+        assert!(!certificate.verify(&slab, &participants));
+
+        let certificate = SlabCertificate { votes: vec![sign(0), sign(1), sign(3)] };
+        assert!(certificate.verify(&slab, &participants));
+
+        // Duplicate voters and more votes than participants are rejected
+        let certificate = SlabCertificate { votes: vec![sign(0), sign(1), sign(3), sign(3)] };
+        assert!(!certificate.verify(&slab, &participants));
+        let votes = vec![sign(0), sign(1), sign(2), sign(3), sign(0)];
+        assert!(!SlabCertificate { votes }.verify(&slab, &participants));
+
+        // The certificate doesn't hold for another slab at the same index
+        let mut other = Slab::new(vec![4]);
+        other.set_index(1);
+        assert!(!certificate.verify(&other, &participants));
+
+        // A vote signed with another key is ignored
+        let mut forged = sign(2);
+        forged.id = 3;
+        let certificate = SlabCertificate { votes: vec![sign(0), sign(1), forged] };
+        assert!(!certificate.verify(&slab, &participants));
+    }
+}
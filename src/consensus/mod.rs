@@ -0,0 +1,21 @@
+/// Blocks and block proposals, with their Streamlet metadata.
+pub mod block;
+
+/// Participant signatures over the slabs appended from finalized blocks.
+pub mod certificate;
+
+/// P2P protocol exchanging block proposals and votes between participants.
+pub mod protocol;
+
+/// Participant state: leader schedule, notarization and finalization rules,
+/// and persistence of finalized blocks.
+pub mod state;
+
+/// Signed votes on proposed blocks.
+pub mod vote;
+
+pub use block::{Block, BlockProposal};
+pub use certificate::{SlabCertificate, SlabVote};
+pub use protocol::ProtocolConsensus;
+pub use state::{ConsensusEvent, ConsensusState, ConsensusStatePtr, ParticipantSettings};
+pub use vote::Vote;
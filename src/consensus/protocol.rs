@@ -0,0 +1,173 @@
+use async_executor::Executor;
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use log::debug;
+
+use crate::{
+    net::{
+        ChannelPtr, MessageSubscription, P2pPtr, ProtocolBase, ProtocolBasePtr,
+        ProtocolJobsManager, ProtocolJobsManagerPtr,
+    },
+    tx::Transaction,
+    Result,
+};
+
+use super::{block::BlockProposal, certificate::SlabVote, state::ConsensusStatePtr, vote::Vote};
+
+/// Exchanges transactions, block proposals, votes and slab votes between
+/// consensus participants. New messages are relayed to every other
+/// channel, so participants don't need to be directly connected.
+pub struct ProtocolConsensus {
+    tx_sub: MessageSubscription<Transaction>,
+    proposal_sub: MessageSubscription<BlockProposal>,
+    vote_sub: MessageSubscription<Vote>,
+    slab_vote_sub: MessageSubscription<SlabVote>,
+    jobsman: ProtocolJobsManagerPtr,
+    state: ConsensusStatePtr,
+    p2p: P2pPtr,
+}
+
+impl ProtocolConsensus {
+    pub async fn new(
+        channel: ChannelPtr,
+        state: ConsensusStatePtr,
+        p2p: P2pPtr,
+    ) -> ProtocolBasePtr {
+        let message_subsytem = channel.get_message_subsystem();
+        message_subsytem.add_dispatch::<Transaction>().await;
+        message_subsytem.add_dispatch::<BlockProposal>().await;
+        message_subsytem.add_dispatch::<Vote>().await;
+        message_subsytem.add_dispatch::<SlabVote>().await;
+
+        let tx_sub =
+            channel.clone().subscribe_msg::<Transaction>().await.expect("Missing tx dispatcher!");
+
+        let proposal_sub = channel
+            .clone()
+            .subscribe_msg::<BlockProposal>()
+            .await
+            .expect("Missing proposal dispatcher!");
+
+        let vote_sub =
+            channel.clone().subscribe_msg::<Vote>().await.expect("Missing vote dispatcher!");
+
+        let slab_vote_sub = channel
+            .clone()
+            .subscribe_msg::<SlabVote>()
+            .await
+            .expect("Missing slab vote dispatcher!");
+
+        Arc::new(Self {
+            tx_sub,
+            proposal_sub,
+            vote_sub,
+            slab_vote_sub,
+            jobsman: ProtocolJobsManager::new("ProtocolConsensus", channel),
+            state,
+            p2p,
+        })
+    }
+
+    async fn handle_receive_tx(self: Arc<Self>) -> Result<()> {
+        debug!(target: "CONSENSUS", "ProtocolConsensus::handle_receive_tx() [START]");
+        loop {
+            let tx = self.tx_sub.receive().await?;
+
+            let added = match self.state.lock().await.append_tx((*tx).clone()).await {
+                Ok(added) => added,
+                Err(e) => {
+                    debug!(target: "CONSENSUS", "Rejected transaction: {}", e);
+                    false
+                }
+            };
+
+            if added {
+                self.p2p.broadcast((*tx).clone()).await?;
+            }
+        }
+    }
+
+    async fn handle_receive_proposal(self: Arc<Self>) -> Result<()> {
+        debug!(target: "CONSENSUS", "ProtocolConsensus::handle_receive_proposal() [START]");
+        loop {
+            let proposal = self.proposal_sub.receive().await?;
+
+            // The state is released before broadcasting, so slow channels
+            // don't hold up the other handlers.
+            let vote = {
+                let mut state = self.state.lock().await;
+                if state.is_known_block(&proposal.block.hash()) {
+                    continue
+                }
+
+                let epoch = state.current_epoch();
+                let vote = match state.receive_proposal(&proposal, epoch).await {
+                    Ok(vote) => vote,
+                    Err(e) => {
+                        debug!(target: "CONSENSUS", "Rejected proposal: {}", e);
+                        continue
+                    }
+                };
+
+                if let Some(vote) = &vote {
+                    state.receive_vote(vote).await?;
+                }
+                vote
+            };
+
+            self.p2p.broadcast((*proposal).clone()).await?;
+
+            if let Some(vote) = vote {
+                self.p2p.broadcast(vote).await?;
+            }
+        }
+    }
+
+    async fn handle_receive_vote(self: Arc<Self>) -> Result<()> {
+        debug!(target: "CONSENSUS", "ProtocolConsensus::handle_receive_vote() [START]");
+        loop {
+            let vote = self.vote_sub.receive().await?;
+
+            let result = self.state.lock().await.receive_vote(&vote).await;
+            match result {
+                Ok(true) => self.p2p.broadcast((*vote).clone()).await?,
+                Ok(false) => {}
+                Err(e) => debug!(target: "CONSENSUS", "Rejected vote: {}", e),
+            }
+        }
+    }
+
+    async fn handle_receive_slab_vote(self: Arc<Self>) -> Result<()> {
+        debug!(target: "CONSENSUS", "ProtocolConsensus::handle_receive_slab_vote() [START]");
+        loop {
+            let vote = self.slab_vote_sub.receive().await?;
+
+            let result = self.state.lock().await.receive_slab_vote(&vote);
+            match result {
+                Ok(true) => self.p2p.broadcast((*vote).clone()).await?,
+                Ok(false) => {}
+                Err(e) => debug!(target: "CONSENSUS", "Rejected slab vote: {}", e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolBase for ProtocolConsensus {
+    /// Starts the consensus protocol. Runs the transaction, proposal, vote
+    /// and slab vote handlers on the protocol task manager.
+    async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+        debug!(target: "CONSENSUS", "ProtocolConsensus::start() [START]");
+        self.jobsman.clone().start(executor.clone());
+        self.jobsman.clone().spawn(self.clone().handle_receive_tx(), executor.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_receive_proposal(), executor.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_receive_vote(), executor.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_receive_slab_vote(), executor.clone()).await;
+        debug!(target: "CONSENSUS", "ProtocolConsensus::start() [END]");
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "ProtocolConsensus"
+    }
+}
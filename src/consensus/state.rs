@@ -0,0 +1,969 @@
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_std::sync::{Arc, Mutex};
+use blake2b_simd::Params;
+use log::{debug, error, info, warn};
+
+use crate::{
+    blockchain::{
+        rocks::{columns, IteratorMode},
+        Rocks, RocksColumn, Slab, SlabStore,
+    },
+    crypto::{
+        keypair::{PublicKey, SecretKey},
+        schnorr::SchnorrPublic,
+    },
+    net::P2pPtr,
+    node::{
+        mempool::{Mempool, MEMPOOL_BUNDLE_SIZE},
+        state::State,
+    },
+    tx::Transaction,
+    util::{
+        serial::{deserialize, serialize},
+        sleep,
+    },
+    Error, Result,
+};
+
+use super::{
+    block::{Block, BlockProposal},
+    certificate::{slab_hash, SlabCertificate, SlabVote},
+    vote::Vote,
+};
+
+/// Default upper bound of the network delay in seconds. An epoch lasts
+/// `2 * delta`.
+pub const DELTA: u64 = 5;
+
+/// Maximum number of votes buffered per participant for blocks we haven't
+/// received yet. The oldest one is dropped when the limit is reached.
+const MAX_PENDING_VOTES: usize = 16;
+
+/// Number of slabs past the last certified one we accept slab votes for.
+const MAX_SLAB_VOTES_AHEAD: u64 = 16;
+
+const LEADER_PERSONALIZATION: &[u8; 16] = b"DarkFi_EpochLdr_";
+
+pub type ConsensusStatePtr = Arc<Mutex<ConsensusState>>;
+
+/// Settings of a node taking part in the consensus.
+pub struct ParticipantSettings {
+    /// Our participant secret key
+    pub secret: SecretKey,
+    /// Unix timestamp of the genesis epoch
+    pub genesis_time: u64,
+    /// Upper bound of the network delay in seconds
+    pub delta: u64,
+    /// State the transactions are verified against
+    pub state: Arc<Mutex<State>>,
+}
+
+/// Outcome of the consensus to pass on to the network, sent once the
+/// state lock is released.
+pub enum ConsensusEvent {
+    /// Our signature over a slab appended from a finalized block
+    SlabVote(SlabVote),
+    /// Slab signed by more than 2n/3 participants, in index order
+    SlabCertified(Slab, SlabCertificate),
+}
+
+/// Streamlet consensus state of a participant.
+///
+/// Participants are a fixed, ordered list of public keys known to every
+/// node. Each epoch has a single leader who proposes a block extending the
+/// longest notarized chain it has seen. A block is notarized once it gets
+/// votes from more than 2n/3 participants, and when three adjacent blocks
+/// with consecutive epochs are notarized, the chain up to the middle one
+/// is finalized. Finalized blocks are persisted, and the transactions of
+/// each block are appended to the slab store as one slab.
+///
+/// Transactions and proposed blocks are verified against the state built
+/// from the finalized blocks, and each participant votes at most once per
+/// epoch. Participants then sign each appended slab, and a slab signed by
+/// more than 2n/3 of them gets a certificate, which other nodes check
+/// before storing it.
+pub struct ConsensusState {
+    /// Our index in the participants list
+    id: u64,
+    secret: SecretKey,
+    participants: Vec<PublicKey>,
+    /// Unix timestamp of the genesis epoch
+    genesis_time: u64,
+    /// Upper bound of the network delay in seconds
+    delta: u64,
+    /// Last finalized block, every fork extends it
+    last_finalized: Block,
+    /// Chains of not yet finalized blocks. A block extending a notarized
+    /// block inside a fork starts a new fork holding a copy of the blocks
+    /// up to its parent.
+    forks: Vec<Vec<Block>>,
+    /// Verified transactions not yet included in a finalized block
+    mempool: Mempool,
+    /// Last epoch we voted in
+    last_voted_epoch: Option<u64>,
+    /// Votes that arrived before their block, oldest first
+    pending_votes: Vec<Vote>,
+    blocks: RocksColumn<columns::Blocks>,
+    slabstore: Arc<SlabStore>,
+    certificates: RocksColumn<columns::SlabCertificates>,
+    /// Every slab up to this index has a certificate
+    last_certified: u64,
+    /// Slab votes for the slabs past the last certified one
+    slab_votes: Vec<SlabVote>,
+    events_s: async_channel::Sender<ConsensusEvent>,
+}
+
+impl ConsensusState {
+    pub fn new(
+        participants: Vec<PublicKey>,
+        settings: ParticipantSettings,
+        rocks: Arc<Rocks>,
+        slabstore: Arc<SlabStore>,
+        events_s: async_channel::Sender<ConsensusEvent>,
+    ) -> Result<Self> {
+        let secret = settings.secret;
+        let public = PublicKey::from_secret(secret);
+        let id = match participants.iter().position(|p| *p == public) {
+            Some(id) => id as u64,
+            None => return Err(Error::ConsensusError("Secret key is not a participant")),
+        };
+
+        let blocks = RocksColumn::<columns::Blocks>::new(rocks.clone());
+        let certificates = RocksColumn::<columns::SlabCertificates>::new(rocks);
+
+        // Resume from the last persisted block, or start from genesis.
+        let mut last_finalized: Option<Block> = None;
+        for (_, value) in blocks.iterator(IteratorMode::Start)? {
+            let block: Block = deserialize(&value)?;
+            if last_finalized.as_ref().map_or(true, |last| block.sl > last.sl) {
+                last_finalized = Some(block);
+            }
+        }
+
+        let last_finalized = match last_finalized {
+            Some(block) => block,
+            None => {
+                let genesis = Block::genesis_block();
+                blocks.put(genesis.sl, genesis.clone())?;
+                genesis
+            }
+        };
+
+        let mut last_certified = 0;
+        while certificates.key_exist(last_certified + 1)? {
+            last_certified += 1;
+        }
+
+        let mut consensus = Self {
+            id,
+            secret,
+            participants,
+            genesis_time: settings.genesis_time,
+            delta: settings.delta,
+            last_finalized,
+            forks: vec![],
+            mempool: Mempool::new(settings.state),
+            last_voted_epoch: None,
+            pending_votes: vec![],
+            blocks,
+            slabstore,
+            certificates,
+            last_certified,
+            slab_votes: vec![],
+            events_s,
+        };
+
+        // Votes from before a restart are lost, sign the slabs which
+        // weren't certified yet again.
+        for index in (last_certified + 1)..(consensus.slabstore.get_last_index()? + 1) {
+            if let Some(slab) = consensus.slabstore.get(serialize(&index))? {
+                consensus.sign_slab(&deserialize(&slab)?)?;
+            }
+        }
+
+        Ok(consensus)
+    }
+
+    pub fn get_last_finalized(&self) -> &Block {
+        &self.last_finalized
+    }
+
+    /// Index of the last slab such that every slab up to it is certified.
+    pub fn last_certified(&self) -> u64 {
+        self.last_certified
+    }
+
+    /// Epoch calculated from the elapsed time since genesis.
+    pub fn current_epoch(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        now.saturating_sub(self.genesis_time) / (2 * self.delta)
+    }
+
+    /// Seconds remaining until the start of the next epoch.
+    fn next_epoch_start(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let elapsed = now.saturating_sub(self.genesis_time);
+        2 * self.delta - (elapsed % (2 * self.delta))
+    }
+
+    /// The leader schedule is derived from a hash of the epoch, so every
+    /// participant computes the same leader without communication.
+    pub fn epoch_leader(&self, epoch: u64) -> u64 {
+        let hash = Params::new()
+            .hash_length(8)
+            .personal(LEADER_PERSONALIZATION)
+            .to_state()
+            .update(&epoch.to_le_bytes())
+            .finalize();
+
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(hash.as_bytes());
+        u64::from_le_bytes(buf) % self.participants.len() as u64
+    }
+
+    pub fn is_epoch_leader(&self, epoch: u64) -> bool {
+        self.epoch_leader(epoch) == self.id
+    }
+
+    /// Verify a transaction and add it to the mempool, to be included in
+    /// a future proposal. Returns false if we already had it.
+    pub async fn append_tx(&mut self, tx: Transaction) -> Result<bool> {
+        if self.mempool.contains(&tx) {
+            return Ok(false)
+        }
+
+        self.mempool.add_tx(tx).await?;
+        Ok(true)
+    }
+
+    /// Nullifiers revealed by the transactions of the fork blocks before
+    /// `len`, which aren't applied to the state yet.
+    fn fork_nullifiers(&self, fork: Option<usize>, len: usize) -> HashSet<[u8; 32]> {
+        let blocks: &[Block] = match fork {
+            Some(index) => &self.forks[index][..len],
+            None => &[],
+        };
+
+        blocks
+            .iter()
+            .flat_map(|block| block.txs.iter())
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.revealed.nullifier.to_bytes()))
+            .collect()
+    }
+
+    /// Last block of the longest notarized chain, made of the notarized
+    /// blocks at the start of a fork, or the last finalized block if no
+    /// fork starts with a notarized block. Returned along with the fork
+    /// index and the chain length.
+    fn longest_notarized_chain(&self) -> (&Block, Option<usize>, usize) {
+        let mut tip = &self.last_finalized;
+        let mut index = None;
+        let mut length = 0;
+        for (i, fork) in self.forks.iter().enumerate() {
+            let notarized = fork.iter().take_while(|block| block.metadata.notarized).count();
+            if notarized > length {
+                tip = &fork[notarized - 1];
+                index = Some(i);
+                length = notarized;
+            }
+        }
+        (tip, index, length)
+    }
+
+    /// Build a block proposal for the given epoch if we are its leader.
+    /// The block holds the mempool transactions which are still valid on
+    /// top of the chain it extends.
+    pub async fn propose_block(&self, epoch: u64) -> Option<BlockProposal> {
+        if !self.is_epoch_leader(epoch) {
+            return None
+        }
+
+        let (tip, index, length) = self.longest_notarized_chain();
+        let mut spent = self.fork_nullifiers(index, length);
+
+        let mut txs = vec![];
+        for tx in self.mempool.txs() {
+            if txs.len() >= MEMPOOL_BUNDLE_SIZE {
+                break
+            }
+
+            if self.forks.iter().flatten().any(|block| block.txs.contains(tx)) {
+                continue
+            }
+
+            if let Err(e) = self.mempool.verify_txs(std::slice::from_ref(tx), &mut spent).await {
+                debug!(target: "CONSENSUS", "Leaving transaction out of proposal: {}", e);
+                continue
+            }
+
+            txs.push(tx.clone());
+        }
+
+        let block = Block::new(tip.hash(), epoch, txs);
+        Some(BlockProposal::new(&self.secret, self.id, block))
+    }
+
+    /// Check if the block is already part of one of our forks.
+    pub fn is_known_block(&self, hash: &[u8; 32]) -> bool {
+        self.forks.iter().flatten().any(|block| block.hash() == *hash)
+    }
+
+    /// Verify a proposal for the given epoch and add its block to the fork
+    /// it extends. Returns our vote when the block extends the longest
+    /// notarized chain and we haven't voted in this epoch yet.
+    pub async fn receive_proposal(
+        &mut self,
+        proposal: &BlockProposal,
+        epoch: u64,
+    ) -> Result<Option<Vote>> {
+        let block = &proposal.block;
+        if block.sl != epoch {
+            return Err(Error::ConsensusError("Proposal is not for the current epoch"))
+        }
+
+        if proposal.id != self.epoch_leader(epoch) {
+            return Err(Error::ConsensusError("Proposal is not from the epoch leader"))
+        }
+
+        let hash = block.hash();
+        if !self.participants[proposal.id as usize].verify(&hash, &proposal.signature) {
+            return Err(Error::ConsensusError("Invalid proposal signature"))
+        }
+
+        if self.is_known_block(&hash) {
+            return Ok(None)
+        }
+
+        if block.txs.len() > MEMPOOL_BUNDLE_SIZE {
+            return Err(Error::ConsensusError("Proposal has too many transactions"))
+        }
+
+        // The transactions must be valid on top of the chain the block
+        // extends, so finalized blocks never hold invalid transactions.
+        let (extended, parent_len) = self.find_extended_fork(block)?;
+        let mut spent = self.fork_nullifiers(extended, parent_len);
+        if let Err(e) = self.mempool.verify_txs(&block.txs, &mut spent).await {
+            debug!(target: "CONSENSUS", "Invalid transaction in proposal: {}", e);
+            return Err(Error::ConsensusError("Proposal holds an invalid transaction"))
+        }
+
+        // Votes and flags are local to each node.
+        let block = Block::new(block.st, block.sl, block.txs.clone());
+
+        let (_, _, longest) = self.longest_notarized_chain();
+        let index = match extended {
+            Some(index) if parent_len == self.forks[index].len() => {
+                self.forks[index].push(block);
+                index
+            }
+            Some(index) => {
+                let mut fork = self.forks[index][..parent_len].to_vec();
+                fork.push(block);
+                self.forks.push(fork);
+                self.forks.len() - 1
+            }
+            None => {
+                self.forks.push(vec![block]);
+                self.forks.len() - 1
+            }
+        };
+
+        // Only vote once per epoch, and only if the block extends a fully
+        // notarized chain, which is at least as long as any other we've seen.
+        let fork = &self.forks[index];
+        let own_vote = if self.last_voted_epoch.map_or(false, |voted| voted >= epoch) {
+            debug!(target: "CONSENSUS", "Already voted in epoch {}", epoch);
+            None
+        } else if parent_len < longest ||
+            !fork[..parent_len].iter().all(|b| b.metadata.notarized)
+        {
+            debug!(target: "CONSENSUS", "Not voting for block of epoch {}", epoch);
+            None
+        } else {
+            self.last_voted_epoch = Some(epoch);
+            Some(Vote::new(&self.secret, hash, self.id))
+        };
+
+        // Count the votes that arrived before the block
+        let (votes, pending): (Vec<Vote>, Vec<Vote>) =
+            self.pending_votes.drain(..).partition(|vote| vote.block == hash);
+        self.pending_votes = pending;
+        for vote in votes {
+            self.add_vote(&vote).await?;
+        }
+
+        Ok(own_vote)
+    }
+
+    /// Find the fork holding the block parent, along with the number of
+    /// fork blocks up to and including the parent. The parent is either a
+    /// fork tip, or a notarized block inside a fork, in which case the
+    /// block branches off it. `None` means the block extends the last
+    /// finalized block and starts a new fork.
+    fn find_extended_fork(&self, block: &Block) -> Result<(Option<usize>, usize)> {
+        let mut branch = None;
+        for (index, fork) in self.forks.iter().enumerate() {
+            let position = match fork.iter().position(|b| b.hash() == block.st) {
+                Some(position) => position,
+                None => continue,
+            };
+
+            let parent = &fork[position];
+            if block.sl <= parent.sl {
+                return Err(Error::ConsensusError("Block doesn't extend any known chain"))
+            }
+
+            if position + 1 == fork.len() {
+                return Ok((Some(index), fork.len()))
+            }
+
+            if parent.metadata.notarized {
+                branch = Some((Some(index), position + 1));
+            }
+        }
+
+        if let Some(branch) = branch {
+            return Ok(branch)
+        }
+
+        if block.st != self.last_finalized.hash() || block.sl <= self.last_finalized.sl {
+            return Err(Error::ConsensusError("Block doesn't extend any known chain"))
+        }
+
+        Ok((None, 0))
+    }
+
+    /// Verify and record a vote. Returns false if we already had it.
+    /// Votes for a block we haven't received yet are kept until its
+    /// proposal arrives, since messages can be reordered on the way.
+    pub async fn receive_vote(&mut self, vote: &Vote) -> Result<bool> {
+        let public = match self.participants.get(vote.id as usize) {
+            Some(public) => public,
+            None => return Err(Error::ConsensusError("Vote from unknown participant")),
+        };
+
+        if !vote.verify(public) {
+            return Err(Error::ConsensusError("Invalid vote signature"))
+        }
+
+        if self.is_known_block(&vote.block) {
+            return self.add_vote(vote).await
+        }
+
+        if self.pending_votes.iter().any(|v| v.id == vote.id && v.block == vote.block) {
+            return Ok(false)
+        }
+
+        let mut voter_pending = self.pending_votes.iter().filter(|v| v.id == vote.id);
+        if voter_pending.clone().count() >= MAX_PENDING_VOTES {
+            let oldest = voter_pending.next().unwrap().clone();
+            self.pending_votes.retain(|v| *v != oldest);
+        }
+
+        debug!(target: "CONSENSUS", "Buffering vote for unknown block");
+        self.pending_votes.push(vote.clone());
+        Ok(true)
+    }
+
+    /// Record a verified vote for a known block. Once a block gathers more
+    /// than 2n/3 votes it gets notarized, which may finalize part of its
+    /// fork.
+    async fn add_vote(&mut self, vote: &Vote) -> Result<bool> {
+        let nodes_count = self.participants.len();
+        let mut notarized = false;
+        // Forks branching off each other hold copies of the same blocks
+        for fork in self.forks.iter_mut() {
+            if let Some(block) = fork.iter_mut().find(|block| block.hash() == vote.block) {
+                if block.metadata.votes.iter().any(|v| v.id == vote.id) {
+                    return Ok(false)
+                }
+
+                block.metadata.votes.push(vote.clone());
+                if !block.metadata.notarized && block.metadata.votes.len() > 2 * nodes_count / 3 {
+                    debug!(target: "CONSENSUS", "Block of epoch {} notarized", block.sl);
+                    block.metadata.notarized = true;
+                    notarized = true;
+                }
+            }
+        }
+
+        if notarized {
+            self.check_finalization().await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Finalization rule: when a fork holds three adjacent notarized blocks
+    /// with consecutive epochs, the chain up to the middle one is final.
+    /// The last finalized block counts as the first block of every fork.
+    async fn check_finalization(&mut self) -> Result<()> {
+        // Fork index and number of fork blocks to finalize
+        let mut best: Option<(usize, usize)> = None;
+        for (index, fork) in self.forks.iter().enumerate() {
+            let notarized = fork.iter().take_while(|block| block.metadata.notarized).count();
+
+            let mut epochs = vec![self.last_finalized.sl];
+            epochs.extend(fork[..notarized].iter().map(|block| block.sl));
+
+            let finalized = (1..epochs.len().saturating_sub(1))
+                .rev()
+                .find(|&i| epochs[i - 1] + 1 == epochs[i] && epochs[i] + 1 == epochs[i + 1]);
+            if let Some(finalized) = finalized {
+                if best.map_or(true, |(_, best)| finalized > best) {
+                    best = Some((index, finalized));
+                }
+            }
+        }
+
+        let (index, finalized) = match best {
+            Some(best) => best,
+            None => return Ok(()),
+        };
+
+        let blocks: Vec<Block> = self.forks[index][..finalized].to_vec();
+        let hashes: Vec<[u8; 32]> = blocks.iter().map(|block| block.hash()).collect();
+        for mut block in blocks {
+            block.metadata.finalized = true;
+            self.finalize_block(&block).await?;
+            self.last_finalized = block;
+        }
+
+        // Forks starting with the finalized blocks keep the blocks after
+        // them, the others don't extend the new last finalized block.
+        let forks = std::mem::take(&mut self.forks);
+        self.forks = forks
+            .into_iter()
+            .filter(|fork| {
+                fork.len() > finalized &&
+                    fork[..finalized].iter().map(|block| block.hash()).eq(hashes.iter().copied())
+            })
+            .map(|mut fork| fork.split_off(finalized))
+            .collect();
+
+        Ok(())
+    }
+
+    /// Persist the block and append its transactions to the slab store
+    /// as a single slab, applying them to the state. The slab is then
+    /// signed and waits for the signatures of the other participants.
+    async fn finalize_block(&mut self, block: &Block) -> Result<()> {
+        info!(target: "CONSENSUS", "Finalized block of epoch {}", block.sl);
+        self.blocks.put(block.sl, block.clone())?;
+
+        if block.txs.is_empty() {
+            return Ok(())
+        }
+
+        if let Some(slab) = self.mempool.apply_txs(block.txs.clone(), &self.slabstore).await? {
+            self.sign_slab(&slab)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sign a slab appended from a finalized block, and count our vote.
+    fn sign_slab(&mut self, slab: &Slab) -> Result<()> {
+        let vote = SlabVote::new(&self.secret, slab, self.id);
+        let _ = self.events_s.try_send(ConsensusEvent::SlabVote(vote.clone()));
+        self.slab_votes.push(vote);
+        self.check_certification()
+    }
+
+    /// Verify and record the vote of a participant on a slab. Returns
+    /// false if we already had it, or the slab is already certified.
+    pub fn receive_slab_vote(&mut self, vote: &SlabVote) -> Result<bool> {
+        let public = match self.participants.get(vote.id as usize) {
+            Some(public) => public,
+            None => return Err(Error::ConsensusError("Slab vote from unknown participant")),
+        };
+
+        if !vote.verify(public) {
+            return Err(Error::ConsensusError("Invalid slab vote signature"))
+        }
+
+        if vote.index <= self.last_certified {
+            return Ok(false)
+        }
+
+        if vote.index > self.last_certified + MAX_SLAB_VOTES_AHEAD {
+            return Err(Error::ConsensusError("Slab vote too far ahead"))
+        }
+
+        if self.slab_votes.iter().any(|v| v.id == vote.id && v.index == vote.index) {
+            return Ok(false)
+        }
+
+        self.slab_votes.push(vote.clone());
+        self.check_certification()?;
+        Ok(true)
+    }
+
+    /// Certify the slabs following the last certified one, in order, as
+    /// long as more than 2n/3 participants signed the same slab as ours.
+    fn check_certification(&mut self) -> Result<()> {
+        loop {
+            let index = self.last_certified + 1;
+            let slab: Slab = match self.slabstore.get(serialize(&index))? {
+                Some(slab) => deserialize(&slab)?,
+                None => return Ok(()),
+            };
+
+            let hash = slab_hash(&slab);
+            let votes: Vec<SlabVote> = self
+                .slab_votes
+                .iter()
+                .filter(|vote| vote.index == index && vote.slab == hash)
+                .cloned()
+                .collect();
+
+            if votes.len() <= 2 * self.participants.len() / 3 {
+                return Ok(())
+            }
+
+            debug!(target: "CONSENSUS", "Slab {} certified", index);
+            let certificate = SlabCertificate { votes };
+            self.certificates.put(index, certificate.clone())?;
+            self.last_certified = index;
+            self.slab_votes.retain(|vote| vote.index > index);
+            let _ = self.events_s.try_send(ConsensusEvent::SlabCertified(slab, certificate));
+        }
+    }
+
+    /// At the start of each epoch, propose a block if we are the leader,
+    /// vote for it, and broadcast both to the network. Errors are logged,
+    /// the loop keeps running until the task is cancelled.
+    pub async fn proposal_loop(state: ConsensusStatePtr, p2p: P2pPtr) {
+        loop {
+            let seconds = state.lock().await.next_epoch_start();
+            sleep(seconds as u32).await;
+
+            let (proposal, vote) = {
+                let mut consensus = state.lock().await;
+                let epoch = consensus.current_epoch();
+                let proposal = match consensus.propose_block(epoch).await {
+                    Some(proposal) => proposal,
+                    None => continue,
+                };
+
+                debug!(target: "CONSENSUS", "Proposing block for epoch {}", epoch);
+                let vote = match consensus.receive_proposal(&proposal, epoch).await {
+                    Ok(vote) => vote,
+                    Err(e) => {
+                        error!(target: "CONSENSUS", "Failed to process own proposal: {}", e);
+                        continue
+                    }
+                };
+
+                if let Some(vote) = &vote {
+                    if let Err(e) = consensus.receive_vote(vote).await {
+                        error!(target: "CONSENSUS", "Failed to process own vote: {}", e);
+                    }
+                }
+
+                (proposal, vote)
+            };
+
+            if let Err(e) = p2p.broadcast(proposal).await {
+                warn!(target: "CONSENSUS", "Failed to broadcast proposal: {}", e);
+            }
+
+            if let Some(vote) = vote {
+                if let Err(e) = p2p.broadcast(vote).await {
+                    warn!(target: "CONSENSUS", "Failed to broadcast vote: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use group::ff::Field;
+    use incrementalmerkletree::bridgetree::BridgeTree;
+    use pasta_curves::pallas;
+    use rand::rngs::OsRng;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::{
+        crypto::{merkle_node::MerkleNode, proof::VerifyingKey, schnorr::SchnorrSecret},
+        tx::TransactionClearInput,
+        zk::circuit::{MintContract, SpendContract},
+    };
+
+    /// Consensus state of the first of four participants, along with the
+    /// secret keys of all of them.
+    fn test_state() -> Result<(ConsensusState, Vec<SecretKey>, TempDir)> {
+        let dir = tempdir()?;
+        let rocks = Rocks::new(dir.path())?;
+        let slabstore = SlabStore::new(RocksColumn::<columns::Slabs>::new(rocks.clone()))?;
+        let (events_s, _) = async_channel::unbounded();
+
+        let state = State {
+            tree: BridgeTree::<MerkleNode, 32>::new(100),
+            merkle_roots: RocksColumn::<columns::MerkleRoots>::new(rocks.clone()),
+            merkle_anchor_window: 10,
+            last_slab_index: 0,
+            nullifiers: RocksColumn::<columns::Nullifiers>::new(rocks.clone()),
+            public_keys: vec![],
+            mint_vk: VerifyingKey::build(11, &MintContract::default()),
+            spend_vk: VerifyingKey::build(11, &SpendContract::default()),
+        };
+
+        let secrets: Vec<SecretKey> = (0..4).map(|_| SecretKey::random(&mut OsRng)).collect();
+        let participants = secrets.iter().map(|secret| PublicKey::from_secret(*secret)).collect();
+
+        let settings = ParticipantSettings {
+            secret: secrets[0],
+            genesis_time: 0,
+            delta: DELTA,
+            state: Arc::new(Mutex::new(state)),
+        };
+        let state = ConsensusState::new(participants, settings, rocks, slabstore, events_s)?;
+        Ok((state, secrets, dir))
+    }
+
+    /// Receive the leader proposal of a block extending `parent`, returns
+    /// the block hash and our vote.
+    async fn propose(
+        state: &mut ConsensusState,
+        secrets: &[SecretKey],
+        parent: [u8; 32],
+        epoch: u64,
+        txs: Vec<Transaction>,
+    ) -> Result<([u8; 32], Option<Vote>)> {
+        let leader = state.epoch_leader(epoch);
+        let block = Block::new(parent, epoch, txs);
+        let hash = block.hash();
+        let proposal = BlockProposal::new(&secrets[leader as usize], leader, block);
+        Ok((hash, state.receive_proposal(&proposal, epoch).await?))
+    }
+
+    async fn vote(
+        state: &mut ConsensusState,
+        secrets: &[SecretKey],
+        hash: [u8; 32],
+        voters: &[u64],
+    ) -> Result<()> {
+        for id in voters {
+            state.receive_vote(&Vote::new(&secrets[*id as usize], hash, *id)).await?;
+        }
+        Ok(())
+    }
+
+    fn is_notarized(state: &ConsensusState, hash: &[u8; 32]) -> bool {
+        state.forks.iter().flatten().any(|block| block.hash() == *hash && block.metadata.notarized)
+    }
+
+    /// Deposit signed by a key which isn't a cashier key.
+    fn unknown_cashier_deposit() -> Transaction {
+        let secret = SecretKey::random(&mut OsRng);
+        Transaction {
+            clear_inputs: vec![TransactionClearInput {
+                value: 1,
+                token_id: pallas::Base::random(&mut OsRng),
+                value_blind: pallas::Scalar::random(&mut OsRng),
+                token_blind: pallas::Scalar::random(&mut OsRng),
+                signature_public: PublicKey::from_secret(secret),
+                signature: secret.sign(&[]),
+            }],
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+
+    #[async_std::test]
+    async fn notarization() -> Result<()> {
+        let (mut state, secrets, _dir) = test_state()?;
+        let genesis = state.get_last_finalized().hash();
+
+        // A vote arriving before its proposal is counted once it arrives
+        let hash = Block::new(genesis, 1, vec![]).hash();
+        vote(&mut state, &secrets, hash, &[1]).await?;
+        propose(&mut state, &secrets, genesis, 1, vec![]).await?;
+
+        vote(&mut state, &secrets, hash, &[2]).await?;
+        assert!(!is_notarized(&state, &hash));
+
+        // More than 2n/3 votes notarize the block
+        vote(&mut state, &secrets, hash, &[3]).await?;
+        assert!(is_notarized(&state, &hash));
+
+        assert!(!state.receive_vote(&Vote::new(&secrets[3], hash, 3)).await?);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn longest_notarized_chain() -> Result<()> {
+        let (mut state, secrets, _dir) = test_state()?;
+        let genesis = state.get_last_finalized().hash();
+
+        // Two forks, none with consecutive epochs so nothing gets finalized
+        let (a1, _) = propose(&mut state, &secrets, genesis, 2, vec![]).await?;
+        let (b1, _) = propose(&mut state, &secrets, genesis, 3, vec![]).await?;
+        let (a2, _) = propose(&mut state, &secrets, a1, 4, vec![]).await?;
+        for hash in [a1, b1, a2] {
+            vote(&mut state, &secrets, hash, &[1, 2, 3]).await?;
+        }
+        assert_eq!(state.forks.len(), 2);
+
+        let (tip, _, length) = state.longest_notarized_chain();
+        assert_eq!(tip.hash(), a2);
+        assert_eq!(length, 2);
+
+        // We only vote for blocks extending the longest notarized chain
+        let (_, vote) = propose(&mut state, &secrets, b1, 5, vec![]).await?;
+        assert!(vote.is_none());
+        let (_, vote) = propose(&mut state, &secrets, a2, 6, vec![]).await?;
+        assert!(vote.is_some());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn extend_notarized_block() -> Result<()> {
+        let (mut state, secrets, _dir) = test_state()?;
+        let genesis = state.get_last_finalized().hash();
+
+        // A notarized chain followed by a block which never gets notarized
+        let (a1, _) = propose(&mut state, &secrets, genesis, 2, vec![]).await?;
+        let (a2, _) = propose(&mut state, &secrets, a1, 4, vec![]).await?;
+        let (a3, _) = propose(&mut state, &secrets, a2, 6, vec![]).await?;
+        vote(&mut state, &secrets, a1, &[1, 2, 3]).await?;
+        vote(&mut state, &secrets, a2, &[1, 2, 3]).await?;
+        assert!(!is_notarized(&state, &a3));
+
+        let (tip, _, length) = state.longest_notarized_chain();
+        assert_eq!(tip.hash(), a2);
+        assert_eq!(length, 2);
+
+        // A block extending the notarized chain branches off it and gets
+        // our vote, the un-notarized tip stays in its own fork
+        let (b3, vote_b3) = propose(&mut state, &secrets, a2, 8, vec![]).await?;
+        assert!(vote_b3.is_some());
+        assert_eq!(state.forks.len(), 2);
+        assert!(state.is_known_block(&a3));
+
+        vote(&mut state, &secrets, b3, &[1, 2, 3]).await?;
+        let (tip, _, length) = state.longest_notarized_chain();
+        assert_eq!(tip.hash(), b3);
+        assert_eq!(length, 3);
+
+        // Blocks can only branch off notarized blocks
+        propose(&mut state, &secrets, a3, 9, vec![]).await?;
+        assert!(propose(&mut state, &secrets, a3, 10, vec![]).await.is_err());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn single_vote_per_epoch() -> Result<()> {
+        let (mut state, secrets, _dir) = test_state()?;
+        let genesis = state.get_last_finalized().hash();
+
+        // Two notarized forks of the same length
+        let (a1, _) = propose(&mut state, &secrets, genesis, 1, vec![]).await?;
+        let (b1, _) = propose(&mut state, &secrets, genesis, 3, vec![]).await?;
+        vote(&mut state, &secrets, a1, &[1, 2, 3]).await?;
+        vote(&mut state, &secrets, b1, &[1, 2, 3]).await?;
+
+        // The leader proposes a block on each of them in the same epoch,
+        // we only vote for the first one we receive
+        let (_, vote) = propose(&mut state, &secrets, a1, 4, vec![]).await?;
+        assert!(vote.is_some());
+        let (_, vote) = propose(&mut state, &secrets, b1, 4, vec![]).await?;
+        assert!(vote.is_none());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn invalid_transactions() -> Result<()> {
+        let (mut state, secrets, _dir) = test_state()?;
+        let genesis = state.get_last_finalized().hash();
+
+        let tx = unknown_cashier_deposit();
+        assert!(state.append_tx(tx.clone()).await.is_err());
+        assert!(state.mempool.is_empty());
+
+        // Proposals holding invalid transactions are rejected
+        assert!(propose(&mut state, &secrets, genesis, 1, vec![tx]).await.is_err());
+        assert!(state.forks.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn finalization() -> Result<()> {
+        let (mut state, secrets, _dir) = test_state()?;
+        let genesis = state.get_last_finalized().hash();
+
+        let (b2, _) = propose(&mut state, &secrets, genesis, 2, vec![]).await?;
+        let (b3, _) = propose(&mut state, &secrets, b2, 3, vec![]).await?;
+        let (b4, _) = propose(&mut state, &secrets, b3, 4, vec![]).await?;
+        // Competing fork, dropped once a conflicting block is finalized
+        propose(&mut state, &secrets, genesis, 1, vec![]).await?;
+
+        vote(&mut state, &secrets, b2, &[1, 2, 3]).await?;
+        vote(&mut state, &secrets, b3, &[1, 2, 3]).await?;
+        assert_eq!(state.get_last_finalized().sl, 0);
+
+        // Three notarized blocks with consecutive epochs finalize the chain
+        // up to the middle one
+        vote(&mut state, &secrets, b4, &[1, 2, 3]).await?;
+        assert_eq!(state.get_last_finalized().hash(), b3);
+        assert!(state.get_last_finalized().metadata.finalized);
+        assert!(state.blocks.get(2u64)?.is_some());
+        assert!(state.blocks.get(3u64)?.is_some());
+
+        assert_eq!(state.forks.len(), 1);
+        assert_eq!(state.forks[0].len(), 1);
+        assert_eq!(state.forks[0][0].hash(), b4);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn slab_certification() -> Result<()> {
+        let (mut state, secrets, _dir) = test_state()?;
+
+        let mut slabs = vec![];
+        for payload in [vec![1], vec![2]] {
+            let mut slab = Slab::new(payload);
+            slab.set_index(state.slabstore.get_last_index()? + 1);
+            state.slabstore.put(slab.clone())?;
+            slabs.push(slab);
+        }
+
+        // Votes for the second slab wait for the first one
+        state.sign_slab(&slabs[1])?;
+        for id in [1, 2] {
+            assert!(state.receive_slab_vote(&SlabVote::new(&secrets[id as usize], &slabs[1], id))?);
+        }
+        assert_eq!(state.last_certified(), 0);
+
+        // A vote on another slab at the same index doesn't count
+        let mut other = Slab::new(vec![3]);
+        other.set_index(1);
+        state.sign_slab(&slabs[0])?;
+        state.receive_slab_vote(&SlabVote::new(&secrets[1], &other, 1))?;
+        assert_eq!(state.last_certified(), 0);
+
+        // Once the first slab is certified, both are
+        state.receive_slab_vote(&SlabVote::new(&secrets[2], &slabs[0], 2))?;
+        state.receive_slab_vote(&SlabVote::new(&secrets[3], &slabs[0], 3))?;
+        assert_eq!(state.last_certified(), 2);
+
+        let certificate: SlabCertificate = deserialize(&state.certificates.get(1u64)?.unwrap())?;
+        assert!(certificate.verify(&slabs[0], &state.participants));
+        assert!(!state.receive_slab_vote(&SlabVote::new(&secrets[1], &slabs[0], 1))?);
+
+        Ok(())
+    }
+}
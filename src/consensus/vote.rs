@@ -0,0 +1,61 @@
+use std::io;
+
+use crate::{
+    crypto::{
+        keypair::{PublicKey, SecretKey},
+        schnorr::{SchnorrPublic, SchnorrSecret, Signature},
+    },
+    impl_vec, net,
+    util::serial::{Decodable, Encodable, VarInt},
+    Result,
+};
+
+/// Vote of a participant for a proposed block, signing the block hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vote {
+    /// Signature over the block hash
+    pub vote: Signature,
+    /// Hash of the block to vote on
+    pub block: [u8; 32],
+    /// Voter index in the participants list
+    pub id: u64,
+}
+
+impl Vote {
+    pub fn new(secret: &SecretKey, block: [u8; 32], id: u64) -> Self {
+        let vote = secret.sign(&block);
+        Self { vote, block, id }
+    }
+
+    pub fn verify(&self, public: &PublicKey) -> bool {
+        public.verify(&self.block, &self.vote)
+    }
+}
+
+impl net::Message for Vote {
+    fn name() -> &'static str {
+        "vote"
+    }
+}
+
+impl Encodable for Vote {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.vote.encode(&mut s)?;
+        len += self.block.encode(&mut s)?;
+        len += self.id.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Vote {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            vote: Decodable::decode(&mut d)?,
+            block: Decodable::decode(&mut d)?,
+            id: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl_vec!(Vote);
@@ -29,11 +29,11 @@ pub enum Error {
     #[error(transparent)]
     ParseBigIntError(#[from] num_bigint::ParseBigIntError),
 
-    #[cfg(any(feature = "rpc", feature = "node"))]
+    #[cfg(any(feature = "rpc", feature = "node", feature = "net"))]
     #[error("Url parse error `{0}`")]
     UrlParseError(String),
 
-    #[cfg(any(feature = "rpc"))]
+    #[cfg(any(feature = "rpc", feature = "net"))]
     #[error("Socks error `{0}`")]
     SocksError(String),
 
@@ -107,6 +107,14 @@ pub enum Error {
     #[error("ZmqError: `{0}`")]
     ZmqError(String),
 
+    #[cfg(feature = "node")]
+    #[error(transparent)]
+    ReqError(#[from] crate::node::service::reqrep::ReqError),
+
+    #[cfg(feature = "node")]
+    #[error("Secure channel error: `{0}`")]
+    SecureChannelError(&'static str),
+
     #[cfg(feature = "blockchain")]
     #[error("Rocksdb error: `{0}`")]
     RocksdbError(String),
@@ -119,6 +127,10 @@ pub enum Error {
     #[error("SlabsStore Error: `{0}`")]
     SlabsStore(String),
 
+    #[cfg(feature = "node")]
+    #[error("Consensus error: `{0}`")]
+    ConsensusError(&'static str),
+
     #[error("JsonRpc Error: `{0}`")]
     JsonRpcError(String),
 
@@ -151,6 +163,12 @@ pub enum Error {
     #[error("Channel timed out")]
     ChannelTimeout,
 
+    #[error("Request timed out")]
+    RequestTimeout,
+
+    #[error("Stream rejected by peer")]
+    StreamRejected,
+
     #[error("Service stopped")]
     ServiceStopped,
 
@@ -160,6 +178,24 @@ pub enum Error {
     #[error("Malformed packet")]
     MalformedPacket,
 
+    #[error("Channel encryption error: `{0}`")]
+    ChannelEncryptionError(&'static str),
+
+    #[error("Peer does not support channel encryption")]
+    ChannelEncryptionRequired,
+
+    #[error("Incompatible protocol version: {0}")]
+    IncompatibleProtocolVersion(u32),
+
+    #[error("Peer is banned")]
+    PeerBanned,
+
+    #[error("Packet too large")]
+    PacketTooLarge,
+
+    #[error("Port mapping failed: `{0}`")]
+    PortMappingFailed(String),
+
     #[error("No config file detected. Please create one.")]
     ConfigNotFound,
 
@@ -245,7 +281,7 @@ impl From<async_native_tls::Error> for Error {
     }
 }
 
-#[cfg(feature = "rpc")]
+#[cfg(any(feature = "rpc", feature = "net"))]
 impl From<url::ParseError> for Error {
     fn from(err: url::ParseError) -> Error {
         Error::UrlParseError(err.to_string())
@@ -279,7 +315,7 @@ impl From<Box<bincode::ErrorKind>> for Error {
     }
 }
 
-#[cfg(feature = "rpc")]
+#[cfg(any(feature = "rpc", feature = "net"))]
 impl From<fast_socks5::SocksError> for Error {
     fn from(err: fast_socks5::SocksError) -> Error {
         Error::SocksError(err.to_string())
@@ -13,6 +13,9 @@ pub mod node;
 #[cfg(feature = "node")]
 pub mod tx;
 
+#[cfg(feature = "node")]
+pub mod consensus;
+
 #[cfg(feature = "net")]
 pub mod net;
 
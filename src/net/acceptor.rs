@@ -1,5 +1,6 @@
 use log::*;
 use smol::{Async, Executor};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
     net::{SocketAddr, TcpListener},
     sync::Arc,
@@ -8,7 +9,7 @@ use std::{
 use crate::error::{Error, Result};
 //use crate::net::error::{, Result};
 use crate::{
-    net::{Channel, ChannelPtr},
+    net::{Channel, ChannelPtr, SettingsPtr, TransportListener},
     system::{StoppableTask, StoppableTaskPtr, Subscriber, SubscriberPtr, Subscription},
 };
 
@@ -19,22 +20,30 @@ pub type AcceptorPtr = Arc<Acceptor>;
 pub struct Acceptor {
     channel_subscriber: SubscriberPtr<Result<ChannelPtr>>,
     task: StoppableTaskPtr,
+    settings: SettingsPtr,
 }
 
 impl Acceptor {
     /// Create new Acceptor object.
-    pub fn new() -> Arc<Self> {
-        Arc::new(Self { channel_subscriber: Subscriber::new(), task: StoppableTask::new() })
+    pub fn new(settings: SettingsPtr) -> Arc<Self> {
+        Arc::new(Self {
+            channel_subscriber: Subscriber::new(),
+            task: StoppableTask::new(),
+            settings,
+        })
     }
     /// Start accepting inbound socket connections. Creates a listener to start
     /// listening on a local socket address. Then runs an accept loop in a new
     /// thread, erroring if a connection problem occurs.
-    pub fn start(
+    pub async fn start(
         self: Arc<Self>,
         accept_addr: SocketAddr,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
-        let listener = Self::setup(accept_addr)?;
+        let listener: Box<dyn TransportListener> = match &self.settings.transport {
+            Some(transport) => transport.listen(accept_addr).await?,
+            None => Box::new(Self::setup(accept_addr)?),
+        };
 
         // Start detached task and return instantly
         self.accept(listener, executor);
@@ -55,7 +64,7 @@ impl Acceptor {
 
     /// Start listening on a local socket address.
     fn setup(accept_addr: SocketAddr) -> Result<Async<TcpListener>> {
-        let listener = match Async::<TcpListener>::bind(accept_addr) {
+        let listener = match Self::bind(accept_addr) {
             Ok(listener) => listener,
             Err(err) => {
                 error!("Bind listener failed: {}", err);
@@ -74,9 +83,23 @@ impl Acceptor {
         Ok(listener)
     }
 
+    /// Bind a TCP listener. IPv6 listeners only accept IPv6 connections, so
+    /// a node can listen on the same port for both IPv4 and IPv6.
+    fn bind(accept_addr: SocketAddr) -> std::io::Result<Async<TcpListener>> {
+        let socket =
+            Socket::new(Domain::for_address(accept_addr), Type::STREAM, Some(Protocol::TCP))?;
+        if accept_addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.bind(&accept_addr.into())?;
+        socket.listen(128)?;
+        Async::new(TcpListener::from(socket))
+    }
+
     /// Run the accept loop in a new thread and error if a connection problem
     /// occurs.
-    fn accept(self: Arc<Self>, listener: Async<TcpListener>, executor: Arc<Executor<'_>>) {
+    fn accept(self: Arc<Self>, listener: Box<dyn TransportListener>, executor: Arc<Executor<'_>>) {
         self.task.clone().start(
             self.clone().run_accept_loop(listener),
             |result| self.handle_stop(result),
@@ -86,9 +109,9 @@ impl Acceptor {
     }
 
     /// Run the accept loop.
-    async fn run_accept_loop(self: Arc<Self>, listener: Async<TcpListener>) -> Result<()> {
+    async fn run_accept_loop(self: Arc<Self>, listener: Box<dyn TransportListener>) -> Result<()> {
         loop {
-            let channel = self.tick_accept(&listener).await?;
+            let channel = self.tick_accept(listener.as_ref()).await?;
             self.channel_subscriber.notify(Ok(channel)).await;
         }
     }
@@ -108,17 +131,11 @@ impl Acceptor {
 
     /// Single attempt to accept an incoming connection. Stops after one
     /// attempt.
-    async fn tick_accept(&self, listener: &Async<TcpListener>) -> Result<ChannelPtr> {
-        let (stream, peer_addr) = match listener.accept().await {
-            Ok((s, a)) => (s, a),
-            Err(err) => {
-                error!("Error listening for connections: {}", err);
-                return Err(Error::ServiceStopped)
-            }
-        };
+    async fn tick_accept(&self, listener: &dyn TransportListener) -> Result<ChannelPtr> {
+        let (stream, peer_addr) = listener.accept().await?;
         info!("Accepted client: {}", peer_addr);
 
-        let channel = Channel::new(stream, peer_addr).await;
+        let channel = Channel::new(stream, peer_addr, self.settings.clone()).await;
         Ok(channel)
     }
 }
@@ -0,0 +1,243 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use url::{Host, Url};
+
+use crate::{
+    error::{Error, Result},
+    impl_vec,
+    util::serial::{Decodable, Encodable, VarInt},
+};
+
+/// Network address of a node. Either a socket address, or a hostname
+/// which is resolved when connecting. Onion hostnames can only be
+/// reached through a SOCKS5 proxy.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NetAddr {
+    Ip(SocketAddr),
+    Host(String, u16),
+}
+
+/// Group of addresses which may be controlled by a single party, used to
+/// spread outbound connections and stored hosts across networks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NetworkGroup {
+    /// The /16 of an IPv4 address, or the /32 of an IPv6 address
+    Ip(IpAddr),
+    /// Onion addresses, which anyone can create at will
+    Onion,
+    /// Other hostnames, which may resolve to any network
+    Host,
+}
+
+impl NetAddr {
+    /// Parse the host and port of an url such as `tcp://127.0.0.1:11001`
+    /// or `tor://xxxxxxxx.onion:11001`.
+    pub fn from_url(url: &Url) -> Result<Self> {
+        let port = url.port().ok_or(Error::InvalidAddress)?;
+        match url.host() {
+            Some(Host::Ipv4(ip)) => Ok(Self::Ip(SocketAddr::new(ip.into(), port))),
+            Some(Host::Ipv6(ip)) => Ok(Self::Ip(SocketAddr::new(ip.into(), port))),
+            // Hosts of non-special schemes aren't parsed as IPv4 addresses
+            Some(Host::Domain(host)) => match host.parse::<IpAddr>() {
+                Ok(ip) => Ok(Self::Ip(SocketAddr::new(ip, port))),
+                Err(_) => Ok(Self::Host(host.to_lowercase(), port)),
+            },
+            None => Err(Error::InvalidAddress),
+        }
+    }
+
+    pub fn is_onion(&self) -> bool {
+        match self {
+            Self::Ip(_) => false,
+            Self::Host(host, _) => host.ends_with(".onion"),
+        }
+    }
+
+    /// Returns false for addresses no node can be reached at: unspecified
+    /// IPs, eg. a node listening on all interfaces, and port 0.
+    pub fn is_connectable(&self) -> bool {
+        self.port() != 0 && !self.ip().map_or(false, |ip| ip.is_unspecified())
+    }
+
+    /// The host part of the address, without the port.
+    pub fn host(&self) -> String {
+        match self {
+            Self::Ip(addr) => addr.ip().to_string(),
+            Self::Host(host, _) => host.clone(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Host(_, port) => *port,
+        }
+    }
+
+    /// The IP address, unless the address is a hostname.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Ip(addr) => Some(addr.ip()),
+            Self::Host(..) => None,
+        }
+    }
+
+    /// The network the address belongs to: its /24 for IPv4 and its /48
+    /// for IPv6. Hostnames don't belong to a known network.
+    pub fn subnet(&self) -> Option<IpAddr> {
+        match self.ip()? {
+            IpAddr::V4(ip) => {
+                let [a, b, c, _] = ip.octets();
+                Some(IpAddr::V4(Ipv4Addr::new(a, b, c, 0)))
+            }
+            IpAddr::V6(ip) => {
+                let [a, b, c, ..] = ip.segments();
+                Some(IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0)))
+            }
+        }
+    }
+
+    /// The group used to spread outbound connections and stored hosts
+    /// across networks. Hostnames don't tell which network they are in, so
+    /// all onion addresses share a group, and so do all other hostnames.
+    /// Loopback addresses don't belong to a group.
+    pub fn network_group(&self) -> Option<NetworkGroup> {
+        let ip = match self {
+            Self::Ip(addr) => addr.ip(),
+            Self::Host(..) if self.is_onion() => return Some(NetworkGroup::Onion),
+            Self::Host(..) => return Some(NetworkGroup::Host),
+        };
+
+        match ip {
+            ip if ip.is_loopback() => None,
+            IpAddr::V4(ip) => {
+                let [a, b, ..] = ip.octets();
+                Some(NetworkGroup::Ip(IpAddr::V4(Ipv4Addr::new(a, b, 0, 0))))
+            }
+            IpAddr::V6(ip) => {
+                let [a, b, ..] = ip.segments();
+                Some(NetworkGroup::Ip(IpAddr::V6(Ipv6Addr::new(a, b, 0, 0, 0, 0, 0, 0))))
+            }
+        }
+    }
+}
+
+impl From<SocketAddr> for NetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Ip(addr)
+    }
+}
+
+impl fmt::Display for NetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl FromStr for NetAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self::Ip(addr))
+        }
+
+        if s.contains("://") {
+            return Self::from_url(&Url::parse(s)?)
+        }
+
+        // Hostnames are parsed as the host of an url, which validates them
+        Self::from_url(&Url::parse(&format!("tcp://{}", s))?)
+    }
+}
+
+impl Serialize for NetAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NetAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Encodable for NetAddr {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        match self {
+            Self::Ip(addr) => {
+                len += 0u8.encode(&mut s)?;
+                len += addr.encode(&mut s)?;
+            }
+            Self::Host(host, port) => {
+                len += 1u8.encode(&mut s)?;
+                len += host.encode(&mut s)?;
+                len += port.encode(&mut s)?;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for NetAddr {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let tag: u8 = Decodable::decode(&mut d)?;
+        match tag {
+            0 => Ok(Self::Ip(Decodable::decode(&mut d)?)),
+            1 => Ok(Self::Host(Decodable::decode(&mut d)?, Decodable::decode(&mut d)?)),
+            _ => Err(Error::ParseFailed("couldn't decode NetAddr")),
+        }
+    }
+}
+
+impl_vec!(NetAddr);
+
+#[cfg(test)]
+mod tests {
+    use super::{NetAddr, NetworkGroup};
+    use crate::util::serial::{deserialize, serialize};
+
+    #[test]
+    fn net_addr_test() {
+        let addr: NetAddr = "127.0.0.1:11001".parse().unwrap();
+        assert_eq!(addr, NetAddr::Ip("127.0.0.1:11001".parse().unwrap()));
+        assert_eq!("tcp://127.0.0.1:11001".parse::<NetAddr>().unwrap(), addr);
+
+        let addr: NetAddr = "tor://ExampleExample.onion:11001".parse().unwrap();
+        assert_eq!(addr, NetAddr::Host("exampleexample.onion".to_string(), 11001));
+        assert!(addr.is_onion());
+        assert_eq!(addr.to_string(), "exampleexample.onion:11001");
+
+        assert_eq!(addr.network_group(), Some(NetworkGroup::Onion));
+
+        let addr: NetAddr = "seed.dark.fi:11001".parse().unwrap();
+        assert!(!addr.is_onion());
+        assert_eq!(addr.network_group(), Some(NetworkGroup::Host));
+        assert_eq!(deserialize::<NetAddr>(&serialize(&addr)).unwrap(), addr);
+
+        assert!("seed.dark.fi".parse::<NetAddr>().is_err());
+        assert_eq!(addr.subnet(), None);
+
+        let addr: NetAddr = "10.1.2.3:11001".parse().unwrap();
+        assert_eq!(addr.subnet(), Some("10.1.2.0".parse().unwrap()));
+        assert_eq!(addr.network_group(), Some(NetworkGroup::Ip("10.1.0.0".parse().unwrap())));
+        assert_eq!("127.0.0.1:11001".parse::<NetAddr>().unwrap().network_group(), None);
+        let addr: NetAddr = "[2001:db8:1:2::1]:11001".parse().unwrap();
+        assert_eq!(addr.subnet(), Some("2001:db8:1::".parse().unwrap()));
+        assert!(addr.is_connectable());
+        assert!(!"[::]:11001".parse::<NetAddr>().unwrap().is_connectable());
+        assert!(!"0.0.0.0:11001".parse::<NetAddr>().unwrap().is_connectable());
+        assert!(!"seed.dark.fi:0".parse::<NetAddr>().unwrap().is_connectable());
+    }
+}
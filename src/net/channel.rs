@@ -1,67 +1,126 @@
 use async_std::sync::Mutex;
 use futures::{
     io::{ReadHalf, WriteHalf},
-    AsyncReadExt,
+    AsyncReadExt, AsyncWriteExt,
 };
+use rand::Rng;
 use serde_json::json;
 use std::{
-    net::{SocketAddr, TcpStream},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
-use log::{debug, error, info};
-use smol::{Async, Executor};
+use log::{debug, error, info, warn};
+use smol::{future, Executor};
 
 use crate::{
     error::{Error, Result},
     net::{
+        encryption::{Cipher, ENCRYPTED_COMMAND, ENCRYPTION_OVERHEAD},
         message,
+        message::{Message, ServiceBitflag},
         message_subscriber::{MessageSubscription, MessageSubsystem},
+        metrics::ChannelStats,
+        protocol::ProtocolBasePtr,
+        rate_limit::TokenBucket,
+        session::SessionBitflag,
+        ChannelEncryption, NetAddr, SettingsPtr, TransportStream,
     },
     system::{StoppableTask, StoppableTaskPtr, Subscriber, SubscriberPtr, Subscription},
+    util::serial::deserialize,
 };
 
 /// Atomic pointer to async channel.
 pub type ChannelPtr = Arc<Channel>;
 
+/// Command the messages without a dispatcher are counted under.
+const UNKNOWN_COMMAND: &str = "unknown";
+
+/// Misbehaviour score at which a peer gets banned.
+pub const BAN_THRESHOLD: u32 = 100;
+
 struct ChannelInfo {
     last_msg: String,
     last_status: String,
+    stats: ChannelStats,
 }
 
 impl ChannelInfo {
     fn new() -> Self {
-        Self { last_msg: String::new(), last_status: String::new() }
+        Self { last_msg: String::new(), last_status: String::new(), stats: ChannelStats::new() }
     }
 
     async fn get_info(&self) -> serde_json::Value {
         json!({
             "last_msg": self.last_msg,
             "last_status": self.last_status,
+            "stats": self.stats.get_info(),
+        })
+    }
+}
+
+/// What the peer told us about itself during the version handshake.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub version: u32,
+    pub user_agent: String,
+    pub timestamp: u64,
+    pub services: ServiceBitflag,
+    pub listen_addr: Option<NetAddr>,
+}
+
+impl PeerInfo {
+    fn get_info(&self) -> serde_json::Value {
+        json!({
+            "version": self.version,
+            "user_agent": self.user_agent,
+            "timestamp": self.timestamp,
+            "services": self.services,
+            "listen_addr": self.listen_addr.as_ref().map(|addr| addr.to_string()),
         })
     }
 }
 
 /// Async channel for communication between nodes.
 pub struct Channel {
-    reader: Mutex<ReadHalf<Async<TcpStream>>>,
-    writer: Mutex<WriteHalf<Async<TcpStream>>>,
-    address: SocketAddr,
+    reader: Mutex<ReadHalf<Box<dyn TransportStream>>>,
+    writer: Mutex<WriteHalf<Box<dyn TransportStream>>>,
+    address: NetAddr,
     message_subsystem: MessageSubsystem,
     stop_subscriber: SubscriberPtr<Error>,
     receive_task: StoppableTaskPtr,
     stopped: AtomicBool,
+    ban_score: AtomicU32,
+    session: AtomicU32,
     info: Mutex<ChannelInfo>,
+    /// Handshake state of the transport encryption
+    encryption: Mutex<Option<ChannelEncryption>>,
+    /// Ciphers of each direction once the channel is encrypted. Sending
+    /// and receiving use separate locks so they don't wait on each other.
+    send_cipher: Mutex<Option<Cipher>>,
+    recv_cipher: Mutex<Option<Cipher>>,
+    /// Set once the packets from the peer are decrypted
+    receiving: AtomicBool,
+    peer_info: Mutex<Option<PeerInfo>>,
+    services: Mutex<ServiceBitflag>,
+    rate_limiter: Mutex<TokenBucket>,
+    /// Protocols started on the channel
+    protocols: Mutex<Vec<ProtocolBasePtr>>,
+    settings: SettingsPtr,
 }
 
 impl Channel {
     /// Sets up a new channel. Creates a reader and writer TCP stream and
     /// summons the message subscriber subsystem. Performs a network
     /// handshake on the subsystem dispatchers.
-    pub async fn new(stream: Async<TcpStream>, address: SocketAddr) -> Arc<Self> {
+    pub async fn new(
+        stream: Box<dyn TransportStream>,
+        address: NetAddr,
+        settings: SettingsPtr,
+    ) -> Arc<Self> {
         let (reader, writer) = stream.split();
         let reader = Mutex::new(reader);
         let writer = Mutex::new(writer);
@@ -77,12 +136,118 @@ impl Channel {
             stop_subscriber: Subscriber::new(),
             receive_task: StoppableTask::new(),
             stopped: AtomicBool::new(false),
+            ban_score: AtomicU32::new(0),
+            session: AtomicU32::new(0),
             info: Mutex::new(ChannelInfo::new()),
+            encryption: Mutex::new(None),
+            send_cipher: Mutex::new(None),
+            recv_cipher: Mutex::new(None),
+            receiving: AtomicBool::new(false),
+            peer_info: Mutex::new(None),
+            services: Mutex::new(message::SERVICE_NONE),
+            rate_limiter: Mutex::new(TokenBucket::new(
+                settings.channel_rate_limit,
+                settings.channel_rate_burst,
+            )),
+            protocols: Mutex::new(Vec::new()),
+            settings,
         })
     }
 
+    /// Set up transport encryption. Must be called before the channel is
+    /// started, so the peer's version message is seen by the encryption.
+    pub async fn set_encryption(&self, encryption: ChannelEncryption) {
+        *self.encryption.lock().await = Some(encryption);
+    }
+
+    /// Announce our encryption keys in the version message.
+    pub async fn announce_keys(&self, version: &mut message::VersionMessage) {
+        if let Some(encryption) = self.encryption.lock().await.as_mut() {
+            encryption.announce(version);
+        }
+    }
+
+    /// MAC of the handshake transcript, sent in our verack.
+    pub async fn transcript_mac(&self) -> Result<[u8; 32]> {
+        match self.encryption.lock().await.as_ref() {
+            Some(encryption) => encryption.transcript_mac(),
+            None => Ok([0u8; 32]),
+        }
+    }
+
+    /// Returns true once both directions of the channel are encrypted.
+    pub async fn is_encrypted(&self) -> bool {
+        self.encryption.lock().await.as_ref().map_or(false, |encryption| encryption.is_encrypted())
+    }
+
+    /// The identity key the peer announced in its version message.
+    pub async fn peer_identity(&self) -> Option<[u8; 32]> {
+        self.encryption.lock().await.as_ref().and_then(|encryption| encryption.peer_identity())
+    }
+
+    /// Store the peer's version information. The services used on the
+    /// channel are the ones offered by both sides.
+    pub async fn set_peer_info(&self, peer_info: PeerInfo, local_services: ServiceBitflag) {
+        *self.services.lock().await = local_services & peer_info.services;
+        *self.peer_info.lock().await = Some(peer_info);
+    }
+
+    /// Version information of the peer, known once it sent its version.
+    pub async fn peer_info(&self) -> Option<PeerInfo> {
+        self.peer_info.lock().await.clone()
+    }
+
+    /// Protocol version spoken on the channel, the newest one both nodes
+    /// know. Known once the peer sent its version.
+    pub async fn protocol_version(&self) -> Option<u32> {
+        self.peer_info
+            .lock()
+            .await
+            .as_ref()
+            .map(|peer_info| std::cmp::min(message::PROTOCOL_VERSION, peer_info.version))
+    }
+
+    /// Services supported by both sides of the channel.
+    pub async fn services(&self) -> ServiceBitflag {
+        *self.services.lock().await
+    }
+
+    /// Returns true if both sides support all the given services.
+    pub async fn supports(&self, services: ServiceBitflag) -> bool {
+        self.services().await & services == services
+    }
+
+    /// Record the session the channel was registered with.
+    pub(crate) fn set_session(&self, session: SessionBitflag) {
+        self.session.store(session, Ordering::Relaxed);
+    }
+
+    /// Session the channel belongs to.
+    pub fn session(&self) -> SessionBitflag {
+        self.session.load(Ordering::Relaxed)
+    }
+
+    /// Message counters and connection statistics of the channel.
+    pub async fn stats(&self) -> ChannelStats {
+        self.info.lock().await.stats.clone()
+    }
+
+    /// Record the round trip time of the last ping.
+    pub async fn set_ping_rtt(&self, rtt_ms: u64) {
+        self.info.lock().await.stats.ping_rtt_ms = Some(rtt_ms);
+    }
+
     pub async fn get_info(&self) -> serde_json::Value {
-        self.info.lock().await.get_info().await
+        let mut info = self.info.lock().await.get_info().await;
+        info["encrypted"] = json!(self.is_encrypted().await);
+        info["services"] = json!(self.services().await);
+        info["protocol_version"] = json!(self.protocol_version().await);
+        info["ban_score"] = json!(self.ban_score.load(Ordering::Relaxed));
+        info["peer"] = match self.peer_info.lock().await.as_ref() {
+            Some(peer_info) => peer_info.get_info(),
+            None => serde_json::Value::Null,
+        };
+        info
     }
 
     /// Starts the channel. Runs a receive loop to start receiving messages or
@@ -111,9 +276,67 @@ impl Channel {
         self.stop_subscriber.notify(Error::ChannelStopped).await;
         self.receive_task.stop().await;
         self.message_subsystem.trigger_error(Error::ChannelStopped).await;
+        // Protocols hold the channel, so they're released here
+        self.protocols.lock().await.clear();
         debug!(target: "net", "Channel::stop() [END, address={}]", self.address());
     }
 
+    /// Keep a started protocol, so it's told when the channel shuts down.
+    pub async fn add_protocol(&self, protocol: ProtocolBasePtr) {
+        self.protocols.lock().await.push(protocol);
+    }
+
+    /// Close the channel gracefully. Runs the shutdown hook of the
+    /// protocols, waits for the pending sends to be written out and closes
+    /// the stream, so the peer sees the channel ending, then stops the
+    /// channel.
+    pub async fn shutdown(&self) {
+        debug!(target: "net", "Channel::shutdown() [START, address={}]", self.address());
+        let protocols = std::mem::take(&mut *self.protocols.lock().await);
+        for protocol in protocols {
+            if let Err(err) = protocol.shutdown().await {
+                warn!(
+                    target: "net",
+                    "Unable to shut down {} on [{}]: {}",
+                    protocol.name(),
+                    self.address(),
+                    err
+                );
+            }
+        }
+
+        // Sends hold the writer until their packet is written, and closing
+        // flushes the stream first
+        if let Err(err) = self.writer.lock().await.close().await {
+            debug!(target: "net", "Unable to close channel [{}]: {}", self.address(), err);
+        }
+
+        self.stop().await;
+        debug!(target: "net", "Channel::shutdown() [END, address={}]", self.address());
+    }
+
+    /// Add to the misbehaviour score of the peer. The channel is stopped
+    /// once the score reaches `BAN_THRESHOLD`, and the session bans the
+    /// peer.
+    pub async fn misbehave(&self, score: u32, reason: &str) {
+        let previous = self.ban_score.fetch_add(score, Ordering::Relaxed);
+        warn!(
+            target: "net",
+            "Peer {} misbehaved: {} [score={}]",
+            self.address(),
+            reason,
+            previous + score
+        );
+        if previous < BAN_THRESHOLD && previous + score >= BAN_THRESHOLD {
+            self.stop().await;
+        }
+    }
+
+    /// Returns true if the peer misbehaved enough to be banned.
+    pub fn is_banned(&self) -> bool {
+        self.ban_score.load(Ordering::Relaxed) >= BAN_THRESHOLD
+    }
+
     /// Creates a subscription to a stopped signal.
     pub async fn subscribe_stop(&self) -> Subscription<Error> {
         debug!(target: "net",
@@ -130,8 +353,8 @@ impl Channel {
         sub
     }
 
-    /// Sends a message across a channel. Calls function 'send_message' that
-    /// creates a new payload and sends it over the TCP connection as a
+    /// Sends a message across a channel. Encodes the message and calls
+    /// function 'send_message' that sends it over the TCP connection as a
     /// packet. Returns an error if something goes wrong.
     pub async fn send<M: message::Message>(&self, message: M) -> Result<()> {
         debug!(target: "net",
@@ -143,8 +366,17 @@ impl Channel {
             return Err(Error::ChannelStopped)
         }
 
+        // Oversized messages would get the peer to drop us, so they are
+        // refused without touching the channel.
+        let mut payload = Vec::new();
+        message.encode(&mut payload)?;
+        if payload.len() > M::max_size() {
+            return Err(Error::PacketTooLarge)
+        }
+        let payload_len = payload.len();
+
         // Catch failure and stop channel, return a net error
-        let result = match self.send_message(message).await {
+        let result = match self.send_message::<M>(payload).await {
             Ok(()) => Ok(()),
             Err(err) => {
                 error!("Channel send error for [{}]: {}", self.address(), err);
@@ -162,22 +394,36 @@ impl Channel {
             let info = &mut *self.info.lock().await;
             info.last_msg = M::name().to_string();
             info.last_status = "sent".to_string();
+            if result.is_ok() {
+                info.stats.record_send(M::name(), payload_len);
+            }
         }
 
         result
     }
 
-    /// Implements send message functionality. Creates a new payload and encodes
-    /// it. Then creates a message packet- the base type of the network- and
-    /// copies the payload into it. Then we send the packet over the TCP
-    /// stream.
-    async fn send_message<M: message::Message>(&self, message: M) -> Result<()> {
-        let mut payload = Vec::new();
-        message.encode(&mut payload)?;
+    /// Implements send message functionality. Creates a message packet- the
+    /// base type of the network- and copies the encoded payload into it.
+    /// Then we send the packet over the TCP stream.
+    async fn send_message<M: message::Message>(&self, payload: Vec<u8>) -> Result<()> {
         let packet = message::Packet { command: String::from(M::name()), payload };
 
+        // Packets are sealed once we hold the writer, so they go out in the
+        // order of their nonces. The cipher itself isn't held while writing.
         let stream = &mut *self.writer.lock().await;
-        message::send_packet(stream, packet).await
+        let packet = match self.send_cipher.lock().await.as_mut() {
+            Some(cipher) => cipher.seal_packet(packet)?,
+            None => packet,
+        };
+        message::send_packet(stream, packet).await?;
+
+        // Everything we send after our verack is encrypted
+        if M::name() == message::VerackMessage::name() {
+            if let Some(encryption) = self.encryption.lock().await.as_mut() {
+                *self.send_cipher.lock().await = encryption.activate_send();
+            }
+        }
+        Ok(())
     }
 
     /// Subscribe to a messages on the message subsystem.
@@ -196,9 +442,62 @@ impl Channel {
         sub
     }
 
+    /// Send a request and wait for the response with the same request id.
+    /// The request id is set to a random value, so concurrent requests on
+    /// the channel don't see each other's responses. Fails with
+    /// `RequestTimeout` if no response arrives in time, and with
+    /// `ChannelStopped` as soon as the channel stops.
+    ///
+    /// The response message must have a dispatcher registered on the
+    /// message subsystem.
+    pub async fn request<Req: message::Request, Resp: message::Response>(
+        &self,
+        mut request: Req,
+        timeout: Duration,
+    ) -> Result<Arc<Resp>> {
+        debug!(target: "net",
+            "Channel::request() [START, command={:?}, address={}]",
+            Req::name(),
+            self.address()
+        );
+        let request_id = rand::thread_rng().gen();
+        request.set_request_id(request_id);
+
+        // Subscribe before sending so the response can't be missed
+        let resp_sub = self.subscribe_msg::<Resp>().await?;
+        let stop_sub = self.subscribe_stop().await;
+
+        let response = async {
+            self.send(request).await?;
+            loop {
+                let response = resp_sub.receive().await?;
+                if response.request_id() == request_id {
+                    return Ok::<_, Error>(response)
+                }
+            }
+        };
+        let stopped = async {
+            stop_sub.receive().await;
+            Err(Error::ChannelStopped)
+        };
+        let result = async_std::future::timeout(timeout, future::or(response, stopped))
+            .await
+            .unwrap_or(Err(Error::RequestTimeout));
+
+        resp_sub.unsubscribe().await;
+        stop_sub.unsubscribe().await;
+
+        debug!(target: "net",
+            "Channel::request() [END, command={:?}, address={}]",
+            Req::name(),
+            self.address()
+        );
+        result
+    }
+
     /// Return the local socket address.
-    pub fn address(&self) -> SocketAddr {
-        self.address
+    pub fn address(&self) -> NetAddr {
+        self.address.clone()
     }
 
     /// End of file error. Triggered when unexpected end of file occurs.
@@ -209,6 +508,17 @@ impl Channel {
         }
     }
 
+    /// Errors caused by the peer sending garbage.
+    fn is_malformed_error(err: &Error) -> bool {
+        matches!(
+            err,
+            Error::MalformedPacket |
+                Error::PacketTooLarge |
+                Error::ChannelEncryptionError(_) |
+                Error::Utf8Error(_)
+        )
+    }
+
     /// Perform network handshake for message subsystem dispatchers.
     async fn setup_dispatchers(message_subsystem: &MessageSubsystem) {
         message_subsystem.add_dispatch::<message::VersionMessage>().await;
@@ -235,7 +545,7 @@ impl Channel {
         let reader = &mut *self.reader.lock().await;
 
         loop {
-            let packet = match message::read_packet(reader).await {
+            let packet = match self.read_packet(reader).await {
                 Ok(packet) => packet,
                 Err(err) => {
                     if Self::is_eof_error(err.clone()) {
@@ -247,19 +557,117 @@ impl Channel {
                         "Channel::receive_loop() stopping channel {:?}",
                         self.address()
                     );
-                    self.stop().await;
+                    if Self::is_malformed_error(&err) {
+                        // Stops the channel
+                        self.misbehave(BAN_THRESHOLD, &err.to_string()).await;
+                    } else {
+                        self.stop().await;
+                    }
                     return Err(Error::ChannelStopped)
                 }
             };
             {
+                // Peers choose the commands they send, so the ones we
+                // don't know are counted together
+                let known = self.message_subsystem.max_size(&packet.command).await.is_some();
+                let command = if known { packet.command.as_str() } else { UNKNOWN_COMMAND };
                 let info = &mut *self.info.lock().await;
-                info.last_msg = packet.command.clone();
+                info.last_msg = command.to_string();
                 info.last_status = "recv".to_string();
+                info.stats.record_recv(command, packet.payload.len());
+            }
+
+            if !self.rate_limiter.lock().await.take() {
+                self.misbehave(BAN_THRESHOLD / 10, "Rate limit exceeded").await;
+                continue
             }
 
             // Send result to our subscribers
-            self.message_subsystem.notify(&packet.command, packet.payload).await;
+            if self.message_subsystem.notify(&packet.command, packet.payload).await.is_err() {
+                self.misbehave(BAN_THRESHOLD / 2, "Invalid message").await;
+            }
+        }
+    }
+
+    /// Read a packet and decrypt it if the peer's verack was received.
+    /// The peer's keys are taken from its version message before it is
+    /// passed to the subscribers. Packets larger than their message type
+    /// allows are rejected before the payload is read. Only encrypted
+    /// channels and the handshake messages take an encryption lock.
+    async fn read_packet(
+        &self,
+        reader: &mut ReadHalf<Box<dyn TransportStream>>,
+    ) -> Result<message::Packet> {
+        let (command, payload_len) = message::read_packet_header(reader).await?;
+        // The type of encrypted messages is only known once decrypted, so
+        // they may be as large as the largest message
+        let receiving = self.receiving.load(Ordering::Acquire);
+        let max_size = if command == ENCRYPTED_COMMAND && receiving {
+            let largest = self
+                .message_subsystem
+                .largest_max_size()
+                .await
+                .unwrap_or(message::DEFAULT_MAX_MESSAGE_SIZE);
+            (largest + ENCRYPTION_OVERHEAD).min(self.settings.max_packet_size)
+        } else {
+            self.max_message_size(&command).await
+        };
+        if payload_len > max_size {
+            return Err(Error::PacketTooLarge)
+        }
+        let payload = message::read_packet_payload(reader, payload_len).await?;
+        let mut packet = message::Packet { command, payload };
+
+        if receiving {
+            if let Some(cipher) = self.recv_cipher.lock().await.as_mut() {
+                packet = cipher.open_packet(packet)?;
+            }
+            if packet.payload.len() > self.max_message_size(&packet.command).await {
+                return Err(Error::PacketTooLarge)
+            }
+        }
+
+        if packet.command == message::VersionMessage::name() ||
+            packet.command == message::VerackMessage::name()
+        {
+            self.receive_handshake(&packet).await?;
+        }
+
+        Ok(packet)
+    }
+
+    /// Pass the peer's version and verack to the encryption handshake.
+    async fn receive_handshake(&self, packet: &message::Packet) -> Result<()> {
+        let mut encryption = self.encryption.lock().await;
+        let encryption = match encryption.as_mut() {
+            Some(encryption) => encryption,
+            None => return Ok(()),
+        };
+
+        if packet.command == message::VersionMessage::name() {
+            let version: message::VersionMessage = deserialize(&packet.payload)?;
+            encryption.receive_version(&version)?;
+        } else {
+            let verack: message::VerackMessage = deserialize(&packet.payload)?;
+            encryption.verify_transcript(&verack.transcript)?;
+
+            // Everything the peer sends after its verack is encrypted
+            if let Some(cipher) = encryption.activate_recv() {
+                *self.recv_cipher.lock().await = Some(cipher);
+                self.receiving.store(true, Ordering::Release);
+            }
         }
+        Ok(())
+    }
+
+    /// Maximum payload size of a message type. Unknown messages are
+    /// dropped by the subsystem, so they get the default limit.
+    async fn max_message_size(&self, command: &str) -> usize {
+        self.message_subsystem
+            .max_size(command)
+            .await
+            .unwrap_or(message::DEFAULT_MAX_MESSAGE_SIZE)
+            .min(self.settings.max_packet_size)
     }
 
     /// Handle network errors. Panic if error passes silently, otherwise
@@ -276,3 +684,111 @@ impl Channel {
         debug!(target: "net", "Channel::handle_stop() [END, address={}]", self.address());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+    use futures::future::join_all;
+    use std::{collections::HashSet, io, sync::Arc, time::Duration};
+
+    use crate::{
+        net::{
+            message::{PingMessage, PongMessage},
+            sim::SimNetwork,
+            Message, Request, Response, SESSION_SEED,
+        },
+        util::serial::{Decodable, Encodable},
+        Error, Result,
+    };
+
+    /// Request no node answers.
+    struct TestMessage {
+        id: u32,
+    }
+
+    impl Message for TestMessage {
+        fn name() -> &'static str {
+            "test"
+        }
+    }
+
+    impl Encodable for TestMessage {
+        fn encode<S: io::Write>(&self, s: S) -> Result<usize> {
+            self.id.encode(s)
+        }
+    }
+
+    impl Decodable for TestMessage {
+        fn decode<D: io::Read>(d: D) -> Result<Self> {
+            Ok(Self { id: Decodable::decode(d)? })
+        }
+    }
+
+    impl Request for TestMessage {
+        fn request_id(&self) -> u32 {
+            self.id
+        }
+
+        fn set_request_id(&mut self, id: u32) {
+            self.id = id;
+        }
+    }
+
+    impl Response for TestMessage {
+        fn request_id(&self) -> u32 {
+            self.id
+        }
+    }
+
+    #[test]
+    fn request_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(6);
+            network.set_latency(Duration::from_millis(10));
+            let nodes = network.create_nodes(2, |_, _| {}).await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            let node = nodes[1].clone();
+            let connected = || async {
+                node.channels().await.iter().any(|channel| channel.session() != SESSION_SEED)
+            };
+            assert!(network.wait_until(Duration::from_secs(20), connected).await);
+            let channel = node
+                .channels()
+                .await
+                .into_iter()
+                .find(|channel| channel.session() != SESSION_SEED)
+                .unwrap();
+
+            // Concurrent requests each get their own response
+            let requests = (0..8).map(|_| {
+                channel.request::<_, PongMessage>(PingMessage { nonce: 0 }, Duration::from_secs(5))
+            });
+            let mut nonces = HashSet::new();
+            for pong in join_all(requests).await {
+                nonces.insert(pong.unwrap().nonce);
+            }
+            assert_eq!(nonces.len(), 8);
+
+            // The peer doesn't answer test messages
+            channel.get_message_subsystem().add_dispatch::<TestMessage>().await;
+            let result = channel
+                .request::<_, TestMessage>(TestMessage { id: 0 }, Duration::from_millis(200))
+                .await;
+            assert!(matches!(result, Err(Error::RequestTimeout)));
+
+            // Pending requests are cancelled when the channel stops
+            let channel2 = channel.clone();
+            let request = ex.spawn(async move {
+                channel2
+                    .request::<_, TestMessage>(TestMessage { id: 0 }, Duration::from_secs(60))
+                    .await
+            });
+            smol::Timer::after(Duration::from_millis(100)).await;
+            channel.stop().await;
+            let result = async_std::future::timeout(Duration::from_secs(5), request).await.unwrap();
+            assert!(matches!(result, Err(Error::ChannelStopped)));
+        }));
+    }
+}
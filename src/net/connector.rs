@@ -1,13 +1,10 @@
-use async_std::future::timeout;
+use async_std::{future::timeout, net::ToSocketAddrs};
 use smol::Async;
-use std::{
-    net::{SocketAddr, TcpStream},
-    time::Duration,
-};
+use std::{net::TcpStream, time::Duration};
 
 use crate::{
     error::{Error, Result},
-    net::{Channel, ChannelPtr, SettingsPtr},
+    net::{socks5, Channel, ChannelPtr, NetAddr, SettingsPtr, TransportStream},
 };
 
 /// Create outbound socket connections.
@@ -21,14 +18,14 @@ impl Connector {
         Self { settings }
     }
 
-    /// Establish an outbound connection.
-    pub async fn connect(&self, hostaddr: SocketAddr) -> Result<ChannelPtr> {
+    /// Establish an outbound connection. Connections are routed through
+    /// the SOCKS5 proxy if one is configured, or opened with the transport
+    /// set in the settings.
+    pub async fn connect(&self, hostaddr: NetAddr) -> Result<ChannelPtr> {
         let stream_result =
             timeout(Duration::from_secs(self.settings.connect_timeout_seconds.into()), async {
-                match Async::<TcpStream>::connect(hostaddr).await {
-                    Ok(stream) => Ok(Channel::new(stream, hostaddr).await),
-                    Err(_) => Err(Error::ConnectFailed),
-                }
+                let stream = self.dial(&hostaddr).await?;
+                Ok(Channel::new(stream, hostaddr.clone(), self.settings.clone()).await)
             })
             .await;
         match stream_result {
@@ -36,4 +33,40 @@ impl Connector {
             Err(_) => Err(Error::ConnectTimeout),
         }
     }
+
+    /// Returns false for addresses which can't be dialed with the current
+    /// settings, ie. onion addresses without a proxy.
+    pub fn can_dial(&self, hostaddr: &NetAddr) -> bool {
+        !hostaddr.is_onion() ||
+            self.settings.transport.is_some() ||
+            self.settings.socks5_proxy.is_some()
+    }
+
+    async fn dial(&self, hostaddr: &NetAddr) -> Result<Box<dyn TransportStream>> {
+        if let Some(transport) = &self.settings.transport {
+            return transport.dial(hostaddr).await
+        }
+
+        if let Some(proxy) = &self.settings.socks5_proxy {
+            return socks5::connect(proxy, hostaddr).await
+        }
+
+        let addrs = match hostaddr {
+            NetAddr::Ip(addr) => vec![*addr],
+            // Onion addresses can't be resolved without a proxy
+            NetAddr::Host(_, _) if hostaddr.is_onion() => return Err(Error::NoSocks5UrlFound),
+            NetAddr::Host(host, port) => (host.as_str(), *port)
+                .to_socket_addrs()
+                .await
+                .map_err(|_| Error::ConnectFailed)?
+                .collect(),
+        };
+
+        for addr in addrs {
+            if let Ok(stream) = Async::<TcpStream>::connect(addr).await {
+                return Ok(Box::new(stream))
+            }
+        }
+        Err(Error::ConnectFailed)
+    }
 }
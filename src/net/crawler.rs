@@ -0,0 +1,267 @@
+use async_std::{future::timeout, sync::Mutex};
+use futures::future::join_all;
+use log::{debug, info};
+use serde_json::json;
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_executor::Executor;
+
+use crate::{
+    error::{Error, Result},
+    net::{
+        message, message_subscriber::MessageSubscription, protocol::ProtocolVersion, ChannelPtr,
+        Connector, NetAddr, P2p, P2pPtr,
+    },
+    system::{StoppableTask, StoppableTaskPtr},
+    util::sleep,
+};
+
+/// Number of hosts crawled at once.
+const CRAWL_BATCH: usize = 16;
+/// Maximum number of addresses kept from a single host.
+const MAX_ADDRS: usize = 1000;
+
+/// Counters of the crawler since the node started.
+#[derive(Clone, Debug, Default)]
+pub struct CrawlStats {
+    /// Number of completed crawls of the host store
+    pub rounds: u64,
+    /// Hosts that completed the version handshake
+    pub successes: u64,
+    /// Hosts that couldn't be reached
+    pub failures: u64,
+    /// Addresses received from the crawled hosts
+    pub addrs_received: u64,
+    /// When the last crawl finished, in seconds since the unix epoch
+    pub last_round: u64,
+}
+
+/// Crawls the network on seed nodes. Each host of the store is
+/// periodically connected to, so the hosts seeds share are known to be
+/// reachable, and asked for the addresses it knows so new nodes are
+/// discovered.
+pub struct Crawler {
+    p2p: Weak<P2p>,
+    task: StoppableTaskPtr,
+    stats: Mutex<CrawlStats>,
+}
+
+impl Crawler {
+    /// Create a new crawler.
+    pub fn new(p2p: Weak<P2p>) -> Arc<Self> {
+        Arc::new(Self { p2p, task: StoppableTask::new(), stats: Mutex::new(CrawlStats::default()) })
+    }
+
+    /// Start crawling the hosts every `Settings.crawl_interval_seconds`.
+    pub async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) {
+        info!(target: "net", "Starting the crawler");
+        self.task.clone().start(
+            self.clone().crawl_loop(executor.clone()),
+            // Ignore stop handler
+            |_| async {},
+            Error::ServiceStopped,
+            executor,
+        );
+    }
+
+    /// Stop the crawler.
+    pub async fn stop(&self) {
+        self.task.stop().await;
+    }
+
+    /// Return the crawler counters.
+    pub async fn stats(&self) -> CrawlStats {
+        self.stats.lock().await.clone()
+    }
+
+    pub async fn get_info(&self) -> serde_json::Value {
+        let hosts = self.p2p().hosts();
+        let stats = self.stats().await;
+        json!({
+            "rounds": stats.rounds,
+            "successes": stats.successes,
+            "failures": stats.failures,
+            "addrs_received": stats.addrs_received,
+            "last_round": stats.last_round,
+            "hosts": hosts.load_all().await.len(),
+            "reachable": hosts.load_reachable().await.len(),
+        })
+    }
+
+    fn p2p(&self) -> P2pPtr {
+        self.p2p.upgrade().unwrap()
+    }
+
+    async fn crawl_loop(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+        let interval = self.p2p().settings().crawl_interval_seconds;
+        loop {
+            let started = now();
+            self.crawl(started.saturating_sub(interval as u64), executor.clone()).await;
+
+            let mut stats = self.stats.lock().await;
+            stats.rounds += 1;
+            stats.last_round = now();
+            drop(stats);
+
+            sleep(interval).await;
+        }
+    }
+
+    /// Visit the hosts not tried since `since`, a batch at a time. Hosts we
+    /// can't dial, eg. onion addresses without a proxy, are kept as they
+    /// are rather than marked as unreachable.
+    async fn crawl(&self, since: u64, executor: Arc<Executor<'_>>) {
+        let p2p = self.p2p();
+        let hosts = p2p.hosts();
+        let self_addrs = p2p.external_addrs().await;
+        let connector = Connector::new(p2p.settings());
+
+        let stale: Vec<NetAddr> = hosts
+            .load_stale(since)
+            .await
+            .into_iter()
+            .filter(|addr| !self_addrs.contains(addr) && connector.can_dial(addr))
+            .collect();
+        debug!(target: "net", "Crawling {} hosts", stale.len());
+
+        for batch in stale.chunks(CRAWL_BATCH) {
+            let visits = batch.iter().map(|addr| self.visit(&p2p, addr.clone(), executor.clone()));
+            join_all(visits).await;
+        }
+    }
+
+    /// Check a host is reachable and store the addresses it knows.
+    async fn visit(&self, p2p: &P2pPtr, addr: NetAddr, executor: Arc<Executor<'_>>) {
+        let hosts = p2p.hosts();
+
+        // Connected peers are known to be up, and share their addresses
+        // through the address protocol
+        if p2p.exists(&addr).await {
+            hosts.mark_success(&addr).await;
+            return
+        }
+        if hosts.is_banned(&addr).await || !p2p.add_pending(addr.clone()).await {
+            return
+        }
+
+        let result = self.query(p2p, addr.clone(), executor).await;
+        p2p.remove_pending(&addr).await;
+
+        let mut stats = self.stats.lock().await;
+        match result {
+            Ok(mut addrs) => {
+                debug!(target: "net", "Crawled [{}], received {} addrs", addr, addrs.len());
+                stats.successes += 1;
+                stats.addrs_received += addrs.len() as u64;
+                drop(stats);
+
+                addrs.truncate(MAX_ADDRS);
+                hosts.mark_success(&addr).await;
+                hosts.store(addrs).await;
+            }
+            Err(err) => {
+                debug!(target: "net", "Unable to crawl [{}]: {}", addr, err);
+                stats.failures += 1;
+                drop(stats);
+
+                hosts.mark_failure(&addr).await;
+            }
+        }
+    }
+
+    /// Connect to a host and perform the version handshake, without
+    /// registering the channel, then ask it for addresses. Hosts that
+    /// complete the handshake are reachable even if they send no address.
+    async fn query(
+        &self,
+        p2p: &P2pPtr,
+        addr: NetAddr,
+        executor: Arc<Executor<'_>>,
+    ) -> Result<Vec<NetAddr>> {
+        let connector = Connector::new(p2p.settings());
+        let channel = connector.connect(addr).await?;
+
+        let addrs_sub = channel.clone().subscribe_msg::<message::AddrsMessage>().await?;
+        let protocol_version = ProtocolVersion::new(channel.clone(), p2p.clone()).await;
+        channel.clone().start(executor.clone());
+
+        if let Err(err) = protocol_version.run(executor).await {
+            channel.stop().await;
+            return Err(err)
+        }
+
+        let query_timeout = Duration::from_secs(p2p.settings().seed_query_timeout_seconds.into());
+        let addrs = match timeout(query_timeout, Self::get_addrs(&channel, addrs_sub)).await {
+            Ok(Ok(addrs)) => addrs,
+            Ok(Err(_)) | Err(_) => Vec::new(),
+        };
+        channel.stop().await;
+        Ok(addrs)
+    }
+
+    async fn get_addrs(
+        channel: &ChannelPtr,
+        addrs_sub: MessageSubscription<message::AddrsMessage>,
+    ) -> Result<Vec<NetAddr>> {
+        channel.clone().send(message::GetAddrsMessage {}).await?;
+        let addrs_msg = addrs_sub.receive().await?;
+        Ok(addrs_msg.addrs.clone())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+    use std::{sync::Arc, time::Duration};
+
+    use crate::net::sim::SimNetwork;
+
+    #[test]
+    fn crawler_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(5);
+            network.set_latency(Duration::from_millis(10));
+            let nodes = network
+                .create_nodes(5, |index, settings| {
+                    if index == 0 {
+                        settings.seed_mode = true;
+                        settings.crawl_interval_seconds = 1;
+                        settings.connect_timeout_seconds = 1;
+                    }
+                })
+                .await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            // The seed checks every node it learned about
+            let seed = nodes[0].clone();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        seed.hosts().load_reachable().await.len() == nodes.len() - 1
+                    })
+                    .await
+            );
+
+            // Nodes cut off from the seed are no longer shared
+            let lost = SimNetwork::node_addr(4);
+            network.partition(&[SimNetwork::node_addr(0).ip()], &[lost.ip()]);
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        !seed.hosts().load_reachable().await.contains(&lost.into())
+                    })
+                    .await
+            );
+            let stats = seed.crawler().await.stats().await;
+            assert!(stats.rounds > 0 && stats.failures > 0);
+        }));
+    }
+}
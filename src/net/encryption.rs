@@ -0,0 +1,421 @@
+use std::sync::Arc;
+
+use blake2b_simd::Params as Blake2bParams;
+use crypto_api_chachapoly::ChachaPolyIetf;
+use pasta_curves::{
+    group::{
+        ff::{Field, PrimeField},
+        Group, GroupEncoding,
+    },
+    pallas,
+};
+use rand::rngs::OsRng;
+
+use crate::{
+    error::{Error, Result},
+    net::message::{Packet, VersionMessage, MAX_COMMAND_LENGTH},
+    util::serial::{deserialize, serialize},
+};
+
+const CIPHER_KDF_PERSONALIZATION: &[u8; 16] = b"DarkFi_NetCipher";
+const TRANSCRIPT_KDF_PERSONALIZATION: &[u8; 16] = b"DarkFi_NetTransc";
+
+const AEAD_TAG_SIZE: usize = 16;
+
+/// Command of the packets wrapping encrypted packets.
+pub const ENCRYPTED_COMMAND: &str = "enc";
+
+/// Size added to a packet by encryption: the command and payload lengths,
+/// the command and the tag.
+pub const ENCRYPTION_OVERHEAD: usize = 1 + MAX_COMMAND_LENGTH + 9 + AEAD_TAG_SIZE;
+
+/// Static key identifying a node on the network.
+pub struct NodeIdentity {
+    secret: pallas::Scalar,
+    public: pallas::Point,
+}
+
+impl NodeIdentity {
+    pub fn random() -> Self {
+        Self::from_secret(pallas::Scalar::random(&mut OsRng))
+    }
+
+    pub fn from_secret_bytes(bytes: [u8; 32]) -> Result<Self> {
+        match pallas::Scalar::from_repr(bytes).into() {
+            Some(secret) => Ok(Self::from_secret(secret)),
+            None => Err(Error::ChannelEncryptionError("Invalid node identity secret")),
+        }
+    }
+
+    fn from_secret(secret: pallas::Scalar) -> Self {
+        Self { secret, public: pallas::Point::generator() * secret }
+    }
+
+    pub fn public_bytes(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+}
+
+/// ChaCha20-Poly1305 cipher for one direction of a channel. Packets are
+/// read in the order they are sent, so a counter is used as the nonce.
+pub struct Cipher {
+    key: [u8; 32],
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: [u8; 32]) -> Self {
+        Self { key, counter: 0 }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce();
+        let mut ciphertext = vec![0u8; plaintext.len() + AEAD_TAG_SIZE];
+        ChachaPolyIetf::aead_cipher()
+            .seal_to(&mut ciphertext, plaintext, &[], &self.key, &nonce)
+            .map_err(|_| Error::ChannelEncryptionError("Encryption failed"))?;
+        Ok(ciphertext)
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < AEAD_TAG_SIZE {
+            return Err(Error::ChannelEncryptionError("Packet is too short"))
+        }
+
+        let nonce = self.nonce();
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let len = ChachaPolyIetf::aead_cipher()
+            .open_to(&mut plaintext, ciphertext, &[], &self.key, &nonce)
+            .map_err(|_| Error::ChannelEncryptionError("Decryption failed"))?;
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+
+    /// Wrap a packet in an encrypted packet.
+    pub fn seal_packet(&mut self, packet: Packet) -> Result<Packet> {
+        let plaintext = serialize(&(packet.command, packet.payload));
+        let payload = self.seal(&plaintext)?;
+        Ok(Packet { command: ENCRYPTED_COMMAND.to_string(), payload })
+    }
+
+    /// Unwrap the packet sealed by the peer.
+    pub fn open_packet(&mut self, packet: Packet) -> Result<Packet> {
+        if packet.command != ENCRYPTED_COMMAND {
+            return Err(Error::ChannelEncryptionError("Unencrypted packet"))
+        }
+        let plaintext = self.open(&packet.payload)?;
+        let (command, payload): (String, Vec<u8>) = deserialize(&plaintext)?;
+        Ok(Packet { command, payload })
+    }
+}
+
+/// Transport encryption state of a channel.
+///
+/// Both nodes send an ephemeral key and their identity key in the version
+/// message. The cipher keys are derived from the ephemeral-ephemeral DH
+/// and both ephemeral-static DHs, so only the owners of the announced
+/// identities can read the channel. A node encrypts everything it sends
+/// after its verack, and decrypts everything it receives after the
+/// peer's verack. The ciphers of both directions are handed over to the
+/// channel then, so sending and receiving don't wait on each other.
+///
+/// The verack carries a MAC of both version messages, keyed with the same
+/// DHs, so a change to either of them, eg. clearing the encryption flags
+/// to downgrade the channel, is detected even when the channel isn't
+/// encrypted. An announced identity is only tied to a node when its key
+/// is pinned for the address we dialed, otherwise a man in the middle can
+/// announce its own identity.
+pub struct ChannelEncryption {
+    identity: Arc<NodeIdentity>,
+    enabled: bool,
+    /// Identity pinned for the peer's address
+    expected_identity: Option<[u8; 32]>,
+    ephem_secret: pallas::Scalar,
+    ephem_public: pallas::Point,
+    peer_identity: Option<[u8; 32]>,
+    sent_version: Vec<u8>,
+    received_version: Vec<u8>,
+    /// MAC keys of the transcripts we send and receive
+    transcript_keys: Option<([u8; 32], [u8; 32])>,
+    pending: Option<([u8; 32], [u8; 32])>,
+    sending: bool,
+    receiving: bool,
+}
+
+impl ChannelEncryption {
+    pub fn new(
+        identity: Arc<NodeIdentity>,
+        enabled: bool,
+        expected_identity: Option<[u8; 32]>,
+    ) -> Self {
+        let ephem_secret = pallas::Scalar::random(&mut OsRng);
+        let ephem_public = pallas::Point::generator() * ephem_secret;
+        Self {
+            identity,
+            enabled,
+            expected_identity,
+            ephem_secret,
+            ephem_public,
+            peer_identity: None,
+            sent_version: vec![],
+            received_version: vec![],
+            transcript_keys: None,
+            pending: None,
+            sending: false,
+            receiving: false,
+        }
+    }
+
+    /// Announce our keys to the peer in the version message, which must
+    /// be complete as it is recorded in the transcript.
+    pub fn announce(&mut self, version: &mut VersionMessage) {
+        version.encryption = self.enabled;
+        version.identity = self.identity.public_bytes();
+        version.ephem_public = self.ephem_public.to_bytes();
+        self.sent_version = serialize(version);
+    }
+
+    /// Derive the transcript and cipher keys from the peer's version
+    /// message. The ciphers are only used once both sides sent their
+    /// verack.
+    pub fn receive_version(&mut self, version: &VersionMessage) -> Result<()> {
+        if let Some(expected) = self.expected_identity {
+            if version.identity != expected {
+                return Err(Error::ChannelEncryptionError("Unexpected peer identity"))
+            }
+            // The pinned identity only authenticates an encrypted channel
+            if !self.enabled || !version.encryption {
+                return Err(Error::ChannelEncryptionRequired)
+            }
+        }
+
+        self.peer_identity = Some(version.identity);
+        self.received_version = serialize(version);
+
+        let peer_identity = decode_point(&version.identity)?;
+        let peer_ephem = decode_point(&version.ephem_public)?;
+
+        let ee = (peer_ephem * self.ephem_secret).to_bytes();
+        let es = (peer_ephem * self.identity.secret).to_bytes();
+        let se = (peer_identity * self.ephem_secret).to_bytes();
+        // The peer computes the two ephemeral-static DHs the other way round
+        let (dh1, dh2) = if es <= se { (es, se) } else { (se, es) };
+
+        let derive = |sender_ephem: &[u8; 32]| {
+            let hash = Blake2bParams::new()
+                .hash_length(32)
+                .personal(CIPHER_KDF_PERSONALIZATION)
+                .to_state()
+                .update(&ee)
+                .update(&dh1)
+                .update(&dh2)
+                .update(sender_ephem)
+                .finalize();
+            let mut key = [0u8; 32];
+            key.copy_from_slice(hash.as_bytes());
+            key
+        };
+
+        let send = derive(&self.ephem_public.to_bytes());
+        let recv = derive(&version.ephem_public);
+
+        // Separate keys for the transcript MACs, since they are used
+        // whether the channel gets encrypted or not
+        let transcript_key = |key: &[u8; 32]| {
+            let hash = Blake2bParams::new()
+                .hash_length(32)
+                .personal(TRANSCRIPT_KDF_PERSONALIZATION)
+                .hash(key);
+            let mut transcript_key = [0u8; 32];
+            transcript_key.copy_from_slice(hash.as_bytes());
+            transcript_key
+        };
+        self.transcript_keys = Some((transcript_key(&send), transcript_key(&recv)));
+
+        if self.enabled && version.encryption {
+            self.pending = Some((send, recv));
+        }
+        Ok(())
+    }
+
+    /// MAC of the version messages, sent in our verack.
+    pub fn transcript_mac(&self) -> Result<[u8; 32]> {
+        match &self.transcript_keys {
+            Some((key, _)) => Ok(mac(key, &self.sent_version, &self.received_version)),
+            None => Err(Error::ChannelEncryptionError("Missing peer version")),
+        }
+    }
+
+    /// Check the MAC from the peer's verack against the version messages
+    /// we sent and received.
+    pub fn verify_transcript(&self, peer_mac: &[u8; 32]) -> Result<()> {
+        let expected = match &self.transcript_keys {
+            Some((_, key)) => mac(key, &self.received_version, &self.sent_version),
+            None => return Err(Error::ChannelEncryptionError("Missing peer version")),
+        };
+
+        // Constant time comparison
+        let diff = expected.iter().zip(peer_mac.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 {
+            return Err(Error::ChannelEncryptionError("Handshake transcript mismatch"))
+        }
+        Ok(())
+    }
+
+    /// Called after sending our verack. Returns the cipher sealing the
+    /// packets we send, unless the channel stays unencrypted.
+    pub fn activate_send(&mut self) -> Option<Cipher> {
+        let (key, _) = self.pending?;
+        self.sending = true;
+        Some(Cipher::new(key))
+    }
+
+    /// Called after receiving the peer's verack. Returns the cipher
+    /// opening the packets we receive, unless the channel stays
+    /// unencrypted.
+    pub fn activate_recv(&mut self) -> Option<Cipher> {
+        let (_, key) = self.pending?;
+        self.receiving = true;
+        Some(Cipher::new(key))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.sending && self.receiving
+    }
+
+    pub fn peer_identity(&self) -> Option<[u8; 32]> {
+        self.peer_identity
+    }
+}
+
+/// MAC of the version message sent by a node and the one it received.
+fn mac(key: &[u8; 32], sent: &[u8], received: &[u8]) -> [u8; 32] {
+    let hash = Blake2bParams::new()
+        .hash_length(32)
+        .key(key)
+        .to_state()
+        .update(&(sent.len() as u64).to_le_bytes())
+        .update(sent)
+        .update(received)
+        .finalize();
+    let mut mac = [0u8; 32];
+    mac.copy_from_slice(hash.as_bytes());
+    mac
+}
+
+fn decode_point(bytes: &[u8; 32]) -> Result<pallas::Point> {
+    match pallas::Point::from_bytes(bytes).into() {
+        Some(point) => Ok(point),
+        None => Err(Error::ChannelEncryptionError("Invalid public key")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ChannelEncryption, NodeIdentity};
+    use crate::net::message::{Packet, VersionMessage, PROTOCOL_VERSION, SERVICE_NONE};
+
+    fn version_message(encryption: &mut ChannelEncryption) -> VersionMessage {
+        let mut version = VersionMessage {
+            version: PROTOCOL_VERSION,
+            user_agent: String::new(),
+            timestamp: 0,
+            services: SERVICE_NONE,
+            listen_addr: None,
+            encryption: false,
+            identity: [0u8; 32],
+            ephem_public: [0u8; 32],
+            remote_addr: None,
+        };
+        encryption.announce(&mut version);
+        version
+    }
+
+    #[test]
+    fn channel_encryption_test() {
+        let mut alice = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, None);
+        let mut bob = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, None);
+
+        let alice_version = version_message(&mut alice);
+        let bob_version = version_message(&mut bob);
+        alice.receive_version(&bob_version).unwrap();
+        bob.receive_version(&alice_version).unwrap();
+
+        bob.verify_transcript(&alice.transcript_mac().unwrap()).unwrap();
+        alice.verify_transcript(&bob.transcript_mac().unwrap()).unwrap();
+
+        let mut send = alice.activate_send().unwrap();
+        let mut recv = bob.activate_recv().unwrap();
+
+        let packet = Packet { command: "ping".to_string(), payload: vec![1, 2, 3] };
+        let sealed = send.seal_packet(packet).unwrap();
+        assert_eq!(sealed.command, "enc");
+
+        let opened = recv.open_packet(sealed).unwrap();
+        assert_eq!(opened.command, "ping");
+        assert_eq!(opened.payload, vec![1, 2, 3]);
+
+        // Packets sent in clear are rejected once the peer's verack is in
+        let packet = Packet { command: "ping".to_string(), payload: vec![] };
+        assert!(recv.open_packet(packet).is_err());
+
+        // Without encryption on one side, packets are sent as they are
+        let mut carol = ChannelEncryption::new(Arc::new(NodeIdentity::random()), false, None);
+        carol.receive_version(&alice_version).unwrap();
+        assert!(carol.activate_send().is_none());
+        assert!(!carol.is_encrypted());
+    }
+
+    #[test]
+    fn channel_downgrade_test() {
+        let mut alice = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, None);
+        let mut bob = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, None);
+
+        // A man in the middle clears the encryption flag of Alice's version
+        let mut alice_version = version_message(&mut alice);
+        let bob_version = version_message(&mut bob);
+        alice.receive_version(&bob_version).unwrap();
+        alice_version.encryption = false;
+        bob.receive_version(&alice_version).unwrap();
+        assert!(!bob.is_encrypted());
+
+        // Neither transcript matches what the other node saw
+        assert!(bob.verify_transcript(&alice.transcript_mac().unwrap()).is_err());
+        assert!(alice.verify_transcript(&bob.transcript_mac().unwrap()).is_err());
+    }
+
+    #[test]
+    fn pinned_identity_test() {
+        let bob_identity = Arc::new(NodeIdentity::random());
+        let mut bob = ChannelEncryption::new(bob_identity.clone(), true, None);
+        let bob_version = version_message(&mut bob);
+
+        let pinned = Some(bob_identity.public_bytes());
+        let mut alice = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, pinned);
+        version_message(&mut alice);
+        assert!(alice.receive_version(&bob_version).is_ok());
+
+        // Another identity is refused
+        let mut mallory = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, None);
+        let mallory_version = version_message(&mut mallory);
+        let mut alice = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, pinned);
+        version_message(&mut alice);
+        assert!(alice.receive_version(&mallory_version).is_err());
+
+        // And so is the pinned identity on an unencrypted channel
+        let mut bob = ChannelEncryption::new(bob_identity, false, None);
+        let bob_version = version_message(&mut bob);
+        let mut alice = ChannelEncryption::new(Arc::new(NodeIdentity::random()), true, pinned);
+        version_message(&mut alice);
+        assert!(alice.receive_version(&bob_version).is_err());
+    }
+}
@@ -1,42 +1,352 @@
 use async_std::sync::Mutex;
+use log::{debug, warn};
 use rand::seq::SliceRandom;
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::Result,
+    impl_vec,
+    net::{NetAddr, SettingsPtr},
+    util::serial::{deserialize, serialize, Decodable, Encodable, VarInt},
+};
 
 /// Pointer to hosts class.
 pub type HostsPtr = Arc<Hosts>;
 
-/// Manages a store of network addresses.
+/// Maximum number of addresses kept in the host store.
+pub const HOSTS_MAX_SIZE: usize = 2000;
+
+/// Hosts are dropped after this many failed connection attempts in a row.
+pub const HOSTS_MAX_FAILURES: u32 = 10;
+
+/// Maximum number of addresses kept from a single network group, so
+/// peers announcing many addresses of one network can't fill the store.
+/// Hostnames are free to create, so they share a group, and so do onion
+/// addresses.
+pub const HOSTS_MAX_PER_GROUP: usize = 32;
+
+/// What we know about a host address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HostInfo {
+    /// Last time the address was announced to us
+    pub last_seen: u64,
+    /// Last time we connected to the host
+    pub last_success: u64,
+    /// Last time we tried to connect to the host
+    pub last_attempt: u64,
+    /// Failed connection attempts since the last success
+    pub failures: u32,
+}
+
+impl HostInfo {
+    /// Returns true once we connected to the host. Other hosts were only
+    /// announced to us.
+    pub fn is_tried(&self) -> bool {
+        self.last_success > 0
+    }
+
+    /// Returns true if our last connection attempt to the host succeeded.
+    pub fn is_reachable(&self) -> bool {
+        self.is_tried() && self.failures == 0
+    }
+
+    /// Score used to choose outbound peers. Hosts we connected to
+    /// recently score best, and every failed attempt lowers the score.
+    pub fn score(&self, now: u64) -> i64 {
+        let hours_since = |time: u64| (now.saturating_sub(time) / 3600) as i64;
+
+        let mut score = 0;
+        if self.last_success > 0 {
+            score += 100 - hours_since(self.last_success).min(100);
+        }
+        score += 20 - hours_since(self.last_seen).min(20);
+        score -= 30 * self.failures as i64;
+        score
+    }
+}
+
+impl Encodable for HostInfo {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.last_seen.encode(&mut s)?;
+        len += self.last_success.encode(&mut s)?;
+        len += self.last_attempt.encode(&mut s)?;
+        len += self.failures.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for HostInfo {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            last_seen: Decodable::decode(&mut d)?,
+            last_success: Decodable::decode(&mut d)?,
+            last_attempt: Decodable::decode(&mut d)?,
+            failures: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl_vec!((NetAddr, HostInfo));
+impl_vec!((String, u64));
+
+/// Manages a store of network addresses, and the hosts banned for
+/// misbehaving. The store is saved to `Settings.hosts_path` so it
+/// survives restarts, along with the anchors: the outbound peers we were
+/// connected to, which are reconnected first on restart.
 pub struct Hosts {
-    addrs: Mutex<Vec<SocketAddr>>,
+    addrs: Mutex<HashMap<NetAddr, HostInfo>>,
+    /// Ban expiry times by host, so banned peers can't come back on
+    /// another port.
+    bans: Mutex<HashMap<String, u64>>,
+    anchors: Mutex<Vec<NetAddr>>,
+    settings: SettingsPtr,
 }
 
 impl Hosts {
-    /// Create a new host list.
-    pub fn new() -> Arc<Self> {
-        Arc::new(Self { addrs: Mutex::new(Vec::new()) })
+    /// Create a new host list, loading the saved hosts if there are any.
+    pub fn new(settings: SettingsPtr) -> Arc<Self> {
+        let (addrs, bans) = match &settings.hosts_path {
+            Some(path) => match Self::load(path) {
+                Ok(hosts) => hosts,
+                Err(err) => {
+                    warn!(target: "net", "Unable to load hosts from {:?}: {}", path, err);
+                    (HashMap::new(), HashMap::new())
+                }
+            },
+            None => (HashMap::new(), HashMap::new()),
+        };
+
+        let anchors = match &settings.hosts_path {
+            Some(path) => Self::load_anchors(&anchors_path(path)).unwrap_or_else(|err| {
+                warn!(target: "net", "Unable to load anchors: {}", err);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+        Arc::new(Self {
+            addrs: Mutex::new(addrs),
+            bans: Mutex::new(bans),
+            anchors: Mutex::new(anchors),
+            settings,
+        })
+    }
+
+    fn load_anchors(path: &Path) -> Result<Vec<NetAddr>> {
+        if !path.exists() {
+            return Ok(Vec::new())
+        }
+        deserialize(&fs::read(path)?)
+    }
+
+    fn load(path: &Path) -> Result<(HashMap<NetAddr, HostInfo>, HashMap<String, u64>)> {
+        if !path.exists() {
+            return Ok((HashMap::new(), HashMap::new()))
+        }
+
+        let (addrs, bans): (Vec<(NetAddr, HostInfo)>, Vec<(String, u64)>) =
+            deserialize(&fs::read(path)?)?;
+        debug!(target: "net", "Loaded {} hosts and {} bans from {:?}", addrs.len(), bans.len(), path);
+        Ok((addrs.into_iter().collect(), bans.into_iter().collect()))
+    }
+
+    /// Write the host store to disk. Does nothing if no path is configured.
+    pub async fn save(&self) -> Result<()> {
+        let path = match &self.settings.hosts_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let now = now();
+        let addrs: Vec<(NetAddr, HostInfo)> =
+            self.addrs.lock().await.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        let bans: Vec<(String, u64)> = self
+            .bans
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+
+        write_file(path, &serialize(&(addrs, bans)))?;
+        write_file(&anchors_path(path), &serialize(&*self.anchors.lock().await))
+    }
+
+    /// Add new hosts to the host list. Addresses already known are marked
+    /// as seen, and banned hosts and addresses we can't connect to are
+    /// ignored.
+    pub async fn store(&self, addrs: Vec<NetAddr>) {
+        let now = now();
+        for addr in addrs {
+            if !addr.is_connectable() || self.is_banned(&addr).await {
+                continue
+            }
+
+            let mut hosts = self.addrs.lock().await;
+            if !hosts.contains_key(&addr) {
+                if let Some(group) = addr.network_group() {
+                    let group_size =
+                        hosts.keys().filter(|host| host.network_group() == Some(group)).count();
+                    if group_size >= HOSTS_MAX_PER_GROUP {
+                        continue
+                    }
+                }
+                if hosts.len() >= HOSTS_MAX_SIZE {
+                    Self::evict_worst(&mut hosts, now);
+                }
+            }
+            hosts.entry(addr).or_default().last_seen = now;
+        }
+    }
+
+    fn evict_worst(hosts: &mut HashMap<NetAddr, HostInfo>, now: u64) {
+        let worst = hosts.iter().min_by_key(|(_, info)| info.score(now)).map(|(k, _)| k.clone());
+        if let Some(worst) = worst {
+            hosts.remove(&worst);
+        }
+    }
+
+    /// Record a successful connection to a host.
+    pub async fn mark_success(&self, addr: &NetAddr) {
+        let now = now();
+        let mut hosts = self.addrs.lock().await;
+        let info = hosts.entry(addr.clone()).or_default();
+        info.last_seen = now;
+        info.last_success = now;
+        info.last_attempt = now;
+        info.failures = 0;
     }
 
-    /// Checks if a host address is in the host list.
-    async fn contains(&self, addrs: &[SocketAddr]) -> bool {
-        let a_set: HashSet<_> = addrs.iter().copied().collect();
-        self.addrs.lock().await.iter().any(|item| a_set.contains(item))
+    /// Record a failed connection attempt. Hosts failing too often are
+    /// dropped from the store.
+    pub async fn mark_failure(&self, addr: &NetAddr) {
+        let mut hosts = self.addrs.lock().await;
+        if let Some(info) = hosts.get_mut(addr) {
+            info.last_attempt = now();
+            info.failures += 1;
+            if info.failures >= HOSTS_MAX_FAILURES {
+                debug!(target: "net", "Dropping host {} after {} failures", addr, info.failures);
+                hosts.remove(addr);
+            }
+        }
     }
 
-    /// Add a new host to the host list.
-    pub async fn store(&self, addrs: Vec<SocketAddr>) {
-        if !self.contains(&addrs).await {
-            self.addrs.lock().await.extend(addrs)
+    /// Ban the host of an address for `Settings.ban_duration_seconds`.
+    /// Loopback peers, eg. behind a Tor onion service, share one address,
+    /// so they are only disconnected.
+    pub async fn ban(&self, addr: &NetAddr) {
+        if addr.ip().map_or(false, |ip| ip.is_loopback()) {
+            debug!(target: "net", "Not banning loopback peer {}", addr);
+            return
         }
+
+        let host = addr.host();
+        warn!(target: "net", "Banning host {}", host);
+        let until = now() + self.settings.ban_duration_seconds as u64;
+        self.bans.lock().await.insert(host.clone(), until);
+        self.addrs.lock().await.retain(|addr, _| addr.host() != host);
     }
 
-    /// Return a single host address.
-    pub async fn load_single(&self) -> Option<SocketAddr> {
-        self.addrs.lock().await.choose(&mut rand::thread_rng()).cloned()
+    /// Checks if the host of an address is banned. Expired bans are lifted.
+    pub async fn is_banned(&self, addr: &NetAddr) -> bool {
+        let mut bans = self.bans.lock().await;
+        let host = addr.host();
+        match bans.get(&host) {
+            Some(until) if *until > now() => true,
+            Some(_) => {
+                bans.remove(&host);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Return a single host address, chosen at random.
+    pub async fn load_single(&self) -> Option<NetAddr> {
+        let hosts = self.addrs.lock().await;
+        let addrs: Vec<&NetAddr> = hosts.keys().collect();
+        addrs.choose(&mut rand::thread_rng()).map(|addr| (*addr).clone())
+    }
+
+    /// Return the hosts ordered by score, best first. Hosts with the same
+    /// score are shuffled.
+    pub async fn load_ranked(&self) -> Vec<NetAddr> {
+        let now = now();
+        let mut hosts: Vec<(NetAddr, i64)> = self
+            .addrs
+            .lock()
+            .await
+            .iter()
+            .map(|(addr, info)| (addr.clone(), info.score(now)))
+            .collect();
+        hosts.shuffle(&mut rand::thread_rng());
+        hosts.sort_by(|a, b| b.1.cmp(&a.1));
+        hosts.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Return the hosts we never connected to.
+    pub async fn load_untried(&self) -> Vec<NetAddr> {
+        self.addrs
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, info)| !info.is_tried())
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Return the hosts we didn't try to connect to since `since`, in
+    /// seconds since the unix epoch. Hosts tried least recently come first.
+    pub async fn load_stale(&self, since: u64) -> Vec<NetAddr> {
+        let mut hosts: Vec<(NetAddr, u64)> = self
+            .addrs
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, info)| info.last_attempt < since)
+            .map(|(addr, info)| (addr.clone(), info.last_attempt))
+            .collect();
+        hosts.sort_by_key(|(_, last_attempt)| *last_attempt);
+        hosts.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Return the hosts whose last connection attempt succeeded.
+    pub async fn load_reachable(&self) -> Vec<NetAddr> {
+        self.addrs
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, info)| info.is_reachable())
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Replace the anchors saved with the host store.
+    pub async fn set_anchors(&self, anchors: Vec<NetAddr>) {
+        *self.anchors.lock().await = anchors;
+    }
+
+    /// Return the anchors loaded from the host store.
+    pub async fn anchors(&self) -> Vec<NetAddr> {
+        self.anchors.lock().await.clone()
     }
 
     /// Return the list of hosts.
-    pub async fn load_all(&self) -> Vec<SocketAddr> {
-        self.addrs.lock().await.clone()
+    pub async fn load_all(&self) -> Vec<NetAddr> {
+        self.addrs.lock().await.keys().cloned().collect()
+    }
+
+    /// Return what we know about a host.
+    pub async fn info(&self, addr: &NetAddr) -> Option<HostInfo> {
+        self.addrs.lock().await.get(addr).cloned()
     }
 
     /// Check if the host list is empty.
@@ -44,3 +354,93 @@ impl Hosts {
         self.addrs.lock().await.is_empty()
     }
 }
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The anchors are kept next to the host store.
+fn anchors_path(hosts_path: &Path) -> PathBuf {
+    hosts_path.with_extension("anchors")
+}
+
+/// Write to a temporary file first so a crash can't leave a truncated
+/// file behind.
+fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    use super::{anchors_path, Hosts, HOSTS_MAX_PER_GROUP};
+    use crate::net::{NetAddr, Settings};
+
+    #[test]
+    fn hosts_test() {
+        smol::block_on(async {
+            let dir = tempdir().unwrap();
+            let path = dir.path().join("hosts");
+            let settings =
+                Arc::new(Settings { hosts_path: Some(path.clone()), ..Default::default() });
+
+            let good: NetAddr = "192.0.2.1:11001".parse().unwrap();
+            let bad: NetAddr = "192.0.2.2:11001".parse().unwrap();
+            let evil: NetAddr = "192.0.2.3:11001".parse().unwrap();
+
+            let hosts = Hosts::new(settings.clone());
+            let unspecified: NetAddr = "0.0.0.0:11001".parse().unwrap();
+            hosts.store(vec![good.clone(), bad.clone(), evil.clone(), good.clone()]).await;
+            hosts.store(vec![unspecified]).await;
+            assert_eq!(hosts.load_all().await.len(), 3);
+
+            hosts.mark_success(&good).await;
+            hosts.mark_failure(&bad).await;
+            assert_eq!(hosts.load_untried().await.len(), 2);
+            assert_eq!(hosts.load_ranked().await[0], good);
+            assert_eq!(hosts.load_ranked().await[2], bad);
+            assert_eq!(hosts.load_reachable().await, vec![good.clone()]);
+            assert_eq!(hosts.load_stale(1).await, vec![evil.clone()]);
+
+            // Bans apply to the host on any port
+            hosts.ban(&evil).await;
+            assert!(hosts.is_banned(&"192.0.2.3:23456".parse().unwrap()).await);
+            hosts.store(vec![evil.clone()]).await;
+            assert_eq!(hosts.load_all().await.len(), 2);
+
+            // Loopback peers share one address and are never banned
+            let onion_peer: NetAddr = "127.0.0.1:34567".parse().unwrap();
+            hosts.ban(&onion_peer).await;
+            assert!(!hosts.is_banned(&onion_peer).await);
+
+            hosts.set_anchors(vec![good.clone()]).await;
+            hosts.save().await.unwrap();
+            assert!(anchors_path(&path).exists());
+            let hosts2 = Hosts::new(settings);
+            assert_eq!(hosts2.info(&good).await, hosts.info(&good).await);
+            assert_eq!(hosts2.info(&bad).await.unwrap().failures, 1);
+            assert!(hosts2.is_banned(&evil).await);
+            assert_eq!(hosts2.anchors().await, vec![good]);
+
+            // A single network can't fill the store
+            let flood: Vec<NetAddr> =
+                (0..100).map(|i| format!("10.1.{}.1:11001", i).parse().unwrap()).collect();
+            hosts2.store(flood).await;
+            assert_eq!(hosts2.load_all().await.len(), 2 + HOSTS_MAX_PER_GROUP);
+
+            // Neither can hostnames, nor onion addresses
+            let flood: Vec<NetAddr> =
+                (0..100).map(|i| format!("node{}.example.com:11001", i).parse().unwrap()).collect();
+            hosts2.store(flood).await;
+            let flood: Vec<NetAddr> =
+                (0..100).map(|i| format!("node{:0>12}.onion:11001", i).parse().unwrap()).collect();
+            hosts2.store(flood).await;
+            assert_eq!(hosts2.load_all().await.len(), 2 + 3 * HOSTS_MAX_PER_GROUP);
+        });
+    }
+}
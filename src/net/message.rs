@@ -1,17 +1,54 @@
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use log::debug;
-use std::{io, net::SocketAddr};
+use std::io;
 
 use crate::{
+    net::NetAddr,
     util::serial::{Decodable, Encodable, VarInt},
     Error, Result,
 };
 
 const MAGIC_BYTES: [u8; 4] = [0xd9, 0xef, 0xb6, 0x7d];
 
+/// Version of the network protocol spoken by this node.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this node can talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// bitwise service flags announced in the version message
+pub type ServiceBitflag = u64;
+pub const SERVICE_NONE: ServiceBitflag = 0;
+pub const SERVICE_RELAY: ServiceBitflag = 0b0001;
+/// Flags from this bit on are free to use for application protocols.
+pub const SERVICE_APP_START: ServiceBitflag = 1 << 16;
+
+/// Maximum length of a packet command.
+pub const MAX_COMMAND_LENGTH: usize = 32;
+/// Default maximum size of an encoded message.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
 /// Generic message template.
 pub trait Message: 'static + Encodable + Decodable + Send + Sync {
     fn name() -> &'static str;
+
+    /// Maximum size of the encoded message. Peers sending larger packets
+    /// are disconnected before the payload is read.
+    fn max_size() -> usize {
+        DEFAULT_MAX_MESSAGE_SIZE
+    }
+}
+
+/// Message answered by a response carrying the same request id. Requests
+/// are sent with `Channel::request`, which sets the id.
+pub trait Request: Message {
+    fn request_id(&self) -> u32;
+
+    fn set_request_id(&mut self, id: u32);
+}
+
+/// Response to a request, carrying the id of the request it answers.
+pub trait Response: Message {
+    fn request_id(&self) -> u32;
 }
 
 /// Outbound keep-alive message.
@@ -30,31 +67,84 @@ pub struct GetAddrsMessage {}
 /// Sends address information to inbound connection. Response to GetAddrs
 /// message.
 pub struct AddrsMessage {
-    pub addrs: Vec<SocketAddr>,
+    pub addrs: Vec<NetAddr>,
 }
 
 /// Requests version information of outbound connection.
-pub struct VersionMessage {}
+pub struct VersionMessage {
+    /// Protocol version of the node
+    pub version: u32,
+    /// Name and version of the node software
+    pub user_agent: String,
+    /// Unix time of the node in seconds
+    pub timestamp: u64,
+    /// Services offered by the node
+    pub services: ServiceBitflag,
+    /// Address the node accepts connections on
+    pub listen_addr: Option<NetAddr>,
+    /// Whether the node supports channel encryption
+    pub encryption: bool,
+    /// Public key identifying the node
+    pub identity: [u8; 32],
+    /// Ephemeral public key used to derive the channel keys
+    pub ephem_public: [u8; 32],
+    /// Address the node sees the peer connecting from, so nodes behind a
+    /// NAT can discover their external address
+    pub remote_addr: Option<NetAddr>,
+}
 
 /// Sends version information to inbound connection. Response to VersionMessage.
-pub struct VerackMessage {}
+pub struct VerackMessage {
+    /// Protocol version used on the channel
+    pub version: u32,
+    /// MAC of the version messages sent and received by the node
+    pub transcript: [u8; 32],
+}
 
 impl Message for PingMessage {
     fn name() -> &'static str {
         "ping"
     }
+
+    fn max_size() -> usize {
+        4
+    }
+}
+
+impl Request for PingMessage {
+    fn request_id(&self) -> u32 {
+        self.nonce
+    }
+
+    fn set_request_id(&mut self, id: u32) {
+        self.nonce = id;
+    }
 }
 
 impl Message for PongMessage {
     fn name() -> &'static str {
         "pong"
     }
+
+    fn max_size() -> usize {
+        4
+    }
+}
+
+impl Response for PongMessage {
+    fn request_id(&self) -> u32 {
+        self.nonce
+    }
 }
 
 impl Message for GetAddrsMessage {
     fn name() -> &'static str {
         "getaddr"
     }
+
+    fn max_size() -> usize {
+        0
+    }
 }
 
 impl Message for AddrsMessage {
@@ -67,12 +157,20 @@ impl Message for VersionMessage {
     fn name() -> &'static str {
         "version"
     }
+
+    fn max_size() -> usize {
+        1024
+    }
 }
 
 impl Message for VerackMessage {
     fn name() -> &'static str {
         "verack"
     }
+
+    fn max_size() -> usize {
+        4 + 32
+    }
 }
 
 impl Encodable for PingMessage {
@@ -131,26 +229,49 @@ impl Decodable for AddrsMessage {
 }
 
 impl Encodable for VersionMessage {
-    fn encode<S: io::Write>(&self, _s: S) -> Result<usize> {
-        Ok(0)
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.version.encode(&mut s)?;
+        len += self.user_agent.encode(&mut s)?;
+        len += self.timestamp.encode(&mut s)?;
+        len += self.services.encode(&mut s)?;
+        len += self.listen_addr.encode(&mut s)?;
+        len += self.encryption.encode(&mut s)?;
+        len += self.identity.encode(&mut s)?;
+        len += self.ephem_public.encode(&mut s)?;
+        len += self.remote_addr.encode(&mut s)?;
+        Ok(len)
     }
 }
 
 impl Decodable for VersionMessage {
-    fn decode<D: io::Read>(_d: D) -> Result<Self> {
-        Ok(Self {})
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            version: Decodable::decode(&mut d)?,
+            user_agent: Decodable::decode(&mut d)?,
+            timestamp: Decodable::decode(&mut d)?,
+            services: Decodable::decode(&mut d)?,
+            listen_addr: Decodable::decode(&mut d)?,
+            encryption: Decodable::decode(&mut d)?,
+            identity: Decodable::decode(&mut d)?,
+            ephem_public: Decodable::decode(&mut d)?,
+            remote_addr: Decodable::decode(&mut d)?,
+        })
     }
 }
 
 impl Encodable for VerackMessage {
-    fn encode<S: io::Write>(&self, _s: S) -> Result<usize> {
-        Ok(0)
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.version.encode(&mut s)?;
+        len += self.transcript.encode(&mut s)?;
+        Ok(len)
     }
 }
 
 impl Decodable for VerackMessage {
-    fn decode<D: io::Read>(_d: D) -> Result<Self> {
-        Ok(Self {})
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self { version: Decodable::decode(&mut d)?, transcript: Decodable::decode(&mut d)? })
     }
 }
 
@@ -161,8 +282,23 @@ pub struct Packet {
     pub payload: Vec<u8>,
 }
 
-/// Reads and decodes an inbound payload.
-pub async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Packet> {
+/// Reads and decodes an inbound payload. Packets with a payload larger than
+/// `max_payload_len` are rejected before the payload is read.
+pub async fn read_packet<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    max_payload_len: usize,
+) -> Result<Packet> {
+    let (command, payload_len) = read_packet_header(stream).await?;
+    if payload_len > max_payload_len {
+        return Err(Error::PacketTooLarge)
+    }
+    let payload = read_packet_payload(stream, payload_len).await?;
+    Ok(Packet { command, payload })
+}
+
+/// Reads the header of an inbound packet. Returns the command and the
+/// length of the payload which follows.
+pub async fn read_packet_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<(String, usize)> {
     // Packets have a 4 byte header of magic digits
     // This is used for network debugging
     let mut magic = [0u8; 4];
@@ -175,6 +311,9 @@ pub async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Packet>
 
     // The type of the message
     let command_len = VarInt::decode_async(stream).await?.0 as usize;
+    if command_len > MAX_COMMAND_LENGTH {
+        return Err(Error::MalformedPacket)
+    }
     let mut cmd = vec![0u8; command_len];
     if command_len > 0 {
         stream.read_exact(&mut cmd).await?;
@@ -183,7 +322,15 @@ pub async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Packet>
     debug!(target: "net", "read command: {}", cmd);
 
     let payload_len = VarInt::decode_async(stream).await?.0 as usize;
+    Ok((cmd, payload_len))
+}
 
+/// Reads the payload of an inbound packet. The length must be checked
+/// against the size limits first.
+pub async fn read_packet_payload<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    payload_len: usize,
+) -> Result<Vec<u8>> {
     // The message-dependent data (see message types)
     let mut payload = vec![0u8; payload_len];
     if payload_len > 0 {
@@ -191,7 +338,7 @@ pub async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Packet>
     }
     debug!(target: "net", "read payload {} bytes", payload_len);
 
-    Ok(Packet { command: cmd, payload })
+    Ok(payload)
 }
 
 /// Sends an outbound packet by writing data to TCP stream.
@@ -42,10 +42,12 @@ impl<M: Message> MessageSubscription<M> {
 #[async_trait]
 /// Generic interface for message dispatcher.
 trait MessageDispatcherInterface: Send + Sync {
-    async fn trigger(&self, payload: Vec<u8>);
+    async fn trigger(&self, payload: Vec<u8>) -> Result<()>;
 
     async fn trigger_error(&self, err: Error);
 
+    fn max_size(&self) -> usize;
+
     fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
 }
 
@@ -131,17 +133,19 @@ impl<M: Message> MessageDispatcher<M> {
 // Local implementation of the Message Dispatcher Interface.
 impl<M: Message> MessageDispatcherInterface for MessageDispatcher<M> {
     /// Deserialize data into a message type.
-    async fn trigger(&self, payload: Vec<u8>) {
+    async fn trigger(&self, payload: Vec<u8>) -> Result<()> {
         // deserialize data into type
         // send down the pipes
         let cursor = Cursor::new(payload);
         match M::decode(cursor) {
             Ok(message) => {
                 let message = Ok(Arc::new(message));
-                self.trigger_all(message).await
+                self.trigger_all(message).await;
+                Ok(())
             }
             Err(err) => {
                 error!("Unable to decode data. Dropping...: {}", err);
+                Err(err)
             }
         }
     }
@@ -152,6 +156,11 @@ impl<M: Message> MessageDispatcherInterface for MessageDispatcher<M> {
         self.trigger_all(Err(err)).await;
     }
 
+    /// Maximum size of the encoded message.
+    fn max_size(&self) -> usize {
+        M::max_size()
+    }
+
     /// Converts to Any trait. Enables the dynamic modification of static types.
     fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
         self
@@ -198,24 +207,34 @@ impl MessageSubsystem {
         Ok(sub)
     }
 
-    /// Sends a message out to subscribers. Returns an error if the message
-    /// doesn't send.
-    pub async fn notify(&self, command: &str, payload: Vec<u8>) {
+    /// Sends a message out to subscribers. Returns an error if the payload
+    /// can't be decoded as the message.
+    pub async fn notify(&self, command: &str, payload: Vec<u8>) -> Result<()> {
         let dispatcher = self.dispatchers.lock().await.get(command).cloned();
 
         match dispatcher {
-            Some(dispatcher) => {
-                dispatcher.trigger(payload).await;
-            }
+            Some(dispatcher) => dispatcher.trigger(payload).await,
             None => {
                 warn!(
                     "MessageSubsystem::notify(\"{}\", payload) did not find a dispatcher",
                     command
                 );
+                Ok(())
             }
         }
     }
 
+    /// Maximum size of the message with the given command, if there is a
+    /// dispatcher for it.
+    pub async fn max_size(&self, command: &str) -> Option<usize> {
+        self.dispatchers.lock().await.get(command).map(|dispatcher| dispatcher.max_size())
+    }
+
+    /// Largest maximum size of the messages with a dispatcher.
+    pub async fn largest_max_size(&self) -> Option<usize> {
+        self.dispatchers.lock().await.values().map(|dispatcher| dispatcher.max_size()).max()
+    }
+
     /// Send a message to all subscriber channels. Clear any inactive channels.
     pub async fn trigger_error(&self, err: Error) {
         // TODO: this could be parallelized
@@ -278,7 +297,7 @@ async fn _do_message_subscriber_test() {
     // receive message and publish
     //   1. based on string, lookup relevant dispatcher interface
     //   2. publish data there
-    subsystem.notify("verver", payload).await;
+    subsystem.notify("verver", payload).await.unwrap();
 
     // receive
     //    1. do a get easy
@@ -0,0 +1,350 @@
+use async_executor::Executor;
+use async_std::future::timeout;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use log::{debug, warn};
+use serde_json::json;
+use smol::{Async, Timer};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::Result,
+    net::{
+        session::{
+            SessionBitflag, SESSION_INBOUND, SESSION_MANUAL, SESSION_OUTBOUND, SESSION_SEED,
+        },
+        P2p,
+    },
+};
+
+/// Message counters of a command, or of all the commands of a channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+impl MessageStats {
+    fn add(&mut self, other: &Self) {
+        self.messages_in += other.messages_in;
+        self.bytes_in += other.bytes_in;
+        self.messages_out += other.messages_out;
+        self.bytes_out += other.bytes_out;
+    }
+
+    fn get_info(&self) -> serde_json::Value {
+        json!({
+            "messages_in": self.messages_in,
+            "bytes_in": self.bytes_in,
+            "messages_out": self.messages_out,
+            "bytes_out": self.bytes_out,
+        })
+    }
+}
+
+/// Statistics of a channel. Bytes are counted on message payloads, before
+/// encryption.
+#[derive(Clone, Debug)]
+pub struct ChannelStats {
+    /// When the channel was opened, in seconds since the unix epoch
+    pub connected_at: u64,
+    /// Round trip time of the last ping, in milliseconds
+    pub ping_rtt_ms: Option<u64>,
+    /// Counters per message command
+    pub commands: BTreeMap<String, MessageStats>,
+}
+
+impl ChannelStats {
+    pub fn new() -> Self {
+        let connected_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Self { connected_at, ping_rtt_ms: None, commands: BTreeMap::new() }
+    }
+
+    pub(crate) fn record_recv(&mut self, command: &str, bytes: usize) {
+        let stats = self.commands.entry(command.to_string()).or_default();
+        stats.messages_in += 1;
+        stats.bytes_in += bytes as u64;
+    }
+
+    pub(crate) fn record_send(&mut self, command: &str, bytes: usize) {
+        let stats = self.commands.entry(command.to_string()).or_default();
+        stats.messages_out += 1;
+        stats.bytes_out += bytes as u64;
+    }
+
+    /// Counters of all the commands.
+    pub fn total(&self) -> MessageStats {
+        let mut total = MessageStats::default();
+        for stats in self.commands.values() {
+            total.add(stats);
+        }
+        total
+    }
+
+    pub fn get_info(&self) -> serde_json::Value {
+        let commands: BTreeMap<_, _> =
+            self.commands.iter().map(|(command, stats)| (command, stats.get_info())).collect();
+
+        json!({
+            "connected_at": self.connected_at,
+            "ping_rtt_ms": self.ping_rtt_ms,
+            "total": self.total().get_info(),
+            "commands": commands,
+        })
+    }
+}
+
+impl Default for ChannelStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics of the channels of a session. Counters include the channels
+/// that were closed since the node started.
+#[derive(Clone, Debug, Default)]
+pub struct SessionStats {
+    /// Number of connected channels
+    pub channels: usize,
+    /// Counters per message command
+    pub commands: BTreeMap<String, MessageStats>,
+}
+
+impl SessionStats {
+    /// Add the counters of a channel.
+    pub fn add(&mut self, stats: &ChannelStats) {
+        for (command, command_stats) in &stats.commands {
+            self.commands.entry(command.clone()).or_default().add(command_stats);
+        }
+    }
+
+    /// Counters of all the commands.
+    pub fn total(&self) -> MessageStats {
+        let mut total = MessageStats::default();
+        for stats in self.commands.values() {
+            total.add(stats);
+        }
+        total
+    }
+
+    pub fn get_info(&self) -> serde_json::Value {
+        let commands: BTreeMap<_, _> =
+            self.commands.iter().map(|(command, stats)| (command, stats.get_info())).collect();
+
+        json!({
+            "channels": self.channels,
+            "total": self.total().get_info(),
+            "commands": commands,
+        })
+    }
+}
+
+/// Name of a session, used in `get_info` and as a metric label.
+pub fn session_name(session: SessionBitflag) -> &'static str {
+    match session {
+        SESSION_INBOUND => "inbound",
+        SESSION_OUTBOUND => "outbound",
+        SESSION_MANUAL => "manual",
+        SESSION_SEED => "seed",
+        _ => "unknown",
+    }
+}
+
+/// Render the metrics of the network in the Prometheus text exposition
+/// format.
+pub async fn render(p2p: &P2p) -> String {
+    let sessions: Vec<_> = p2p
+        .session_stats()
+        .await
+        .into_iter()
+        .map(|(session, stats)| (session_name(session), stats))
+        .collect();
+
+    let mut channels = Vec::new();
+    for channel in p2p.channels().await {
+        channels.push((channel.address().to_string(), channel.stats().await));
+    }
+
+    render_stats(&sessions, &channels)
+}
+
+fn render_stats(sessions: &[(&str, SessionStats)], channels: &[(String, ChannelStats)]) -> String {
+    let mut out = String::new();
+
+    metric_header(&mut out, "darkfi_p2p_channels", "gauge", "Connected channels per session");
+    for (session, stats) in sessions {
+        let _ = writeln!(out, "darkfi_p2p_channels{{session=\"{}\"}} {}", session, stats.channels);
+    }
+
+    let counters: [(&str, &str, fn(&MessageStats) -> u64); 4] = [
+        ("darkfi_p2p_messages_received_total", "Messages received", |s| s.messages_in),
+        ("darkfi_p2p_bytes_received_total", "Payload bytes received", |s| s.bytes_in),
+        ("darkfi_p2p_messages_sent_total", "Messages sent", |s| s.messages_out),
+        ("darkfi_p2p_bytes_sent_total", "Payload bytes sent", |s| s.bytes_out),
+    ];
+    for (name, help, value) in counters {
+        metric_header(&mut out, name, "counter", help);
+        for (session, stats) in sessions {
+            for (command, command_stats) in &stats.commands {
+                let _ = writeln!(
+                    out,
+                    "{}{{session=\"{}\",command=\"{}\"}} {}",
+                    name,
+                    session,
+                    escape_label(command),
+                    value(command_stats)
+                );
+            }
+        }
+    }
+
+    metric_header(
+        &mut out,
+        "darkfi_p2p_channel_connected_at_seconds",
+        "gauge",
+        "Unix time the channel was opened",
+    );
+    for (peer, stats) in channels {
+        let _ = writeln!(
+            out,
+            "darkfi_p2p_channel_connected_at_seconds{{peer=\"{}\"}} {}",
+            escape_label(peer),
+            stats.connected_at
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "darkfi_p2p_channel_ping_rtt_milliseconds",
+        "gauge",
+        "Round trip time of the last ping",
+    );
+    for (peer, stats) in channels {
+        if let Some(rtt) = stats.ping_rtt_ms {
+            let _ = writeln!(
+                out,
+                "darkfi_p2p_channel_ping_rtt_milliseconds{{peer=\"{}\"}} {}",
+                escape_label(peer),
+                rtt
+            );
+        }
+    }
+
+    out
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Time a client has to send its request and read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Pause after failing to accept a connection, as errors such as running
+/// out of file descriptors don't go away at once.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Serve the metrics over HTTP on `GET /metrics`. Each connection is served
+/// in its own task, so a client that never sends its request doesn't hold
+/// up the others.
+pub(crate) async fn listen(
+    listener: Async<TcpListener>,
+    p2p: Arc<P2p>,
+    executor: Arc<Executor<'_>>,
+) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!(target: "net", "Unable to accept a metrics connection: {}", err);
+                Timer::after(ACCEPT_RETRY_DELAY).await;
+                continue
+            }
+        };
+
+        let p2p = p2p.clone();
+        executor
+            .spawn(async move {
+                match timeout(REQUEST_TIMEOUT, serve(stream, &p2p)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => {
+                        debug!(target: "net", "Metrics request from {} failed: {}", peer_addr, err)
+                    }
+                    Err(_) => debug!(target: "net", "Metrics request from {} timed out", peer_addr),
+                }
+            })
+            .detach();
+    }
+}
+
+async fn serve(mut stream: Async<TcpStream>, p2p: &P2p) -> Result<()> {
+    // Only the request line matters, the rest of the request is ignored
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(p2p).await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_test() {
+        let mut channel = ChannelStats::new();
+        channel.record_recv("ping", 4);
+        channel.record_recv("ping", 4);
+        channel.record_send("pong", 4);
+        channel.record_send("privmsg", 100);
+        channel.ping_rtt_ms = Some(12);
+
+        let total = channel.total();
+        assert_eq!(total.messages_in, 2);
+        assert_eq!(total.bytes_in, 8);
+        assert_eq!(total.messages_out, 2);
+        assert_eq!(total.bytes_out, 104);
+
+        let mut session = SessionStats { channels: 1, ..Default::default() };
+        session.add(&channel);
+        session.add(&channel);
+        assert_eq!(session.commands["ping"].messages_in, 4);
+        assert_eq!(session.total().bytes_out, 208);
+
+        let out =
+            render_stats(&[("outbound", session)], &[("127.0.0.1:11000".to_string(), channel)]);
+        assert!(out.contains("darkfi_p2p_channels{session=\"outbound\"} 1\n"));
+        assert!(out.contains(
+            "darkfi_p2p_bytes_received_total{session=\"outbound\",command=\"ping\"} 16\n"
+        ));
+        assert!(
+            out.contains("darkfi_p2p_channel_ping_rtt_milliseconds{peer=\"127.0.0.1:11000\"} 12\n")
+        );
+        assert!(out.contains("# TYPE darkfi_p2p_messages_sent_total counter\n"));
+    }
+}
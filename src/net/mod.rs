@@ -3,6 +3,10 @@
 /// connections and to handle network errors.
 pub mod acceptor;
 
+/// Network addresses of nodes. Addresses are either socket addresses or
+/// hostnames, including onion addresses.
+pub mod addr;
+
 /// Async channel that handles the sending of messages across the network.
 /// Public interface is used to create new channels, to stop and start
 /// a channel, and to send messages.
@@ -14,6 +18,15 @@ pub mod channel;
 /// connection.
 pub mod connector;
 
+/// Crawler run by seed nodes, which periodically connects to the known
+/// hosts to check they are reachable and to discover new ones.
+pub mod crawler;
+
+/// Transport encryption of channels. Nodes exchange identity and ephemeral
+/// keys in the version handshake and derive ChaCha20-Poly1305 keys for
+/// each direction of the channel.
+pub mod encryption;
+
 /// Hosts are a list of network addresses used when establishing an outbound
 /// connection. Hosts are shared across the network through the address
 /// protocol. When attempting to connect, a node will loop through addresses in
@@ -48,6 +61,15 @@ pub mod message_subscriber;
 /// converted into messages and passed to an event loop.
 pub mod message;
 
+/// Per-channel message counters, per-session aggregates, and their
+/// exposition in the Prometheus text format.
+pub mod metrics;
+
+/// Discovery of the external addresses of nodes behind a NAT, from the
+/// addresses peers see them connecting from and from ports mapped on the
+/// router with NAT-PMP.
+pub mod nat;
+
 /// P2P provides all core functionality to interact with the peer-to-peer
 /// network.
 ///
@@ -74,6 +96,9 @@ pub mod p2p;
 /// asynchronous execution of the protocols.
 pub mod protocol;
 
+/// Token bucket used to limit the rate of messages peers can send.
+pub mod rate_limit;
+
 /// Defines the interaction between nodes during a connection. Consists of an
 /// inbound session, which describes how to set up an incoming connection, and
 /// an outbound session, which describes setting up an outbound connection. Also
@@ -85,13 +110,39 @@ pub mod session;
 /// Network configuration settings.
 pub mod settings;
 
+/// Simulated in-memory network, with controllable latency, packet loss
+/// and partitions, for testing many nodes in a single process.
+#[cfg(test)]
+pub mod sim;
+
+/// Minimal SOCKS5 client used to route outbound connections through a
+/// proxy such as Tor.
+pub mod socks5;
+
+/// Transport abstraction over the streams channels run on. Nodes use TCP
+/// by default.
+pub mod transport;
+
 pub use acceptor::{Acceptor, AcceptorPtr};
-pub use channel::{Channel, ChannelPtr};
+pub use addr::{NetAddr, NetworkGroup};
+pub use channel::{Channel, ChannelPtr, PeerInfo, BAN_THRESHOLD};
 pub use connector::Connector;
-pub use hosts::{Hosts, HostsPtr};
-pub use message::Message;
+pub use crawler::{CrawlStats, Crawler};
+pub use encryption::{ChannelEncryption, NodeIdentity};
+pub use hosts::{HostInfo, Hosts, HostsPtr};
+pub use message::{
+    Message, Request, Response, ServiceBitflag, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    SERVICE_APP_START, SERVICE_NONE, SERVICE_RELAY,
+};
 pub use message_subscriber::MessageSubscription;
+pub use metrics::{ChannelStats, MessageStats, SessionStats};
+pub use nat::{ExternalAddrs, ExternalAddrsPtr, NatPmp, PortMapper, PortMapperPtr};
 pub use p2p::{P2p, P2pPtr};
-pub use protocol::{ProtocolBase, ProtocolBasePtr, ProtocolJobsManager, ProtocolJobsManagerPtr};
+pub use protocol::{
+    new_stream_id, Gossip, GossipPtr, GossipSettings, ProtocolBase, ProtocolBasePtr,
+    ProtocolJobsManager, ProtocolJobsManagerPtr, ReceivedStream, StreamId, StreamSettings, Streams,
+    StreamsPtr,
+};
 pub use session::{SESSION_ALL, SESSION_INBOUND, SESSION_MANUAL, SESSION_OUTBOUND, SESSION_SEED};
 pub use settings::{Settings, SettingsPtr};
+pub use transport::{Transport, TransportListener, TransportPtr, TransportStream};
@@ -0,0 +1,319 @@
+use async_std::{future::timeout, sync::Mutex};
+use async_trait::async_trait;
+use log::info;
+use smol::Async;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    net::{NetAddr, NetworkGroup, SettingsPtr},
+};
+
+/// Atomic pointer to a port mapper.
+pub type PortMapperPtr = Arc<dyn PortMapper>;
+/// Atomic pointer to the external addresses of a node.
+pub type ExternalAddrsPtr = Arc<ExternalAddrs>;
+
+/// Maximum number of peers whose reports of our address are kept.
+const MAX_REPORTS: usize = 1000;
+
+/// Maps ports of the router a node is behind, so peers can reach the node
+/// through the NAT.
+#[async_trait]
+pub trait PortMapper: Send + Sync {
+    /// Map a port of the router to a local address for `lifetime` seconds.
+    /// Returns the external address of the mapping.
+    async fn map_port(&self, local_addr: SocketAddr, lifetime: u32) -> Result<SocketAddr>;
+
+    /// Remove the mapping of a local address.
+    async fn unmap_port(&self, local_addr: SocketAddr) -> Result<()>;
+}
+
+/// Addresses peers can reach the node on. The external addresses in the
+/// settings are used if there are any. Otherwise they are discovered: the
+/// ports mapped on the router, and the address peers see us connecting
+/// from, once enough peers agree on it.
+pub struct ExternalAddrs {
+    settings: SettingsPtr,
+    /// Our IP as reported by each peer, keyed by the peer network
+    reports: Mutex<HashMap<IpAddr, IpAddr>>,
+    mapped: Mutex<Vec<SocketAddr>>,
+}
+
+impl ExternalAddrs {
+    pub fn new(settings: SettingsPtr) -> Arc<Self> {
+        Arc::new(Self {
+            settings,
+            reports: Mutex::new(HashMap::new()),
+            mapped: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Record the address a peer sees us connecting from. Only reports from
+    /// peers we connected to are meaningful, since inbound peers already
+    /// know the address they dialed. Peers of a network count once, so a
+    /// single network can't make us advertise a wrong address.
+    pub async fn report(&self, peer: &NetAddr, observed: &NetAddr) {
+        let ip = match observed.ip() {
+            Some(ip) if !ip.is_unspecified() => ip,
+            _ => return,
+        };
+        let reporter = match (peer.network_group(), peer.ip()) {
+            (Some(NetworkGroup::Ip(group)), _) => group,
+            // Loopback peers aren't grouped, and hostnames don't tell which
+            // network the peer is in
+            (None, Some(ip)) => ip,
+            _ => return,
+        };
+
+        let mut reports = self.reports.lock().await;
+        if !reports.contains_key(&reporter) && reports.len() >= MAX_REPORTS {
+            return
+        }
+        let previous = reports.insert(reporter, ip);
+
+        if previous != Some(ip) && Self::agreeing(&reports, ip) == self.quorum() {
+            info!(target: "net", "Discovered external IP {}", ip);
+        }
+    }
+
+    fn agreeing(reports: &HashMap<IpAddr, IpAddr>, ip: IpAddr) -> usize {
+        reports.values().filter(|reported| **reported == ip).count()
+    }
+
+    fn quorum(&self) -> usize {
+        self.settings.external_addr_quorum.max(1) as usize
+    }
+
+    /// Record the external addresses of the ports mapped on the router.
+    pub async fn set_mapped(&self, mapped: Vec<SocketAddr>) {
+        *self.mapped.lock().await = mapped;
+    }
+
+    /// IPs reported by enough peers to be advertised.
+    pub async fn discovered_ips(&self) -> Vec<IpAddr> {
+        let reports = self.reports.lock().await;
+        let mut ips: Vec<IpAddr> = reports
+            .values()
+            .filter(|ip| Self::agreeing(&reports, **ip) >= self.quorum())
+            .cloned()
+            .collect();
+        ips.sort();
+        ips.dedup();
+        ips
+    }
+
+    /// The addresses advertised to peers. Nodes without inbound listeners
+    /// can't be reached, so nothing is discovered for them.
+    pub async fn addrs(&self) -> Vec<NetAddr> {
+        if !self.settings.external_addrs.is_empty() {
+            return self.settings.external_addrs.clone()
+        }
+
+        let mut addrs: Vec<NetAddr> =
+            self.mapped.lock().await.iter().map(|addr| NetAddr::from(*addr)).collect();
+
+        for ip in self.discovered_ips().await {
+            for inbound in &self.settings.inbound {
+                if inbound.is_ipv4() != ip.is_ipv4() {
+                    continue
+                }
+                let addr = NetAddr::from(SocketAddr::new(ip, inbound.port()));
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        addrs
+    }
+}
+
+/// Port of the NAT-PMP service on the gateway.
+const NAT_PMP_PORT: u16 = 5351;
+/// Number of times a NAT-PMP request is sent before giving up.
+const NAT_PMP_TRIES: u32 = 4;
+/// Delay before the first retransmission, doubled on each try.
+const NAT_PMP_INITIAL_DELAY_MS: u64 = 250;
+
+/// NAT-PMP (RFC 6886) client, mapping TCP ports on the gateway.
+pub struct NatPmp {
+    gateway: SocketAddr,
+}
+
+impl NatPmp {
+    /// Create a client for the gateway of the local network, usually the
+    /// default route.
+    pub fn new(gateway: IpAddr) -> Arc<Self> {
+        Arc::new(Self { gateway: SocketAddr::new(gateway, NAT_PMP_PORT) })
+    }
+
+    /// Send a request, retransmitting it until the gateway replies to it.
+    /// Returns the reply once its result code is checked.
+    async fn request(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let local: IpAddr = match self.gateway {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = Async::<UdpSocket>::bind(SocketAddr::new(local, 0))?;
+
+        let mut delay = Duration::from_millis(NAT_PMP_INITIAL_DELAY_MS);
+        for _ in 0..NAT_PMP_TRIES {
+            socket.send_to(request, self.gateway).await?;
+
+            let mut buf = [0u8; 16];
+            if let Ok(result) = timeout(delay, socket.recv_from(&mut buf)).await {
+                let (len, from) = result?;
+                // Replies have the version 0 and the opcode of the request
+                // plus 128
+                if from == self.gateway && len >= 8 && buf[0] == 0 && buf[1] == request[1] + 128 {
+                    let code = u16::from_be_bytes([buf[2], buf[3]]);
+                    if code != 0 {
+                        return Err(Error::PortMappingFailed(format!("result code {}", code)))
+                    }
+                    return Ok(buf[..len].to_vec())
+                }
+            }
+            delay *= 2;
+        }
+
+        Err(Error::PortMappingFailed("gateway did not reply".into()))
+    }
+
+    async fn external_ip(&self) -> Result<IpAddr> {
+        let reply = self.request(&[0, 0]).await?;
+        if reply.len() < 12 {
+            return Err(Error::PortMappingFailed("truncated reply".into()))
+        }
+        Ok(Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11]).into())
+    }
+
+    /// Request a TCP mapping. A lifetime of 0 removes the mapping.
+    async fn request_mapping(&self, port: u16, lifetime: u32) -> Result<u16> {
+        let mut request = vec![0, 2, 0, 0];
+        request.extend_from_slice(&port.to_be_bytes());
+        // Suggest the same external port
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&lifetime.to_be_bytes());
+
+        let reply = self.request(&request).await?;
+        if reply.len() < 16 {
+            return Err(Error::PortMappingFailed("truncated reply".into()))
+        }
+        Ok(u16::from_be_bytes([reply[10], reply[11]]))
+    }
+}
+
+#[async_trait]
+impl PortMapper for NatPmp {
+    async fn map_port(&self, local_addr: SocketAddr, lifetime: u32) -> Result<SocketAddr> {
+        let external_port = self.request_mapping(local_addr.port(), lifetime).await?;
+        let external_ip = self.external_ip().await?;
+        Ok(SocketAddr::new(external_ip, external_port))
+    }
+
+    async fn unmap_port(&self, local_addr: SocketAddr) -> Result<()> {
+        self.request_mapping(local_addr.port(), 0).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+
+    use super::*;
+    use crate::net::{sim::SimNetwork, Settings};
+
+    #[test]
+    fn external_addrs_test() {
+        smol::block_on(async {
+            let settings = Settings {
+                inbound: vec!["0.0.0.0:11001".parse().unwrap(), "[::]:11002".parse().unwrap()],
+                external_addr_quorum: 2,
+                ..Default::default()
+            };
+            let external = ExternalAddrs::new(Arc::new(settings));
+            let ours: NetAddr = "203.0.113.5:40000".parse().unwrap();
+
+            // Peers of one network only count once
+            external.report(&"198.51.100.1:11001".parse().unwrap(), &ours).await;
+            external.report(&"198.51.100.2:11001".parse().unwrap(), &ours).await;
+            assert!(external.addrs().await.is_empty());
+
+            external.report(&"192.0.2.1:11001".parse().unwrap(), &ours).await;
+            assert_eq!(external.addrs().await, vec!["203.0.113.5:11001".parse().unwrap()]);
+
+            // A peer changing its report takes back its vote
+            let other: NetAddr = "203.0.113.6:40000".parse().unwrap();
+            external.report(&"192.0.2.1:11001".parse().unwrap(), &other).await;
+            assert!(external.addrs().await.is_empty());
+
+            external.set_mapped(vec!["203.0.113.7:21001".parse().unwrap()]).await;
+            assert_eq!(external.addrs().await, vec!["203.0.113.7:21001".parse().unwrap()]);
+        });
+    }
+
+    /// Router mapping every port to the same port of a public address.
+    struct FakeRouter;
+
+    #[async_trait]
+    impl PortMapper for FakeRouter {
+        async fn map_port(&self, local_addr: SocketAddr, _lifetime: u32) -> Result<SocketAddr> {
+            Ok(SocketAddr::new([198, 51, 100, 1].into(), local_addr.port()))
+        }
+
+        async fn unmap_port(&self, _local_addr: SocketAddr) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn external_addr_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(4);
+            network.set_latency(Duration::from_millis(10));
+            let nodes = network
+                .create_nodes(5, |index, settings| match index {
+                    3 => {
+                        settings.external_addrs.clear();
+                        settings.external_addr_quorum = 100;
+                        settings.port_mapper = Some(Arc::new(FakeRouter));
+                    }
+                    4 => {
+                        settings.external_addrs.clear();
+                        settings.external_addr_quorum = 2;
+                    }
+                    _ => {}
+                })
+                .await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            // The mapped port is advertised once the node runs
+            let mapped: NetAddr = "198.51.100.1:11000".parse().unwrap();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(5), || async {
+                        nodes[3].external_addrs().await == vec![mapped.clone()]
+                    })
+                    .await
+            );
+
+            // The seed and an outbound peer, in distinct networks, see the
+            // node connecting from its address
+            let discovered: NetAddr = SimNetwork::node_addr(4).into();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        nodes[4].external_addrs().await == vec![discovered.clone()]
+                    })
+                    .await
+            );
+        }));
+    }
+}
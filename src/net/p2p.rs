@@ -1,30 +1,51 @@
-use async_std::sync::Mutex;
+use async_std::{future::timeout, sync::Mutex};
+use futures::future::join_all;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
-    net::SocketAddr,
-    sync::Arc,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use async_executor::Executor;
-use log::debug;
+use log::{debug, info, warn};
 use serde_json::json;
+use smol::Async;
 
 use crate::{
     error::{Error, Result},
     net::{
+        crawler::Crawler,
         message::Message,
+        metrics,
+        metrics::SessionStats,
+        nat::{ExternalAddrs, ExternalAddrsPtr, PortMapperPtr},
         protocol::{register_default_protocols, ProtocolRegistry},
-        session::{InboundSession, ManualSession, OutboundSession, SeedSession, Session},
-        Channel, ChannelPtr, Hosts, HostsPtr, Settings, SettingsPtr,
+        session::{
+            InboundSession, ManualSession, OutboundSession, SeedSession, Session, SessionBitflag,
+        },
+        Channel, ChannelPtr, Hosts, HostsPtr, NetAddr, NodeIdentity, Settings, SettingsPtr,
     },
     system::{Subscriber, SubscriberPtr, Subscription},
+    util::sleep,
 };
 
+/// Interval in seconds between two saves of the host store.
+const HOSTS_SAVE_INTERVAL: u32 = 300;
+/// Lifetime in seconds requested for port mappings. Mappings are renewed
+/// halfway through.
+const PORT_MAPPING_LIFETIME: u32 = 3600;
+/// Seconds a channel is given to shut down gracefully before it's closed.
+const CHANNEL_SHUTDOWN_TIMEOUT: u64 = 5;
+
 /// List of channels that are awaiting connection.
-pub type PendingChannels = Mutex<HashSet<SocketAddr>>;
+pub type PendingChannels = Mutex<HashSet<NetAddr>>;
 /// List of connected channels.
-pub type ConnectedChannels<T> = Mutex<HashMap<SocketAddr, Arc<T>>>;
+pub type ConnectedChannels<T> = Mutex<HashMap<NetAddr, Arc<T>>>;
 /// Atomic pointer to p2p interface.
 pub type P2pPtr = Arc<P2p>;
 
@@ -37,6 +58,8 @@ enum P2pState {
     Started,
     // p2p is running and the network is active.
     Run,
+    // p2p was shut down, it can be started again.
+    Stopped,
 }
 
 impl fmt::Display for P2pState {
@@ -49,6 +72,7 @@ impl fmt::Display for P2pState {
                 Self::Start => "start",
                 Self::Started => "started",
                 Self::Run => "run",
+                Self::Stopped => "stopped",
             }
         )
     }
@@ -61,22 +85,34 @@ pub struct P2p {
     channel_subscriber: SubscriberPtr<Result<ChannelPtr>>,
     // Used both internally and externally
     stop_subscriber: SubscriberPtr<Error>,
+    // Set by stop(), so a stop before run() subscribed isn't missed
+    stop_requested: AtomicBool,
     hosts: HostsPtr,
+    external: ExternalAddrsPtr,
     protocol_registry: ProtocolRegistry,
 
     // We keep a reference to the sessions used for get info
     session_manual: Mutex<Option<Arc<ManualSession>>>,
     session_inbound: Mutex<Option<Arc<InboundSession>>>,
     session_outbound: Mutex<Option<Arc<OutboundSession>>>,
+    crawler: Mutex<Option<Arc<Crawler>>>,
 
     state: Mutex<P2pState>,
+    // Counters of the channels that were closed, per session
+    closed_stats: Mutex<BTreeMap<SessionBitflag, SessionStats>>,
 
     settings: SettingsPtr,
+    identity: Arc<NodeIdentity>,
 }
 
 impl P2p {
     /// Create a new p2p network.
     pub async fn new(settings: Settings) -> Arc<Self> {
+        let identity = match settings.identity_secret {
+            Some(secret) => NodeIdentity::from_secret_bytes(secret)
+                .expect("Invalid node identity secret in settings"),
+            None => NodeIdentity::random(),
+        };
         let settings = Arc::new(settings);
 
         let self_ = Arc::new(Self {
@@ -84,20 +120,26 @@ impl P2p {
             channels: Mutex::new(HashMap::new()),
             channel_subscriber: Subscriber::new(),
             stop_subscriber: Subscriber::new(),
-            hosts: Hosts::new(),
+            stop_requested: AtomicBool::new(false),
+            hosts: Hosts::new(settings.clone()),
+            external: ExternalAddrs::new(settings.clone()),
             protocol_registry: ProtocolRegistry::new(),
             session_manual: Mutex::new(None),
             session_inbound: Mutex::new(None),
             session_outbound: Mutex::new(None),
+            crawler: Mutex::new(None),
             state: Mutex::new(P2pState::Open),
+            closed_stats: Mutex::new(BTreeMap::new()),
             settings,
+            identity: Arc::new(identity),
         });
 
         let parent = Arc::downgrade(&self_);
 
         *self_.session_manual.lock().await = Some(ManualSession::new(parent.clone()));
         *self_.session_inbound.lock().await = Some(InboundSession::new(parent.clone()));
-        *self_.session_outbound.lock().await = Some(OutboundSession::new(parent));
+        *self_.session_outbound.lock().await = Some(OutboundSession::new(parent.clone()));
+        *self_.crawler.lock().await = Some(Crawler::new(parent));
 
         register_default_protocols(self_.clone()).await;
 
@@ -105,21 +147,45 @@ impl P2p {
     }
 
     pub async fn get_info(&self) -> serde_json::Value {
-        let external_addr = self
-            .settings
-            .external_addr
-            .map(|addr| serde_json::Value::from(addr.to_string()))
-            .unwrap_or(serde_json::Value::Null);
+        let external_addrs: Vec<String> =
+            self.external_addrs().await.iter().map(|addr| addr.to_string()).collect();
+
+        let stats: BTreeMap<_, _> = self
+            .session_stats()
+            .await
+            .into_iter()
+            .map(|(session, stats)| (metrics::session_name(session), stats.get_info()))
+            .collect();
+
+        let crawler = match self.settings.seed_mode {
+            true => self.crawler().await.get_info().await,
+            false => serde_json::Value::Null,
+        };
 
         json!({
-            "external_addr": external_addr,
+            "crawler": crawler,
+            "external_addrs": external_addrs,
+            "identity": hex::encode(self.identity.public_bytes()),
             "session_manual": self.session_manual().await.get_info().await,
             "session_inbound": self.session_inbound().await.get_info().await,
             "session_outbound": self.session_outbound().await.get_info().await,
             "state": self.state.lock().await.to_string(),
+            "stats": stats,
         })
     }
 
+    /// Message counters of each session, including the channels that were
+    /// closed since the node started.
+    pub async fn session_stats(&self) -> BTreeMap<SessionBitflag, SessionStats> {
+        let mut sessions = self.closed_stats.lock().await.clone();
+        for channel in self.channels().await {
+            let session = sessions.entry(channel.session()).or_default();
+            session.channels += 1;
+            session.add(&channel.stats().await);
+        }
+        sessions
+    }
+
     /// Invoke startup and seeding sequence. Call from constructing thread.
     pub async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
         debug!(target: "net", "P2p::start() [BEGIN]");
@@ -146,12 +212,19 @@ impl P2p {
     pub async fn session_outbound(&self) -> Arc<OutboundSession> {
         self.session_outbound.lock().await.as_ref().unwrap().clone()
     }
+    pub async fn crawler(&self) -> Arc<Crawler> {
+        self.crawler.lock().await.as_ref().unwrap().clone()
+    }
 
     /// Synchronize the blockchain and then begin long running sessions,
-    /// call after start() is invoked.
+    /// call after start() is invoked. Returns once the network is shut
+    /// down with stop().
     pub async fn run(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
         debug!(target: "net", "P2p::run() [BEGIN]");
 
+        // Subscribe first, so a stop while the sessions start isn't missed
+        let stop_sub = self.subscribe_stop().await;
+
         *self.state.lock().await = P2pState::Run;
 
         let manual = self.session_manual().await;
@@ -160,24 +233,153 @@ impl P2p {
         }
 
         let inbound = self.session_inbound().await;
-        inbound.clone().start(executor.clone())?;
+        inbound.clone().start(executor.clone()).await?;
 
         let outbound = self.session_outbound().await;
         outbound.clone().start(executor.clone()).await?;
 
-        let stop_sub = self.subscribe_stop().await;
+        let crawler = self.crawler().await;
+        if self.settings.seed_mode {
+            crawler.clone().start(executor.clone()).await;
+        }
+
+        // Periodically save the host store, the task is cancelled when
+        // it's dropped
+        let _save_task = executor.spawn(self.clone().save_hosts_loop());
+
+        let _metrics_task = match self.settings.metrics_addr {
+            Some(addr) => {
+                let listener = Async::<TcpListener>::bind(addr)?;
+                info!(target: "net", "Serving metrics on http://{}/metrics", addr);
+                Some(executor.spawn(metrics::listen(listener, self.clone(), executor.clone())))
+            }
+            None => None,
+        };
+
+        let port_mapping_task = match &self.settings.port_mapper {
+            Some(mapper) => Some(executor.spawn(self.clone().port_mapping_loop(mapper.clone()))),
+            None => None,
+        };
+
         // Wait for stop signal
-        stop_sub.receive().await;
+        if !self.stop_requested.load(Ordering::SeqCst) {
+            stop_sub.receive().await;
+        }
+        self.stop_requested.store(false, Ordering::SeqCst);
+        info!(target: "net", "Shutting down the network");
 
-        // Stop the sessions
-        manual.stop().await;
+        // Keep the outbound peers as anchors before the slots are stopped
+        self.update_anchors().await;
+
+        // Stop accepting and making connections
         inbound.stop().await;
         outbound.stop().await;
+        manual.stop().await;
+        crawler.stop().await;
+
+        self.close_channels().await;
+        self.write_hosts().await;
 
+        // Cancel the renewals before removing the mappings
+        drop(port_mapping_task);
+        self.unmap_ports().await;
+
+        *self.state.lock().await = P2pState::Stopped;
         debug!(target: "net", "P2p::run() [END]");
         Ok(())
     }
 
+    /// Shut down the network. run() stops the sessions, lets the protocols
+    /// of each channel send their last messages, closes the channels once
+    /// the pending sends are written out and saves the host store before
+    /// returning. A stop requested before run() makes it shut down as soon
+    /// as the sessions started.
+    pub async fn stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.stop_subscriber.notify(Error::ServiceStopped).await;
+    }
+
+    /// Shut down the connected channels, closing the ones that don't
+    /// finish in time.
+    async fn close_channels(&self) {
+        let channels = self.channels().await;
+        let closing = channels.iter().map(|channel| async move {
+            let shutdown_timeout = Duration::from_secs(CHANNEL_SHUTDOWN_TIMEOUT);
+            if timeout(shutdown_timeout, channel.shutdown()).await.is_err() {
+                warn!(target: "net", "Channel [{}] didn't shut down in time", channel.address());
+                channel.stop().await;
+            }
+        });
+        join_all(closing).await;
+    }
+
+    async fn save_hosts_loop(self: Arc<Self>) {
+        loop {
+            sleep(HOSTS_SAVE_INTERVAL).await;
+            self.save_hosts().await;
+        }
+    }
+
+    /// Save the host store, with the connected outbound peers as anchors.
+    /// The previous anchors are kept while no outbound peer is connected.
+    async fn save_hosts(&self) {
+        self.update_anchors().await;
+        self.write_hosts().await;
+    }
+
+    async fn write_hosts(&self) {
+        if let Err(err) = self.hosts.save().await {
+            warn!(target: "net", "Unable to save hosts: {}", err);
+        }
+    }
+
+    async fn update_anchors(&self) {
+        let anchors = self.session_outbound().await.connected_addrs().await;
+        if !anchors.is_empty() {
+            self.hosts.set_anchors(anchors).await;
+        }
+    }
+
+    /// Map the inbound ports on the router, renewing the mappings before
+    /// they expire.
+    async fn port_mapping_loop(self: Arc<Self>, mapper: PortMapperPtr) {
+        loop {
+            let mut mapped = Vec::new();
+            for local_addr in self.mapped_inbound() {
+                match mapper.map_port(*local_addr, PORT_MAPPING_LIFETIME).await {
+                    Ok(addr) => {
+                        debug!(target: "net", "Mapped {} to external address {}", local_addr, addr);
+                        mapped.push(addr);
+                    }
+                    Err(err) => warn!(target: "net", "Unable to map {}: {}", local_addr, err),
+                }
+            }
+            self.external.set_mapped(mapped).await;
+
+            sleep(PORT_MAPPING_LIFETIME / 2).await;
+        }
+    }
+
+    /// Inbound addresses mapped on the router. IPv6 addresses are reachable
+    /// without a NAT, so only the IPv4 ones are mapped.
+    fn mapped_inbound(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.settings.inbound.iter().filter(|addr| addr.is_ipv4())
+    }
+
+    async fn unmap_ports(&self) {
+        let mapper = match &self.settings.port_mapper {
+            Some(mapper) => mapper,
+            None => return,
+        };
+
+        for local_addr in self.mapped_inbound() {
+            if let Err(err) = mapper.unmap_port(*local_addr).await {
+                warn!(target: "net", "Unable to unmap {}: {}", local_addr, err);
+            }
+        }
+        self.external.set_mapped(Vec::new()).await;
+    }
+
     /// Broadcasts a message across all channels.
     pub async fn broadcast<M: Message + Clone>(&self, message: M) -> Result<()> {
         for channel in self.channels.lock().await.values() {
@@ -194,21 +396,37 @@ impl P2p {
 
     /// Remove a channel from the list of connected channels.
     pub async fn remove(&self, channel: ChannelPtr) {
-        self.channels.lock().await.remove(&channel.address());
+        if self.channels.lock().await.remove(&channel.address()).is_none() {
+            return
+        }
+
+        // Keep the counters of the channel in its session aggregate
+        let stats = channel.stats().await;
+        self.closed_stats.lock().await.entry(channel.session()).or_default().add(&stats);
+    }
+
+    /// Return the connected channels.
+    pub async fn channels(&self) -> Vec<ChannelPtr> {
+        self.channels.lock().await.values().cloned().collect()
     }
 
     /// Check whether a channel is stored in the list of connected channels.
-    pub async fn exists(&self, addr: &SocketAddr) -> bool {
+    pub async fn exists(&self, addr: &NetAddr) -> bool {
         self.channels.lock().await.contains_key(addr)
     }
 
     /// Add a channel to the list of pending channels.
-    pub async fn add_pending(&self, addr: SocketAddr) -> bool {
+    pub async fn add_pending(&self, addr: NetAddr) -> bool {
         self.pending.lock().await.insert(addr)
     }
 
+    /// Check whether a connection to an address is pending.
+    pub async fn is_pending(&self, addr: &NetAddr) -> bool {
+        self.pending.lock().await.contains(addr)
+    }
+
     /// Remove a channel from the list of pending channels.
-    pub async fn remove_pending(&self, addr: &SocketAddr) {
+    pub async fn remove_pending(&self, addr: &NetAddr) {
         self.pending.lock().await.remove(addr);
     }
 
@@ -222,6 +440,21 @@ impl P2p {
         self.settings.clone()
     }
 
+    /// Return an atomic pointer to the node identity key.
+    pub fn identity(&self) -> Arc<NodeIdentity> {
+        self.identity.clone()
+    }
+
+    /// Return an atomic pointer to our external addresses.
+    pub fn external(&self) -> ExternalAddrsPtr {
+        self.external.clone()
+    }
+
+    /// Return the addresses peers can reach us on, configured or discovered.
+    pub async fn external_addrs(&self) -> Vec<NetAddr> {
+        self.external.addrs().await
+    }
+
     /// Return an atomic pointer to the list of hosts.
     pub fn hosts(&self) -> HostsPtr {
         self.hosts.clone()
@@ -241,3 +474,133 @@ impl P2p {
         self.stop_subscriber.clone().subscribe().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+    use async_trait::async_trait;
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tempfile::tempdir;
+
+    use crate::{
+        net::{
+            sim::{start_node, SimNetwork},
+            NetAddr, P2p, ProtocolBase, ProtocolBasePtr, SESSION_OUTBOUND,
+        },
+        Result,
+    };
+
+    /// Counts the channels it was shut down on.
+    struct ProtocolShutdown {
+        count: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ProtocolBase for ProtocolShutdown {
+        async fn start(self: Arc<Self>, _executor: Arc<Executor<'_>>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> Result<()> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "ProtocolShutdown"
+        }
+    }
+
+    #[test]
+    fn shutdown_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(8);
+            network.set_latency(Duration::from_millis(10));
+
+            // The anchors are saved next to the host store
+            let dir = tempdir().unwrap();
+            let hosts_path = dir.path().join("hosts");
+            let anchors_path = hosts_path.with_extension("anchors");
+            let nodes = network
+                .create_nodes(3, |index, settings| {
+                    if index == 2 {
+                        settings.hosts_path = Some(hosts_path.clone());
+                    }
+                })
+                .await;
+
+            let count = Arc::new(AtomicUsize::new(0));
+            let count2 = count.clone();
+            nodes[2]
+                .protocol_registry()
+                .register(SESSION_OUTBOUND, move |_channel, _p2p| {
+                    let count = count2.clone();
+                    async move { Arc::new(ProtocolShutdown { count }) as ProtocolBasePtr }
+                })
+                .await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            let node = nodes[2].clone();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        node.session_outbound().await.connected_addrs().await.len() == 2
+                    })
+                    .await
+            );
+            let anchors = node.session_outbound().await.connected_addrs().await;
+
+            // Stopping returns from run once the channels are closed
+            node.stop().await;
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(10), || async {
+                        node.get_info().await["state"] == "stopped"
+                    })
+                    .await
+            );
+            assert_eq!(count.load(Ordering::SeqCst), 2);
+            assert!(hosts_path.exists() && anchors_path.exists());
+
+            // The peers see the channels closing
+            let ip = Some(SimNetwork::node_addr(2).ip());
+            let closed = || async {
+                for peer in &nodes[..2] {
+                    if peer.channels().await.iter().any(|channel| channel.address().ip() == ip) {
+                        return false
+                    }
+                }
+                true
+            };
+            assert!(network.wait_until(Duration::from_secs(5), closed).await);
+
+            // A new node with the same host store reconnects to its anchors
+            let mut settings = network.node_settings(2);
+            settings.seeds = vec![SimNetwork::node_addr(0).into()];
+            settings.outbound_connections = 2;
+            settings.hosts_path = Some(hosts_path.clone());
+            let restarted = P2p::new(settings).await;
+            let anchors: HashSet<NetAddr> = anchors.into_iter().collect();
+            let loaded: HashSet<NetAddr> = restarted.hosts().anchors().await.into_iter().collect();
+            assert_eq!(loaded, anchors);
+
+            start_node(restarted.clone(), ex.clone());
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        let connected = restarted.session_outbound().await.connected_addrs().await;
+                        connected.into_iter().collect::<HashSet<_>>() == anchors
+                    })
+                    .await
+            );
+        }));
+    }
+}
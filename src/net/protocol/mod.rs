@@ -9,6 +9,16 @@
 /// address information to their local store.
 pub mod protocol_address;
 
+/// Generic gossip protocol. Any message type can be flooded through the
+/// network with a gossip layer, which identifies messages by the hash of
+/// their content and keeps a bounded cache of the ids it has seen, so
+/// every node passes on and relays a message only once.
+///
+/// Messages seen for the first time are optionally validated, passed to
+/// the subscribers, then relayed to the other peers except the one they
+/// came from, or to a random subset of them when a fanout is set.
+pub mod protocol_gossip;
+
 /// Manages the tasks for the network protocol. Used by other connection
 /// protocols to handle asynchronous task execution across the network. Runs all
 /// tasks that are handed to it on an executor that has stopping functionality.
@@ -35,6 +45,16 @@ pub mod protocol_ping;
 /// store.
 pub mod protocol_seed;
 
+/// Protocol transferring payloads too large for a single message. A stream
+/// is read from an `AsyncRead` and sent in chunks, with the receiver
+/// acknowledging the chunks it wrote to disk so the sender never has more
+/// than a window of them in flight. The stream ends with its hash, which
+/// the receiver checks before passing the stream's file on.
+///
+/// Interrupted streams are kept by the receiver, so sending a stream again
+/// with the same id resumes it where it stopped.
+pub mod protocol_stream;
+
 /// Protocol for version information handshake between nodes at the start of a
 /// connection. Implements the process for exchanging version information
 /// between nodes. This is the first step when establishing a p2p connection.
@@ -50,9 +70,13 @@ pub mod protocol_base;
 pub mod protocol_registry;
 
 pub use protocol_address::ProtocolAddress;
+pub use protocol_gossip::{Gossip, GossipPtr, GossipSettings, ProtocolGossip};
 pub use protocol_jobs_manager::{ProtocolJobsManager, ProtocolJobsManagerPtr};
 pub use protocol_ping::ProtocolPing;
 pub use protocol_seed::ProtocolSeed;
+pub use protocol_stream::{
+    new_stream_id, ProtocolStream, ReceivedStream, StreamId, StreamSettings, Streams, StreamsPtr,
+};
 pub use protocol_version::ProtocolVersion;
 
 pub use protocol_base::{ProtocolBase, ProtocolBasePtr};
@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use log::debug;
+use rand::seq::SliceRandom;
 use smol::Executor;
 
 use crate::{
@@ -10,16 +11,20 @@ use crate::{
         message,
         message_subscriber::MessageSubscription,
         protocol::{ProtocolBase, ProtocolBasePtr, ProtocolJobsManager, ProtocolJobsManagerPtr},
-        ChannelPtr, HostsPtr, P2pPtr,
+        ChannelPtr, HostsPtr, P2pPtr, BAN_THRESHOLD,
     },
 };
 
+/// Maximum number of addresses in a single address message.
+const MAX_ADDRS: usize = 1000;
+
 /// Defines address and get-address messages.
 pub struct ProtocolAddress {
     channel: ChannelPtr,
     addrs_sub: MessageSubscription<message::AddrsMessage>,
     get_addrs_sub: MessageSubscription<message::GetAddrsMessage>,
     hosts: HostsPtr,
+    seed_mode: bool,
     jobsman: ProtocolJobsManagerPtr,
 }
 
@@ -28,6 +33,7 @@ impl ProtocolAddress {
     /// subscription and adds them to the address protocol instance.
     pub async fn new(channel: ChannelPtr, p2p: P2pPtr) -> ProtocolBasePtr {
         let hosts = p2p.hosts();
+        let seed_mode = p2p.settings().seed_mode;
 
         // Creates a subscription to address message.
         let addrs_sub = channel
@@ -48,6 +54,7 @@ impl ProtocolAddress {
             addrs_sub,
             get_addrs_sub,
             hosts,
+            seed_mode,
             jobsman: ProtocolJobsManager::new("ProtocolAddress", channel),
         })
     }
@@ -60,6 +67,11 @@ impl ProtocolAddress {
         loop {
             let addrs_msg = self.addrs_sub.receive().await?;
 
+            if addrs_msg.addrs.len() > MAX_ADDRS {
+                self.channel.misbehave(BAN_THRESHOLD / 5, "Too many addresses").await;
+                continue
+            }
+
             debug!(
                 target: "net",
                 "ProtocolAddress::handle_receive_addrs() received {} addrs",
@@ -74,7 +86,8 @@ impl ProtocolAddress {
 
     /// Handles receiving the get-address message. Continually recieves
     /// get-address messages on the get-address subsciption. Then replies
-    /// with an address message.
+    /// with an address message. Seed nodes only reply with the hosts they
+    /// could reach, unless they couldn't reach any yet.
     async fn handle_receive_get_addrs(self: Arc<Self>) -> Result<()> {
         debug!(target: "net", "ProtocolAddress::handle_receive_get_addrs() [START]");
         loop {
@@ -83,7 +96,15 @@ impl ProtocolAddress {
             debug!(target: "net", "ProtocolAddress::handle_receive_get_addrs() received GetAddrs message");
 
             // Loads the list of hosts.
-            let addrs = self.hosts.load_all().await;
+            let mut addrs = match self.seed_mode {
+                true => self.hosts.load_reachable().await,
+                false => Vec::new(),
+            };
+            if addrs.is_empty() {
+                addrs = self.hosts.load_all().await;
+            }
+            addrs.shuffle(&mut rand::thread_rng());
+            addrs.truncate(MAX_ADDRS);
             debug!(
                 target: "net",
                 "ProtocolAddress::handle_receive_get_addrs() sending {} addrs",
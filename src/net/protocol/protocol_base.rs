@@ -10,5 +10,11 @@ pub type ProtocolBasePtr = Arc<dyn ProtocolBase + Send + Sync>;
 pub trait ProtocolBase {
     async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()>;
 
+    /// Called when the network shuts down, before the channel is closed.
+    /// Protocols can send their last messages here.
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
     fn name(&self) -> &'static str;
 }
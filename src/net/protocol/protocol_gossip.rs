@@ -0,0 +1,471 @@
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use blake2b_simd::Params as Blake2bParams;
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use rand::seq::SliceRandom;
+use smol::Executor;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Result,
+    net::{
+        message::{Message, ServiceBitflag},
+        message_subscriber::MessageSubscription,
+        protocol::{ProtocolBase, ProtocolBasePtr, ProtocolJobsManager, ProtocolJobsManagerPtr},
+        session::SessionBitflag,
+        ChannelPtr, NetAddr, P2p, P2pPtr,
+    },
+    system::{Subscriber, SubscriberPtr, Subscription},
+    util::serial::serialize,
+};
+
+const MESSAGE_ID_PERSONALIZATION: &[u8; 16] = b"DarkFi_GossipMsg";
+
+/// Content hash identifying a gossiped message.
+pub type MessageId = [u8; 32];
+
+/// Pointer to a gossip layer.
+pub type GossipPtr<M> = Arc<Gossip<M>>;
+
+type Validator<M> = Box<dyn Fn(Arc<M>) -> BoxFuture<'static, Result<bool>> + Send + Sync>;
+
+/// Compute the id of a message from its command and encoded content, so
+/// the same message gets the same id whichever peer relays it.
+pub fn message_id<M: Message>(message: &M) -> MessageId {
+    let hash = Blake2bParams::new()
+        .hash_length(32)
+        .personal(MESSAGE_ID_PERSONALIZATION)
+        .to_state()
+        .update(M::name().as_bytes())
+        .update(&serialize(message))
+        .finalize();
+    let mut id = [0u8; 32];
+    id.copy_from_slice(hash.as_bytes());
+    id
+}
+
+/// Tuning of a gossip layer.
+#[derive(Clone, Debug)]
+pub struct GossipSettings {
+    /// Number of message ids remembered. The least recently seen ids are
+    /// forgotten first.
+    pub seen_cache_size: usize,
+    /// Seconds a message id is remembered after it was first seen
+    pub seen_ttl_seconds: u64,
+    /// Number of peers a message is relayed to, 0 relays to all peers
+    pub fanout: usize,
+}
+
+impl Default for GossipSettings {
+    fn default() -> Self {
+        Self { seen_cache_size: 10000, seen_ttl_seconds: 600, fanout: 0 }
+    }
+}
+
+/// Bounded cache of the message ids we've seen, evicting the least
+/// recently seen ids. Ids expire once they're older than the TTL, so a
+/// message can be gossiped again later.
+pub struct SeenCache {
+    capacity: usize,
+    ttl: Duration,
+    /// First time an id was seen, and its position in the LRU order
+    entries: HashMap<MessageId, (Instant, u64)>,
+    /// Ids in order of use. Ids seen again are pushed at the back, and
+    /// the stale positions are skipped when evicting.
+    order: VecDeque<(MessageId, u64)>,
+    counter: u64,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            counter: 0,
+        }
+    }
+
+    /// Mark an id as seen. Returns true if it wasn't seen before.
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        self.insert_at(id, Instant::now())
+    }
+
+    fn insert_at(&mut self, id: MessageId, now: Instant) -> bool {
+        self.counter += 1;
+        let position = self.counter;
+
+        let is_new = match self.entries.get_mut(&id) {
+            Some((first_seen, last_position)) => {
+                let expired = now.saturating_duration_since(*first_seen) >= self.ttl;
+                if expired {
+                    *first_seen = now;
+                }
+                *last_position = position;
+                expired
+            }
+            None => {
+                self.entries.insert(id, (now, position));
+                true
+            }
+        };
+        self.order.push_back((id, position));
+
+        while self.entries.len() > self.capacity {
+            self.evict_oldest();
+        }
+        // Drop the stale positions left by ids seen several times
+        if self.order.len() > self.capacity * 2 {
+            let entries = &self.entries;
+            self.order.retain(|(id, position)| entries.get(id).map(|e| e.1) == Some(*position));
+        }
+
+        is_new
+    }
+
+    /// Forget an id, so the message is handled again the next time it is
+    /// seen.
+    pub fn remove(&mut self, id: &MessageId) {
+        // The position left in the LRU order is stale and gets skipped
+        self.entries.remove(id);
+    }
+
+    fn evict_oldest(&mut self) {
+        while let Some((id, position)) = self.order.pop_front() {
+            if self.entries.get(&id).map(|entry| entry.1) == Some(position) {
+                self.entries.remove(&id);
+                return
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Gossip layer flooding a message type through the network. Messages
+/// seen for the first time are passed to the subscribers and relayed to
+/// the other peers, except the one we got them from. Duplicates are
+/// dropped using the seen cache.
+pub struct Gossip<M: Message + Clone> {
+    p2p: Weak<P2p>,
+    services: ServiceBitflag,
+    settings: GossipSettings,
+    seen: Mutex<SeenCache>,
+    subscriber: SubscriberPtr<Arc<M>>,
+    validator: Mutex<Option<Validator<M>>>,
+}
+
+impl<M: Message + Clone> Gossip<M> {
+    /// Create a gossip layer for a message type, and register its protocol
+    /// on the channels of the given sessions where both nodes announced
+    /// `services`. Must be called before the network is started.
+    pub async fn new(
+        p2p: P2pPtr,
+        session_flags: SessionBitflag,
+        services: ServiceBitflag,
+        settings: GossipSettings,
+    ) -> GossipPtr<M> {
+        let seen = SeenCache::new(
+            settings.seen_cache_size,
+            Duration::from_secs(settings.seen_ttl_seconds),
+        );
+        let self_ = Arc::new(Self {
+            p2p: Arc::downgrade(&p2p),
+            services,
+            settings,
+            seen: Mutex::new(seen),
+            subscriber: Subscriber::new(),
+            validator: Mutex::new(None),
+        });
+
+        let gossip = self_.clone();
+        p2p.protocol_registry()
+            .register_with_services(session_flags, services, move |channel, _p2p| {
+                let gossip = gossip.clone();
+                async move { ProtocolGossip::new(channel, gossip).await }
+            })
+            .await;
+
+        self_
+    }
+
+    /// Set a check run on new messages received from peers. Messages for
+    /// which it returns false are neither passed on nor relayed.
+    pub async fn set_validator<F, Fut>(&self, validator: F)
+    where
+        F: 'static + Fn(Arc<M>) -> Fut + Send + Sync,
+        Fut: 'static + Future<Output = Result<bool>> + Send,
+    {
+        let validator =
+            move |message| Box::pin(validator(message)) as BoxFuture<'static, Result<bool>>;
+        *self.validator.lock().await = Some(Box::new(validator));
+    }
+
+    /// Subscribe to the new messages received from peers.
+    pub async fn subscribe(&self) -> Subscription<Arc<M>> {
+        self.subscriber.clone().subscribe().await
+    }
+
+    /// Gossip a message of our own. Our subscribers aren't notified.
+    pub async fn broadcast(&self, message: M) -> Result<()> {
+        self.seen.lock().await.insert(message_id(&message));
+        self.relay(&message, None).await;
+        Ok(())
+    }
+
+    /// Handle a message received from a peer. Returns true if the message
+    /// was new and valid.
+    async fn receive(&self, message: Arc<M>, origin: &NetAddr) -> Result<bool> {
+        let id = message_id(&*message);
+        if !self.seen.lock().await.insert(id) {
+            return Ok(false)
+        }
+
+        if let Some(validator) = self.validator.lock().await.as_ref() {
+            let valid = match validator(message.clone()).await {
+                Ok(valid) => valid,
+                Err(err) => {
+                    // The check didn't run, so the message is handled
+                    // again if a peer sends it
+                    self.seen.lock().await.remove(&id);
+                    return Err(err)
+                }
+            };
+            if !valid {
+                debug!(target: "net", "Gossip dropped invalid {} from {}", M::name(), origin);
+                return Ok(false)
+            }
+        }
+
+        self.subscriber.notify(message.clone()).await;
+        self.relay(&message, Some(origin)).await;
+        Ok(true)
+    }
+
+    /// Send a message to the peers supporting the gossip services, except
+    /// the peer it came from. At most `fanout` random peers are chosen.
+    async fn relay(&self, message: &M, origin: Option<&NetAddr>) {
+        let p2p = match self.p2p.upgrade() {
+            Some(p2p) => p2p,
+            None => return,
+        };
+
+        let mut channels = Vec::new();
+        for channel in p2p.channels().await {
+            if Some(&channel.address()) == origin || !channel.supports(self.services).await {
+                continue
+            }
+            channels.push(channel);
+        }
+
+        if self.settings.fanout > 0 && channels.len() > self.settings.fanout {
+            channels.shuffle(&mut rand::thread_rng());
+            channels.truncate(self.settings.fanout);
+        }
+
+        for channel in channels {
+            // A failing peer shouldn't keep the others from getting it
+            if let Err(err) = channel.send(message.clone()).await {
+                warn!(target: "net", "Unable to relay {} to {}: {}", M::name(), channel.address(), err);
+            }
+        }
+    }
+}
+
+/// Receives the messages of a gossip layer on a channel.
+pub struct ProtocolGossip<M: Message + Clone> {
+    channel: ChannelPtr,
+    message_sub: MessageSubscription<M>,
+    gossip: GossipPtr<M>,
+    jobsman: ProtocolJobsManagerPtr,
+}
+
+impl<M: Message + Clone> ProtocolGossip<M> {
+    pub async fn new(channel: ChannelPtr, gossip: GossipPtr<M>) -> ProtocolBasePtr {
+        channel.get_message_subsystem().add_dispatch::<M>().await;
+        let message_sub =
+            channel.subscribe_msg::<M>().await.expect("Missing gossip message dispatcher!");
+
+        Arc::new(Self {
+            channel: channel.clone(),
+            message_sub,
+            gossip,
+            jobsman: ProtocolJobsManager::new("ProtocolGossip", channel),
+        })
+    }
+
+    async fn handle_receive_message(self: Arc<Self>) -> Result<()> {
+        debug!(target: "net", "ProtocolGossip::handle_receive_message() [START]");
+        loop {
+            let message = self.message_sub.receive().await?;
+            if let Err(err) = self.gossip.receive(message, &self.channel.address()).await {
+                warn!(target: "net", "Unable to handle gossiped {}: {}", M::name(), err);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<M: Message + Clone> ProtocolBase for ProtocolGossip<M> {
+    /// Starts the gossip protocol. Runs the message handler on the
+    /// protocol task manager.
+    async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+        debug!(target: "net", "ProtocolGossip::start() [START]");
+        self.jobsman.clone().start(executor.clone());
+        self.jobsman.clone().spawn(self.clone().handle_receive_message(), executor).await;
+        debug!(target: "net", "ProtocolGossip::start() [END]");
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "ProtocolGossip"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+    use std::{
+        io,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::{message_id, Gossip, GossipSettings, SeenCache};
+    use crate::{
+        net::{sim::SimNetwork, Message, SERVICE_RELAY, SESSION_ALL},
+        util::serial::{Decodable, Encodable},
+        Result,
+    };
+
+    #[derive(Clone)]
+    struct TestMessage {
+        text: String,
+    }
+
+    impl Message for TestMessage {
+        fn name() -> &'static str {
+            "test"
+        }
+    }
+
+    impl Encodable for TestMessage {
+        fn encode<S: io::Write>(&self, s: S) -> Result<usize> {
+            self.text.encode(s)
+        }
+    }
+
+    impl Decodable for TestMessage {
+        fn decode<D: io::Read>(d: D) -> Result<Self> {
+            Ok(Self { text: Decodable::decode(d)? })
+        }
+    }
+
+    #[test]
+    fn seen_cache_test() {
+        let start = Instant::now();
+        let mut cache = SeenCache::new(2, Duration::from_secs(10));
+        let id = |text: &str| message_id(&TestMessage { text: text.to_string() });
+
+        assert!(cache.insert_at(id("a"), start));
+        assert!(!cache.insert_at(id("a"), start));
+        assert!(cache.insert_at(id("b"), start));
+
+        // "a" was seen more recently than "b", so "b" is evicted
+        assert!(!cache.insert_at(id("a"), start));
+        assert!(cache.insert_at(id("c"), start));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.insert_at(id("a"), start));
+        assert!(cache.insert_at(id("b"), start));
+
+        // Ids expire after the TTL
+        assert!(cache.insert_at(id("b"), start + Duration::from_secs(10)));
+
+        // Forgotten ids are new again
+        cache.remove(&id("b"));
+        assert!(cache.insert_at(id("b"), start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn gossip_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(4);
+            network.set_latency(Duration::from_millis(10));
+            let nodes = network.create_nodes(6, |_, _| {}).await;
+
+            let mut gossips = Vec::new();
+            let mut subs = Vec::new();
+            for node in &nodes {
+                let gossip = Gossip::<TestMessage>::new(
+                    node.clone(),
+                    SESSION_ALL,
+                    SERVICE_RELAY,
+                    GossipSettings::default(),
+                )
+                .await;
+                // Messages starting with "!" are invalid
+                gossip
+                    .set_validator(|message: Arc<TestMessage>| async move {
+                        Ok(!message.text.starts_with('!'))
+                    })
+                    .await;
+                subs.push(gossip.subscribe().await);
+                gossips.push(gossip);
+            }
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        for node in &nodes[1..] {
+                            if node.connections_count().await < 2 {
+                                return false
+                            }
+                        }
+                        true
+                    })
+                    .await
+            );
+
+            gossips[3].broadcast(TestMessage { text: "!invalid".to_string() }).await.unwrap();
+            gossips[3].broadcast(TestMessage { text: "hello".to_string() }).await.unwrap();
+
+            // Every other node gets the valid message
+            for (i, sub) in subs.iter().enumerate() {
+                if i == 3 {
+                    continue
+                }
+                let message = async_std::future::timeout(Duration::from_secs(5), sub.receive())
+                    .await
+                    .unwrap();
+                assert_eq!(message.text, "hello");
+            }
+
+            // Another node sending the same message doesn't deliver it twice
+            gossips[5].broadcast(TestMessage { text: "hello".to_string() }).await.unwrap();
+            async_std::task::sleep(Duration::from_millis(500)).await;
+            for (i, sub) in subs.iter().enumerate() {
+                if i == 3 {
+                    continue
+                }
+                let duplicate =
+                    async_std::future::timeout(Duration::from_millis(10), sub.receive()).await;
+                assert!(duplicate.is_err());
+            }
+        }));
+    }
+}
@@ -1,11 +1,13 @@
 use async_trait::async_trait;
 use log::{debug, error};
-use rand::Rng;
 use smol::Executor;
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    error::{Error, Result},
+    error::Result,
     net::{
         message,
         message_subscriber::MessageSubscription,
@@ -19,7 +21,6 @@ use crate::{
 pub struct ProtocolPing {
     channel: ChannelPtr,
     ping_sub: MessageSubscription<message::PingMessage>,
-    pong_sub: MessageSubscription<message::PongMessage>,
     settings: SettingsPtr,
     jobsman: ProtocolJobsManagerPtr,
 }
@@ -36,50 +37,41 @@ impl ProtocolPing {
             .await
             .expect("Missing ping dispatcher!");
 
-        // Creates a subscription to pong message.
-        let pong_sub = channel
-            .clone()
-            .subscribe_msg::<message::PongMessage>()
-            .await
-            .expect("Missing pong dispatcher!");
-
         Arc::new(Self {
             channel: channel.clone(),
             ping_sub,
-            pong_sub,
             settings,
             jobsman: ProtocolJobsManager::new("ProtocolPing", channel),
         })
     }
 
-    /// Runs ping-pong protocol. Creates a subscription to pong, then starts a
-    /// loop. Loop sleeps for the duration of the channel heartbeat, then
-    /// sends a ping message with a random nonce. Loop starts a timer, waits
-    /// for the pong reply and insures the nonce is the same.
+    /// Runs ping-pong protocol. Loop sleeps for the duration of the
+    /// channel heartbeat, then sends a ping request and waits for the
+    /// matching pong. Peers that don't reply within a heartbeat are
+    /// disconnected.
     async fn run_ping_pong(self: Arc<Self>) -> Result<()> {
         debug!(target: "net", "ProtocolPing::run_ping_pong() [START]");
+        let pong_timeout = Duration::from_secs(self.settings.channel_heartbeat_seconds.into());
         loop {
             // Wait channel_heartbeat amount of time.
             sleep(self.settings.channel_heartbeat_seconds).await;
 
-            // Create a random nonce.
-            let nonce = Self::random_nonce();
-
-            // Send ping message.
-            let ping = message::PingMessage { nonce };
-            self.channel.clone().send(ping).await?;
-            debug!(target: "net", "ProtocolPing::run_ping_pong() send Ping message");
             // Start the timer for ping timer.
             let start = Instant::now();
 
-            // Wait for pong, check nonce matches.
-            let pong_msg = self.pong_sub.receive().await?;
-            if pong_msg.nonce != nonce {
-                error!("Wrong nonce for ping reply. Disconnecting from channel.");
+            // The request sets the nonce, and waits for the pong with the
+            // same nonce.
+            let ping = message::PingMessage { nonce: 0 };
+            debug!(target: "net", "ProtocolPing::run_ping_pong() send Ping message");
+            if let Err(err) =
+                self.channel.request::<_, message::PongMessage>(ping, pong_timeout).await
+            {
+                error!("No reply to ping: {}. Disconnecting from channel.", err);
                 self.channel.stop().await;
-                return Err(Error::ChannelStopped)
+                return Err(err)
             }
             let duration = start.elapsed().as_millis();
+            self.channel.set_ping_rtt(duration as u64).await;
             debug!(target: "net", "Received Pong message {}ms from [{:?}]", duration, self.channel.address());
         }
     }
@@ -99,11 +91,6 @@ impl ProtocolPing {
             debug!(target: "net", "ProtocolPing::reply_to_ping() sent Pong reply");
         }
     }
-
-    fn random_nonce() -> u32 {
-        let mut rng = rand::thread_rng();
-        rng.gen()
-    }
 }
 
 #[async_trait]
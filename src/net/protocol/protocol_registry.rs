@@ -3,13 +3,18 @@ use futures::future::BoxFuture;
 use log::debug;
 use std::future::Future;
 
-use crate::net::{protocol::ProtocolBasePtr, session::SessionBitflag, ChannelPtr, P2pPtr};
+use crate::net::{
+    message::{ServiceBitflag, SERVICE_NONE},
+    protocol::ProtocolBasePtr,
+    session::SessionBitflag,
+    ChannelPtr, P2pPtr,
+};
 
 type Constructor =
     Box<dyn Fn(ChannelPtr, P2pPtr) -> BoxFuture<'static, ProtocolBasePtr> + Send + Sync>;
 
 pub struct ProtocolRegistry {
-    protocol_constructors: Mutex<Vec<(SessionBitflag, Constructor)>>,
+    protocol_constructors: Mutex<Vec<(SessionBitflag, ServiceBitflag, Constructor)>>,
 }
 
 impl ProtocolRegistry {
@@ -22,11 +27,29 @@ impl ProtocolRegistry {
     where
         C: 'static + Fn(ChannelPtr, P2pPtr) -> F + Send + Sync,
         F: 'static + Future<Output = ProtocolBasePtr> + Send,
+    {
+        self.register_with_services(session_flags, SERVICE_NONE, constructor).await
+    }
+
+    /// Register a protocol which is only started on channels where both
+    /// nodes announced all the given services.
+    pub async fn register_with_services<C, F>(
+        &self,
+        session_flags: SessionBitflag,
+        services: ServiceBitflag,
+        constructor: C,
+    ) where
+        C: 'static + Fn(ChannelPtr, P2pPtr) -> F + Send + Sync,
+        F: 'static + Future<Output = ProtocolBasePtr> + Send,
     {
         let constructor = move |channel, p2p| {
             Box::pin(constructor(channel, p2p)) as BoxFuture<'static, ProtocolBasePtr>
         };
-        self.protocol_constructors.lock().await.push((session_flags, Box::new(constructor)));
+        self.protocol_constructors.lock().await.push((
+            session_flags,
+            services,
+            Box::new(constructor),
+        ));
     }
 
     pub async fn attach(
@@ -34,9 +57,9 @@ impl ProtocolRegistry {
         selector_id: SessionBitflag,
         channel: ChannelPtr,
         p2p: P2pPtr,
-    ) -> Vec<ProtocolBasePtr> {
-        let mut protocols: Vec<ProtocolBasePtr> = Vec::new();
-        for (session_flags, construct) in self.protocol_constructors.lock().await.iter() {
+    ) -> Vec<(ServiceBitflag, ProtocolBasePtr)> {
+        let mut protocols: Vec<(ServiceBitflag, ProtocolBasePtr)> = Vec::new();
+        for (session_flags, services, construct) in self.protocol_constructors.lock().await.iter() {
             // Skip protocols that are not registered for this session
             if selector_id & session_flags == 0 {
                 continue
@@ -44,7 +67,7 @@ impl ProtocolRegistry {
 
             let protocol: ProtocolBasePtr = construct(channel.clone(), p2p.clone()).await;
             debug!(target: "net", "Attached {}", protocol.name());
-            protocols.push(protocol)
+            protocols.push((*services, protocol))
         }
         protocols
     }
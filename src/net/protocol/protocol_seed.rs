@@ -7,8 +7,9 @@ use crate::{
     error::Result,
     net::{
         message,
+        nat::ExternalAddrsPtr,
         protocol::{ProtocolBase, ProtocolBasePtr},
-        ChannelPtr, HostsPtr, P2pPtr, SettingsPtr,
+        ChannelPtr, HostsPtr, P2pPtr,
     },
 };
 
@@ -16,31 +17,32 @@ use crate::{
 pub struct ProtocolSeed {
     channel: ChannelPtr,
     hosts: HostsPtr,
-    settings: SettingsPtr,
+    external: ExternalAddrsPtr,
 }
 
 impl ProtocolSeed {
     /// Create a new seed protocol.
     pub async fn new(channel: ChannelPtr, p2p: P2pPtr) -> ProtocolBasePtr {
         let hosts = p2p.hosts();
-        let settings = p2p.settings();
+        let external = p2p.external();
 
-        Arc::new(Self { channel, hosts, settings })
+        Arc::new(Self { channel, hosts, external })
     }
 
-    /// Sends own external address over a channel. Imports own external address
-    /// from settings, then adds that address to an address message and
-    /// sends it out over the channel.
+    /// Sends own external addresses over a channel. Gets own external
+    /// addresses, configured or discovered, then adds them to an address
+    /// message and sends it out over the channel.
     pub async fn send_self_address(&self) -> Result<()> {
-        match self.settings.external_addr {
-            Some(addr) => {
-                debug!(target: "net", "ProtocolSeed::send_own_address() addr={}", addr);
-                let addr = message::AddrsMessage { addrs: vec![addr] };
-                Ok(self.channel.clone().send(addr).await?)
-            }
-            // Do nothing if external address is not configured
-            None => Ok(()),
+        let addrs = self.external.addrs().await;
+        // Do nothing if no external address is known
+        if addrs.is_empty() {
+            return Ok(())
         }
+
+        for addr in &addrs {
+            debug!(target: "net", "ProtocolSeed::send_own_address() addr={}", addr);
+        }
+        Ok(self.channel.clone().send(message::AddrsMessage { addrs }).await?)
     }
 }
 
@@ -0,0 +1,928 @@
+use async_std::{fs::File, future::timeout, sync::Mutex};
+use async_trait::async_trait;
+use blake2b_simd::{Params as Blake2bParams, State as Blake2bState};
+use futures::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use log::{debug, warn};
+use rand::Rng;
+use smol::{Executor, Timer};
+use std::{
+    collections::HashMap,
+    fs, io, mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    net::{
+        message::{Message, ServiceBitflag},
+        message_subscriber::MessageSubscription,
+        protocol::{ProtocolBase, ProtocolBasePtr, ProtocolJobsManager, ProtocolJobsManagerPtr},
+        rate_limit::TokenBucket,
+        session::SessionBitflag,
+        ChannelPtr, NetAddr, P2pPtr,
+    },
+    system::{Subscriber, SubscriberPtr, Subscription},
+    util::serial::{Decodable, Encodable},
+};
+
+const STREAM_HASH_PERSONALIZATION: &[u8; 16] = b"DarkFi_P2pStream";
+
+/// Size of the chunks a stream is sent in. The last chunk may be shorter.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// The receiver created or found the stream. The offset is where the
+/// sender resumes.
+const STREAM_OPENED: u8 = 0;
+/// The receiver stored the data up to the offset.
+const STREAM_PROGRESS: u8 = 1;
+/// The receiver checked the stream and passed it on.
+const STREAM_COMPLETE: u8 = 2;
+/// The receiver dropped the stream.
+const STREAM_REJECTED: u8 = 3;
+
+/// Identifies a stream. Sending a stream again with the same id resumes it.
+pub type StreamId = u64;
+
+/// Pointer to a stream layer.
+pub type StreamsPtr = Arc<Streams>;
+
+/// Pick a random stream id.
+pub fn new_stream_id() -> StreamId {
+    rand::thread_rng().gen()
+}
+
+fn stream_hasher() -> Blake2bState {
+    Blake2bParams::new().hash_length(32).personal(STREAM_HASH_PERSONALIZATION).to_state()
+}
+
+fn finalize(hasher: &Blake2bState) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(hasher.finalize().as_bytes());
+    hash
+}
+
+/// Tuning of a stream layer.
+#[derive(Clone, Debug)]
+pub struct StreamSettings {
+    /// Directory the incoming streams are written to
+    pub directory: PathBuf,
+    /// Number of chunks sent before waiting for the receiver to store them
+    pub window: usize,
+    /// Largest stream accepted from a peer, in bytes
+    pub max_stream_size: u64,
+    /// Number of streams received at once
+    pub max_incoming: usize,
+    /// Number of streams received at once from a single host
+    pub max_incoming_per_peer: usize,
+    /// Seconds an interrupted stream is kept for the sender to resume it
+    pub idle_timeout_seconds: u64,
+    /// Seconds to wait for the receiver to acknowledge data
+    pub ack_timeout_seconds: u64,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            directory: std::env::temp_dir().join("darkfi_streams"),
+            window: 8,
+            max_stream_size: 64 * 1024 * 1024,
+            max_incoming: 8,
+            max_incoming_per_peer: 2,
+            idle_timeout_seconds: 300,
+            ack_timeout_seconds: 30,
+        }
+    }
+}
+
+/// Stream received from a peer. Its data is in a file of the stream
+/// directory, which is removed once the stream is dropped. Subscribers
+/// keeping the data have to copy the file.
+#[derive(Debug)]
+pub struct ReceivedStream {
+    pub id: StreamId,
+    /// Peer the stream was completed by
+    pub peer: NetAddr,
+    pub length: u64,
+    pub path: PathBuf,
+}
+
+impl ReceivedStream {
+    /// Open the data of the stream for reading.
+    pub async fn open(&self) -> Result<File> {
+        Ok(File::open(&self.path).await?)
+    }
+}
+
+impl Drop for ReceivedStream {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Peer a stream is received from. Peers of encrypted channels are told
+/// apart by their identity key, others by their host. Unlike the port of
+/// an inbound channel, both stay the same when the peer reconnects.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum StreamSource {
+    Identity([u8; 32]),
+    Host(String),
+}
+
+impl StreamSource {
+    fn new(identity: Option<[u8; 32]>, addr: &NetAddr) -> Self {
+        match identity {
+            Some(identity) => Self::Identity(identity),
+            None => Self::Host(addr.host()),
+        }
+    }
+}
+
+/// Stream being received. Its data is hashed and written to a file as it
+/// arrives, the file is removed if the stream is dropped before the end.
+struct IncomingStream {
+    /// Host of the peer, the incoming streams are limited per host
+    host: String,
+    file: File,
+    path: PathBuf,
+    received: u64,
+    hasher: Blake2bState,
+    last_activity: Instant,
+}
+
+impl IncomingStream {
+    /// Write the data of a chunk the stream doesn't have yet.
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        self.file.flush().await?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for IncomingStream {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Transfers payloads too large for a single message, such as slab ranges
+/// or proving keys. A stream is read from an `AsyncRead` and sent as a
+/// series of chunks, at most `window` of them waiting for the receiver to
+/// acknowledge them, and ends with the hash of the whole stream.
+///
+/// The receiver writes the streams to files as the chunks arrive, and
+/// passes them to its subscribers once they're complete. Interrupted
+/// streams are kept for a while. Sending a stream again with the same id
+/// to the same peer, even over another channel, only sends the data the
+/// receiver is missing. Streams are kept apart per peer, so a peer can't
+/// write to the streams of another one, and a host can't take more than
+/// its share of the incoming streams.
+pub struct Streams {
+    services: ServiceBitflag,
+    settings: StreamSettings,
+    /// Messages per second peers let us send, chunks are paced under it
+    rate_limit: u32,
+    incoming: Mutex<HashMap<(StreamSource, StreamId), IncomingStream>>,
+    subscriber: SubscriberPtr<Arc<ReceivedStream>>,
+}
+
+impl Streams {
+    /// Create a stream layer, and register its protocol on the channels of
+    /// the given sessions where both nodes announced `services`. Must be
+    /// called before the network is started.
+    pub async fn new(
+        p2p: P2pPtr,
+        session_flags: SessionBitflag,
+        services: ServiceBitflag,
+        settings: StreamSettings,
+    ) -> StreamsPtr {
+        let self_ = Arc::new(Self {
+            services,
+            settings,
+            rate_limit: p2p.settings().channel_rate_limit,
+            incoming: Mutex::new(HashMap::new()),
+            subscriber: Subscriber::new(),
+        });
+
+        let streams = self_.clone();
+        p2p.protocol_registry()
+            .register_with_services(session_flags, services, move |channel, _p2p| {
+                let streams = streams.clone();
+                async move { ProtocolStream::new(channel, streams).await }
+            })
+            .await;
+
+        self_
+    }
+
+    /// Subscribe to the streams received from peers, once they're
+    /// complete and their hash checked.
+    pub async fn subscribe(&self) -> Subscription<Arc<ReceivedStream>> {
+        self.subscriber.clone().subscribe().await
+    }
+
+    /// Send the content of `reader` to the peer of a channel. Returns the
+    /// length of the stream once the peer checked it.
+    ///
+    /// To resume an interrupted stream, send it again with the same id and
+    /// a reader starting from the beginning. The data the peer already has
+    /// is read to compute the hash, but not sent.
+    pub async fn send<R: AsyncRead + Unpin>(
+        &self,
+        channel: &ChannelPtr,
+        stream_id: StreamId,
+        mut reader: R,
+    ) -> Result<u64> {
+        if !channel.supports(self.services).await {
+            return Err(Error::StreamRejected)
+        }
+
+        let ack_sub = channel.subscribe_msg::<StreamAckMessage>().await?;
+        let result = self.send_stream(channel, &ack_sub, stream_id, &mut reader).await;
+        ack_sub.unsubscribe().await;
+
+        match &result {
+            Ok(length) => {
+                debug!(target: "net",
+                    "Sent stream {} of {} bytes to [{}]",
+                    stream_id,
+                    length,
+                    channel.address()
+                );
+            }
+            Err(err) => {
+                debug!(target: "net",
+                    "Unable to send stream {} to [{}]: {}",
+                    stream_id,
+                    channel.address(),
+                    err
+                );
+            }
+        }
+        result
+    }
+
+    async fn send_stream<R: AsyncRead + Unpin>(
+        &self,
+        channel: &ChannelPtr,
+        ack_sub: &MessageSubscription<StreamAckMessage>,
+        stream_id: StreamId,
+        reader: &mut R,
+    ) -> Result<u64> {
+        channel.send(StreamOpenMessage { stream_id }).await?;
+        let resume_offset = self.wait_ack(ack_sub, stream_id, STREAM_OPENED).await?;
+
+        let mut hasher = stream_hasher();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+
+        // Skip the data the peer already has
+        let mut offset = 0;
+        while offset < resume_offset {
+            let len = CHUNK_SIZE.min((resume_offset - offset) as usize);
+            if read_chunk(reader, &mut buf[..len]).await? < len {
+                return Err(Error::Io(io::ErrorKind::UnexpectedEof))
+            }
+            hasher.update(&buf[..len]);
+            offset += len as u64;
+        }
+
+        // Chunks are paced to stay under the rate limit of the peer, which
+        // is assumed to use the same settings, leaving room for the other
+        // protocols.
+        let rate = if self.rate_limit == 0 { 0 } else { (self.rate_limit / 2).max(1) };
+        let window = self.settings.window.max(1);
+        let mut pacer = TokenBucket::new(rate, window as u32);
+
+        let window = (window * CHUNK_SIZE) as u64;
+        let mut acked = resume_offset;
+        loop {
+            let len = read_chunk(reader, &mut buf).await?;
+            if len == 0 {
+                break
+            }
+
+            while offset - acked >= window {
+                acked = acked.max(self.wait_ack(ack_sub, stream_id, STREAM_PROGRESS).await?);
+            }
+            while !pacer.take() {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+
+            hasher.update(&buf[..len]);
+            let chunk = StreamChunkMessage { stream_id, offset, data: buf[..len].to_vec() };
+            channel.send(chunk).await?;
+            offset += len as u64;
+        }
+
+        // Chunks and the end of the stream are handled by distinct tasks
+        // of the peer, so the chunks have to be stored first.
+        while acked < offset {
+            acked = acked.max(self.wait_ack(ack_sub, stream_id, STREAM_PROGRESS).await?);
+        }
+
+        let end = StreamEndMessage { stream_id, length: offset, hash: finalize(&hasher) };
+        channel.send(end).await?;
+        self.wait_ack(ack_sub, stream_id, STREAM_COMPLETE).await?;
+
+        Ok(offset)
+    }
+
+    /// Wait for an acknowledgement of a stream with the given status, and
+    /// return its offset. Acknowledgements left over from an earlier
+    /// attempt to send the stream are skipped.
+    async fn wait_ack(
+        &self,
+        ack_sub: &MessageSubscription<StreamAckMessage>,
+        stream_id: StreamId,
+        status: u8,
+    ) -> Result<u64> {
+        let ack_timeout = Duration::from_secs(self.settings.ack_timeout_seconds);
+        let ack = async {
+            loop {
+                let ack = ack_sub.receive().await?;
+                if ack.stream_id != stream_id {
+                    continue
+                }
+                if ack.status == STREAM_REJECTED {
+                    return Err(Error::StreamRejected)
+                }
+                if ack.status == status {
+                    return Ok::<_, Error>(ack.offset)
+                }
+            }
+        };
+        timeout(ack_timeout, ack).await.unwrap_or(Err(Error::RequestTimeout))
+    }
+
+    /// Start receiving a stream from a peer, or find the stream being
+    /// resumed. Returns the status and the length received so far.
+    async fn open(&self, source: &StreamSource, host: &str, stream_id: StreamId) -> (u8, u64) {
+        let mut incoming = self.incoming.lock().await;
+
+        let idle_timeout = Duration::from_secs(self.settings.idle_timeout_seconds);
+        incoming.retain(|_, stream| stream.last_activity.elapsed() < idle_timeout);
+
+        let key = (source.clone(), stream_id);
+        if let Some(stream) = incoming.get_mut(&key) {
+            stream.last_activity = Instant::now();
+            return (STREAM_OPENED, stream.received)
+        }
+
+        let from_host = incoming.values().filter(|stream| stream.host == host).count();
+        if incoming.len() >= self.settings.max_incoming ||
+            from_host >= self.settings.max_incoming_per_peer
+        {
+            return (STREAM_REJECTED, 0)
+        }
+
+        let file_name = format!("{:016x}.part", rand::thread_rng().gen::<u64>());
+        let path = self.settings.directory.join(file_name);
+        let file = match self.create_file(&path).await {
+            Ok(file) => file,
+            Err(err) => {
+                warn!(target: "net", "Unable to create the file of stream {}: {}", stream_id, err);
+                return (STREAM_REJECTED, 0)
+            }
+        };
+
+        let stream = IncomingStream {
+            host: host.to_string(),
+            file,
+            path,
+            received: 0,
+            hasher: stream_hasher(),
+            last_activity: Instant::now(),
+        };
+        incoming.insert(key, stream);
+        (STREAM_OPENED, 0)
+    }
+
+    async fn create_file(&self, path: &Path) -> Result<File> {
+        async_std::fs::create_dir_all(&self.settings.directory).await?;
+        Ok(File::create(path).await?)
+    }
+
+    /// Append a chunk to a stream of a peer. Chunks the stream already has,
+    /// sent again when it's resumed, are skipped.
+    async fn receive_chunk(&self, source: &StreamSource, chunk: &StreamChunkMessage) -> (u8, u64) {
+        let mut incoming = self.incoming.lock().await;
+        let key = (source.clone(), chunk.stream_id);
+        let stream = match incoming.get_mut(&key) {
+            Some(stream) => stream,
+            None => return (STREAM_REJECTED, 0),
+        };
+
+        // Chunks can't leave a gap in the stream, nor go over its limit
+        let received = stream.received;
+        let end = match chunk.offset.checked_add(chunk.data.len() as u64) {
+            Some(end) if chunk.offset <= received && end <= self.settings.max_stream_size => end,
+            _ => {
+                incoming.remove(&key);
+                return (STREAM_REJECTED, 0)
+            }
+        };
+
+        if end > received {
+            let new_data = &chunk.data[(received - chunk.offset) as usize..];
+            if let Err(err) = stream.write(new_data).await {
+                warn!(target: "net", "Unable to write stream {}: {}", chunk.stream_id, err);
+                incoming.remove(&key);
+                return (STREAM_REJECTED, 0)
+            }
+        }
+        stream.last_activity = Instant::now();
+        (STREAM_PROGRESS, stream.received)
+    }
+
+    /// Check a complete stream of a peer and pass it to the subscribers.
+    async fn receive_end(
+        &self,
+        source: &StreamSource,
+        peer: &NetAddr,
+        end: &StreamEndMessage,
+    ) -> (u8, u64) {
+        let mut stream = match self.incoming.lock().await.remove(&(source.clone(), end.stream_id)) {
+            Some(stream) => stream,
+            None => return (STREAM_REJECTED, 0),
+        };
+
+        if stream.received != end.length || finalize(&stream.hasher) != end.hash {
+            warn!(target: "net", "Stream {} from [{}] is corrupted", end.stream_id, peer);
+            return (STREAM_REJECTED, 0)
+        }
+
+        debug!(target: "net",
+            "Received stream {} of {} bytes from [{}]",
+            end.stream_id,
+            end.length,
+            peer
+        );
+        // The file now belongs to the received stream
+        let received = ReceivedStream {
+            id: end.stream_id,
+            peer: peer.clone(),
+            length: end.length,
+            path: mem::take(&mut stream.path),
+        };
+        self.subscriber.notify(Arc::new(received)).await;
+        (STREAM_COMPLETE, end.length)
+    }
+}
+
+/// Fill `buf` from a reader. Returns the length read, shorter than the
+/// buffer only at the end of the reader.
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        let read = reader.read(&mut buf[len..]).await?;
+        if read == 0 {
+            break
+        }
+        len += read;
+    }
+    Ok(len)
+}
+
+/// Receives the streams of a stream layer on a channel.
+pub struct ProtocolStream {
+    channel: ChannelPtr,
+    open_sub: MessageSubscription<StreamOpenMessage>,
+    chunk_sub: MessageSubscription<StreamChunkMessage>,
+    end_sub: MessageSubscription<StreamEndMessage>,
+    streams: StreamsPtr,
+    jobsman: ProtocolJobsManagerPtr,
+}
+
+impl ProtocolStream {
+    pub async fn new(channel: ChannelPtr, streams: StreamsPtr) -> ProtocolBasePtr {
+        let message_subsystem = channel.get_message_subsystem();
+        message_subsystem.add_dispatch::<StreamOpenMessage>().await;
+        message_subsystem.add_dispatch::<StreamChunkMessage>().await;
+        message_subsystem.add_dispatch::<StreamEndMessage>().await;
+        message_subsystem.add_dispatch::<StreamAckMessage>().await;
+
+        let open_sub = channel
+            .subscribe_msg::<StreamOpenMessage>()
+            .await
+            .expect("Missing StreamOpenMessage dispatcher!");
+        let chunk_sub = channel
+            .subscribe_msg::<StreamChunkMessage>()
+            .await
+            .expect("Missing StreamChunkMessage dispatcher!");
+        let end_sub = channel
+            .subscribe_msg::<StreamEndMessage>()
+            .await
+            .expect("Missing StreamEndMessage dispatcher!");
+
+        Arc::new(Self {
+            channel: channel.clone(),
+            open_sub,
+            chunk_sub,
+            end_sub,
+            streams,
+            jobsman: ProtocolJobsManager::new("ProtocolStream", channel),
+        })
+    }
+
+    async fn send_ack(&self, stream_id: StreamId, (status, offset): (u8, u64)) -> Result<()> {
+        self.channel.send(StreamAckMessage { stream_id, offset, status }).await
+    }
+
+    /// The identity of the peer is only known once the channel is encrypted.
+    async fn source(&self) -> StreamSource {
+        let identity = if self.channel.is_encrypted().await {
+            self.channel.peer_identity().await
+        } else {
+            None
+        };
+        StreamSource::new(identity, &self.channel.address())
+    }
+
+    async fn handle_receive_open(self: Arc<Self>) -> Result<()> {
+        debug!(target: "net", "ProtocolStream::handle_receive_open() [START]");
+        loop {
+            let open = self.open_sub.receive().await?;
+            let source = self.source().await;
+            let host = self.channel.address().host();
+            let ack = self.streams.open(&source, &host, open.stream_id).await;
+            self.send_ack(open.stream_id, ack).await?;
+        }
+    }
+
+    async fn handle_receive_chunk(self: Arc<Self>) -> Result<()> {
+        debug!(target: "net", "ProtocolStream::handle_receive_chunk() [START]");
+        loop {
+            let chunk = self.chunk_sub.receive().await?;
+            let ack = self.streams.receive_chunk(&self.source().await, &chunk).await;
+            self.send_ack(chunk.stream_id, ack).await?;
+        }
+    }
+
+    async fn handle_receive_end(self: Arc<Self>) -> Result<()> {
+        debug!(target: "net", "ProtocolStream::handle_receive_end() [START]");
+        loop {
+            let end = self.end_sub.receive().await?;
+            let source = self.source().await;
+            let ack = self.streams.receive_end(&source, &self.channel.address(), &end).await;
+            self.send_ack(end.stream_id, ack).await?;
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolBase for ProtocolStream {
+    /// Starts the stream protocol. Runs the handlers of the stream
+    /// messages on the protocol task manager.
+    async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+        debug!(target: "net", "ProtocolStream::start() [START]");
+        self.jobsman.clone().start(executor.clone());
+        self.jobsman.clone().spawn(self.clone().handle_receive_open(), executor.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_receive_chunk(), executor.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_receive_end(), executor).await;
+        debug!(target: "net", "ProtocolStream::start() [END]");
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "ProtocolStream"
+    }
+}
+
+/// Starts sending a stream, or resumes it.
+pub struct StreamOpenMessage {
+    pub stream_id: StreamId,
+}
+
+/// Part of a stream, starting at `offset`.
+pub struct StreamChunkMessage {
+    pub stream_id: StreamId,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// Ends a stream with its length and hash.
+pub struct StreamEndMessage {
+    pub stream_id: StreamId,
+    pub length: u64,
+    pub hash: [u8; 32],
+}
+
+/// Sent by the receiver of a stream in reply to the other stream messages.
+pub struct StreamAckMessage {
+    pub stream_id: StreamId,
+    pub offset: u64,
+    pub status: u8,
+}
+
+impl Message for StreamOpenMessage {
+    fn name() -> &'static str {
+        "streamopen"
+    }
+
+    fn max_size() -> usize {
+        8
+    }
+}
+
+impl Message for StreamChunkMessage {
+    fn name() -> &'static str {
+        "streamchunk"
+    }
+
+    fn max_size() -> usize {
+        CHUNK_SIZE + 32
+    }
+}
+
+impl Message for StreamEndMessage {
+    fn name() -> &'static str {
+        "streamend"
+    }
+
+    fn max_size() -> usize {
+        48
+    }
+}
+
+impl Message for StreamAckMessage {
+    fn name() -> &'static str {
+        "streamack"
+    }
+
+    fn max_size() -> usize {
+        17
+    }
+}
+
+impl Encodable for StreamOpenMessage {
+    fn encode<S: io::Write>(&self, s: S) -> Result<usize> {
+        self.stream_id.encode(s)
+    }
+}
+
+impl Decodable for StreamOpenMessage {
+    fn decode<D: io::Read>(d: D) -> Result<Self> {
+        Ok(Self { stream_id: Decodable::decode(d)? })
+    }
+}
+
+impl Encodable for StreamChunkMessage {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.stream_id.encode(&mut s)?;
+        len += self.offset.encode(&mut s)?;
+        len += self.data.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for StreamChunkMessage {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            stream_id: Decodable::decode(&mut d)?,
+            offset: Decodable::decode(&mut d)?,
+            data: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl Encodable for StreamEndMessage {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.stream_id.encode(&mut s)?;
+        len += self.length.encode(&mut s)?;
+        len += self.hash.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for StreamEndMessage {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            stream_id: Decodable::decode(&mut d)?,
+            length: Decodable::decode(&mut d)?,
+            hash: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl Encodable for StreamAckMessage {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.stream_id.encode(&mut s)?;
+        len += self.offset.encode(&mut s)?;
+        len += self.status.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for StreamAckMessage {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            stream_id: Decodable::decode(&mut d)?,
+            offset: Decodable::decode(&mut d)?,
+            status: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+    use async_std::sync::Mutex;
+    use futures::{io::Cursor, AsyncRead, AsyncReadExt};
+    use std::{
+        collections::HashMap,
+        fs, io,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tempfile::tempdir;
+
+    use super::{
+        new_stream_id, StreamChunkMessage, StreamSettings, StreamSource, Streams, CHUNK_SIZE,
+        STREAM_OPENED, STREAM_PROGRESS, STREAM_REJECTED,
+    };
+    use crate::{
+        net::{sim::SimNetwork, NetAddr, SERVICE_RELAY, SESSION_ALL, SESSION_SEED},
+        system::Subscriber,
+        Error,
+    };
+
+    /// Reader failing instead of ending once its data is read.
+    struct FailingReader {
+        data: Cursor<Vec<u8>>,
+    }
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            match Pin::new(&mut self.data).poll_read(cx, buf) {
+                Poll::Ready(Ok(0)) => Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
+                poll => poll,
+            }
+        }
+    }
+
+    #[test]
+    fn stream_source_test() {
+        let addr: NetAddr = "192.0.2.1:11001".parse().unwrap();
+        let reconnected: NetAddr = "192.0.2.1:52311".parse().unwrap();
+
+        // Reconnecting from another port doesn't change the source
+        assert_eq!(StreamSource::new(None, &addr), StreamSource::new(None, &reconnected));
+        assert_eq!(StreamSource::new(None, &addr), StreamSource::Host("192.0.2.1".into()));
+        assert_eq!(StreamSource::new(Some([1; 32]), &addr), StreamSource::Identity([1; 32]));
+    }
+
+    #[test]
+    fn stream_limits_test() {
+        smol::block_on(async {
+            let dir = tempdir().unwrap();
+            let settings = StreamSettings {
+                directory: dir.path().to_path_buf(),
+                max_incoming: 3,
+                max_incoming_per_peer: 2,
+                max_stream_size: 8,
+                ..Default::default()
+            };
+            let streams = Streams {
+                services: SERVICE_RELAY,
+                settings,
+                rate_limit: 0,
+                incoming: Mutex::new(HashMap::new()),
+                subscriber: Subscriber::new(),
+            };
+            let host = |i: u8| format!("192.0.2.{}", i);
+            let peer = |i: u8| StreamSource::Host(host(i));
+            let identity = |i: u8| StreamSource::Identity([i; 32]);
+
+            // Hosts only get their share of the incoming streams, whatever
+            // identities their peers use
+            assert_eq!(streams.open(&peer(1), &host(1), 1).await, (STREAM_OPENED, 0));
+            assert_eq!(streams.open(&identity(1), &host(1), 2).await, (STREAM_OPENED, 0));
+            assert_eq!(streams.open(&peer(1), &host(1), 3).await.0, STREAM_REJECTED);
+            assert_eq!(streams.open(&identity(2), &host(1), 3).await.0, STREAM_REJECTED);
+            assert_eq!(streams.open(&peer(2), &host(2), 1).await, (STREAM_OPENED, 0));
+            assert_eq!(streams.open(&peer(3), &host(3), 1).await.0, STREAM_REJECTED);
+
+            // Chunks are written to the file of the stream
+            let chunk = StreamChunkMessage { stream_id: 1, offset: 0, data: vec![1, 2, 3] };
+            assert_eq!(streams.receive_chunk(&peer(1), &chunk).await, (STREAM_PROGRESS, 3));
+            let path = streams.incoming.lock().await[&(peer(1), 1)].path.clone();
+            assert_eq!(fs::read(&path).unwrap(), vec![1, 2, 3]);
+
+            // Streams with the same id from distinct peers are kept apart
+            assert_eq!(streams.receive_chunk(&peer(3), &chunk).await.0, STREAM_REJECTED);
+            assert_eq!(streams.open(&peer(2), &host(2), 1).await, (STREAM_OPENED, 0));
+
+            // Chunks leaving a gap, going over the limit or overflowing the
+            // offset drop the stream and its file
+            let chunk = StreamChunkMessage { stream_id: 1, offset: 3, data: vec![0; 6] };
+            assert_eq!(streams.receive_chunk(&peer(1), &chunk).await.0, STREAM_REJECTED);
+            assert!(!path.exists());
+            assert_eq!(streams.open(&peer(1), &host(1), 1).await, (STREAM_OPENED, 0));
+            let chunk = StreamChunkMessage { stream_id: 2, offset: u64::MAX, data: vec![0] };
+            assert_eq!(streams.receive_chunk(&identity(1), &chunk).await.0, STREAM_REJECTED);
+            let chunk = StreamChunkMessage { stream_id: 1, offset: 1, data: vec![0] };
+            assert_eq!(streams.receive_chunk(&peer(1), &chunk).await.0, STREAM_REJECTED);
+        });
+    }
+
+    #[test]
+    fn stream_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(7);
+            network.set_latency(Duration::from_millis(10));
+            let nodes = network.create_nodes(2, |_, _| {}).await;
+
+            let dir = tempdir().unwrap();
+            let settings = StreamSettings {
+                directory: dir.path().to_path_buf(),
+                window: 2,
+                max_stream_size: 4 * CHUNK_SIZE as u64,
+                ..Default::default()
+            };
+            let mut streams = Vec::new();
+            for node in &nodes {
+                let node_streams =
+                    Streams::new(node.clone(), SESSION_ALL, SERVICE_RELAY, settings.clone()).await;
+                streams.push(node_streams);
+            }
+            let sub = streams[0].subscribe().await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            let node = nodes[1].clone();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        node.channels()
+                            .await
+                            .iter()
+                            .any(|channel| channel.session() != SESSION_SEED)
+                    })
+                    .await
+            );
+            let channel = node
+                .channels()
+                .await
+                .into_iter()
+                .find(|channel| channel.session() != SESSION_SEED)
+                .unwrap();
+
+            let data: Vec<u8> = (0..3 * CHUNK_SIZE + 1000).map(|i| i as u8).collect();
+            let stream_id = new_stream_id();
+
+            // The transfer is interrupted after two chunks
+            let reader = FailingReader { data: Cursor::new(data[..2 * CHUNK_SIZE + 10].to_vec()) };
+            let result = streams[1].send(&channel, stream_id, reader).await;
+            assert!(matches!(result, Err(Error::Io(io::ErrorKind::ConnectionReset))));
+            let receiver = streams[0].clone();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(5), || async {
+                        let incoming = receiver.incoming.lock().await;
+                        let mut streams = incoming.iter().filter(|((_, id), _)| *id == stream_id);
+                        streams.next().map(|(_, stream)| stream.received) ==
+                            Some(2 * CHUNK_SIZE as u64)
+                    })
+                    .await
+            );
+
+            // Then resumed, without sending the first chunks again
+            let sent = streams[1].send(&channel, stream_id, Cursor::new(data.clone())).await;
+            assert_eq!(sent.unwrap(), data.len() as u64);
+            let received =
+                async_std::future::timeout(Duration::from_secs(5), sub.receive()).await.unwrap();
+            assert_eq!(received.id, stream_id);
+            assert_eq!(received.length, data.len() as u64);
+            let mut received_data = vec![];
+            received.open().await.unwrap().read_to_end(&mut received_data).await.unwrap();
+            assert_eq!(received_data, data);
+
+            // The file is removed with the stream
+            let path = received.path.clone();
+            drop(received);
+            assert!(!path.exists());
+
+            let chunk_bytes = channel.stats().await.commands["streamchunk"].bytes_out;
+            assert!(chunk_bytes < data.len() as u64 + 1000);
+
+            // Streams over the limit of the receiver are rejected
+            let data = vec![0u8; 4 * CHUNK_SIZE + 1];
+            let result = streams[1].send(&channel, new_stream_id(), Cursor::new(data)).await;
+            assert!(matches!(result, Err(Error::StreamRejected)));
+        }));
+    }
+}
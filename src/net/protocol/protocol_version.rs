@@ -1,27 +1,55 @@
 use async_std::future::timeout;
 use log::*;
 use smol::Executor;
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
     error::{Error, Result},
-    net::{message, message_subscriber::MessageSubscription, ChannelPtr, SettingsPtr},
+    net::{
+        message, message_subscriber::MessageSubscription, nat::ExternalAddrsPtr, ChannelEncryption,
+        ChannelPtr, P2pPtr, PeerInfo, SettingsPtr, SESSION_INBOUND,
+    },
 };
 
 /// Implements the protocol version handshake sent out by nodes at the beginning
-/// of a connection.
+/// of a connection. Peers speaking an incompatible protocol version are
+/// rejected, and the services offered by both nodes are negotiated. The
+/// version messages also carry the keys used to encrypt the channel once
+/// both nodes sent their verack, and the veracks authenticate both
+/// version messages so they can't be tampered with. Each node also tells
+/// the other which address it sees it connecting from, which nodes behind
+/// a NAT use to discover their external address.
 pub struct ProtocolVersion {
     channel: ChannelPtr,
     version_sub: MessageSubscription<message::VersionMessage>,
     verack_sub: MessageSubscription<message::VerackMessage>,
     settings: SettingsPtr,
+    external: ExternalAddrsPtr,
 }
 
 impl ProtocolVersion {
     /// Create a new version protocol. Makes a version and version
     /// acknowledgement subscription, then adds them to a version protocol
-    /// instance.
-    pub async fn new(channel: ChannelPtr, settings: SettingsPtr) -> Arc<Self> {
+    /// instance. Sets up the channel encryption with the node identity.
+    pub async fn new(channel: ChannelPtr, p2p: P2pPtr) -> Arc<Self> {
+        let settings = p2p.settings();
+        let external = p2p.external();
+
+        let enabled = settings.channel_encryption || settings.require_encryption;
+        // Inbound peers connect from any port, so only the identities of
+        // the nodes we dial can be pinned
+        let expected_identity = if channel.session() == SESSION_INBOUND {
+            None
+        } else {
+            settings.pinned_identities.get(&channel.address()).copied()
+        };
+        channel
+            .set_encryption(ChannelEncryption::new(p2p.identity(), enabled, expected_identity))
+            .await;
+
         // Creates a version subscription.
         let version_sub = channel
             .clone()
@@ -36,7 +64,7 @@ impl ProtocolVersion {
             .await
             .expect("Missing verack dispatcher!");
 
-        Arc::new(Self { channel, version_sub, verack_sub, settings })
+        Arc::new(Self { channel, version_sub, verack_sub, settings, external })
     }
     /// Start version information exchange. Start the timer. Send version info
     /// and wait for version acknowledgement. Wait for version info and send
@@ -63,7 +91,12 @@ impl ProtocolVersion {
     async fn exchange_versions(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
         debug!(target: "net", "ProtocolVersion::exchange_versions() [START]");
 
-        let send = executor.spawn(self.clone().send_version());
+        // Our version has to go out before our verack, since the peer
+        // needs our keys before it can read anything we encrypt.
+        let version = self.version_message().await;
+        self.channel.clone().send(version).await?;
+
+        let send = executor.spawn(self.clone().recv_verack());
         let recv = executor.spawn(self.recv_version());
 
         send.await?;
@@ -72,16 +105,17 @@ impl ProtocolVersion {
         debug!(target: "net", "ProtocolVersion::exchange_versions() [END]");
         Ok(())
     }
-    /// Send version info and wait for version acknowledgement.
-    async fn send_version(self: Arc<Self>) -> Result<()> {
-        debug!(target: "net", "ProtocolVersion::send_version() [START]");
-        let version = message::VersionMessage {};
-        self.channel.clone().send(version).await?;
-
+    /// Wait for version acknowledgement of the sent version info.
+    async fn recv_verack(self: Arc<Self>) -> Result<()> {
+        debug!(target: "net", "ProtocolVersion::recv_verack() [START]");
         // Wait for version acknowledgement
-        let _verack_msg = self.verack_sub.receive().await?;
+        let verack_msg = self.verack_sub.receive().await?;
+        debug!(
+            target: "net",
+            "Peer {} uses protocol version {}", self.channel.address(), verack_msg.version
+        );
 
-        debug!(target: "net", "ProtocolVersion::send_version() [END]");
+        debug!(target: "net", "ProtocolVersion::recv_verack() [END]");
         Ok(())
     }
     /// Recieve version info, check the message is okay and send version
@@ -89,15 +123,136 @@ impl ProtocolVersion {
     async fn recv_version(self: Arc<Self>) -> Result<()> {
         debug!(target: "net", "ProtocolVersion::recv_version() [START]");
         // Rec
-        let _version_msg = self.version_sub.receive().await?;
+        let version_msg = self.version_sub.receive().await?;
 
         // Check the message is OK
+        if version_msg.version < message::MIN_PROTOCOL_VERSION {
+            warn!(
+                target: "net",
+                "Peer {} uses incompatible protocol version {}",
+                self.channel.address(),
+                version_msg.version
+            );
+            return Err(Error::IncompatibleProtocolVersion(version_msg.version))
+        }
+
+        if self.settings.require_encryption && !version_msg.encryption {
+            warn!(target: "net", "Peer {} does not support encryption", self.channel.address());
+            return Err(Error::ChannelEncryptionRequired)
+        }
+
+        debug!(
+            target: "net",
+            "Peer {} runs {} with services {:#x}",
+            self.channel.address(),
+            version_msg.user_agent,
+            version_msg.services
+        );
+
+        let peer_info = PeerInfo {
+            version: version_msg.version,
+            user_agent: version_msg.user_agent.clone(),
+            timestamp: version_msg.timestamp,
+            services: version_msg.services,
+            listen_addr: version_msg.listen_addr.clone(),
+        };
+        self.channel.set_peer_info(peer_info, self.settings.services).await;
+
+        // Inbound peers see the port we connected from, and could claim any
+        // address since they dialed us, so only outbound peers are asked
+        if self.channel.session() != SESSION_INBOUND {
+            if let Some(observed) = &version_msg.remote_addr {
+                self.external.report(&self.channel.address(), observed).await;
+            }
+        }
 
-        // Send version acknowledgement
-        let verack = message::VerackMessage {};
+        // Send version acknowledgement with the version both nodes speak
+        let version = std::cmp::min(message::PROTOCOL_VERSION, version_msg.version);
+        let transcript = self.channel.transcript_mac().await?;
+        let verack = message::VerackMessage { version, transcript };
         self.channel.clone().send(verack).await?;
 
         debug!(target: "net", "ProtocolVersion::recv_version() [END]");
         Ok(())
     }
+    /// Build our version message from the settings, our external addresses
+    /// and the channel keys.
+    async fn version_message(&self) -> message::VersionMessage {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut version = message::VersionMessage {
+            version: message::PROTOCOL_VERSION,
+            user_agent: self.settings.user_agent.clone(),
+            timestamp,
+            services: self.settings.services,
+            listen_addr: self.external.addrs().await.first().cloned(),
+            encryption: false,
+            identity: [0u8; 32],
+            ephem_public: [0u8; 32],
+            remote_addr: Some(self.channel.address()),
+        };
+        self.channel.announce_keys(&mut version).await;
+        version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+    use std::{sync::Arc, time::Duration};
+
+    use crate::net::{
+        sim::SimNetwork, NetAddr, NodeIdentity, PROTOCOL_VERSION, SERVICE_APP_START, SERVICE_RELAY,
+    };
+
+    #[test]
+    fn handshake_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(9);
+            network.set_latency(Duration::from_millis(10));
+            let mut secret = [0u8; 32];
+            secret[0] = 2;
+            let identity = NodeIdentity::from_secret_bytes(secret).unwrap().public_bytes();
+            let nodes = network
+                .create_nodes(3, |index, settings| {
+                    if index == 0 {
+                        return
+                    }
+                    settings.services |= SERVICE_APP_START;
+                    settings.user_agent = format!("node{}", index);
+                    if index == 1 {
+                        // Node 1 dials node 2 and expects its identity
+                        let node2_addr: NetAddr = SimNetwork::node_addr(2).into();
+                        settings.peers = vec![node2_addr.clone()];
+                        settings.pinned_identities.insert(node2_addr, identity);
+                    } else {
+                        settings.identity_secret = Some(secret);
+                    }
+                })
+                .await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            let node2_addr: NetAddr = SimNetwork::node_addr(2).into();
+            let find_channel = || async {
+                let channels = nodes[1].channels().await;
+                channels.into_iter().find(|channel| channel.address() == node2_addr)
+            };
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        find_channel().await.is_some()
+                    })
+                    .await
+            );
+
+            // Both nodes offer the application service, and the channel is
+            // encrypted with the pinned identity
+            let channel = find_channel().await.unwrap();
+            assert_eq!(channel.services().await, SERVICE_RELAY | SERVICE_APP_START);
+            assert_eq!(channel.protocol_version().await, Some(PROTOCOL_VERSION));
+            assert_eq!(channel.peer_info().await.unwrap().user_agent, "node2");
+            assert!(channel.is_encrypted().await);
+            assert_eq!(channel.peer_identity().await, Some(identity));
+        }));
+    }
 }
@@ -0,0 +1,72 @@
+use std::time::Instant;
+
+/// Token bucket limiting the rate of messages received on a channel.
+/// The bucket holds up to `burst` tokens and is refilled with `rate`
+/// tokens per second. Every message takes a token.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A rate of zero disables the limit.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let capacity = burst.max(1) as f64;
+        Self { rate: rate as f64, capacity, tokens: capacity, last_refill: Instant::now() }
+    }
+
+    /// Take a token. Returns false if the bucket is empty.
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true
+        }
+
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return false
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn token_bucket_test() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, 5);
+
+        // The burst can be used at once
+        for _ in 0..5 {
+            assert!(bucket.take_at(start));
+        }
+        assert!(!bucket.take_at(start));
+
+        // 10 tokens per second, but no more than the burst
+        assert!(bucket.take_at(start + Duration::from_millis(100)));
+        assert!(!bucket.take_at(start + Duration::from_millis(100)));
+        for _ in 0..5 {
+            assert!(bucket.take_at(start + Duration::from_secs(10)));
+        }
+        assert!(!bucket.take_at(start + Duration::from_secs(10)));
+
+        let mut unlimited = TokenBucket::new(0, 0);
+        for _ in 0..1000 {
+            assert!(unlimited.take_at(start));
+        }
+    }
+}
@@ -14,11 +14,16 @@ use crate::{
     error::{Error, Result},
     net::{
         session::{Session, SessionBitflag, SESSION_INBOUND},
-        Acceptor, AcceptorPtr, ChannelPtr, P2p,
+        Acceptor, AcceptorPtr, ChannelPtr, NetAddr, NetworkGroup, P2p, Settings,
     },
     system::{StoppableTask, StoppableTaskPtr},
 };
 
+/// Number of peers with the lowest ping that are never evicted.
+const EVICTION_PROTECT_PING: usize = 4;
+/// Number of longest connected peers that are never evicted.
+const EVICTION_PROTECT_AGE: usize = 4;
+
 struct InboundInfo {
     channel: ChannelPtr,
 }
@@ -29,74 +34,82 @@ impl InboundInfo {
     }
 }
 
-/// Defines inbound connections session.
+/// Defines inbound connections session. Runs an acceptor for each of the
+/// inbound addresses in the settings. The inbound limits apply to the
+/// channels of all the acceptors.
 pub struct InboundSession {
     p2p: Weak<P2p>,
-    acceptor: AcceptorPtr,
-    accept_task: StoppableTaskPtr,
-    connect_infos: Mutex<HashMap<SocketAddr, InboundInfo>>,
+    acceptors: Mutex<Vec<(AcceptorPtr, StoppableTaskPtr)>>,
+    connect_infos: Mutex<HashMap<NetAddr, InboundInfo>>,
 }
 
 impl InboundSession {
     /// Create a new inbound session.
     pub fn new(p2p: Weak<P2p>) -> Arc<Self> {
-        let acceptor = Acceptor::new();
-
         Arc::new(Self {
             p2p,
-            acceptor,
-            accept_task: StoppableTask::new(),
+            acceptors: Mutex::new(Vec::new()),
             connect_infos: Mutex::new(HashMap::new()),
         })
     }
 
-    /// Starts the inbound session. Begins by accepting connections and fails if
-    /// the address is not configured. Then runs the channel subscription
-    /// loop.
-    pub fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
-        match self.p2p().settings().inbound {
-            Some(accept_addr) => {
-                self.clone().start_accept_session(accept_addr, executor.clone())?;
-            }
-            None => {
-                info!(target: "net", "Not configured for accepting incoming connections.");
-                return Ok(())
-            }
+    /// Starts the inbound session. Begins by accepting connections on each
+    /// inbound address and fails if one of them can't be listened on. Then
+    /// runs a channel subscription loop for each acceptor.
+    pub async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+        let accept_addrs = self.p2p().settings().inbound.clone();
+        if accept_addrs.is_empty() {
+            info!(target: "net", "Not configured for accepting incoming connections.");
+            return Ok(())
         }
 
-        self.accept_task.clone().start(
-            self.clone().channel_sub_loop(executor.clone()),
-            // Ignore stop handler
-            |_| async {},
-            Error::ServiceStopped,
-            executor,
-        );
+        for accept_addr in accept_addrs {
+            let acceptor = self.clone().start_accept_session(accept_addr, executor.clone()).await?;
+
+            let accept_task = StoppableTask::new();
+            accept_task.clone().start(
+                self.clone().channel_sub_loop(acceptor.clone(), executor.clone()),
+                // Ignore stop handler
+                |_| async {},
+                Error::ServiceStopped,
+                executor.clone(),
+            );
+
+            self.acceptors.lock().await.push((acceptor, accept_task));
+        }
 
         Ok(())
     }
     /// Stops the inbound session.
     pub async fn stop(&self) {
-        self.acceptor.stop().await;
-        self.accept_task.stop().await;
+        for (acceptor, accept_task) in &*self.acceptors.lock().await {
+            acceptor.stop().await;
+            accept_task.stop().await;
+        }
     }
-    /// Start accepting connections for inbound session.
-    fn start_accept_session(
+    /// Start accepting connections on an address for inbound session.
+    async fn start_accept_session(
         self: Arc<Self>,
         accept_addr: SocketAddr,
         executor: Arc<Executor<'_>>,
-    ) -> Result<()> {
+    ) -> Result<AcceptorPtr> {
         info!(target: "net", "Starting inbound session on {}", accept_addr);
-        let result = self.acceptor.clone().start(accept_addr, executor);
-        if let Err(err) = result.clone() {
+        let acceptor = Acceptor::new(self.p2p().settings());
+        if let Err(err) = acceptor.clone().start(accept_addr, executor).await {
             error!(target: "net", "Error starting listener: {}", err);
+            return Err(err)
         }
-        result
+        Ok(acceptor)
     }
 
-    /// Wait for all new channels created by the acceptor and call
+    /// Wait for all new channels created by an acceptor and call
     /// setup_channel() on them.
-    async fn channel_sub_loop(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
-        let channel_sub = self.acceptor.clone().subscribe().await;
+    async fn channel_sub_loop(
+        self: Arc<Self>,
+        acceptor: AcceptorPtr,
+        executor: Arc<Executor<'_>>,
+    ) -> Result<()> {
+        let channel_sub = acceptor.subscribe().await;
         loop {
             let channel = channel_sub.receive().await?;
             // Spawn a detached task to process the channel
@@ -115,24 +128,165 @@ impl InboundSession {
     ) -> Result<()> {
         info!(target: "net", "Connected inbound [{}]", channel.address());
 
-        self.clone().register_channel(channel.clone(), executor.clone()).await?;
+        if !self.admit_channel(&channel).await {
+            return Ok(())
+        }
+
+        if let Err(err) = self.clone().register_channel(channel.clone(), executor.clone()).await {
+            self.connect_infos.lock().await.remove(&channel.address());
+            return Err(err)
+        }
 
         self.manage_channel_for_get_info(channel).await;
 
         Ok(())
     }
 
-    async fn manage_channel_for_get_info(&self, channel: ChannelPtr) {
-        let key = channel.address();
-        self.connect_infos.lock().await.insert(key, InboundInfo { channel: channel.clone() });
+    /// Check the inbound limits before the handshake, and track the channel
+    /// if it's admitted. When the session is full, a peer is evicted to
+    /// make room for the new one, unless all the peers are protected.
+    async fn admit_channel(&self, channel: &ChannelPtr) -> bool {
+        let settings = self.p2p().settings();
+        let addr = channel.address();
 
+        // The channel stats are read without holding the lock, so
+        // channels closing meanwhile don't wait for them
+        let connected: Vec<ChannelPtr> = {
+            let mut infos = self.connect_infos.lock().await;
+            if let Err(reason) = check_address_limits(infos.keys(), &addr, &settings) {
+                info!(target: "net", "Refusing inbound peer {}: {}", addr, reason);
+                return false
+            }
+
+            if !is_full(infos.len(), &settings) {
+                infos.insert(addr, InboundInfo { channel: channel.clone() });
+                return true
+            }
+            infos.values().map(|info| info.channel.clone()).collect()
+        };
+
+        let mut candidates = Vec::new();
+        for channel in connected {
+            let stats = channel.stats().await;
+            candidates.push(EvictionCandidate {
+                addr: channel.address(),
+                connected_at: stats.connected_at,
+                ping_rtt_ms: stats.ping_rtt_ms,
+            });
+        }
+
+        let evicted = {
+            let mut infos = self.connect_infos.lock().await;
+            // Other peers may have connected or left in the meantime
+            if let Err(reason) = check_address_limits(infos.keys(), &addr, &settings) {
+                info!(target: "net", "Refusing inbound peer {}: {}", addr, reason);
+                return false
+            }
+
+            let mut evicted = None;
+            if is_full(infos.len(), &settings) {
+                candidates.retain(|candidate| infos.contains_key(&candidate.addr));
+                match select_eviction(candidates) {
+                    Some(victim) => evicted = infos.remove(&victim),
+                    None => {
+                        info!(target: "net", "Inbound session full, refusing {}", addr);
+                        return false
+                    }
+                }
+            }
+
+            infos.insert(addr, InboundInfo { channel: channel.clone() });
+            evicted
+        };
+
+        if let Some(info) = evicted {
+            info!(target: "net", "Evicting inbound peer {}", info.channel.address());
+            info.channel.stop().await;
+        }
+        true
+    }
+
+    async fn manage_channel_for_get_info(&self, channel: ChannelPtr) {
         let stop_sub = channel.subscribe_stop().await;
         stop_sub.receive().await;
 
-        self.connect_infos.lock().await.remove(&key);
+        self.connect_infos.lock().await.remove(&channel.address());
     }
 }
 
+/// Check the per-IP and per-subnet limits for a new inbound peer.
+fn check_address_limits<'a>(
+    connected: impl Iterator<Item = &'a NetAddr>,
+    addr: &NetAddr,
+    settings: &Settings,
+) -> std::result::Result<(), &'static str> {
+    let ip = match addr.ip() {
+        Some(ip) => ip,
+        None => return Ok(()),
+    };
+    // All the peers of an onion service connect from the loopback
+    if ip.is_loopback() {
+        return Ok(())
+    }
+
+    let subnet = addr.subnet();
+    let (mut same_ip, mut same_subnet) = (0, 0);
+    for connected in connected {
+        if connected.ip() == Some(ip) {
+            same_ip += 1;
+        }
+        if connected.subnet() == subnet {
+            same_subnet += 1;
+        }
+    }
+
+    if settings.inbound_per_ip > 0 && same_ip >= settings.inbound_per_ip {
+        return Err("too many connections from this IP")
+    }
+    if settings.inbound_per_subnet > 0 && same_subnet >= settings.inbound_per_subnet {
+        return Err("too many connections from this subnet")
+    }
+    Ok(())
+}
+
+/// Whether the session holds as many channels as the settings allow.
+fn is_full(connected: usize, settings: &Settings) -> bool {
+    settings.inbound_connections > 0 && connected >= settings.inbound_connections as usize
+}
+
+/// Inbound peer considered for eviction.
+struct EvictionCandidate {
+    addr: NetAddr,
+    connected_at: u64,
+    ping_rtt_ms: Option<u64>,
+}
+
+/// Select the inbound peer to evict when the session is full. The peers
+/// with the lowest ping and the longest connected ones are protected.
+/// The youngest peer of the network group with the most remaining peers
+/// is evicted, so a single network can't crowd out diverse peers. Groups
+/// are the ones outbound peers are spread across, as an attacker can
+/// easily get addresses in many subnets of one network. Returns None if
+/// all the peers are protected.
+fn select_eviction(mut candidates: Vec<EvictionCandidate>) -> Option<NetAddr> {
+    candidates.sort_by_key(|candidate| candidate.ping_rtt_ms.unwrap_or(u64::MAX));
+    candidates.drain(..EVICTION_PROTECT_PING.min(candidates.len()));
+
+    candidates.sort_by_key(|candidate| candidate.connected_at);
+    candidates.drain(..EVICTION_PROTECT_AGE.min(candidates.len()));
+
+    let mut networks: HashMap<Option<NetworkGroup>, Vec<EvictionCandidate>> = HashMap::new();
+    for candidate in candidates {
+        networks.entry(candidate.addr.network_group()).or_default().push(candidate);
+    }
+
+    // Ties are broken in favour of the network with the youngest peer
+    let network = networks.into_values().max_by_key(|network| {
+        (network.len(), network.iter().map(|candidate| candidate.connected_at).max())
+    })?;
+    network.into_iter().max_by_key(|candidate| candidate.connected_at).map(|c| c.addr)
+}
+
 #[async_trait]
 impl Session for InboundSession {
     async fn get_info(&self) -> serde_json::Value {
@@ -154,3 +308,46 @@ impl Session for InboundSession {
         SESSION_INBOUND
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(addr: &str, connected_at: u64, ping_rtt_ms: Option<u64>) -> EvictionCandidate {
+        EvictionCandidate { addr: addr.parse().unwrap(), connected_at, ping_rtt_ms }
+    }
+
+    #[test]
+    fn address_limits_test() {
+        let settings = Settings { inbound_per_ip: 2, inbound_per_subnet: 3, ..Default::default() };
+        let connected: Vec<NetAddr> = ["10.0.0.1:1", "10.0.0.1:2", "10.0.0.2:1", "10.0.1.1:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        let check =
+            |addr: &str| check_address_limits(connected.iter(), &addr.parse().unwrap(), &settings);
+        assert!(check("10.0.0.1:3").is_err());
+        assert!(check("10.0.0.3:1").is_err());
+        assert!(check("10.0.1.2:1").is_ok());
+        assert!(check("127.0.0.1:1").is_ok());
+    }
+
+    #[test]
+    fn eviction_test() {
+        // All the peers are protected
+        let candidates =
+            (0..8).map(|i| candidate(&format!("10.0.{}.1:1", i), i, Some(i))).collect();
+        assert_eq!(select_eviction(candidates), None);
+
+        let mut candidates: Vec<_> =
+            (0..8).map(|i| candidate(&format!("10.0.{}.1:1", i), i, Some(i))).collect();
+        // Newer peers from a single network, one of them with the lowest
+        // ping, spread across its subnets
+        candidates.push(candidate("10.1.0.1:1", 100, Some(0)));
+        candidates.push(candidate("10.1.1.1:1", 101, None));
+        candidates.push(candidate("10.1.2.1:1", 102, None));
+        candidates.push(candidate("10.2.0.1:1", 103, None));
+        assert_eq!(select_eviction(candidates), Some("10.1.2.1:1".parse().unwrap()));
+    }
+}
@@ -1,10 +1,7 @@
 use async_std::sync::Mutex;
 use async_trait::async_trait;
 use serde_json::json;
-use std::{
-    net::SocketAddr,
-    sync::{Arc, Weak},
-};
+use std::sync::{Arc, Weak};
 
 use async_executor::Executor;
 use log::*;
@@ -13,7 +10,7 @@ use crate::{
     error::{Error, Result},
     net::{
         session::{Session, SessionBitflag, SESSION_MANUAL},
-        Connector, P2p,
+        Connector, NetAddr, P2p,
     },
     system::{StoppableTask, StoppableTaskPtr},
     util::sleep,
@@ -39,11 +36,11 @@ impl ManualSession {
         }
     }
 
-    pub async fn connect(self: Arc<Self>, addr: &SocketAddr, executor: Arc<Executor<'_>>) {
+    pub async fn connect(self: Arc<Self>, addr: &NetAddr, executor: Arc<Executor<'_>>) {
         let task = StoppableTask::new();
 
         task.clone().start(
-            self.clone().channel_connect_loop(*addr, executor.clone()),
+            self.clone().channel_connect_loop(addr.clone(), executor.clone()),
             // Ignore stop handler
             |_| async {},
             Error::ServiceStopped,
@@ -55,7 +52,7 @@ impl ManualSession {
 
     pub async fn channel_connect_loop(
         self: Arc<Self>,
-        addr: SocketAddr,
+        addr: NetAddr,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         let connector = Connector::new(self.p2p().settings());
@@ -72,11 +69,11 @@ impl ManualSession {
                 break
             }
 
-            self.p2p().add_pending(addr).await;
+            self.p2p().add_pending(addr.clone()).await;
 
             info!(target: "net", "Connecting to manual outbound [{}]", addr);
 
-            match connector.connect(addr).await {
+            match connector.connect(addr.clone()).await {
                 Ok(channel) => {
                     // Blacklist goes here
 
@@ -84,7 +81,20 @@ impl ManualSession {
 
                     let stop_sub = channel.subscribe_stop().await;
 
-                    self.clone().register_channel(channel.clone(), executor.clone()).await?;
+                    // A failed handshake counts as a failed attempt
+                    if let Err(err) =
+                        self.clone().register_channel(channel.clone(), executor.clone()).await
+                    {
+                        info!(
+                            target: "net",
+                            "Handshake with manual outbound [{}] failed: {}", addr, err
+                        );
+                        self.p2p().hosts().mark_failure(&addr).await;
+                        self.p2p().remove_pending(&addr).await;
+                        sleep(settings.connect_timeout_seconds).await;
+                        continue
+                    }
+                    self.p2p().hosts().mark_success(&addr).await;
 
                     // Channel is now connected but not yet setup
 
@@ -98,6 +108,7 @@ impl ManualSession {
                 }
                 Err(err) => {
                     info!(target: "net", "Unable to connect to manual outbound [{}]: {}", addr, err);
+                    self.p2p().hosts().mark_failure(&addr).await;
 
                     sleep(settings.connect_timeout_seconds).await;
                 }
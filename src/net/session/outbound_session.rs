@@ -1,24 +1,33 @@
 use async_std::{sync::Mutex, task::yield_now};
 use std::{
+    collections::HashMap,
     fmt,
-    net::SocketAddr,
     sync::{Arc, Weak},
 };
 
 use async_executor::Executor;
 use async_trait::async_trait;
-use log::{error, info};
+use log::{debug, error, info};
+use rand::seq::SliceRandom;
 use serde_json::json;
 
 use crate::{
     error::{Error, Result},
     net::{
+        protocol::ProtocolVersion,
         session::{Session, SessionBitflag, SESSION_OUTBOUND},
-        ChannelPtr, Connector, P2p,
+        ChannelPtr, Connector, NetAddr, NetworkGroup, P2p,
     },
     system::{StoppableTask, StoppableTaskPtr},
+    util::sleep,
 };
 
+/// Number of best scored hosts an outbound address is picked from.
+const OUTBOUND_CANDIDATES: usize = 8;
+
+/// Interval in seconds between two feeler connections.
+const FEELER_INTERVAL: u32 = 120;
+
 #[derive(Clone)]
 enum OutboundState {
     Open,
@@ -42,14 +51,14 @@ impl fmt::Display for OutboundState {
 
 #[derive(Clone)]
 struct OutboundInfo {
-    addr: Option<SocketAddr>,
+    addr: Option<NetAddr>,
     channel: Option<ChannelPtr>,
     state: OutboundState,
 }
 
 impl OutboundInfo {
     async fn get_info(&self) -> serde_json::Value {
-        let addr = match self.addr {
+        let addr = match &self.addr {
             Some(addr) => serde_json::Value::String(addr.to_string()),
             None => serde_json::Value::Null,
         };
@@ -74,10 +83,18 @@ impl Default for OutboundInfo {
 }
 
 /// Defines outbound connections session.
+///
+/// Outbound peers are spread across network groups, so a single network
+/// can't eclipse the node. The anchors, the peers we were connected to
+/// before a restart, are reconnected first. A feeler connection
+/// periodically checks an address we never connected to, so working
+/// addresses are promoted to the tried hosts.
 pub struct OutboundSession {
     p2p: Weak<P2p>,
     connect_slots: Mutex<Vec<StoppableTaskPtr>>,
     slot_info: Mutex<Vec<OutboundInfo>>,
+    anchors: Mutex<Vec<NetAddr>>,
+    feeler_task: StoppableTaskPtr,
 }
 
 impl OutboundSession {
@@ -87,6 +104,8 @@ impl OutboundSession {
             p2p,
             connect_slots: Mutex::new(Vec::new()),
             slot_info: Mutex::new(Vec::new()),
+            anchors: Mutex::new(Vec::new()),
+            feeler_task: StoppableTask::new(),
         })
     }
 
@@ -94,6 +113,15 @@ impl OutboundSession {
     pub async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
         let slots_count = self.p2p().settings().outbound_connections;
         info!(target: "net", "Starting {} outbound connection slots.", slots_count);
+        if slots_count == 0 {
+            return Ok(())
+        }
+
+        // Anchors are popped from the back
+        let mut anchors = self.p2p().hosts().anchors().await;
+        anchors.reverse();
+        *self.anchors.lock().await = anchors;
+
         // Activate mutex lock on connection slots.
         let mut connect_slots = self.connect_slots.lock().await;
 
@@ -113,6 +141,14 @@ impl OutboundSession {
             connect_slots.push(task);
         }
 
+        self.feeler_task.clone().start(
+            self.clone().feeler_loop(executor.clone()),
+            // Ignore stop handler
+            |_| async {},
+            Error::ServiceStopped,
+            executor,
+        );
+
         Ok(())
     }
 
@@ -123,6 +159,18 @@ impl OutboundSession {
         for slot in connect_slots {
             slot.stop().await;
         }
+        self.feeler_task.stop().await;
+    }
+
+    /// Addresses of the connected outbound peers, saved as anchors.
+    pub async fn connected_addrs(&self) -> Vec<NetAddr> {
+        self.slot_info
+            .lock()
+            .await
+            .iter()
+            .filter(|info| matches!(info.state, OutboundState::Connected))
+            .filter_map(|info| info.addr.clone())
+            .collect()
     }
 
     /// Start making outbound connections. Creates a connector object, then
@@ -136,25 +184,29 @@ impl OutboundSession {
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         let connector = Connector::new(self.p2p().settings());
+        let hosts = self.p2p().hosts();
 
         loop {
             let addr = self.load_address(slot_number).await?;
             info!(target: "net", "#{} connecting to outbound [{}]", slot_number, addr);
-            {
-                let info = &mut self.slot_info.lock().await[slot_number as usize];
-                info.addr = Some(addr);
-                info.state = OutboundState::Pending;
-            }
 
-            match connector.connect(addr).await {
+            match connector.connect(addr.clone()).await {
                 Ok(channel) => {
-                    // Blacklist goes here
-
                     info!(target: "net", "#{} connected to outbound [{}]", slot_number, addr);
 
                     let stop_sub = channel.subscribe_stop().await;
 
-                    self.clone().register_channel(channel.clone(), executor.clone()).await?;
+                    // A failed handshake frees the slot for another host
+                    if let Err(err) =
+                        self.clone().register_channel(channel.clone(), executor.clone()).await
+                    {
+                        info!(target: "net", "Handshake with outbound [{}] failed: {}", addr, err);
+                        hosts.mark_failure(&addr).await;
+                        self.p2p().remove_pending(&addr).await;
+                        self.reset_slot(slot_number).await;
+                        continue
+                    }
+                    hosts.mark_success(&addr).await;
 
                     // Channel is now connected but not yet setup
 
@@ -168,67 +220,246 @@ impl OutboundSession {
 
                     // Wait for channel to close
                     stop_sub.receive().await;
+                    self.reset_slot(slot_number).await;
                 }
                 Err(err) => {
                     info!(target: "net", "Unable to connect to outbound [{}]: {}", addr, err);
-                    {
-                        let info = &mut self.slot_info.lock().await[slot_number as usize];
-                        info.addr = None;
-                        info.channel = None;
-                        info.state = OutboundState::Open;
-                    }
+                    hosts.mark_failure(&addr).await;
+                    self.p2p().remove_pending(&addr).await;
+                    self.reset_slot(slot_number).await;
                 }
             }
         }
     }
 
+    /// Mark a slot as open for a new connection.
+    async fn reset_slot(&self, slot_number: u32) {
+        let info = &mut self.slot_info.lock().await[slot_number as usize];
+        info.addr = None;
+        info.channel = None;
+        info.state = OutboundState::Open;
+    }
+
     /// Loops through host addresses to find a outbound address that we can
     /// connect to. Checks whether address is valid by making sure it isn't
     /// our own inbound address, then checks whether it is already connected
-    /// (exists) or connecting (pending). Keeps looping until address is
-    /// found that passes all checks.
-    async fn load_address(&self, slot_number: u32) -> Result<SocketAddr> {
+    /// (exists) or connecting (pending), and that its network group has room
+    /// left among the slots. Hosts we can't dial, eg. onion addresses without
+    /// a proxy, are skipped. Anchors are tried first, then one of the best
+    /// scored hosts passing all checks is picked at random. Hostnames over
+    /// their share of the slots are only picked when no other host is left,
+    /// and we wait for new hosts if there is none. The address is claimed by
+    /// the slot.
+    async fn load_address(&self, slot_number: u32) -> Result<NetAddr> {
         let p2p = self.p2p();
         let hosts = p2p.hosts();
-        let self_inbound_addr = p2p.settings().external_addr;
+        let self_inbound_addrs = p2p.external_addrs().await;
+        let connector = Connector::new(p2p.settings());
 
         loop {
             yield_now().await;
 
-            let addr = hosts.load_single().await;
+            loop {
+                let anchor = self.anchors.lock().await.pop();
+                let addr = match anchor {
+                    Some(addr) => addr,
+                    None => break,
+                };
+                if Self::is_self_inbound(&addr, &self_inbound_addrs) ||
+                    hosts.is_banned(&addr).await ||
+                    p2p.exists(&addr).await
+                {
+                    continue
+                }
 
-            if addr.is_none() {
+                if self.claim_address(slot_number, &addr, false).await {
+                    info!(target: "net", "#{} reconnecting to anchor [{}]", slot_number, addr);
+                    return Ok(addr)
+                }
+            }
+
+            let ranked = hosts.load_ranked().await;
+
+            if ranked.is_empty() {
                 error!(target: "net", "Hosts address pool is empty. Closing connect slot #{}", slot_number);
                 return Err(Error::ServiceStopped)
             }
-            let addr = addr.unwrap();
 
-            if Self::is_self_inbound(&addr, &self_inbound_addr) {
-                continue
+            let groups = self.outbound_groups(slot_number).await;
+            let slots = p2p.settings().outbound_connections as usize;
+            let mut candidates = Vec::new();
+            let mut crowded = Vec::new();
+            for addr in ranked {
+                if Self::is_self_inbound(&addr, &self_inbound_addrs) || !connector.can_dial(&addr) {
+                    continue
+                }
+
+                if p2p.exists(&addr).await || p2p.is_pending(&addr).await {
+                    continue
+                }
+
+                if group_has_room(&addr, &groups, slots, false) {
+                    candidates.push(addr);
+                    if candidates.len() == OUTBOUND_CANDIDATES {
+                        break
+                    }
+                } else if group_has_room(&addr, &groups, slots, true) &&
+                    crowded.len() < OUTBOUND_CANDIDATES
+                {
+                    crowded.push(addr);
+                }
             }
 
-            if p2p.exists(&addr).await {
-                continue
+            // Eg. nodes only reaching peers over Tor use the crowded groups
+            let is_crowded = candidates.is_empty();
+            if is_crowded {
+                candidates = crowded;
             }
+            let addr = match candidates.choose(&mut rand::thread_rng()) {
+                Some(addr) => addr.clone(),
+                None => {
+                    // All known hosts are in use
+                    sleep(1).await;
+                    continue
+                }
+            };
+
+            if self.claim_address(slot_number, &addr, is_crowded).await {
+                return Ok(addr)
+            }
+        }
+    }
+
+    /// Number of addresses of each network group used by the other slots.
+    async fn outbound_groups(&self, slot_number: u32) -> HashMap<NetworkGroup, usize> {
+        Self::slot_groups(&self.slot_info.lock().await, slot_number)
+    }
 
-            // Obtain a lock on this address to prevent duplicate connections
-            if !p2p.add_pending(addr).await {
+    fn slot_groups(slot_info: &[OutboundInfo], slot_number: u32) -> HashMap<NetworkGroup, usize> {
+        let mut groups = HashMap::new();
+        for (i, info) in slot_info.iter().enumerate() {
+            if i == slot_number as usize {
                 continue
             }
+            if let Some(group) = info.addr.as_ref().and_then(|addr| addr.network_group()) {
+                *groups.entry(group).or_default() += 1;
+            }
+        }
+        groups
+    }
+
+    /// Claim an address for a slot. Fails if the address is pending or if
+    /// other slots filled its network group in the meantime.
+    async fn claim_address(&self, slot_number: u32, addr: &NetAddr, crowded: bool) -> bool {
+        let mut slot_info = self.slot_info.lock().await;
+        let groups = Self::slot_groups(&slot_info, slot_number);
+        if !group_has_room(addr, &groups, slot_info.len(), crowded) {
+            return false
+        }
 
-            return Ok(addr)
+        // Obtain a lock on this address to prevent duplicate connections
+        if !self.p2p().add_pending(addr.clone()).await {
+            return false
         }
+
+        let info = &mut slot_info[slot_number as usize];
+        info.addr = Some(addr.clone());
+        info.state = OutboundState::Pending;
+        true
     }
 
-    /// Checks whether an address is our own inbound address to avoid connecting
-    /// to ourselves.
-    fn is_self_inbound(addr: &SocketAddr, inbound_addr: &Option<SocketAddr>) -> bool {
-        match inbound_addr {
-            Some(inbound_addr) => inbound_addr == addr,
-            // No inbound listening address configured
-            None => false,
+    /// Periodically connect to an address we never connected to. Hosts
+    /// completing the handshake are promoted to the tried hosts, then the
+    /// channel is closed.
+    async fn feeler_loop(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+        let connector = Connector::new(self.p2p().settings());
+
+        loop {
+            sleep(FEELER_INTERVAL).await;
+
+            let p2p = self.p2p();
+            let hosts = p2p.hosts();
+            let self_inbound_addrs = p2p.external_addrs().await;
+
+            let mut untried = hosts.load_untried().await;
+            untried.shuffle(&mut rand::thread_rng());
+            let mut feeler = None;
+            for addr in untried {
+                if Self::is_self_inbound(&addr, &self_inbound_addrs) ||
+                    !connector.can_dial(&addr) ||
+                    p2p.exists(&addr).await
+                {
+                    continue
+                }
+                if p2p.add_pending(addr.clone()).await {
+                    feeler = Some(addr);
+                    break
+                }
+            }
+            let addr = match feeler {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            debug!(target: "net", "Feeler connecting to [{}]", addr);
+            match self.feel(&connector, addr.clone(), executor.clone()).await {
+                Ok(()) => {
+                    debug!(target: "net", "Feeler connection to [{}] succeeded", addr);
+                    hosts.mark_success(&addr).await;
+                }
+                Err(err) => {
+                    debug!(target: "net", "Feeler connection to [{}] failed: {}", addr, err);
+                    hosts.mark_failure(&addr).await;
+                }
+            }
+            p2p.remove_pending(&addr).await;
         }
     }
+
+    /// Connect to an address and perform the version handshake, without
+    /// registering the channel.
+    async fn feel(
+        &self,
+        connector: &Connector,
+        addr: NetAddr,
+        executor: Arc<Executor<'_>>,
+    ) -> Result<()> {
+        let p2p = self.p2p();
+        let channel = connector.connect(addr).await?;
+        let protocol_version = ProtocolVersion::new(channel.clone(), p2p.clone()).await;
+        channel.clone().start(executor.clone());
+        let result = protocol_version.run(executor).await;
+        channel.stop().await;
+        result
+    }
+
+    /// Checks whether an address is one of our own inbound addresses to
+    /// avoid connecting to ourselves.
+    fn is_self_inbound(addr: &NetAddr, inbound_addrs: &[NetAddr]) -> bool {
+        inbound_addrs.contains(addr)
+    }
+}
+
+/// Whether a slot can take an address, given the number of other slots
+/// using each network group. An IP group gets a single slot. Anyone can
+/// create onion addresses and hostnames, so their groups only get half of
+/// the slots, unless `crowded` is set as no other hosts are left.
+fn group_has_room(
+    addr: &NetAddr,
+    groups: &HashMap<NetworkGroup, usize>,
+    slots: usize,
+    crowded: bool,
+) -> bool {
+    let group = match addr.network_group() {
+        Some(group) => group,
+        None => return true,
+    };
+
+    let used = groups.get(&group).copied().unwrap_or_default();
+    match group {
+        NetworkGroup::Ip(_) => used == 0,
+        NetworkGroup::Onion | NetworkGroup::Host => crowded || used < (slots + 1) / 2,
+    }
 }
 
 #[async_trait]
@@ -252,3 +483,40 @@ impl Session for OutboundSession {
         SESSION_OUTBOUND
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(addr: Option<&str>) -> OutboundInfo {
+        OutboundInfo { addr: addr.map(|addr| addr.parse().unwrap()), ..Default::default() }
+    }
+
+    #[test]
+    fn group_has_room_test() {
+        let slots = vec![
+            slot(Some("10.0.0.1:11001")),
+            slot(Some("node1.example.com:11001")),
+            slot(Some("node2.example.com:11001")),
+            slot(None),
+        ];
+        let groups = OutboundSession::slot_groups(&slots, 3);
+        assert_eq!(groups[&NetworkGroup::Host], 2);
+        let has_room = |addr: &str, crowded| {
+            group_has_room(&addr.parse().unwrap(), &groups, slots.len(), crowded)
+        };
+
+        // One slot per IP group
+        assert!(!has_room("10.0.1.1:11001", false));
+        assert!(has_room("10.1.0.1:11001", false));
+
+        // Hostnames and onion addresses get half of the slots each, unless
+        // no other host is left
+        assert!(!has_room("node3.example.com:11001", false));
+        assert!(has_room("node3.example.com:11001", true));
+        assert!(has_room("node000000000001.onion:11001", false));
+
+        // Loopback peers aren't grouped
+        assert!(has_room("127.0.0.1:11001", false));
+    }
+}
@@ -2,7 +2,6 @@ use async_std::future::timeout;
 use async_trait::async_trait;
 use serde_json::json;
 use std::{
-    net::SocketAddr,
     sync::{Arc, Weak},
     time::Duration,
 };
@@ -14,7 +13,7 @@ use crate::{
     error::{Error, Result},
     net::{
         session::{Session, SessionBitflag, SESSION_SEED},
-        Connector, P2p,
+        Connector, NetAddr, P2p,
     },
 };
 
@@ -45,7 +44,7 @@ impl SeedSession {
         let mut tasks = Vec::new();
 
         for (i, seed) in settings.seeds.iter().enumerate() {
-            tasks.push(executor.spawn(self.clone().start_seed(i, *seed, executor.clone())));
+            tasks.push(executor.spawn(self.clone().start_seed(i, seed.clone(), executor.clone())));
         }
 
         // This line loops through all the tasks and waits for them to finish.
@@ -86,7 +85,7 @@ impl SeedSession {
     async fn start_seed(
         self: Arc<Self>,
         seed_index: usize,
-        seed: SocketAddr,
+        seed: NetAddr,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         debug!(target: "net", "SeedSession::start_seed(i={}) [START]", seed_index);
@@ -96,7 +95,7 @@ impl SeedSession {
         };
 
         let connector = Connector::new(settings.clone());
-        match connector.connect(seed).await {
+        match connector.connect(seed.clone()).await {
             Ok(channel) => {
                 // Blacklist goes here
 
@@ -1,10 +1,10 @@
 use async_trait::async_trait;
-use log::debug;
+use log::{debug, info};
 use smol::Executor;
 use std::sync::Arc;
 
 use crate::{
-    error::Result,
+    error::{Error, Result},
     net::{p2p::P2pPtr, protocol::ProtocolVersion, ChannelPtr},
 };
 
@@ -20,6 +20,10 @@ async fn remove_sub_on_stop(p2p: P2pPtr, channel: ChannelPtr) {
         "remove_sub_on_stop(): received stop event. Removing channel {}",
         channel.address()
     );
+    // Ban the peer if it was stopped for misbehaving
+    if channel.is_banned() {
+        p2p.hosts().ban(&channel.address()).await;
+    }
     // Remove channel from p2p
     p2p.remove(channel).await;
     debug!(target: "net", "remove_sub_on_stop() [END]");
@@ -37,16 +41,22 @@ pub trait Session: Sync {
     ) -> Result<()> {
         debug!(target: "net", "Session::register_channel() [START]");
 
+        let p2p = self.p2p();
+        channel.set_session(self.selector_id());
+        if p2p.hosts().is_banned(&channel.address()).await {
+            info!(target: "net", "Refusing banned peer {}", channel.address());
+            return Err(Error::PeerBanned)
+        }
+
         // Protocols should all be initialized but not started
         // We do this so that the protocols can begin receiving and buffering messages
         // while the handshake protocol is ongoing.
         // They are currently in sleep mode.
-        let p2p = self.p2p();
         let protocols =
             p2p.protocol_registry().attach(self.selector_id(), channel.clone(), p2p.clone()).await;
 
         // Perform the handshake protocol
-        let protocol_version = ProtocolVersion::new(channel.clone(), self.p2p().settings()).await;
+        let protocol_version = ProtocolVersion::new(channel.clone(), p2p.clone()).await;
         let handshake_task =
             self.perform_handshake_protocols(protocol_version, channel.clone(), executor.clone());
 
@@ -54,7 +64,14 @@ pub trait Session: Sync {
         channel.start(executor.clone());
 
         // Wait for handshake to finish.
-        handshake_task.await?;
+        if let Err(err) = handshake_task.await {
+            if channel.is_banned() {
+                p2p.hosts().ban(&channel.address()).await;
+            }
+            // Nobody uses the channel, so the peer is disconnected
+            channel.stop().await;
+            return Err(err)
+        }
 
         // Now the channel is ready
         debug!(target: "net", "Session handshake complete. Activating remaining protocols");
@@ -62,9 +79,16 @@ pub trait Session: Sync {
         // Now start all the protocols
         // They are responsible for managing their own lifetimes and
         // correctly self destructing when the channel ends.
-        for protocol in protocols {
+        for (services, protocol) in protocols {
+            // Skip protocols the peer doesn't support
+            if !channel.supports(services).await {
+                debug!(target: "net", "Peer does not support {}, skipping", protocol.name());
+                continue
+            }
+
             // Activate protocol
-            protocol.start(executor.clone()).await?;
+            protocol.clone().start(executor.clone()).await?;
+            channel.add_protocol(protocol).await;
         }
 
         debug!(target: "net", "Session::register_channel() [END]");
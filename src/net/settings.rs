@@ -1,4 +1,10 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use url::Url;
+
+use crate::net::{
+    message::{ServiceBitflag, SERVICE_RELAY},
+    NetAddr, PortMapperPtr, TransportPtr,
+};
 
 /// Atomic pointer to network settings.
 pub type SettingsPtr = Arc<Settings>;
@@ -6,7 +12,18 @@ pub type SettingsPtr = Arc<Settings>;
 /// Defines the network settings.
 #[derive(Clone)]
 pub struct Settings {
-    pub inbound: Option<SocketAddr>,
+    /// Local addresses inbound connections are accepted on, eg. an IPv4
+    /// and an IPv6 address, or the target of an onion service
+    pub inbound: Vec<SocketAddr>,
+    /// Maximum number of inbound channels, 0 disables the limit. Once
+    /// full, a peer is evicted to make room for a new one.
+    pub inbound_connections: u32,
+    /// Maximum inbound channels from a single IP, 0 disables the limit.
+    /// Loopback peers, eg. behind a Tor onion service, are exempt.
+    pub inbound_per_ip: u32,
+    /// Maximum inbound channels from a single /24 (IPv4) or /48 (IPv6)
+    /// network, 0 disables the limit
+    pub inbound_per_subnet: u32,
     pub outbound_connections: u32,
     pub manual_attempt_limit: u32,
 
@@ -15,24 +32,95 @@ pub struct Settings {
     pub channel_handshake_seconds: u32,
     pub channel_heartbeat_seconds: u32,
 
-    pub external_addr: Option<SocketAddr>,
-    pub peers: Vec<SocketAddr>,
-    pub seeds: Vec<SocketAddr>,
+    /// Addresses advertised to peers, which may include onion addresses.
+    /// The first one is announced in the version message. If unset, the
+    /// addresses are discovered from the peers and the port mapper.
+    pub external_addrs: Vec<NetAddr>,
+    /// Number of peers from distinct networks that must report the same
+    /// IP for us before it is advertised
+    pub external_addr_quorum: u32,
+    /// Router the inbound ports are mapped on, eg. with NAT-PMP
+    pub port_mapper: Option<PortMapperPtr>,
+    pub peers: Vec<NetAddr>,
+    pub seeds: Vec<NetAddr>,
+    /// SOCKS5 proxy used for all outbound connections, eg.
+    /// `socks5://127.0.0.1:9050` for Tor. Onion addresses can only be
+    /// reached through a proxy.
+    pub socks5_proxy: Option<Url>,
+    /// Transport used instead of TCP, eg. a simulated network in tests
+    pub transport: Option<TransportPtr>,
+
+    /// Maximum size of a packet, whatever its message type
+    pub max_packet_size: usize,
+    /// Messages per second a peer may send, 0 disables the limit
+    pub channel_rate_limit: u32,
+    /// Messages a peer may send at once above the rate limit
+    pub channel_rate_burst: u32,
+
+    /// Path of the host database. Hosts are only kept in memory if unset.
+    pub hosts_path: Option<PathBuf>,
+    /// How long misbehaving peers stay banned
+    pub ban_duration_seconds: u32,
+
+    /// Offer transport encryption to peers
+    pub channel_encryption: bool,
+    /// Refuse channels with peers that don't support encryption
+    pub require_encryption: bool,
+    /// Secret of the node identity key. A random identity is used if unset.
+    pub identity_secret: Option<[u8; 32]>,
+    /// Identity keys expected from the nodes we dial. Channels with these
+    /// addresses must be encrypted and authenticated with the pinned key.
+    pub pinned_identities: HashMap<NetAddr, [u8; 32]>,
+
+    /// Services announced to peers in the version message
+    pub services: ServiceBitflag,
+    /// Name and version of the node software announced to peers
+    pub user_agent: String,
+
+    /// Address serving the Prometheus metrics over HTTP, disabled if unset
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Run as a seed node: the hosts are crawled to check they are
+    /// reachable, and only reachable hosts are shared with peers
+    pub seed_mode: bool,
+    /// Interval between two crawls of the hosts in seed mode
+    pub crawl_interval_seconds: u32,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
-            inbound: None,
+            inbound: Vec::new(),
+            inbound_connections: 64,
+            inbound_per_ip: 4,
+            inbound_per_subnet: 16,
             outbound_connections: 0,
             manual_attempt_limit: 0,
             seed_query_timeout_seconds: 8,
             connect_timeout_seconds: 10,
             channel_handshake_seconds: 4,
             channel_heartbeat_seconds: 10,
-            external_addr: None,
+            external_addrs: Vec::new(),
+            external_addr_quorum: 3,
+            port_mapper: None,
             peers: Vec::new(),
             seeds: Vec::new(),
+            socks5_proxy: None,
+            transport: None,
+            max_packet_size: 32 * 1024 * 1024,
+            channel_rate_limit: 100,
+            channel_rate_burst: 500,
+            hosts_path: None,
+            ban_duration_seconds: 86400,
+            channel_encryption: true,
+            require_encryption: false,
+            identity_secret: None,
+            pinned_identities: HashMap::new(),
+            services: SERVICE_RELAY,
+            user_agent: format!("darkfi/{}", env!("CARGO_PKG_VERSION")),
+            metrics_addr: None,
+            seed_mode: false,
+            crawl_interval_seconds: 300,
         }
     }
 }
@@ -0,0 +1,877 @@
+use async_executor::Executor;
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use log::warn;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use smol::Timer;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    net::{
+        NetAddr, P2p, P2pPtr, Settings, Transport, TransportListener, TransportPtr, TransportStream,
+    },
+};
+
+/// Atomic pointer to a simulated network.
+pub type SimNetworkPtr = Arc<SimNetwork>;
+
+/// Extra delay of data sent in a lost segment, which has to be
+/// retransmitted.
+pub const RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+
+/// Port nodes listen on in the simulated network.
+pub const SIM_PORT: u16 = 11000;
+
+/// Bytes a connection buffers on its way to the reader. Writes block once
+/// it is full, like with the send buffer of a socket.
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Step of the network clock once it runs along with the system clock.
+pub const CLOCK_TICK: Duration = Duration::from_millis(5);
+
+/// First port handed out to the outbound side of connections.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+struct PipeState {
+    /// Data sent and the network time it is delivered at
+    chunks: VecDeque<(Duration, Vec<u8>)>,
+    /// Bytes sent and not read yet
+    buffered: usize,
+    /// Delivery time of the last chunk, so data is received in order
+    last_delivery: Duration,
+    /// The writer closed its side of the connection
+    closed: bool,
+    /// The connection was cut by a partition, or the reader went away
+    broken: bool,
+    /// Reader waiting for data
+    reader: Option<Waker>,
+    /// Writer waiting for room in the buffer
+    writer: Option<Waker>,
+}
+
+impl PipeState {
+    fn wake(&mut self) {
+        for waker in [self.reader.take(), self.writer.take()].into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+/// One direction of a simulated connection.
+struct Pipe(Mutex<PipeState>);
+
+impl Pipe {
+    fn new() -> Arc<Self> {
+        Arc::new(Self(Mutex::new(PipeState {
+            chunks: VecDeque::new(),
+            buffered: 0,
+            last_delivery: Duration::ZERO,
+            closed: false,
+            broken: false,
+            reader: None,
+            writer: None,
+        })))
+    }
+
+    /// Room left in the buffer. The writer is woken up once there is some.
+    fn room(&self, cx: &mut Context<'_>) -> io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        if state.closed || state.broken {
+            return Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        let room = PIPE_CAPACITY - state.buffered;
+        if room == 0 {
+            state.writer = Some(cx.waker().clone());
+        }
+        Ok(room)
+    }
+
+    fn push(&self, data: Vec<u8>, delivery: Duration) -> io::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if state.closed || state.broken {
+            return Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        let delivery = delivery.max(state.last_delivery);
+        state.last_delivery = delivery;
+        state.buffered += data.len();
+        state.chunks.push_back((delivery, data));
+        if let Some(waker) = state.reader.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.closed = true;
+        state.wake();
+    }
+
+    fn cut(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.broken = true;
+        state.chunks.clear();
+        state.buffered = 0;
+        state.wake();
+    }
+}
+
+/// End of a connection on the simulated network.
+pub struct SimStream {
+    network: SimNetworkPtr,
+    read: Arc<Pipe>,
+    write: Arc<Pipe>,
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            // Read the clock first, the network locks pipes while locked
+            let now = self.network.now();
+            let mut state = self.read.0.lock().unwrap();
+            if state.broken {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+            }
+
+            let delivery = match state.chunks.front_mut() {
+                Some((delivery, chunk)) if *delivery <= now => {
+                    let len = buf.len().min(chunk.len());
+                    buf[..len].copy_from_slice(&chunk[..len]);
+                    chunk.drain(..len);
+                    if chunk.is_empty() {
+                        state.chunks.pop_front();
+                    }
+                    state.buffered -= len;
+                    if let Some(waker) = state.writer.take() {
+                        waker.wake();
+                    }
+                    return Poll::Ready(Ok(len))
+                }
+                Some((delivery, _)) => *delivery,
+                // End of file once the remaining data is read
+                None if state.closed => return Poll::Ready(Ok(0)),
+                None => {
+                    state.reader = Some(cx.waker().clone());
+                    return Poll::Pending
+                }
+            };
+
+            // Data is still in flight. The waker is also kept in the pipe
+            // so a partition cutting the connection wakes us up.
+            state.reader = Some(cx.waker().clone());
+            drop(state);
+            if !self.network.wake_at(delivery, cx.waker()) {
+                return Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let len = match self.write.room(cx) {
+            Ok(0) => return Poll::Pending,
+            Ok(room) => buf.len().min(room),
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+
+        let delivery = self.network.delivery_time();
+        Poll::Ready(self.write.push(buf[..len].to_vec(), delivery).map(|()| len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.write.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        self.write.close();
+        // Nobody reads what the peer sends anymore
+        self.read.cut();
+    }
+}
+
+/// Future resolving once the network clock reaches a deadline.
+pub struct Sleep {
+    network: SimNetworkPtr,
+    deadline: Duration,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.network.wake_at(self.deadline, cx.waker()) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+/// Connection between two hosts, kept to cut it on partitions.
+struct Link {
+    hosts: (IpAddr, IpAddr),
+    pipes: (Weak<Pipe>, Weak<Pipe>),
+}
+
+struct SimState {
+    rng: StdRng,
+    /// Network time, only moved forward by `advance`
+    now: Duration,
+    /// Tasks waiting for the clock to reach a given time
+    sleepers: Vec<(Duration, Waker)>,
+    clock_running: bool,
+    latency: Duration,
+    loss: f64,
+    listeners: HashMap<SocketAddr, async_channel::Sender<(SimStream, NetAddr)>>,
+    /// Pairs of hosts which can't reach each other
+    partitions: HashSet<(IpAddr, IpAddr)>,
+    links: Vec<Link>,
+    next_port: u16,
+}
+
+/// In-memory network connecting nodes running in the same process. The
+/// latency and packet loss of the network can be set, and partitions
+/// cut hosts off from each other. Random decisions come from a seeded
+/// generator, so a simulation plays out the same way every time.
+///
+/// Data in flight is delivered by a virtual clock, which only moves with
+/// `advance`. Tests stepping it themselves see every delivery at an exact
+/// time, while `start_clock` lets it follow the system clock for running
+/// whole nodes, whose own timers still use the system clock.
+///
+/// Nodes join the network with settings from `node_settings`, which set
+/// the simulated network as their transport.
+pub struct SimNetwork {
+    state: Mutex<SimState>,
+}
+
+impl SimNetwork {
+    /// Create a new network without latency or packet loss, with its
+    /// clock stopped at zero.
+    pub fn new(seed: u64) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(seed),
+                now: Duration::ZERO,
+                sleepers: Vec::new(),
+                clock_running: false,
+                latency: Duration::ZERO,
+                loss: 0.0,
+                listeners: HashMap::new(),
+                partitions: HashSet::new(),
+                links: Vec::new(),
+                next_port: EPHEMERAL_PORT_START,
+            }),
+        })
+    }
+
+    /// Time elapsed on the network clock.
+    pub fn now(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    /// Move the clock forward, delivering the data due until then.
+    pub fn advance(&self, duration: Duration) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.now += duration;
+            let now = state.now;
+            let (due, waiting): (Vec<_>, Vec<_>) =
+                state.sleepers.drain(..).partition(|(at, _)| *at <= now);
+            state.sleepers = waiting;
+            due
+        };
+        for (_, waker) in due {
+            waker.wake();
+        }
+    }
+
+    /// Advance the clock by `CLOCK_TICK` at every tick of the system clock,
+    /// for as long as the network exists.
+    pub fn start_clock(self: &Arc<Self>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.clock_running {
+                return
+            }
+            state.clock_running = true;
+        }
+
+        let network = Arc::downgrade(self);
+        smol::spawn(async move {
+            loop {
+                Timer::after(CLOCK_TICK).await;
+                match network.upgrade() {
+                    Some(network) => network.advance(CLOCK_TICK),
+                    None => break,
+                }
+            }
+        })
+        .detach();
+    }
+
+    /// Wait for `duration` on the network clock.
+    pub fn sleep(self: &Arc<Self>, duration: Duration) -> Sleep {
+        Sleep { network: self.clone(), deadline: self.now() + duration }
+    }
+
+    /// Set the one way latency of the network.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Set the probability of a segment being lost. Channels run over a
+    /// reliable stream, so lost data is delivered after `RETRANSMIT_DELAY`.
+    pub fn set_loss(&self, loss: f64) {
+        assert!((0.0..=1.0).contains(&loss), "Loss must be a probability");
+        self.state.lock().unwrap().loss = loss;
+    }
+
+    /// Cut the hosts of `side_a` off from the hosts of `side_b`. Open
+    /// connections between them are reset, and new connections time out.
+    pub fn partition(&self, side_a: &[IpAddr], side_b: &[IpAddr]) {
+        let mut state = self.state.lock().unwrap();
+        for a in side_a {
+            for b in side_b {
+                state.partitions.insert((*a, *b));
+                state.partitions.insert((*b, *a));
+            }
+        }
+
+        let partitions = state.partitions.clone();
+        state.links.retain(|link| {
+            if !partitions.contains(&link.hosts) {
+                return link.pipes.0.strong_count() > 0
+            }
+            for pipe in [&link.pipes.0, &link.pipes.1] {
+                if let Some(pipe) = pipe.upgrade() {
+                    pipe.cut();
+                }
+            }
+            false
+        });
+    }
+
+    /// Remove all partitions.
+    pub fn heal(&self) {
+        self.state.lock().unwrap().partitions.clear();
+    }
+
+    /// Transport for the node at the given host.
+    pub fn transport(self: &Arc<Self>, host: IpAddr) -> TransportPtr {
+        Arc::new(SimTransport { network: self.clone(), host })
+    }
+
+    /// Address of the node with the given index. Each node is in its own
+    /// /16 network, so nodes aren't limited as peers from one network.
+    pub fn node_addr(index: usize) -> SocketAddr {
+        let ip = Ipv4Addr::new(10 + (index / 256) as u8, (index % 256) as u8, 0, 1);
+        SocketAddr::new(ip.into(), SIM_PORT)
+    }
+
+    /// Settings of a node listening on the network at `node_addr(index)`.
+    pub fn node_settings(self: &Arc<Self>, index: usize) -> Settings {
+        let addr = Self::node_addr(index);
+        Settings {
+            inbound: vec![addr],
+            external_addrs: vec![addr.into()],
+            transport: Some(self.transport(addr.ip())),
+            ..Default::default()
+        }
+    }
+
+    /// Create `count` nodes. Node 0 is a seed node which only accepts
+    /// connections, and the other nodes bootstrap from it. `configure`
+    /// adjusts the settings of each node. The nodes are started with
+    /// `start_nodes`, so protocols can be registered first.
+    pub async fn create_nodes<F>(self: &Arc<Self>, count: usize, configure: F) -> Vec<P2pPtr>
+    where
+        F: Fn(usize, &mut Settings),
+    {
+        let mut nodes = Vec::new();
+        for index in 0..count {
+            let mut settings = self.node_settings(index);
+            if index > 0 {
+                settings.seeds = vec![Self::node_addr(0).into()];
+                settings.outbound_connections = 2;
+            }
+            configure(index, &mut settings);
+            nodes.push(P2p::new(settings).await);
+        }
+        nodes
+    }
+
+    /// Start the nodes one after the other, waiting for each one to accept
+    /// connections before starting the next. Returns false if a node
+    /// didn't come up.
+    pub async fn start_nodes(
+        self: &Arc<Self>,
+        nodes: &[P2pPtr],
+        executor: Arc<Executor<'static>>,
+    ) -> bool {
+        for node in nodes {
+            start_node(node.clone(), executor.clone());
+
+            for addr in &node.settings().inbound {
+                let listening = || async { self.is_listening(addr) };
+                if !self.wait_until(Duration::from_secs(10), listening).await {
+                    return false
+                }
+            }
+        }
+        true
+    }
+
+    /// Poll a condition until it holds, giving up after `timeout` on the
+    /// network clock, which is started if needed. Returns true if the
+    /// condition was met.
+    pub async fn wait_until<F, Fut>(self: &Arc<Self>, timeout: Duration, mut condition: F) -> bool
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        self.start_clock();
+        let deadline = self.now() + timeout;
+        while self.now() < deadline {
+            if condition().await {
+                return true
+            }
+            Timer::after(Duration::from_millis(50)).await;
+        }
+        condition().await
+    }
+
+    fn is_listening(&self, addr: &SocketAddr) -> bool {
+        self.state.lock().unwrap().listeners.contains_key(addr)
+    }
+
+    /// Time data sent now is delivered at.
+    fn delivery_time(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let loss = state.loss;
+        match state.rng.gen_bool(loss) {
+            true => state.now + state.latency + RETRANSMIT_DELAY,
+            false => state.now + state.latency,
+        }
+    }
+
+    /// Returns true if the clock reached `deadline`, or wakes the task up
+    /// once it does.
+    fn wake_at(&self, deadline: Duration, waker: &Waker) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.now >= deadline {
+            return true
+        }
+        state.sleepers.push((deadline, waker.clone()));
+        false
+    }
+
+    fn listen(self: &Arc<Self>, addr: SocketAddr) -> Result<SimListener> {
+        let mut state = self.state.lock().unwrap();
+        if state.listeners.contains_key(&addr) {
+            return Err(Error::OperationFailed)
+        }
+
+        let (sender, receiver) = async_channel::unbounded();
+        state.listeners.insert(addr, sender);
+        Ok(SimListener { network: self.clone(), addr, receiver })
+    }
+
+    async fn dial(self: &Arc<Self>, host: IpAddr, addr: &NetAddr) -> Result<SimStream> {
+        let addr = match addr {
+            NetAddr::Ip(addr) => *addr,
+            NetAddr::Host(_, _) => return Err(Error::ConnectFailed),
+        };
+
+        let (partitioned, latency) = {
+            let state = self.state.lock().unwrap();
+            (state.partitions.contains(&(host, addr.ip())), state.latency)
+        };
+        if partitioned {
+            // Nothing comes back, so the connection attempt times out
+            return futures::future::pending().await
+        }
+
+        // Round trip of the connection setup
+        self.sleep(latency * 2).await;
+
+        let (listener, port, outbound, inbound) = {
+            let mut state = self.state.lock().unwrap();
+            // The hosts may have been partitioned during the setup
+            if state.partitions.contains(&(host, addr.ip())) {
+                return Err(Error::ConnectFailed)
+            }
+            let listener = match state.listeners.get(&addr) {
+                Some(listener) => listener.clone(),
+                None => return Err(Error::ConnectFailed),
+            };
+
+            let port = state.next_port;
+            state.next_port = state.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+
+            // Forget the connections which were closed since
+            state.links.retain(|link| link.pipes.0.strong_count() > 0);
+            let (outbound, inbound) = (Pipe::new(), Pipe::new());
+            state.links.push(Link {
+                hosts: (host, addr.ip()),
+                pipes: (Arc::downgrade(&outbound), Arc::downgrade(&inbound)),
+            });
+            (listener, port, outbound, inbound)
+        };
+
+        let local =
+            SimStream { network: self.clone(), read: inbound.clone(), write: outbound.clone() };
+        let remote = SimStream { network: self.clone(), read: outbound, write: inbound };
+
+        let peer_addr = SocketAddr::new(host, port).into();
+        listener.send((remote, peer_addr)).await.map_err(|_| Error::ConnectFailed)?;
+        Ok(local)
+    }
+}
+
+struct SimTransport {
+    network: SimNetworkPtr,
+    host: IpAddr,
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    async fn dial(&self, addr: &NetAddr) -> Result<Box<dyn TransportStream>> {
+        Ok(Box::new(self.network.dial(self.host, addr).await?))
+    }
+
+    async fn listen(&self, addr: SocketAddr) -> Result<Box<dyn TransportListener>> {
+        if addr.ip() != self.host {
+            return Err(Error::OperationFailed)
+        }
+        Ok(Box::new(self.network.listen(addr)?))
+    }
+}
+
+struct SimListener {
+    network: SimNetworkPtr,
+    addr: SocketAddr,
+    receiver: async_channel::Receiver<(SimStream, NetAddr)>,
+}
+
+#[async_trait]
+impl TransportListener for SimListener {
+    async fn accept(&self) -> Result<(Box<dyn TransportStream>, NetAddr)> {
+        let (stream, peer_addr) = self.receiver.recv().await.map_err(|_| Error::ServiceStopped)?;
+        Ok((Box::new(stream), peer_addr))
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.network.state.lock().unwrap().listeners.remove(&self.addr);
+    }
+}
+
+/// Start a node in the background. Runs the seed session, then the long
+/// running sessions until the node is stopped.
+pub fn start_node(p2p: P2pPtr, executor: Arc<Executor<'static>>) {
+    executor
+        .clone()
+        .spawn(async move {
+            if let Err(err) = p2p.clone().start(executor.clone()).await {
+                warn!(target: "net", "Simulated node failed to start: {}", err);
+                return
+            }
+            if let Err(err) = p2p.run(executor).await {
+                warn!(target: "net", "Simulated node stopped: {}", err);
+            }
+        })
+        .detach();
+}
+
+#[cfg(test)]
+mod tests {
+    use async_executor::Executor;
+    use async_std::sync::Mutex;
+    use async_trait::async_trait;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use std::{collections::HashSet, io, sync::Arc, time::Duration};
+
+    use super::{SimNetwork, PIPE_CAPACITY};
+    use crate::{
+        net::{
+            ChannelPtr, Message, MessageSubscription, NetAddr, P2pPtr, ProtocolBase,
+            ProtocolBasePtr, ProtocolJobsManager, ProtocolJobsManagerPtr, SESSION_ALL,
+        },
+        util::serial::{Decodable, Encodable},
+        Result,
+    };
+
+    #[test]
+    fn sim_stream_test() {
+        smol::block_on(async {
+            let network = SimNetwork::new(0);
+            network.set_latency(Duration::from_millis(50));
+            let a = SimNetwork::node_addr(1);
+            let b = SimNetwork::node_addr(2);
+            let transport = network.transport(a.ip());
+            let target: NetAddr = b.into();
+
+            // The connection setup takes a round trip on the network clock
+            let listener = network.transport(b.ip()).listen(b).await.unwrap();
+            let dial = transport.dial(&target);
+            let (stream, ()) = futures::join!(dial, async {
+                network.advance(Duration::from_millis(100));
+            });
+            let mut stream = stream.unwrap();
+            let (mut remote, peer_addr) = listener.accept().await.unwrap();
+            assert_eq!(peer_addr.host(), a.ip().to_string());
+
+            // Data arrives once the latency passed
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            network.advance(Duration::from_millis(49));
+            assert!(futures::poll!(remote.read(&mut buf)).is_pending());
+            network.advance(Duration::from_millis(1));
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            assert_eq!(network.now(), Duration::from_millis(150));
+
+            // Writes block while the buffer is full, until the peer reads
+            network.set_latency(Duration::ZERO);
+            let data = vec![7u8; PIPE_CAPACITY + 1];
+            let mut write = stream.write_all(&data);
+            assert!(futures::poll!(&mut write).is_pending());
+            let mut received = vec![0u8; data.len()];
+            let (written, read) = futures::join!(write, remote.read_exact(&mut received));
+            assert!(written.is_ok() && read.is_ok());
+            assert_eq!(received, data);
+
+            // Partitions reset open connections, and new ones hang
+            network.partition(&[a.ip()], &[b.ip()]);
+            assert!(remote.read_exact(&mut buf).await.is_err());
+            let dial = transport.dial(&target);
+            assert!(async_std::future::timeout(Duration::from_millis(200), dial).await.is_err());
+
+            network.heal();
+            assert!(transport.dial(&target).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn seed_discovery_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(1);
+            network.set_latency(Duration::from_millis(10));
+            let nodes = network.create_nodes(6, |_, _| {}).await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            // The seed learns about every node, and the nodes connect to
+            // each other besides the seed.
+            let discovered = network
+                .wait_until(Duration::from_secs(20), || async {
+                    if nodes[0].hosts().load_all().await.len() < nodes.len() - 1 {
+                        return false
+                    }
+                    for node in &nodes[1..] {
+                        if node.connections_count().await < 2 {
+                            return false
+                        }
+                    }
+                    true
+                })
+                .await;
+            assert!(discovered);
+        }));
+    }
+
+    #[test]
+    fn outbound_reconnect_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(2);
+            network.set_latency(Duration::from_millis(10));
+            network.set_loss(0.1);
+            let nodes =
+                network.create_nodes(4, |_, settings| settings.connect_timeout_seconds = 1).await;
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            let node = nodes[1].clone();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        node.connections_count().await >= 2
+                    })
+                    .await
+            );
+
+            let others: Vec<_> = (0..nodes.len())
+                .filter(|i| *i != 1)
+                .map(|i| SimNetwork::node_addr(i).ip())
+                .collect();
+            network.partition(&[SimNetwork::node_addr(1).ip()], &others);
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(5), || async {
+                        node.connections_count().await == 0
+                    })
+                    .await
+            );
+
+            network.heal();
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        node.connections_count().await > 0
+                    })
+                    .await
+            );
+        }));
+    }
+
+    #[derive(Clone)]
+    struct TestMessage {
+        id: u32,
+    }
+
+    impl Message for TestMessage {
+        fn name() -> &'static str {
+            "test"
+        }
+    }
+
+    impl Encodable for TestMessage {
+        fn encode<S: io::Write>(&self, s: S) -> Result<usize> {
+            self.id.encode(s)
+        }
+    }
+
+    impl Decodable for TestMessage {
+        fn decode<D: io::Read>(d: D) -> Result<Self> {
+            Ok(Self { id: Decodable::decode(d)? })
+        }
+    }
+
+    type SeenPtr = Arc<Mutex<HashSet<u32>>>;
+
+    /// Relays messages it didn't see yet to all peers.
+    struct ProtocolFlood {
+        sub: MessageSubscription<TestMessage>,
+        jobsman: ProtocolJobsManagerPtr,
+        seen: SeenPtr,
+        p2p: P2pPtr,
+    }
+
+    impl ProtocolFlood {
+        async fn new(channel: ChannelPtr, seen: SeenPtr, p2p: P2pPtr) -> ProtocolBasePtr {
+            channel.get_message_subsystem().add_dispatch::<TestMessage>().await;
+            let sub = channel.subscribe_msg::<TestMessage>().await.unwrap();
+            Arc::new(Self {
+                sub,
+                jobsman: ProtocolJobsManager::new("ProtocolFlood", channel),
+                seen,
+                p2p,
+            })
+        }
+
+        async fn handle_receive(self: Arc<Self>) -> Result<()> {
+            loop {
+                let message = self.sub.receive().await?;
+                if self.seen.lock().await.insert(message.id) {
+                    self.p2p.broadcast((*message).clone()).await?;
+                }
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ProtocolBase for ProtocolFlood {
+        async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+            self.jobsman.clone().start(executor.clone());
+            self.jobsman.clone().spawn(self.clone().handle_receive(), executor).await;
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "ProtocolFlood"
+        }
+    }
+
+    #[test]
+    fn gossip_test() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let network = SimNetwork::new(3);
+            network.set_latency(Duration::from_millis(10));
+            let nodes = network.create_nodes(6, |_, _| {}).await;
+
+            let mut seen = Vec::new();
+            for node in &nodes {
+                let node_seen: SeenPtr = Arc::new(Mutex::new(HashSet::new()));
+                let seen2 = node_seen.clone();
+                node.protocol_registry()
+                    .register(SESSION_ALL, move |channel, p2p| {
+                        let seen = seen2.clone();
+                        async move { ProtocolFlood::new(channel, seen, p2p).await }
+                    })
+                    .await;
+                seen.push(node_seen);
+            }
+            assert!(network.start_nodes(&nodes, ex.clone()).await);
+
+            // Wait for the nodes to connect to each other
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(20), || async {
+                        for node in &nodes[1..] {
+                            if node.connections_count().await < 2 {
+                                return false
+                            }
+                        }
+                        true
+                    })
+                    .await
+            );
+
+            seen[3].lock().await.insert(1);
+            nodes[3].broadcast(TestMessage { id: 1 }).await.unwrap();
+
+            assert!(
+                network
+                    .wait_until(Duration::from_secs(5), || async {
+                        for node_seen in &seen {
+                            if !node_seen.lock().await.contains(&1) {
+                                return false
+                            }
+                        }
+                        true
+                    })
+                    .await
+            );
+        }));
+    }
+}
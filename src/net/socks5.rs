@@ -0,0 +1,90 @@
+use fast_socks5::client::{Config, Socks5Stream};
+use url::Url;
+
+use crate::{
+    error::Result,
+    net::{NetAddr, TransportStream},
+};
+
+/// Open a connection to `addr` through the SOCKS5 proxy at `proxy`, eg.
+/// `socks5://127.0.0.1:9050`. Hostnames are resolved by the proxy, so
+/// onion addresses can be reached through Tor. Credentials set in the
+/// proxy url are used for username/password authentication.
+pub async fn connect(proxy: &Url, addr: &NetAddr) -> Result<Box<dyn TransportStream>> {
+    let proxy_addr = proxy.socket_addrs(|| Some(1080))?[0].to_string();
+    let host = match addr {
+        NetAddr::Ip(addr) => addr.ip().to_string(),
+        NetAddr::Host(host, _) => host.clone(),
+    };
+
+    let config = Config::default();
+    let stream = match (proxy.username(), proxy.password()) {
+        ("", _) | (_, None) => Socks5Stream::connect(proxy_addr, host, addr.port(), config).await?,
+        (username, Some(password)) => {
+            Socks5Stream::connect_with_password(
+                proxy_addr,
+                host,
+                addr.port(),
+                username.to_string(),
+                password.to_string(),
+                config,
+            )
+            .await?
+        }
+    };
+
+    Ok(Box::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use smol::Async;
+    use std::net::TcpListener;
+    use url::Url;
+
+    use super::connect;
+    use crate::net::NetAddr;
+
+    #[test]
+    fn socks5_connect_test() {
+        smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let proxy: Url =
+                format!("socks5://{}", listener.get_ref().local_addr().unwrap()).parse().unwrap();
+
+            // SOCKS5 stand-in which accepts a single connection and
+            // answers with a greeting instead of forwarding it.
+            let server = smol::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut greeting = [0u8; 2];
+                stream.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting[0], 5);
+                let mut methods = vec![0u8; greeting[1] as usize];
+                stream.read_exact(&mut methods).await.unwrap();
+                assert!(methods.contains(&0));
+                stream.write_all(&[5, 0]).await.unwrap();
+
+                let mut request = [0u8; 5];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(request[..4], [5, 1, 0, 3]);
+                let mut host = vec![0u8; request[4] as usize + 2];
+                stream.read_exact(&mut host).await.unwrap();
+                assert_eq!(&host[..host.len() - 2], b"exampleexample.onion");
+                assert_eq!(host[host.len() - 2..], 11001u16.to_be_bytes());
+
+                stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                stream.write_all(b"hello").await.unwrap();
+            });
+
+            let addr: NetAddr = "exampleexample.onion:11001".parse().unwrap();
+            let mut stream = connect(&proxy, &addr).await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            server.await;
+        });
+    }
+}
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use log::error;
+use smol::Async;
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+use crate::{
+    error::{Error, Result},
+    net::NetAddr,
+};
+
+/// Atomic pointer to a transport.
+pub type TransportPtr = Arc<dyn Transport>;
+
+/// Byte stream a channel runs on.
+pub trait TransportStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TransportStream for T {}
+
+/// Accepts inbound streams.
+#[async_trait]
+pub trait TransportListener: Send + Sync {
+    /// Wait for the next inbound stream. Returns the stream and the
+    /// address of the peer.
+    async fn accept(&self) -> Result<(Box<dyn TransportStream>, NetAddr)>;
+}
+
+/// Opens the streams channels run on. Nodes use TCP unless a transport is
+/// set in `Settings`, which lets tests run nodes on an in-memory network.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Open a stream to a remote address.
+    async fn dial(&self, addr: &NetAddr) -> Result<Box<dyn TransportStream>>;
+
+    /// Start listening for inbound streams on a local address.
+    async fn listen(&self, addr: SocketAddr) -> Result<Box<dyn TransportListener>>;
+}
+
+#[async_trait]
+impl TransportListener for Async<TcpListener> {
+    async fn accept(&self) -> Result<(Box<dyn TransportStream>, NetAddr)> {
+        let (stream, peer_addr) = Async::<TcpListener>::accept(self).await.map_err(|err| {
+            error!("Error listening for connections: {}", err);
+            Error::ServiceStopped
+        })?;
+        Ok((Box::new(stream), peer_addr.into()))
+    }
+}
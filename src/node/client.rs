@@ -1,6 +1,8 @@
+use std::net::SocketAddr;
+
 use async_std::sync::{Arc, Mutex};
 
-use incrementalmerkletree::{bridgetree::BridgeTree, Tree};
+use incrementalmerkletree::{bridgetree::BridgeTree, Frontier, Tree};
 use log::{debug, info, warn};
 use smol::Executor;
 use url::Url;
@@ -12,18 +14,27 @@ use crate::{
         coin::Coin,
         keypair::{Keypair, PublicKey, SecretKey},
         merkle_node::MerkleNode,
+        nullifier::Nullifier,
         proof::ProvingKey,
         types::DrkTokenId,
         OwnCoin,
     },
-    tx,
-    util::serial::{Decodable, Encodable},
+    net, tx,
+    util::serial::{deserialize, Encodable},
     zk::circuit::{MintContract, SpendContract},
     Result,
 };
 
 use super::{
-    service::GatewayClient,
+    compact_slab::CompactSlab,
+    nullifier_filter::SpentStatus,
+    service::{
+        gateway::GatewaySlabsSubscriber,
+        gateway_p2p::{ConsensusSettings, Gateway},
+        reqrep::ReqMetrics,
+        GatewayClient,
+    },
+    slab_payload,
     state::{state_transition, State, StateUpdate},
     wallet::{
         cashierdb::CashierDbPtr,
@@ -59,6 +70,8 @@ pub enum ClientFailed {
     VerifyError(String),
     #[error("Merkle tree already exists")]
     TreeExists,
+    #[error("Unable to find the last slab applied to the wallet, refresh the wallet")]
+    UnknownSlabIndex,
 }
 
 pub type ClientResult<T> = std::result::Result<T, ClientFailed>;
@@ -75,9 +88,112 @@ impl From<super::state::VerifyFailed> for ClientFailed {
     }
 }
 
+/// Where the client gets its slabs from and sends its transactions to.
+pub enum GatewaySettings {
+    /// gatewayd instances reached over ZeroMQ, as protocol and publisher
+    /// endpoints with the pinned public key of each gateway
+    Zmq(Vec<(Url, Url, PublicKey)>),
+    /// Run a P2P gateway node keeping its own copy of the slabs, certified
+    /// by the given consensus participants
+    P2p(net::Settings, Vec<PublicKey>),
+}
+
+enum GatewayConnection {
+    Zmq(GatewayClient),
+    P2p(Arc<Gateway>),
+}
+
+impl GatewayConnection {
+    /// Connect to the gateways, or start the P2P node in the background.
+    /// Compact mode only applies to ZeroMQ gateways, a P2P node always
+    /// syncs the full slabs.
+    async fn start(&mut self, compact: bool, executor: Arc<Executor<'_>>) -> Result<()> {
+        match self {
+            Self::Zmq(gateway) if compact => gateway.start_compact().await,
+            Self::Zmq(gateway) => gateway.start().await,
+            Self::P2p(gateway) => {
+                executor.spawn(gateway.clone().start(executor.clone())).detach();
+
+                // The slabs are requested from the connected peers, the
+                // sync loop catches up later if none connects in time.
+                if !gateway.wait_for_peer().await {
+                    warn!("No gateway peer connected, skipping the initial sync");
+                    return Ok(())
+                }
+                gateway.sync().await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn get_last_index(&mut self) -> Result<u64> {
+        match self {
+            Self::Zmq(gateway) => gateway.get_last_index().await,
+            Self::P2p(gateway) => gateway.get_last_index().await,
+        }
+    }
+
+    /// P2P nodes don't serve compact slabs, the compact slab is built from
+    /// the full slab.
+    async fn get_compact_slab(&mut self, index: u64) -> Result<Option<CompactSlab>> {
+        match self {
+            Self::Zmq(gateway) => gateway.get_compact_slab(index).await,
+            Self::P2p(gateway) => match gateway.get_slab(index).await? {
+                Some(slab) => Ok(Some(CompactSlab::from_slab(&slab)?)),
+                None => Ok(None),
+            },
+        }
+    }
+
+    async fn fetch_slab(&mut self, index: u64) -> Result<Option<Slab>> {
+        match self {
+            Self::Zmq(gateway) => gateway.fetch_slab(index).await,
+            Self::P2p(gateway) => gateway.get_slab(index).await,
+        }
+    }
+
+    async fn put_slab(&mut self, slab: Slab) -> Result<()> {
+        match self {
+            Self::Zmq(gateway) => gateway.put_slab(slab).await,
+            Self::P2p(gateway) => gateway.put_slab(slab).await,
+        }
+    }
+
+    async fn start_subscriber(
+        &self,
+        executor: Arc<Executor<'_>>,
+    ) -> Result<GatewaySlabsSubscriber> {
+        match self {
+            Self::Zmq(gateway) => gateway.start_subscriber(executor).await,
+            Self::P2p(gateway) => Ok(gateway.subscribe()),
+        }
+    }
+
+    /// P2P nodes don't serve nullifier filters, they apply the full slabs
+    /// to the state instead.
+    async fn nullifier_spent_status(
+        &mut self,
+        nullifiers: &[Nullifier],
+        from_index: u64,
+    ) -> Result<Vec<SpentStatus>> {
+        match self {
+            Self::Zmq(gateway) => gateway.nullifier_spent_status(nullifiers, from_index).await,
+            Self::P2p(_) => Ok(vec![SpentStatus::Unknown; nullifiers.len()]),
+        }
+    }
+
+    /// Requests to P2P peers aren't tracked per gateway.
+    fn metrics(&self) -> Vec<(SocketAddr, ReqMetrics)> {
+        match self {
+            Self::Zmq(gateway) => gateway.metrics(),
+            Self::P2p(_) => vec![],
+        }
+    }
+}
+
 pub struct Client {
     pub main_keypair: Keypair,
-    gateway: GatewayClient,
+    gateway: GatewayConnection,
     wallet: WalletPtr,
     mint_pk: ProvingKey,
     spend_pk: ProvingKey,
@@ -86,7 +202,7 @@ pub struct Client {
 impl Client {
     pub async fn new(
         rocks: Arc<Rocks>,
-        gateway_addrs: (Url, Url),
+        gateway_settings: GatewaySettings,
         wallet: WalletPtr,
     ) -> Result<Self> {
         wallet.init_db().await?;
@@ -109,9 +225,18 @@ impl Client {
         let main_keypair = wallet.get_default_keypair().await?;
         info!("Main keypair: {}", Address::from(main_keypair.public).to_string());
 
-        debug!("Creating GatewayClient");
-        let slabstore = RocksColumn::<columns::Slabs>::new(rocks);
-        let gateway = GatewayClient::new(gateway_addrs.0, gateway_addrs.1, slabstore)?;
+        let gateway = match gateway_settings {
+            GatewaySettings::Zmq(gateway_addrs) => {
+                debug!("Creating GatewayClient");
+                let slabstore = RocksColumn::<columns::Slabs>::new(rocks);
+                GatewayConnection::Zmq(GatewayClient::new(gateway_addrs, slabstore)?)
+            }
+            GatewaySettings::P2p(settings, participants) => {
+                debug!("Creating P2P gateway");
+                let consensus = ConsensusSettings { participants, participant: None };
+                GatewayConnection::P2p(Gateway::new(settings, rocks, consensus).await?)
+            }
+        };
 
         // TODO: These should go to a better place.
         debug!("Building proving key for the mint contract...");
@@ -123,8 +248,66 @@ impl Client {
         Ok(client)
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        self.gateway.start().await
+    pub async fn start(&mut self, executor: Arc<Executor<'_>>) -> Result<()> {
+        self.gateway.start(false, executor).await
+    }
+
+    /// Start the client and scan the chain using compact slabs instead of
+    /// downloading and verifying every full slab.
+    pub async fn start_compact(
+        &mut self,
+        state: Arc<Mutex<State>>,
+        executor: Arc<Executor<'_>>,
+    ) -> Result<()> {
+        self.gateway.start(true, executor).await?;
+        self.sync_compact(state).await
+    }
+
+    /// Scan the slabs the state hasn't seen yet using their compact form.
+    /// Slabs holding notes for us are fetched in full and verified, the
+    /// others only update the Merkle tree and nullifiers.
+    pub async fn sync_compact(&mut self, state: Arc<Mutex<State>>) -> Result<()> {
+        debug!("Start compact syncing");
+
+        let last_index = self.gateway.get_last_index().await?;
+        let local_last_index = state.lock().await.last_slab_index;
+        let secret_keys = vec![self.main_keypair.secret];
+
+        for index in (local_last_index + 1)..(last_index + 1) {
+            let compact_slab = match self.gateway.get_compact_slab(index).await? {
+                Some(compact_slab) => compact_slab,
+                None => break,
+            };
+
+            if compact_slab.has_own_notes(&secret_keys) {
+                debug!("Compact slab {} has notes for us, fetching full slab", index);
+                if let Some(slab) = self.gateway.fetch_slab(index).await? {
+                    Self::update_state(
+                        secret_keys.clone(),
+                        &slab,
+                        state.clone(),
+                        self.wallet.clone(),
+                        None,
+                    )
+                    .await?;
+                }
+                continue
+            }
+
+            let mut state = state.lock().await;
+            state
+                .apply_slab(
+                    vec![compact_slab.into_state_update()],
+                    index,
+                    vec![],
+                    None,
+                    self.wallet.clone(),
+                )
+                .await?;
+        }
+
+        debug!("End compact syncing");
+        Ok(())
     }
 
     async fn build_slab_from_tx(
@@ -149,14 +332,25 @@ impl Client {
         } else {
             debug!("Start building tx inputs");
             let mut inputs_value = 0_u64;
-            let state_m = state.lock().await;
             let own_coins = self.wallet.get_own_coins().await?;
 
-            for own_coin in own_coins.iter() {
+            // Coins spent in slabs the state hasn't applied yet, e.g. from
+            // another wallet holding the same keys, would get the
+            // transaction rejected by the gateway.
+            let from_index = state.lock().await.last_slab_index + 1;
+            let statuses = self.coin_spent_status(&own_coins, from_index).await?;
+
+            let state_m = state.lock().await;
+            for (own_coin, status) in own_coins.iter().zip(statuses) {
                 if inputs_value >= value {
                     break
                 }
 
+                if status == SpentStatus::MaybeSpent {
+                    debug!("Skipping coin which might have been spent");
+                    continue
+                }
+
                 let node = MerkleNode(own_coin.coin.0);
                 let (leaf_position, merkle_path) = state_m.tree.authentication_path(&node).unwrap();
                 // TODO: What is this counting? Is it everything or does it know to separate
@@ -272,23 +466,43 @@ impl Client {
         file.write_all(&payload)?;
         */
         debug!("Decoding payload");
-        let tx = tx::Transaction::decode(&payload[..])?;
+        let txs = slab_payload::decode(&payload)?;
 
-        let update: StateUpdate;
+        if state.lock().await.is_applied(slab.get_index()) {
+            debug!("Slab {} already applied, skipping", slab.get_index());
+            return Ok(())
+        }
+
+        // Transactions of a slab are applied in order, so each one is
+        // verified against the state updated by the previous ones.
+        for tx in txs {
+            let update: StateUpdate;
+
+            // This is separate because otherwise the mutex is never unlocked.
+            {
+                debug!("Acquiring state lock");
+                let state = &*state.lock().await;
+                update = state_transition(state, tx)?;
+                debug!("Successfully passed state_transition");
+            }
 
-        // This is separate because otherwise the mutex is never unlocked.
-        {
             debug!("Acquiring state lock");
-            let state = &*state.lock().await;
-            update = state_transition(state, tx)?;
-            debug!("Successfully passed state_transition");
+            let mut state = state.lock().await;
+            debug!("Trying to apply the new state");
+            state
+                .apply(
+                    update,
+                    slab.get_index(),
+                    secret_keys.clone(),
+                    notify.clone(),
+                    Some(wallet.clone()),
+                )
+                .await?;
+            debug!("Successfully passed state.apply");
         }
 
-        debug!("Acquiring state lock");
-        let mut state = state.lock().await;
-        debug!("Trying to apply the new state");
-        state.apply(update, secret_keys, notify, wallet).await?;
-        debug!("Successfully passed state.apply");
+        // Save the tree once the whole slab is applied
+        state.lock().await.save(&wallet).await?;
 
         Ok(())
     }
@@ -303,6 +517,8 @@ impl Client {
         debug!("Start subscriber for cashier");
         let gateway_slabs_sub = self.gateway.start_subscriber(executor.clone()).await?;
 
+        executor.spawn(State::prune_merkle_roots_loop(state.clone())).detach();
+
         let secret_key = self.main_keypair.secret;
         let wallet = self.wallet.clone();
 
@@ -343,6 +559,8 @@ impl Client {
         debug!("Start subscriber for darkfid");
         let gateway_slabs_sub = self.gateway.start_subscriber(executor.clone()).await?;
 
+        executor.spawn(State::prune_merkle_roots_loop(state.clone())).detach();
+
         let secret_key = self.main_keypair.secret;
         let wallet = self.wallet.clone();
 
@@ -371,6 +589,24 @@ impl Client {
         Ok(())
     }
 
+    /// Check the gateway's nullifier filters to find out which coins might
+    /// have been spent in the slabs from `from_index` on. The answer relies
+    /// on the gateway, it is not a proof of non-inclusion, so it is only
+    /// used to leave out coins when building transactions.
+    pub async fn coin_spent_status(
+        &mut self,
+        own_coins: &[OwnCoin],
+        from_index: u64,
+    ) -> Result<Vec<SpentStatus>> {
+        let nullifiers: Vec<Nullifier> = own_coins.iter().map(|coin| coin.nullifier).collect();
+        self.gateway.nullifier_spent_status(&nullifiers, from_index).await
+    }
+
+    /// Request statistics for each configured gateway.
+    pub fn gateway_metrics(&self) -> Vec<(SocketAddr, ReqMetrics)> {
+        self.gateway.metrics()
+    }
+
     pub async fn init_db(&self) -> Result<()> {
         self.wallet.init_db().await
     }
@@ -408,4 +644,147 @@ impl Client {
     pub async fn get_tree(&self) -> Result<BridgeTree<MerkleNode, 32>> {
         self.wallet.get_tree().await
     }
+
+    /// Index of the last slab applied to the wallet's merkle tree. Wallets
+    /// written before the index was stored find it from the slab the
+    /// current root of their tree appeared in.
+    pub async fn get_last_slab_index(
+        &self,
+        merkle_roots: &RocksColumn<columns::MerkleRoots>,
+    ) -> Result<u64> {
+        if let Some(index) = self.wallet.get_last_slab_index().await? {
+            return Ok(index)
+        }
+
+        let root = self.wallet.get_tree().await?.root();
+        if root == BridgeTree::<MerkleNode, 32>::new(100).root() {
+            return Ok(0)
+        }
+
+        match merkle_roots.get(root)?.map(|index| deserialize::<u64>(&index)) {
+            Some(Ok(index)) => Ok(index),
+            _ => Err(ClientFailed::UnknownSlabIndex.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use group::ff::Field;
+    use pasta_curves::pallas;
+    use rand::rngs::OsRng;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        crypto::{
+            note::Note,
+            proof::VerifyingKey,
+            types::{DrkCoinBlind, DrkSerial, DrkValueBlind},
+        },
+        node::{
+            compact_slab::CompactOutput,
+            nullifier_filter::NullifierFilter,
+            service::gateway::tests::{start_test_gateway, TestReplies},
+            wallet::walletdb::WalletDb,
+        },
+        util::serial::serialize,
+    };
+
+    fn dummy_coin(secret: &SecretKey) -> OwnCoin {
+        let serial = DrkSerial::random(&mut OsRng);
+        let note = Note {
+            serial,
+            value: 42,
+            token_id: DrkTokenId::random(&mut OsRng),
+            coin_blind: DrkCoinBlind::random(&mut OsRng),
+            value_blind: DrkValueBlind::random(&mut OsRng),
+        };
+
+        let coin = Coin(pallas::Base::random(&mut OsRng));
+        let nullifier = Nullifier::new(*secret, serial);
+
+        OwnCoin { coin, note, secret: *secret, nullifier }
+    }
+
+    #[async_std::test]
+    async fn coin_spent_status_test() -> Result<()> {
+        let secret = SecretKey::random(&mut OsRng);
+        let spent = dummy_coin(&secret);
+        let unspent = dummy_coin(&secret);
+
+        let mut filter = NullifierFilter::new(0);
+        filter.insert_slab(1, &[spent.nullifier]);
+
+        // GETLASTINDEX and GETNULLIFIERFILTER
+        let mut replies = TestReplies::new();
+        replies.insert((2, vec![]), serialize(&1u64));
+        replies.insert((3, serialize(&0u64)), serialize(&filter));
+        let gateway = start_test_gateway(replies).await?;
+
+        let dir = tempdir()?;
+        let rocks = Rocks::new(dir.path())?;
+        let wallet = WalletDb::new("sqlite::memory:", "darkfi").await?;
+        let mut client = Client::new(rocks, GatewaySettings::Zmq(vec![gateway]), wallet).await?;
+        client.start(Arc::new(Executor::new())).await?;
+
+        let statuses = client.coin_spent_status(&[spent, unspent], 1).await?;
+        assert_eq!(statuses, vec![SpentStatus::MaybeSpent, SpentStatus::NotSpent]);
+
+        Ok(())
+    }
+
+    /// Replies of a gateway at slab 1 serving the given compact slab.
+    fn compact_slab_replies(compact_slab: &CompactSlab) -> TestReplies {
+        let compact_slab = serialize(compact_slab);
+        let hash = blake2b_simd::blake2b(&compact_slab).as_bytes().to_vec();
+
+        // GETLASTINDEX, GETCOMPACTSLAB and GETCOMPACTSLABHASH
+        let mut replies = TestReplies::new();
+        replies.insert((2, vec![]), serialize(&1u64));
+        replies.insert((4, serialize(&1u64)), compact_slab);
+        replies.insert((7, serialize(&1u64)), hash);
+        replies
+    }
+
+    #[async_std::test]
+    async fn sync_compact_mismatch_test() -> Result<()> {
+        let keypair = Keypair::random(&mut OsRng);
+        let output = |coin| {
+            let note = dummy_coin(&keypair.secret).note;
+            CompactOutput { coin, enc_note: note.encrypt(&keypair.public).unwrap() }
+        };
+
+        // The second gateway holds a different slab 1
+        let coin = Coin(pallas::Base::random(&mut OsRng));
+        let other_coin = Coin(pallas::Base::random(&mut OsRng));
+        let compact_slab = CompactSlab::new(1, vec![], vec![output(coin)]);
+        let other_compact_slab = CompactSlab::new(1, vec![], vec![output(other_coin)]);
+        let gateway = start_test_gateway(compact_slab_replies(&compact_slab)).await?;
+        let other_gateway = start_test_gateway(compact_slab_replies(&other_compact_slab)).await?;
+
+        let dir = tempdir()?;
+        let rocks = Rocks::new(dir.path())?;
+        let wallet = WalletDb::new("sqlite::memory:", "darkfi").await?;
+        let gateway_settings = GatewaySettings::Zmq(vec![gateway, other_gateway]);
+        let mut client = Client::new(rocks.clone(), gateway_settings, wallet).await?;
+
+        let state = Arc::new(Mutex::new(State {
+            tree: BridgeTree::<MerkleNode, 32>::new(100),
+            merkle_roots: RocksColumn::<columns::MerkleRoots>::new(rocks.clone()),
+            merkle_anchor_window: 10,
+            last_slab_index: 0,
+            nullifiers: RocksColumn::<columns::Nullifiers>::new(rocks),
+            public_keys: vec![],
+            mint_vk: VerifyingKey::build(11, &MintContract::default()),
+            spend_vk: VerifyingKey::build(11, &SpendContract::default()),
+        }));
+
+        // The compact slab is rejected and nothing is applied
+        assert!(client.start_compact(state.clone(), Arc::new(Executor::new())).await.is_err());
+        assert_eq!(state.lock().await.last_slab_index, 0);
+        assert!(client.get_own_coins().await?.is_empty());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,174 @@
+use std::io;
+
+use crate::{
+    blockchain::Slab,
+    crypto::{coin::Coin, keypair::SecretKey, note::EncryptedNote, nullifier::Nullifier},
+    impl_vec,
+    util::serial::{Decodable, Encodable, VarInt},
+    Result,
+};
+
+use super::{slab_payload, state::StateUpdate};
+
+/// Output of a transaction stripped from its mint proof.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactOutput {
+    pub coin: Coin,
+    pub enc_note: EncryptedNote,
+}
+
+/// Light representation of a slab, holding only the data a client needs
+/// to scan for its own notes and to update its Merkle tree: the revealed
+/// nullifiers, and for each output the coin and the encrypted note
+/// (ephemeral public key and ciphertext). Proofs and signatures are left
+/// out, so a compact slab can't be verified on its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactSlab {
+    index: u64,
+    nullifiers: Vec<Nullifier>,
+    outputs: Vec<CompactOutput>,
+}
+
+impl CompactSlab {
+    pub fn new(index: u64, nullifiers: Vec<Nullifier>, outputs: Vec<CompactOutput>) -> Self {
+        Self { index, nullifiers, outputs }
+    }
+
+    /// Build a compact slab from a full slab by decoding its transactions.
+    pub fn from_slab(slab: &Slab) -> Result<Self> {
+        let txs = slab_payload::decode(&slab.get_payload())?;
+
+        let nullifiers = txs
+            .iter()
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.revealed.nullifier))
+            .collect();
+        let outputs = txs
+            .into_iter()
+            .flat_map(|tx| tx.outputs)
+            .map(|output| CompactOutput { coin: output.revealed.coin, enc_note: output.enc_note })
+            .collect();
+
+        Ok(Self { index: slab.get_index(), nullifiers, outputs })
+    }
+
+    pub fn get_index(&self) -> u64 {
+        self.index
+    }
+
+    /// Check if any of the outputs can be decrypted with one of the
+    /// given secret keys.
+    pub fn has_own_notes(&self, secret_keys: &[SecretKey]) -> bool {
+        self.outputs
+            .iter()
+            .any(|output| secret_keys.iter().any(|secret| output.enc_note.decrypt(secret).is_ok()))
+    }
+
+    /// Convert into a state update without verifying the transaction.
+    pub fn into_state_update(self) -> StateUpdate {
+        let mut coins = vec![];
+        let mut enc_notes = vec![];
+        for output in self.outputs {
+            coins.push(output.coin);
+            enc_notes.push(output.enc_note);
+        }
+
+        StateUpdate { nullifiers: self.nullifiers, coins, enc_notes }
+    }
+}
+
+impl Encodable for CompactOutput {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.coin.encode(&mut s)?;
+        len += self.enc_note.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CompactOutput {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self { coin: Decodable::decode(&mut d)?, enc_note: Decodable::decode(&mut d)? })
+    }
+}
+
+impl Encodable for CompactSlab {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.index.encode(&mut s)?;
+        len += self.nullifiers.encode(&mut s)?;
+        len += self.outputs.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CompactSlab {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            index: Decodable::decode(&mut d)?,
+            nullifiers: Decodable::decode(&mut d)?,
+            outputs: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl_vec!(Nullifier);
+impl_vec!(CompactOutput);
+
+#[cfg(test)]
+mod tests {
+    use group::ff::Field;
+    use pasta_curves::pallas;
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::{
+        crypto::{
+            keypair::Keypair,
+            note::Note,
+            types::{DrkCoinBlind, DrkSerial, DrkTokenId, DrkValueBlind},
+        },
+        util::serial::{deserialize, serialize},
+    };
+
+    fn output_for(keypair: &Keypair) -> CompactOutput {
+        let note = Note {
+            serial: DrkSerial::random(&mut OsRng),
+            value: 42,
+            token_id: DrkTokenId::random(&mut OsRng),
+            coin_blind: DrkCoinBlind::random(&mut OsRng),
+            value_blind: DrkValueBlind::random(&mut OsRng),
+        };
+
+        CompactOutput {
+            coin: Coin(pallas::Base::random(&mut OsRng)),
+            enc_note: note.encrypt(&keypair.public).unwrap(),
+        }
+    }
+
+    #[test]
+    fn compact_slab_encode_decode_test() {
+        let nullifier =
+            Nullifier::new(SecretKey::random(&mut OsRng), pallas::Base::random(&mut OsRng));
+        let outputs = vec![output_for(&Keypair::random(&mut OsRng))];
+        let compact_slab = CompactSlab::new(7, vec![nullifier], outputs);
+
+        let compact_slab2: CompactSlab = deserialize(&serialize(&compact_slab)).unwrap();
+        assert_eq!(compact_slab, compact_slab2);
+        assert_eq!(compact_slab2.get_index(), 7);
+    }
+
+    #[test]
+    fn has_own_notes_test() {
+        let ours = Keypair::random(&mut OsRng);
+        let theirs = Keypair::random(&mut OsRng);
+
+        let compact_slab = CompactSlab::new(1, vec![], vec![output_for(&theirs)]);
+        assert!(!compact_slab.has_own_notes(&[ours.secret]));
+        assert!(compact_slab.has_own_notes(&[ours.secret, theirs.secret]));
+
+        let compact_slab =
+            CompactSlab::new(1, vec![], vec![output_for(&theirs), output_for(&ours)]);
+        assert!(compact_slab.has_own_notes(&[ours.secret]));
+        assert!(!CompactSlab::new(1, vec![], vec![]).has_own_notes(&[ours.secret]));
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::{HashSet, VecDeque};
+
+use async_std::sync::{Arc, Mutex};
+use log::{debug, warn};
+
+use crate::{
+    blockchain::{Slab, SlabStore},
+    tx::Transaction,
+    Error, Result,
+};
+
+use super::{
+    slab_payload,
+    state::{state_transition, State, StateUpdate, VerifyFailed},
+};
+
+/// Maximum number of transactions waiting in the mempool.
+pub const MEMPOOL_MAX_SIZE: usize = 1000;
+
+/// Maximum number of transactions bundled into a single slab.
+pub const MEMPOOL_BUNDLE_SIZE: usize = 20;
+
+/// Interval in seconds between two bundles.
+pub const MEMPOOL_BUNDLE_INTERVAL: u32 = 10;
+
+/// Transactions waiting to be bundled into a slab.
+///
+/// Transactions are verified against the current state when they enter the
+/// mempool, and their nullifiers are reserved so no other pending
+/// transaction can spend the same coins. Transactions don't carry a fee
+/// yet, so they are bundled in arrival order.
+pub struct Mempool {
+    state: Arc<Mutex<State>>,
+    txs: VecDeque<Transaction>,
+    nullifiers: HashSet<[u8; 32]>,
+}
+
+impl Mempool {
+    pub fn new(state: Arc<Mutex<State>>) -> Self {
+        Self { state, txs: VecDeque::new(), nullifiers: HashSet::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    pub fn contains(&self, tx: &Transaction) -> bool {
+        self.txs.contains(tx)
+    }
+
+    /// Transactions waiting in the mempool, in arrival order.
+    pub fn txs(&self) -> impl Iterator<Item = &Transaction> {
+        self.txs.iter()
+    }
+
+    /// Verify a transaction and add it to the mempool.
+    pub async fn add_tx(&mut self, tx: Transaction) -> Result<()> {
+        if self.txs.len() >= MEMPOOL_MAX_SIZE {
+            return Err(Error::ServicesError("Mempool is full"))
+        }
+
+        for (i, input) in tx.inputs.iter().enumerate() {
+            if self.nullifiers.contains(&input.revealed.nullifier.to_bytes()) {
+                return Err(VerifyFailed::DuplicateNullifier(i).into())
+            }
+        }
+
+        state_transition(&*self.state.lock().await, tx.clone())?;
+
+        for input in tx.inputs.iter() {
+            self.nullifiers.insert(input.revealed.nullifier.to_bytes());
+        }
+
+        debug!(target: "MEMPOOL", "Added transaction, {} pending", self.txs.len() + 1);
+        self.txs.push_back(tx);
+        Ok(())
+    }
+
+    /// Verify transactions ordered by someone else, such as the block of
+    /// a consensus leader, against the current state. `spent` holds the
+    /// nullifiers revealed by the transactions ordered before them which
+    /// aren't applied to the state yet, and is extended with the ones of
+    /// each transaction that passes.
+    pub async fn verify_txs(
+        &self,
+        txs: &[Transaction],
+        spent: &mut HashSet<[u8; 32]>,
+    ) -> Result<()> {
+        let state = self.state.lock().await;
+        for tx in txs {
+            for (i, input) in tx.inputs.iter().enumerate() {
+                if spent.contains(&input.revealed.nullifier.to_bytes()) {
+                    return Err(VerifyFailed::DuplicateNullifier(i).into())
+                }
+            }
+
+            state_transition(&*state, tx.clone())?;
+
+            for input in tx.inputs.iter() {
+                spent.insert(input.revealed.nullifier.to_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Take up to `MEMPOOL_BUNDLE_SIZE` transactions out of the mempool and
+    /// append them to the slabstore as a new slab. The transactions are
+    /// verified again, since their anchors might have expired while
+    /// waiting, and the state is updated with the ones that remain valid
+    /// once the slab is stored.
+    /// Returns `None` if no transaction was bundled.
+    pub async fn bundle(&mut self, slabstore: &SlabStore) -> Result<Option<Slab>> {
+        let mut state = self.state.lock().await;
+
+        let mut txs = vec![];
+        while txs.len() < MEMPOOL_BUNDLE_SIZE {
+            match self.txs.pop_front() {
+                Some(tx) => txs.push(tx),
+                None => break,
+            }
+        }
+
+        let (txs, updates, dropped) = verify_batch(&state, txs);
+        for tx in dropped.iter() {
+            self.release_nullifiers(tx);
+        }
+
+        if txs.is_empty() {
+            return Ok(None)
+        }
+
+        // The transactions go back to the mempool if the slab can't be
+        // stored, so the state never gets ahead of the slabstore.
+        let slab = match store_batch(&mut state, slabstore, &txs, updates).await {
+            Ok(slab) => slab,
+            Err(e) => {
+                for tx in txs.into_iter().rev() {
+                    self.txs.push_front(tx);
+                }
+                return Err(e)
+            }
+        };
+
+        for tx in txs.iter() {
+            self.release_nullifiers(tx);
+        }
+
+        debug!(target: "MEMPOOL",
+            "Bundled {} transactions into slab {}", txs.len(), slab.get_index()
+        );
+        Ok(Some(slab))
+    }
+
+    /// Append transactions ordered by someone else, such as a finalized
+    /// consensus block, to the slabstore as a new slab and apply them to
+    /// the state. The transactions which are no longer valid are left out,
+    /// the same way on every node following the same slabs. The mempool
+    /// drops the transactions that were included, and the ones spending
+    /// the same coins.
+    /// Returns `None` if no transaction remained.
+    pub async fn apply_txs(
+        &mut self,
+        txs: Vec<Transaction>,
+        slabstore: &SlabStore,
+    ) -> Result<Option<Slab>> {
+        let mut state = self.state.lock().await;
+
+        let (txs, updates, dropped) = verify_batch(&state, txs);
+        for tx in dropped.iter() {
+            warn!(target: "MEMPOOL", "Left out invalid ordered transaction");
+            self.release_nullifiers(tx);
+        }
+
+        if txs.is_empty() {
+            return Ok(None)
+        }
+
+        let slab = store_batch(&mut state, slabstore, &txs, updates).await?;
+
+        let spent: HashSet<[u8; 32]> = txs
+            .iter()
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.revealed.nullifier.to_bytes()))
+            .collect();
+        let (removed, kept): (VecDeque<Transaction>, VecDeque<Transaction>) =
+            self.txs.drain(..).partition(|tx| {
+                txs.contains(tx) ||
+                    tx.inputs
+                        .iter()
+                        .any(|input| spent.contains(&input.revealed.nullifier.to_bytes()))
+            });
+        self.txs = kept;
+        for tx in removed.iter() {
+            self.release_nullifiers(tx);
+        }
+
+        debug!(target: "MEMPOOL",
+            "Applied {} transactions as slab {}", txs.len(), slab.get_index()
+        );
+        Ok(Some(slab))
+    }
+
+    fn release_nullifiers(&mut self, tx: &Transaction) {
+        for input in tx.inputs.iter() {
+            self.nullifiers.remove(&input.revealed.nullifier.to_bytes());
+        }
+    }
+}
+
+/// Verify a batch of transactions in order. Returns the valid ones with
+/// their state updates, and the dropped ones, which are invalid or spend
+/// the same coins as an earlier transaction of the batch.
+fn verify_batch(
+    state: &State,
+    txs: Vec<Transaction>,
+) -> (Vec<Transaction>, Vec<StateUpdate>, Vec<Transaction>) {
+    let mut valid = vec![];
+    let mut updates = vec![];
+    let mut dropped = vec![];
+    let mut spent = HashSet::new();
+
+    for tx in txs {
+        let duplicate = tx
+            .inputs
+            .iter()
+            .any(|input| spent.contains(&input.revealed.nullifier.to_bytes()));
+        if duplicate {
+            warn!(target: "MEMPOOL", "Dropping transaction: spends a coin twice");
+            dropped.push(tx);
+            continue
+        }
+
+        match state_transition(state, tx.clone()) {
+            Ok(update) => {
+                for input in tx.inputs.iter() {
+                    spent.insert(input.revealed.nullifier.to_bytes());
+                }
+                valid.push(tx);
+                updates.push(update);
+            }
+            Err(e) => {
+                warn!(target: "MEMPOOL", "Dropping transaction: {}", e);
+                dropped.push(tx);
+            }
+        }
+    }
+
+    (valid, updates, dropped)
+}
+
+/// Store verified transactions as the next slab, then apply their updates
+/// to the state.
+async fn store_batch(
+    state: &mut State,
+    slabstore: &SlabStore,
+    txs: &[Transaction],
+    updates: Vec<StateUpdate>,
+) -> Result<Slab> {
+    let index = slabstore.get_last_index()? + 1;
+    let mut slab = Slab::new(slab_payload::encode(txs)?);
+    slab.set_index(index);
+
+    if slabstore.put(slab.clone())?.is_none() {
+        return Err(Error::SlabsStore(format!("Unable to store slab {}", index)))
+    }
+
+    for update in updates {
+        state.apply(update, index, vec![], None, None).await?;
+    }
+
+    Ok(slab)
+}
+
+#[cfg(test)]
+mod tests {
+    use group::ff::Field;
+    use incrementalmerkletree::{bridgetree::BridgeTree, Tree};
+    use rand::rngs::OsRng;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::{
+        blockchain::{rocks::columns, Rocks, RocksColumn},
+        crypto::{
+            keypair::Keypair,
+            merkle_node::MerkleNode,
+            proof::{ProvingKey, VerifyingKey},
+            types::DrkTokenId,
+        },
+        tx::{
+            TransactionBuilder, TransactionBuilderClearInputInfo, TransactionBuilderInputInfo,
+            TransactionBuilderOutputInfo,
+        },
+        util::serial::serialize,
+        zk::circuit::{MintContract, SpendContract},
+    };
+
+    struct TestWallet {
+        mint_pk: ProvingKey,
+        spend_pk: ProvingKey,
+        cashier: Keypair,
+        keypair: Keypair,
+        token_id: DrkTokenId,
+    }
+
+    impl TestWallet {
+        fn new() -> Self {
+            Self {
+                mint_pk: ProvingKey::build(11, &MintContract::default()),
+                spend_pk: ProvingKey::build(11, &SpendContract::default()),
+                cashier: Keypair::random(&mut OsRng),
+                keypair: Keypair::random(&mut OsRng),
+                token_id: DrkTokenId::random(&mut OsRng),
+            }
+        }
+
+        /// Deposit from a cashier to our keypair.
+        fn deposit(&self, cashier: &Keypair, value: u64) -> Result<Transaction> {
+            TransactionBuilder {
+                clear_inputs: vec![TransactionBuilderClearInputInfo {
+                    value,
+                    token_id: self.token_id,
+                    signature_secret: cashier.secret,
+                }],
+                inputs: vec![],
+                outputs: vec![TransactionBuilderOutputInfo {
+                    value,
+                    token_id: self.token_id,
+                    public: self.keypair.public,
+                }],
+            }
+            .build(&self.mint_pk, &self.spend_pk)
+        }
+
+        /// Spend the first output of an applied deposit to someone else.
+        fn spend(&self, state: &State, deposit: &Transaction) -> Result<Transaction> {
+            let output = &deposit.outputs[0];
+            let note = output.enc_note.decrypt(&self.keypair.secret)?;
+            let (leaf_position, merkle_path) =
+                state.tree.authentication_path(&MerkleNode(output.revealed.coin.0)).unwrap();
+
+            TransactionBuilder {
+                clear_inputs: vec![],
+                inputs: vec![TransactionBuilderInputInfo {
+                    leaf_position,
+                    merkle_path,
+                    secret: self.keypair.secret,
+                    note: note.clone(),
+                }],
+                outputs: vec![TransactionBuilderOutputInfo {
+                    value: note.value,
+                    token_id: note.token_id,
+                    public: Keypair::random(&mut OsRng).public,
+                }],
+            }
+            .build(&self.mint_pk, &self.spend_pk)
+        }
+    }
+
+    /// Mempool over a temporary database, removed along with the returned
+    /// directory.
+    fn test_mempool(wallet: &TestWallet) -> Result<(Mempool, Arc<SlabStore>, TempDir)> {
+        let dir = tempdir()?;
+        let rocks = Rocks::new(dir.path())?;
+
+        let state = State {
+            tree: BridgeTree::<MerkleNode, 32>::new(100),
+            merkle_roots: RocksColumn::<columns::MerkleRoots>::new(rocks.clone()),
+            merkle_anchor_window: 10,
+            last_slab_index: 0,
+            nullifiers: RocksColumn::<columns::Nullifiers>::new(rocks.clone()),
+            public_keys: vec![wallet.cashier.public],
+            mint_vk: VerifyingKey::build(11, &MintContract::default()),
+            spend_vk: VerifyingKey::build(11, &SpendContract::default()),
+        };
+
+        let slabstore = SlabStore::new(RocksColumn::<columns::Slabs>::new(rocks))?;
+        Ok((Mempool::new(Arc::new(Mutex::new(state))), slabstore, dir))
+    }
+
+    #[async_std::test]
+    async fn conflicting_nullifiers() -> Result<()> {
+        let wallet = TestWallet::new();
+        let (mut mempool, slabstore, _dir) = test_mempool(&wallet)?;
+
+        // A coin of ours in a previous slab
+        let deposit = wallet.deposit(&wallet.cashier, 42)?;
+        {
+            let mut state = mempool.state.lock().await;
+            let update = state_transition(&*state, deposit.clone())?;
+            state.apply(update, 1, vec![wallet.keypair.secret], None, None).await?;
+        }
+        let mut slab = Slab::new(slab_payload::encode(&[deposit.clone()])?);
+        slab.set_index(1);
+        slabstore.put(slab)?;
+
+        // Two transactions spending the same coin
+        let (spend_a, spend_b) = {
+            let state = mempool.state.lock().await;
+            (wallet.spend(&state, &deposit)?, wallet.spend(&state, &deposit)?)
+        };
+        mempool.add_tx(spend_a).await?;
+        assert!(mempool.add_tx(spend_b.clone()).await.is_err());
+        assert_eq!(mempool.len(), 1);
+
+        // Once bundled, the nullifier is rejected by the state instead
+        assert!(mempool.bundle(&slabstore).await?.is_some());
+        assert!(mempool.nullifiers.is_empty());
+        assert!(mempool.add_tx(spend_b).await.is_err());
+        assert!(mempool.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn bundle_order() -> Result<()> {
+        let wallet = TestWallet::new();
+        let (mut mempool, slabstore, _dir) = test_mempool(&wallet)?;
+
+        let revoked = Keypair::random(&mut OsRng);
+        mempool.state.lock().await.public_keys.push(revoked.public);
+
+        mempool.add_tx(wallet.deposit(&wallet.cashier, 1)?).await?;
+        mempool.add_tx(wallet.deposit(&revoked, 2)?).await?;
+        mempool.add_tx(wallet.deposit(&wallet.cashier, 3)?).await?;
+        mempool.add_tx(wallet.deposit(&wallet.cashier, 4)?).await?;
+        assert_eq!(mempool.len(), 4);
+
+        // Transactions no longer valid by bundle time are dropped
+        mempool.state.lock().await.public_keys.retain(|key| *key != revoked.public);
+
+        let slab = mempool.bundle(&slabstore).await?.unwrap();
+        assert_eq!(slab.get_index(), 1);
+        assert_eq!(slabstore.get_last_index()?, 1);
+        assert_eq!(mempool.state.lock().await.last_slab_index, 1);
+        assert!(mempool.is_empty());
+
+        // Transactions are bundled in arrival order
+        let values: Vec<u64> = slab_payload::decode(&slab.get_payload())?
+            .iter()
+            .map(|tx| tx.clear_inputs[0].value)
+            .collect();
+        assert_eq!(values, vec![1, 3, 4]);
+
+        // Nothing left to bundle
+        assert!(mempool.bundle(&slabstore).await?.is_none());
+        assert_eq!(slabstore.get_last_index()?, 1);
+
+        // Slabs written before bundling hold a single transaction
+        let legacy = slab_payload::decode(&serialize(&wallet.deposit(&wallet.cashier, 6)?))?;
+        assert_eq!(legacy.len(), 1);
+        assert_eq!(legacy[0].clear_inputs[0].value, 6);
+
+        Ok(())
+    }
+}
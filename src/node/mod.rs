@@ -1,5 +1,9 @@
 pub mod client;
+pub mod compact_slab;
+pub mod mempool;
+pub mod nullifier_filter;
 pub mod service;
+pub mod slab_payload;
 pub mod state;
 
 #[cfg(feature = "wallet")]
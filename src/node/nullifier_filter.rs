@@ -0,0 +1,183 @@
+use std::io;
+
+use blake2b_simd::Params;
+
+use crate::{
+    crypto::nullifier::Nullifier,
+    util::serial::{Decodable, Encodable},
+    Result,
+};
+
+/// Number of slabs covered by a single nullifier filter.
+pub const NULLIFIER_FILTER_RANGE: u64 = 100;
+
+/// Size of the filter bit set in bytes.
+const FILTER_SIZE: usize = 8192;
+
+/// Number of hash functions used to set and test bits.
+const FILTER_HASHES: u8 = 4;
+
+const FILTER_PERSONALIZATION: &[u8; 16] = b"DarkFi_NullFiltr";
+
+/// Bloom filter over the nullifiers revealed in a range of slabs.
+///
+/// If `may_contain` returns false, the nullifier was not revealed in any
+/// slab the filter was built from. A positive answer can be a false
+/// positive, in which case the client has to fetch the slabs of the range
+/// to be sure.
+///
+/// Filters are built and served by the gateway and are not committed to
+/// by the slabs or the merkle tree, so they are not proofs of
+/// non-inclusion: an answer is only as trustworthy as the gateway that
+/// served the filter. Clients only use them to leave out the coins which
+/// might have been spent when building a transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NullifierFilter {
+    /// Index of the range, the filter covers slabs
+    /// `[range * NULLIFIER_FILTER_RANGE, (range + 1) * NULLIFIER_FILTER_RANGE)`
+    range: u64,
+    /// Index of the last slab added to the filter
+    last_index: u64,
+    bits: Vec<u8>,
+}
+
+impl NullifierFilter {
+    pub fn new(range: u64) -> Self {
+        Self { range, last_index: 0, bits: vec![0; FILTER_SIZE] }
+    }
+
+    /// Returns the index of the range a slab belongs to.
+    pub fn range_of(slab_index: u64) -> u64 {
+        slab_index / NULLIFIER_FILTER_RANGE
+    }
+
+    pub fn get_range(&self) -> u64 {
+        self.range
+    }
+
+    pub fn get_last_index(&self) -> u64 {
+        self.last_index
+    }
+
+    /// Add the nullifiers revealed in the given slab to the filter.
+    pub fn insert_slab(&mut self, slab_index: u64, nullifiers: &[Nullifier]) {
+        for nullifier in nullifiers {
+            for bit in Self::bit_positions(nullifier) {
+                self.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        if slab_index > self.last_index {
+            self.last_index = slab_index;
+        }
+    }
+
+    /// Mark a slab whose nullifiers could not be read. Every nullifier may
+    /// be contained in the filter afterwards, so clients fall back to
+    /// fetching the slabs of the range.
+    pub fn insert_unknown_slab(&mut self, slab_index: u64) {
+        self.bits.iter_mut().for_each(|byte| *byte = 0xff);
+
+        if slab_index > self.last_index {
+            self.last_index = slab_index;
+        }
+    }
+
+    /// Check if the nullifier might have been revealed in this range.
+    pub fn may_contain(&self, nullifier: &Nullifier) -> bool {
+        Self::bit_positions(nullifier).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_positions(nullifier: &Nullifier) -> impl Iterator<Item = usize> {
+        let bytes = nullifier.to_bytes();
+        (0..FILTER_HASHES).map(move |i| {
+            let hash = Params::new()
+                .hash_length(8)
+                .personal(FILTER_PERSONALIZATION)
+                .to_state()
+                .update(&bytes)
+                .update(&[i])
+                .finalize();
+
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(hash.as_bytes());
+            (u64::from_le_bytes(buf) % (FILTER_SIZE as u64 * 8)) as usize
+        })
+    }
+}
+
+/// Answer to a spentness check against the nullifier filters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpentStatus {
+    /// No filter contains the nullifier.
+    NotSpent,
+    /// A filter contains the nullifier, which can be a false positive.
+    MaybeSpent,
+    /// Some slabs are not covered by a filter, nothing can be said.
+    Unknown,
+}
+
+impl Encodable for NullifierFilter {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.range.encode(&mut s)?;
+        len += self.last_index.encode(&mut s)?;
+        len += self.bits.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for NullifierFilter {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let range = Decodable::decode(&mut d)?;
+        let last_index = Decodable::decode(&mut d)?;
+        let bits: Vec<u8> = Decodable::decode(&mut d)?;
+
+        if bits.len() != FILTER_SIZE {
+            return Err(crate::Error::DecodeError("Invalid nullifier filter size"))
+        }
+
+        Ok(Self { range, last_index, bits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use group::ff::Field;
+    use pasta_curves::pallas;
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::{
+        crypto::keypair::SecretKey,
+        util::serial::{deserialize, serialize},
+    };
+
+    #[test]
+    fn nullifier_filter_test() {
+        let nullifiers: Vec<Nullifier> = (0..50)
+            .map(|_| {
+                Nullifier::new(SecretKey::random(&mut OsRng), pallas::Base::random(&mut OsRng))
+            })
+            .collect();
+
+        let mut filter = NullifierFilter::new(NullifierFilter::range_of(142));
+        assert_eq!(filter.get_range(), 1);
+
+        filter.insert_slab(142, &nullifiers);
+        assert_eq!(filter.get_last_index(), 142);
+
+        for nullifier in nullifiers.iter() {
+            assert!(filter.may_contain(nullifier));
+        }
+
+        let filter2: NullifierFilter = deserialize(&serialize(&filter)).unwrap();
+        assert_eq!(filter, filter2);
+
+        let unknown =
+            Nullifier::new(SecretKey::random(&mut OsRng), pallas::Base::random(&mut OsRng));
+        filter.insert_unknown_slab(143);
+        assert_eq!(filter.get_last_index(), 143);
+        assert!(filter.may_contain(&unknown));
+    }
+}
@@ -1,57 +1,210 @@
 use std::{
+    collections::HashSet,
     convert::From,
     net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use async_executor::Executor;
-use log::debug;
+use async_std::{future::timeout, sync::Mutex};
+use log::{debug, error, info, warn};
 use url::Url;
 
-use super::reqrep::{PeerId, Publisher, RepProtocol, Reply, ReqProtocol, Request, Subscriber};
+use super::reqrep::{
+    PeerId, Publisher, RepProtocol, Reply, ReqError, ReqMetrics, ReqProtocol, Request, Subscriber,
+};
 use crate::{
     blockchain::{rocks::columns, RocksColumn, Slab, SlabStore},
-    util::serial::{deserialize, serialize},
+    crypto::{
+        keypair::{PublicKey, SecretKey},
+        nullifier::Nullifier,
+    },
+    node::{
+        compact_slab::CompactSlab,
+        mempool::{Mempool, MEMPOOL_BUNDLE_INTERVAL},
+        nullifier_filter::{NullifierFilter, SpentStatus, NULLIFIER_FILTER_RANGE},
+        slab_payload,
+        state::State,
+    },
+    tx::Transaction,
+    util::{
+        serial::{deserialize, serialize, Decodable},
+        sleep,
+    },
     Error, Result,
 };
 
 pub type GatewaySlabsSubscriber = async_channel::Receiver<Slab>;
 
+/// Seconds the subscriber waits for a published slab before checking
+/// whether the client failed over to another gateway.
+const SUBSCRIBER_CHECK_INTERVAL: u64 = 10;
+
 #[repr(u8)]
 enum GatewayError {
     NoError,
     UpdateIndex,
     IndexNotExist,
+    InvalidTransaction,
 }
 
+#[derive(Clone, Copy)]
 #[repr(u8)]
 enum GatewayCommand {
     PutSlab,
     GetSlab,
     GetLastIndex,
+    GetNullifierFilter,
+    GetCompactSlab,
+    GetSlabHash,
+    GetOrderingKey,
+    GetCompactSlabHash,
+}
+
+impl GatewayCommand {
+    /// A PutSlab that timed out might have been added to the mempool
+    /// already, repeating it would get the transaction rejected.
+    fn is_idempotent(&self) -> bool {
+        !matches!(self, Self::PutSlab)
+    }
 }
 
+/// Gateway serving slabs over ZeroMQ.
+///
+/// Slabs are ordered by a single gateway, the primary, which bundles the
+/// transactions of its mempool into slabs. Other gateways run as followers
+/// of the primary: they forward the transactions put to them and replicate
+/// its slabs, verifying each of them before storing it. Every gateway
+/// reports the public key of the gateway ordering its slabs, so clients
+/// can check their gateways serve the same chain.
 pub struct GatewayService {
     slabstore: Arc<SlabStore>,
+    /// Filters are updated with a read-modify-write, the lock serialises
+    /// the updates.
+    nullifier_filters: Arc<Mutex<RocksColumn<columns::NullifierFilters>>>,
+    state: Arc<Mutex<State>>,
+    mempool: Arc<Mutex<Mempool>>,
+    /// Connection to the primary gateway when running as a follower
+    upstream: Option<Arc<Mutex<ReqProtocol>>>,
+    /// Public key of the gateway ordering the slabs
+    ordering_key: PublicKey,
     addr: SocketAddr,
     pub_addr: SocketAddr,
+    secret: SecretKey,
+    stop_signal: (async_channel::Sender<()>, async_channel::Receiver<()>),
 }
 
 impl GatewayService {
+    /// `secret` is the gateway's static key. Clients pin the matching
+    /// public key to authenticate the gateway and its published slabs.
+    /// `upstream` holds the protocol endpoint and public key of the primary
+    /// gateway when running as a follower.
     pub fn new(
         addr: SocketAddr,
         pub_addr: SocketAddr,
+        secret: SecretKey,
         rocks: RocksColumn<columns::Slabs>,
+        nullifier_filters: RocksColumn<columns::NullifierFilters>,
+        state: Arc<Mutex<State>>,
+        upstream: Option<(SocketAddr, PublicKey)>,
     ) -> Result<Arc<GatewayService>> {
         let slabstore = SlabStore::new(rocks)?;
+        let nullifier_filters = Arc::new(Mutex::new(nullifier_filters));
+        let mempool = Arc::new(Mutex::new(Mempool::new(state.clone())));
 
-        Ok(Arc::new(GatewayService { slabstore, addr, pub_addr }))
+        let (upstream, ordering_key) = match upstream {
+            Some((upstream_addr, upstream_public)) => {
+                let protocol = ReqProtocol::new(
+                    upstream_addr,
+                    String::from("GATEWAY UPSTREAM"),
+                    upstream_public,
+                );
+                (Some(Arc::new(Mutex::new(protocol))), upstream_public)
+            }
+            None => (None, PublicKey::from_secret(secret)),
+        };
+
+        Ok(Arc::new(GatewayService {
+            slabstore,
+            nullifier_filters,
+            state,
+            mempool,
+            upstream,
+            ordering_key,
+            addr,
+            pub_addr,
+            secret,
+            stop_signal: async_channel::unbounded(),
+        }))
+    }
+
+    /// Rebuild the state from the stored slabs. Slabs were verified before
+    /// being stored, so they are applied without verification.
+    async fn restore_state(&self) -> Result<()> {
+        let last_index = self.state.lock().await.restore(&self.slabstore).await?;
+        info!(target: "GATEWAY DAEMON", "Restored state up to slab {}", last_index);
+        Ok(())
+    }
+
+    /// Add the stored slabs missing from the nullifier filters, for slabs
+    /// stored before the filters existed or before a crash.
+    async fn backfill_nullifier_filters(&self) -> Result<()> {
+        let last_index = self.slabstore.get_last_index()?;
+        let nullifier_filters = self.nullifier_filters.lock().await;
+
+        let mut current: Option<(NullifierFilter, bool)> = None;
+
+        for index in 1..(last_index + 1) {
+            let range = NullifierFilter::range_of(index);
+
+            if current.as_ref().map_or(true, |(filter, _)| filter.get_range() != range) {
+                if let Some((filter, true)) = current.take() {
+                    nullifier_filters.put(filter.get_range(), filter)?;
+                }
+
+                let filter = match nullifier_filters.get(range)? {
+                    Some(filter) => deserialize(&filter)?,
+                    None => NullifierFilter::new(range),
+                };
+                current = Some((filter, false));
+            }
+
+            let (filter, updated) = current.as_mut().unwrap();
+            if filter.get_last_index() >= index {
+                continue
+            }
+
+            let slab: Slab = match self.slabstore.get(serialize(&index))? {
+                Some(slab) => deserialize(&slab)?,
+                None => break,
+            };
+
+            Self::add_slab_to_filter(filter, index, &slab);
+            *updated = true;
+        }
+
+        if let Some((filter, true)) = current {
+            info!(
+                target: "GATEWAY DAEMON",
+                "Backfilled nullifier filters up to slab {}", filter.get_last_index()
+            );
+            nullifier_filters.put(filter.get_range(), filter)?;
+        }
+
+        Ok(())
     }
 
     pub async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
         let service_name = String::from("GATEWAY DAEMON");
 
-        let mut protocol = RepProtocol::new(self.addr, service_name.clone());
+        self.restore_state().await?;
+        self.backfill_nullifier_filters().await?;
+
+        let mut protocol = RepProtocol::new(self.addr, service_name.clone(), self.secret);
 
         let (send, recv) = protocol.start().await?;
 
@@ -59,48 +212,144 @@ impl GatewayService {
         let publisher_task = executor.spawn(Self::start_publisher(
             self.pub_addr,
             service_name,
+            self.secret,
             publish_recv_queue.clone(),
         ));
 
-        let handle_request_task = executor.spawn(self.handle_request_loop(
-            send.clone(),
-            recv.clone(),
-            publish_queue.clone(),
-            executor.clone(),
-        ));
+        // Followers replicate the slabs of the primary instead of
+        // bundling their own
+        let bundle_task = match &self.upstream {
+            Some(upstream) => {
+                upstream.lock().await.start().await?;
+                executor.spawn(self.clone().replicate_loop(publish_queue.clone()))
+            }
+            None => executor.spawn(self.clone().bundle_loop(publish_queue.clone())),
+        };
 
-        protocol.run(executor.clone()).await?;
+        let stop_signal = self.stop_signal.1.clone();
+        let handle_request_task =
+            executor.spawn(self.handle_request_loop(send.clone(), recv.clone(), executor.clone()));
+
+        protocol.run(stop_signal).await?;
 
         let _ = publisher_task.cancel().await;
+        let _ = bundle_task.cancel().await;
         let _ = handle_request_task.cancel().await;
         Ok(())
     }
 
+    /// Stop serving requests, start() returns once the service stopped.
+    pub async fn stop(&self) {
+        self.stop_signal.0.close();
+    }
+
     async fn start_publisher(
         pub_addr: SocketAddr,
         service_name: String,
+        secret: SecretKey,
         publish_recv_queue: async_channel::Receiver<Vec<u8>>,
     ) -> Result<()> {
-        let mut publisher = Publisher::new(pub_addr, service_name);
+        let mut publisher = Publisher::new(pub_addr, service_name, secret);
         publisher.start(publish_recv_queue).await?;
         Ok(())
     }
 
+    /// Periodically bundle the pending transactions into a new slab,
+    /// store it and publish it to the subscribers.
+    async fn bundle_loop(
+        self: Arc<Self>,
+        publish_queue: async_channel::Sender<Vec<u8>>,
+    ) -> Result<()> {
+        loop {
+            sleep(MEMPOOL_BUNDLE_INTERVAL).await;
+
+            if let Err(e) = self.bundle(&publish_queue).await {
+                error!(target: "GATEWAY DAEMON", "Unable to bundle transactions: {}", e);
+            }
+        }
+    }
+
+    async fn bundle(&self, publish_queue: &async_channel::Sender<Vec<u8>>) -> Result<()> {
+        let slab = match self.mempool.lock().await.bundle(&self.slabstore).await? {
+            Some(slab) => slab,
+            None => return Ok(()),
+        };
+
+        Self::update_nullifier_filter(&self.nullifier_filters, slab.get_index(), &slab).await?;
+
+        // publish to all subscribes
+        publish_queue.send(serialize(&slab)).await?;
+        Ok(())
+    }
+
+    /// Periodically fetch the new slabs of the primary gateway, verify
+    /// them, store them and publish them to the subscribers.
+    async fn replicate_loop(
+        self: Arc<Self>,
+        publish_queue: async_channel::Sender<Vec<u8>>,
+    ) -> Result<()> {
+        loop {
+            sleep(MEMPOOL_BUNDLE_INTERVAL).await;
+
+            if let Err(e) = self.replicate(&publish_queue).await {
+                error!(target: "GATEWAY DAEMON", "Unable to replicate slabs: {}", e);
+            }
+        }
+    }
+
+    async fn replicate(&self, publish_queue: &async_channel::Sender<Vec<u8>>) -> Result<()> {
+        let upstream = self.upstream.as_ref().unwrap();
+        let mut upstream = upstream.lock().await;
+
+        let last_index: u64 =
+            deserialize(&upstream.request(GatewayCommand::GetLastIndex as u8, vec![]).await?)?;
+
+        for index in (self.slabstore.get_last_index()? + 1)..(last_index + 1) {
+            let slab: Slab = deserialize(
+                &upstream.request(GatewayCommand::GetSlab as u8, serialize(&index)).await?,
+            )?;
+            if slab.get_index() != index {
+                return Err(Error::ServicesError("Primary gateway sent the wrong slab"))
+            }
+
+            // The slab is only stored if all its transactions are valid,
+            // so a faulty primary can't make us serve invalid slabs
+            let txs = slab_payload::decode(&slab.get_payload())?;
+            self.mempool.lock().await.verify_txs(&txs, &mut HashSet::new()).await?;
+
+            if self.slabstore.put(slab.clone())?.is_none() {
+                return Err(Error::SlabsStore(format!("Unable to store slab {}", index)))
+            }
+            self.state.lock().await.restore(&self.slabstore).await?;
+
+            Self::update_nullifier_filter(&self.nullifier_filters, index, &slab).await?;
+            publish_queue.send(serialize(&slab)).await?;
+            debug!(target: "GATEWAY DAEMON", "Replicated slab {}", index);
+        }
+
+        Ok(())
+    }
+
     async fn handle_request_loop(
         self: Arc<Self>,
         send_queue: async_channel::Sender<(PeerId, Reply)>,
         recv_queue: async_channel::Receiver<(PeerId, Request)>,
-        publish_queue: async_channel::Sender<Vec<u8>>,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         while let Ok(msg) = recv_queue.recv().await {
             let slabstore = self.slabstore.clone();
+            let nullifier_filters = self.nullifier_filters.clone();
+            let mempool = self.mempool.clone();
+            let upstream = self.upstream.clone();
             let _ = executor
                 .spawn(Self::handle_request(
                     msg,
                     slabstore,
+                    nullifier_filters,
+                    mempool,
+                    upstream,
+                    self.ordering_key,
                     send_queue.clone(),
-                    publish_queue.clone(),
                 ))
                 .detach();
         }
@@ -110,8 +359,11 @@ impl GatewayService {
     async fn handle_request(
         msg: (PeerId, Request),
         slabstore: Arc<SlabStore>,
+        nullifier_filters: Arc<Mutex<RocksColumn<columns::NullifierFilters>>>,
+        mempool: Arc<Mutex<Mempool>>,
+        upstream: Option<Arc<Mutex<ReqProtocol>>>,
+        ordering_key: PublicKey,
         send_queue: async_channel::Sender<(PeerId, Reply)>,
-        publish_queue: async_channel::Sender<Vec<u8>>,
     ) -> Result<()> {
         let request = msg.1;
         let peer = msg.0;
@@ -119,22 +371,30 @@ impl GatewayService {
             0 => {
                 debug!(target: "GATEWAY DAEMON", "Received putslab msg");
                 // PUTSLAB
-                let slab = request.get_payload();
-
-                // add to slabstore
-                let error = slabstore.put(deserialize(&slab)?)?;
+                let slab: Slab = deserialize(&request.get_payload())?;
 
                 let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
 
-                if error.is_none() {
-                    reply.set_error(GatewayError::UpdateIndex as u32);
+                // add to mempool, the transaction gets into a slab with
+                // the next bundle. Followers forward it to the primary.
+                let result = match (Transaction::decode(&slab.get_payload()[..]), upstream) {
+                    (Ok(_), Some(upstream)) => upstream
+                        .lock()
+                        .await
+                        .request_once(GatewayCommand::PutSlab as u8, serialize(&slab))
+                        .await
+                        .map(|_| ()),
+                    (Ok(tx), None) => mempool.lock().await.add_tx(tx).await,
+                    (Err(e), _) => Err(e),
+                };
+
+                if let Err(e) = result {
+                    warn!(target: "GATEWAY DAEMON", "Rejected transaction: {}", e);
+                    reply.set_error(GatewayError::InvalidTransaction as u32);
                 }
 
                 // send reply
                 send_queue.send((peer, reply)).await?;
-
-                // publish to all subscribes
-                publish_queue.send(slab).await?;
             }
             1 => {
                 debug!(target: "GATEWAY DAEMON", "Received getslab msg");
@@ -162,70 +422,297 @@ impl GatewayService {
 
                 // GETLASTINDEX
             }
+            3 => {
+                debug!(target: "GATEWAY DAEMON", "Received getnullifierfilter msg");
+                let range: u64 = deserialize(&request.get_payload())?;
+                let filter = nullifier_filters.lock().await.get(range)?;
+
+                let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
+
+                if let Some(payload) = filter {
+                    reply.set_payload(payload);
+                } else {
+                    reply.set_error(GatewayError::IndexNotExist as u32);
+                }
+
+                send_queue.send((peer, reply)).await?;
+
+                // GETNULLIFIERFILTER
+            }
+            4 | 7 => {
+                debug!(target: "GATEWAY DAEMON", "Received getcompactslab msg");
+                let index = request.get_payload();
+                let slab = slabstore.get(index)?;
+
+                let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
+
+                match slab.map(|slab| CompactSlab::from_slab(&deserialize(&slab)?)) {
+                    // GETCOMPACTSLABHASH lets clients cross-check a
+                    // compact slab with the other gateways
+                    Some(Ok(compact_slab)) if request.get_command() == 7 => {
+                        reply.set_payload(slab_hash(&serialize(&compact_slab)))
+                    }
+                    Some(Ok(compact_slab)) => reply.set_payload(serialize(&compact_slab)),
+                    Some(Err(e)) => {
+                        warn!(target: "GATEWAY DAEMON", "Unable to build compact slab: {}", e);
+                        reply.set_error(GatewayError::IndexNotExist as u32);
+                    }
+                    None => reply.set_error(GatewayError::IndexNotExist as u32),
+                }
+
+                send_queue.send((peer, reply)).await?;
+
+                // GETCOMPACTSLAB, GETCOMPACTSLABHASH
+            }
+            5 => {
+                debug!(target: "GATEWAY DAEMON", "Received getslabhash msg");
+                let index = request.get_payload();
+                let slab = slabstore.get(index)?;
+
+                let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
+
+                if let Some(slab) = slab {
+                    reply.set_payload(slab_hash(&slab));
+                } else {
+                    reply.set_error(GatewayError::IndexNotExist as u32);
+                }
+
+                send_queue.send((peer, reply)).await?;
+
+                // GETSLABHASH
+            }
+            6 => {
+                debug!(target: "GATEWAY DAEMON", "Received getorderingkey msg");
+                let reply =
+                    Reply::from(&request, GatewayError::NoError as u32, serialize(&ordering_key));
+                send_queue.send((peer, reply)).await?;
+
+                // GETORDERINGKEY
+            }
             _ => return Err(Error::ServicesError("received wrong command")),
         }
         Ok(())
     }
+
+    /// Add the nullifiers revealed by a newly stored slab to the filter of
+    /// the slab range it belongs to.
+    async fn update_nullifier_filter(
+        nullifier_filters: &Mutex<RocksColumn<columns::NullifierFilters>>,
+        index: u64,
+        slab: &Slab,
+    ) -> Result<()> {
+        let nullifier_filters = nullifier_filters.lock().await;
+
+        let range = NullifierFilter::range_of(index);
+        let mut filter = match nullifier_filters.get(range)? {
+            Some(filter) => deserialize(&filter)?,
+            None => NullifierFilter::new(range),
+        };
+
+        Self::add_slab_to_filter(&mut filter, index, slab);
+        nullifier_filters.put(range, filter)?;
+        Ok(())
+    }
+
+    /// A slab whose payload can't be decoded marks the whole range as
+    /// unknown, so clients fetch the slabs instead of trusting the filter.
+    fn add_slab_to_filter(filter: &mut NullifierFilter, index: u64, slab: &Slab) {
+        match slab_payload::decode(&slab.get_payload()) {
+            Ok(txs) => {
+                let nullifiers: Vec<Nullifier> = txs
+                    .iter()
+                    .flat_map(|tx| tx.inputs.iter().map(|input| input.revealed.nullifier))
+                    .collect();
+                filter.insert_slab(index, &nullifiers);
+            }
+            Err(e) => {
+                warn!(target: "GATEWAY DAEMON", "Unable to decode slab {} payload: {}", index, e);
+                filter.insert_unknown_slab(index);
+            }
+        }
+    }
 }
 
-pub struct GatewayClient {
+/// Connection to a single gateway.
+struct GatewayEndpoint {
     protocol: ReqProtocol,
+    addr: SocketAddr,
+    sub_addr: SocketAddr,
+    public_key: PublicKey,
+}
+
+/// Client for a list of gateways. Requests go to the active gateway and
+/// fail over to the next one on error. The last index is the highest one
+/// a majority of the gateways agree on. Slabs and compact slabs fetched
+/// from the active gateway, or published by it, are cross-checked against
+/// the hashes of the others, and any disagreement between gateways is
+/// reported as an error. All the gateways must follow the same ordering gateway, which
+/// is checked when the client starts.
+pub struct GatewayClient {
+    gateways: Vec<GatewayEndpoint>,
+    /// Shared with the subscriber task, which follows the failovers
+    active: Arc<AtomicUsize>,
     slabstore: Arc<SlabStore>,
     gateway_slabs_sub_s: async_channel::Sender<Slab>,
     gateway_slabs_sub_rv: GatewaySlabsSubscriber,
     is_running: bool,
-    sub_addr: SocketAddr,
+    compact: bool,
 }
 
 impl GatewayClient {
-    pub fn new(addr: Url, sub_addr: Url, rocks: RocksColumn<columns::Slabs>) -> Result<Self> {
-        // TODO: We'll want differentiation between TCP and TLS here.
-        let addr_sock = (
-            addr.host()
-                .ok_or_else(|| Error::UrlParseError(format!("Missing host in {}", addr)))?
-                .to_string(),
-            addr.port().ok_or_else(|| Error::UrlParseError(format!("Missing port in {}", addr)))?,
-        )
-            .to_socket_addrs()?
-            .next()
-            .ok_or(Error::NoUrlFound)?;
-        let protocol = ReqProtocol::new(addr_sock, String::from("GATEWAY CLIENT"));
+    /// `gateway_addrs` holds the protocol and publisher endpoints of each
+    /// gateway, together with its pinned public key.
+    pub fn new(
+        gateway_addrs: Vec<(Url, Url, PublicKey)>,
+        rocks: RocksColumn<columns::Slabs>,
+    ) -> Result<Self> {
+        if gateway_addrs.is_empty() {
+            return Err(Error::NoUrlFound)
+        }
+
+        let mut gateways = vec![];
+        for (addr, sub_addr, public_key) in gateway_addrs {
+            let addr = url_to_socket_addr(&addr)?;
+            let sub_addr = url_to_socket_addr(&sub_addr)?;
+            let protocol = ReqProtocol::new(addr, String::from("GATEWAY CLIENT"), public_key);
+            gateways.push(GatewayEndpoint { protocol, addr, sub_addr, public_key });
+        }
 
         let slabstore = SlabStore::new(rocks)?;
 
         let (gateway_slabs_sub_s, gateway_slabs_sub_rv) = async_channel::unbounded::<Slab>();
 
-        let sub_addr_sock = (
-            sub_addr
-                .host()
-                .ok_or_else(|| Error::UrlParseError(format!("Missing host in {}", sub_addr)))?
-                .to_string(),
-            sub_addr
-                .port()
-                .ok_or_else(|| Error::UrlParseError(format!("Missing port in {}", sub_addr)))?,
-        )
-            .to_socket_addrs()?
-            .next()
-            .ok_or(Error::NoUrlFound)?;
-
         Ok(GatewayClient {
-            protocol,
+            gateways,
+            active: Arc::new(AtomicUsize::new(0)),
             slabstore,
             gateway_slabs_sub_s,
             gateway_slabs_sub_rv,
             is_running: false,
-            sub_addr: sub_addr_sock,
+            compact: false,
         })
     }
 
+    async fn start_protocols(&mut self) -> Result<()> {
+        let mut started = 0;
+        for gateway in self.gateways.iter_mut() {
+            match gateway.protocol.start().await {
+                Ok(()) => started += 1,
+                Err(e) => {
+                    warn!(target: "GATEWAY CLIENT", "Unable to connect to {}: {}", gateway.addr, e)
+                }
+            }
+        }
+
+        if started == 0 {
+            return Err(Error::ServicesError("Unable to connect to any gateway"))
+        }
+
+        self.check_ordering_key().await
+    }
+
+    /// Check that all the gateways serve slabs ordered by the same gateway.
+    /// Gateways following different primaries would hold different slabs
+    /// for the same index.
+    async fn check_ordering_key(&mut self) -> Result<()> {
+        let mut ordering_key: Option<PublicKey> = None;
+
+        for gateway in self.gateways.iter_mut() {
+            let rep = match handle_reply(
+                gateway.protocol.request(GatewayCommand::GetOrderingKey as u8, vec![]).await,
+            ) {
+                Ok(Some(rep)) => rep,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(target: "GATEWAY CLIENT", "Gateway {} failed: {}", gateway.addr, e);
+                    continue
+                }
+            };
+
+            let key: PublicKey = deserialize(&rep)?;
+            match ordering_key {
+                Some(ordering_key) if ordering_key != key => {
+                    error!(
+                        target: "GATEWAY CLIENT",
+                        "Gateway {} follows a different ordering gateway", gateway.addr
+                    );
+                    return Err(Error::ServicesError("Gateways follow different ordering gateways"))
+                }
+                _ => ordering_key = Some(key),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn set_active(&self, active: usize) {
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    /// Send a request to the active gateway, failing over to the next
+    /// gateways in the list until one of them replies. Requests which
+    /// aren't idempotent are sent once, and only fail over when they
+    /// couldn't be sent at all.
+    async fn request(
+        &mut self,
+        command: GatewayCommand,
+        payload: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut last_error = Error::ServicesError("All gateways failed");
+
+        for _ in 0..self.gateways.len() {
+            let active = self.active();
+            let gateway = &mut self.gateways[active];
+            let result = if command.is_idempotent() {
+                gateway.protocol.request(command as u8, payload.clone()).await
+            } else {
+                gateway.protocol.request_once(command as u8, payload.clone()).await
+            };
+
+            match handle_reply(result) {
+                Ok(rep) => return Ok(rep),
+                Err(e) => {
+                    let unsent = matches!(e, Error::ReqError(ReqError::Unreachable(..)));
+                    if !command.is_idempotent() && !unsent {
+                        return Err(e)
+                    }
+
+                    warn!(target: "GATEWAY CLIENT", "Gateway {} failed: {}", gateway.addr, e);
+                    self.set_active((active + 1) % self.gateways.len());
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
     pub async fn start(&mut self) -> Result<()> {
-        self.protocol.start().await?;
+        self.start_protocols().await?;
         self.sync().await?;
         self.is_running = true;
         Ok(())
     }
 
+    /// Start the client without downloading the full slabs. The caller
+    /// is expected to scan the chain using compact slabs.
+    pub async fn start_compact(&mut self) -> Result<()> {
+        self.start_protocols().await?;
+        self.compact = true;
+        self.is_running = true;
+        Ok(())
+    }
+
     pub async fn sync(&mut self) -> Result<u64> {
+        if self.compact {
+            // Full slabs are only fetched on demand in compact mode
+            return self.get_last_index().await
+        }
+
         debug!(target: "GATEWAY CLIENT", "Start Syncing");
 
         let local_last_index = self.slabstore.get_last_index()?;
@@ -255,14 +742,7 @@ impl GatewayClient {
     pub async fn get_slab(&mut self, index: u64) -> Result<Option<Slab>> {
         debug!(target: "GATEWAY CLIENT","Get slab");
 
-        let handle_error = Arc::new(handle_error);
-        let rep = self
-            .protocol
-            .request(GatewayCommand::GetSlab as u8, serialize(&index), handle_error)
-            .await?;
-
-        if let Some(slab) = rep {
-            let slab: Slab = deserialize(&slab)?;
+        if let Some(slab) = self.fetch_slab(index).await? {
             self.gateway_slabs_sub_s.send(slab.clone()).await?;
             self.slabstore.put(slab.clone())?;
             return Ok(Some(slab))
@@ -271,39 +751,152 @@ impl GatewayClient {
         Ok(None)
     }
 
-    pub async fn put_slab(&mut self, mut slab: Slab) -> Result<()> {
-        debug!(target: "GATEWAY CLIENT","Put slab");
+    /// Request a slab from the gateway without storing it or passing it to
+    /// the subscribers.
+    pub async fn fetch_slab(&mut self, index: u64) -> Result<Option<Slab>> {
+        debug!(target: "GATEWAY CLIENT","Fetch slab");
 
-        loop {
-            let last_index = self.sync().await?;
-            slab.set_index(last_index + 1);
-            let slab = serialize(&slab);
+        let rep = self.request(GatewayCommand::GetSlab, serialize(&index)).await?;
+
+        if let Some(slab) = rep {
+            let active = self.active();
+            let command = GatewayCommand::GetSlabHash;
+            cross_check_slab(&mut self.gateways, active, command, index, &slab).await?;
+            return Ok(Some(deserialize(&slab)?))
+        }
+
+        Ok(None)
+    }
+
+    pub async fn get_compact_slab(&mut self, index: u64) -> Result<Option<CompactSlab>> {
+        debug!(target: "GATEWAY CLIENT","Get compact slab");
 
-            let handle_error = Arc::new(handle_error);
+        let rep = self.request(GatewayCommand::GetCompactSlab, serialize(&index)).await?;
 
-            let rep = self
-                .protocol
-                .request(GatewayCommand::PutSlab as u8, slab.clone(), handle_error)
-                .await?;
+        if let Some(compact_slab) = rep {
+            let active = self.active();
+            let command = GatewayCommand::GetCompactSlabHash;
+            cross_check_slab(&mut self.gateways, active, command, index, &compact_slab).await?;
 
-            if rep.is_some() {
-                break
+            let compact_slab: CompactSlab = deserialize(&compact_slab)?;
+            if compact_slab.get_index() != index {
+                return Err(Error::ServicesError("Gateway sent the wrong compact slab"))
             }
+            return Ok(Some(compact_slab))
+        }
+
+        Ok(None)
+    }
+
+    pub async fn put_slab(&mut self, mut slab: Slab) -> Result<()> {
+        debug!(target: "GATEWAY CLIENT","Put slab");
+
+        // The gateway assigns the final index when the transaction is
+        // bundled into a slab.
+        let last_index = self.sync().await?;
+        slab.set_index(last_index + 1);
+
+        let rep = self.request(GatewayCommand::PutSlab, serialize(&slab)).await?;
+        if rep.is_none() {
+            return Err(Error::ServicesError("Transaction rejected by gateway"))
         }
         Ok(())
     }
 
+    /// Ask every gateway for its last index. The index returned is the
+    /// highest one reached by a majority of the gateways, so a single
+    /// gateway can't take over by claiming a higher index. The active
+    /// gateway is switched to one holding all the slabs up to it.
     pub async fn get_last_index(&mut self) -> Result<u64> {
         debug!(target: "GATEWAY CLIENT","Get last index");
 
-        let handle_error = Arc::new(handle_error);
+        let mut indexes: Vec<(usize, u64)> = vec![];
+
+        for (i, gateway) in self.gateways.iter_mut().enumerate() {
+            let rep = match handle_reply(
+                gateway.protocol.request(GatewayCommand::GetLastIndex as u8, vec![]).await,
+            ) {
+                Ok(rep) => rep,
+                Err(e) => {
+                    warn!(target: "GATEWAY CLIENT", "Gateway {} failed: {}", gateway.addr, e);
+                    continue
+                }
+            };
+
+            let index: u64 = match rep {
+                Some(index) => deserialize(&index)?,
+                None => 0,
+            };
+
+            debug!(target: "GATEWAY CLIENT", "Gateway {} last index: {}", gateway.addr, index);
+            indexes.push((i, index));
+        }
+
+        let quorum = self.gateways.len() / 2 + 1;
+        if indexes.len() < quorum {
+            return Err(Error::ServicesError("Not enough gateways replied"))
+        }
+
+        let mut sorted: Vec<u64> = indexes.iter().map(|(_, index)| *index).collect();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+        let last_index = sorted[quorum - 1];
+
+        if !indexes.iter().any(|(i, index)| *i == self.active() && *index >= last_index) {
+            // At least a quorum of gateways reached the index
+            self.set_active(indexes.iter().find(|(_, index)| *index >= last_index).unwrap().0);
+        }
+
+        Ok(last_index)
+    }
+
+    pub async fn get_nullifier_filter(&mut self, range: u64) -> Result<Option<NullifierFilter>> {
+        debug!(target: "GATEWAY CLIENT","Get nullifier filter");
+
+        let rep = self.request(GatewayCommand::GetNullifierFilter, serialize(&range)).await?;
+
+        if let Some(filter) = rep {
+            return Ok(Some(deserialize(&filter)?))
+        }
+
+        Ok(None)
+    }
 
-        let rep =
-            self.protocol.request(GatewayCommand::GetLastIndex as u8, vec![], handle_error).await?;
-        if let Some(index) = rep {
-            return deserialize(&index)
+    /// Check the gateway's nullifier filters for the slabs starting at
+    /// `from_index`, returning a status for each nullifier. The filters
+    /// are trusted as served by the gateway. Slabs that no filter covers
+    /// yet make the answer `Unknown`.
+    pub async fn nullifier_spent_status(
+        &mut self,
+        nullifiers: &[Nullifier],
+        from_index: u64,
+    ) -> Result<Vec<SpentStatus>> {
+        let last_index = self.get_last_index().await?;
+        let mut statuses = vec![SpentStatus::NotSpent; nullifiers.len()];
+
+        for range in NullifierFilter::range_of(from_index)..=NullifierFilter::range_of(last_index) {
+            // Slab indexes start at 1, range 0 covers no slab if none is stored
+            let range_last = ((range + 1) * NULLIFIER_FILTER_RANGE - 1).min(last_index);
+            if range_last == 0 {
+                continue
+            }
+
+            let filter = self.get_nullifier_filter(range).await?;
+            for (nullifier, status) in nullifiers.iter().zip(statuses.iter_mut()) {
+                if *status == SpentStatus::MaybeSpent {
+                    continue
+                }
+
+                match &filter {
+                    Some(filter) if filter.may_contain(nullifier) => {
+                        *status = SpentStatus::MaybeSpent
+                    }
+                    Some(filter) if filter.get_last_index() >= range_last => {}
+                    _ => *status = SpentStatus::Unknown,
+                }
+            }
         }
-        Ok(0)
+
+        Ok(statuses)
     }
 
     pub fn get_slabstore(&self) -> Arc<SlabStore> {
@@ -316,11 +909,31 @@ impl GatewayClient {
     ) -> Result<GatewaySlabsSubscriber> {
         debug!(target: "GATEWAY CLIENT", "Start subscriber");
 
-        let mut subscriber = Subscriber::new(self.sub_addr, String::from("GATEWAY CLIENT"));
-        subscriber.start().await?;
+        // The subscriber task cross-checks the published slabs over its
+        // own connections to the other gateways
+        let mut gateways = vec![];
+        for gateway in self.gateways.iter() {
+            let mut protocol =
+                ReqProtocol::new(gateway.addr, String::from("GATEWAY CLIENT"), gateway.public_key);
+            if let Err(e) = protocol.start().await {
+                warn!(target: "GATEWAY CLIENT", "Unable to connect to {}: {}", gateway.addr, e);
+            }
+            gateways.push(GatewayEndpoint {
+                protocol,
+                addr: gateway.addr,
+                sub_addr: gateway.sub_addr,
+                public_key: gateway.public_key,
+            });
+        }
+
+        let active = self.active();
+        let subscriber = subscribe(&gateways[active]).await?;
+
         executor
             .spawn(Self::subscribe_loop(
                 subscriber,
+                gateways,
+                self.active.clone(),
                 self.slabstore.clone(),
                 self.gateway_slabs_sub_s.clone(),
             ))
@@ -328,24 +941,221 @@ impl GatewayClient {
         Ok(self.gateway_slabs_sub_rv.clone())
     }
 
+    /// Receive the slabs published by the active gateway. When the client
+    /// fails over to another gateway, or the subscription fails, the loop
+    /// subscribes to the new active gateway and fetches the slabs it
+    /// missed in the meantime.
     async fn subscribe_loop(
         mut subscriber: Subscriber,
+        mut gateways: Vec<GatewayEndpoint>,
+        active: Arc<AtomicUsize>,
         slabstore: Arc<SlabStore>,
         gateway_slabs_sub_s: async_channel::Sender<Slab>,
     ) -> Result<()> {
         debug!(target: "GATEWAY CLIENT", "Start subscribe loop");
 
+        let mut current = active.load(Ordering::Relaxed);
+        let mut last_seen: Option<u64> = None;
+
         loop {
-            let slab = subscriber.fetch::<Slab>().await?;
-            debug!(target: "GATEWAY CLIENT", "Received new slab");
-            gateway_slabs_sub_s.send(slab.clone()).await?;
-            slabstore.put(slab)?;
+            let result =
+                timeout(Duration::from_secs(SUBSCRIBER_CHECK_INTERVAL), subscriber.fetch::<Slab>())
+                    .await;
+
+            match result {
+                Ok(Ok(slab)) => {
+                    debug!(target: "GATEWAY CLIENT", "Received new slab");
+                    let index = slab.get_index();
+                    let command = GatewayCommand::GetSlabHash;
+                    let slab_bytes = serialize(&slab);
+                    let checked =
+                        cross_check_slab(&mut gateways, current, command, index, &slab_bytes).await;
+                    match checked {
+                        Ok(()) => {
+                            last_seen = Some(index);
+                            gateway_slabs_sub_s.send(slab.clone()).await?;
+                            slabstore.put(slab)?;
+                        }
+                        Err(e) => warn!(target: "GATEWAY CLIENT",
+                            "Dropped published slab {}: {}", index, e
+                        ),
+                    }
+                }
+                Ok(Err(Error::SecureChannelError(e))) => {
+                    warn!(target: "GATEWAY CLIENT", "Dropped published slab: {}", e);
+                }
+                Ok(Err(e)) => {
+                    warn!(
+                        target: "GATEWAY CLIENT",
+                        "Subscription to {} failed: {}", gateways[current].addr, e
+                    );
+                    let next = (current + 1) % gateways.len();
+                    let _ = active.compare_exchange(
+                        current,
+                        next,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                }
+                // Nothing published, check whether the client failed over
+                Err(_) => {}
+            }
+
+            let new_active = active.load(Ordering::Relaxed);
+            if new_active == current {
+                continue
+            }
+
+            match subscribe(&gateways[new_active]).await {
+                Ok(new_subscriber) => {
+                    debug!(
+                        target: "GATEWAY CLIENT",
+                        "Subscribed to {}", gateways[new_active].addr
+                    );
+                    subscriber = new_subscriber;
+                    current = new_active;
+                }
+                Err(e) => {
+                    warn!(
+                        target: "GATEWAY CLIENT",
+                        "Unable to subscribe to {}: {}", gateways[new_active].addr, e
+                    );
+                    continue
+                }
+            }
+
+            // Fetch the slabs published while switching gateways
+            if let Some(last) = last_seen {
+                for index in (last + 1).. {
+                    let slab = match catch_up_slab(&mut gateways, current, index).await {
+                        Ok(Some(slab)) => slab,
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!(target: "GATEWAY CLIENT",
+                                "Unable to fetch slab {}: {}", index, e
+                            );
+                            break
+                        }
+                    };
+                    last_seen = Some(index);
+                    gateway_slabs_sub_s.send(slab.clone()).await?;
+                    slabstore.put(slab)?;
+                }
+            }
         }
     }
 
     pub fn is_running(&self) -> bool {
         self.is_running
     }
+
+    /// Request statistics for each configured gateway.
+    pub fn metrics(&self) -> Vec<(SocketAddr, ReqMetrics)> {
+        self.gateways.iter().map(|g| (g.addr, g.protocol.metrics().clone())).collect()
+    }
+}
+
+fn url_to_socket_addr(url: &Url) -> Result<SocketAddr> {
+    (
+        url.host()
+            .ok_or_else(|| Error::UrlParseError(format!("Missing host in {}", url)))?
+            .to_string(),
+        url.port().ok_or_else(|| Error::UrlParseError(format!("Missing port in {}", url)))?,
+    )
+        .to_socket_addrs()?
+        .next()
+        .ok_or(Error::NoUrlFound)
+}
+
+/// Compare the hash of a slab received from the `active` gateway with the
+/// hashes the other gateways return for the same index with
+/// `hash_command`, which is `GetSlabHash` for full slabs and
+/// `GetCompactSlabHash` for compact slabs. Gateways which fail or don't
+/// have the slab yet are skipped.
+async fn cross_check_slab(
+    gateways: &mut [GatewayEndpoint],
+    active: usize,
+    hash_command: GatewayCommand,
+    index: u64,
+    slab: &[u8],
+) -> Result<()> {
+    let hash = slab_hash(slab);
+    let active_addr = gateways[active].addr;
+
+    for (i, gateway) in gateways.iter_mut().enumerate() {
+        if i == active {
+            continue
+        }
+
+        let rep = match handle_reply(
+            gateway.protocol.request(hash_command as u8, serialize(&index)).await,
+        ) {
+            Ok(rep) => rep,
+            Err(e) => {
+                warn!(target: "GATEWAY CLIENT", "Gateway {} failed: {}", gateway.addr, e);
+                continue
+            }
+        };
+
+        if let Some(other_hash) = rep {
+            if other_hash != hash {
+                error!(
+                    target: "GATEWAY CLIENT",
+                    "GATEWAYS DISAGREE ON SLAB {}: {} and {} hold different slabs",
+                    index, active_addr, gateway.addr
+                );
+                return Err(Error::ServicesError("Gateways disagree on slab contents"))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Subscribe to the slabs published by a gateway, signed with its pinned
+/// public key.
+async fn subscribe(gateway: &GatewayEndpoint) -> Result<Subscriber> {
+    let mut subscriber =
+        Subscriber::new(gateway.sub_addr, String::from("GATEWAY CLIENT"), gateway.public_key);
+    subscriber.start().await?;
+    Ok(subscriber)
+}
+
+/// Fetch a slab from the `active` gateway and cross-check it with the
+/// others. Returns `None` if the gateway doesn't have it yet.
+async fn catch_up_slab(
+    gateways: &mut [GatewayEndpoint],
+    active: usize,
+    index: u64,
+) -> Result<Option<Slab>> {
+    let rep = handle_reply(
+        gateways[active].protocol.request(GatewayCommand::GetSlab as u8, serialize(&index)).await,
+    )?;
+
+    match rep {
+        Some(slab) => {
+            cross_check_slab(gateways, active, GatewayCommand::GetSlabHash, index, &slab).await?;
+            Ok(Some(deserialize(&slab)?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn slab_hash(slab: &[u8]) -> Vec<u8> {
+    blake2b_simd::blake2b(slab).as_bytes().to_vec()
+}
+
+/// Replies carrying an error code are returned as `None`, so a missing
+/// slab or filter isn't treated as a gateway failure.
+fn handle_reply(rep: Result<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+    match rep {
+        Ok(payload) => Ok(Some(payload)),
+        Err(Error::ReqError(ReqError::Reply(status_code))) => {
+            handle_error(status_code);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
 }
 
 fn handle_error(status_code: u32) {
@@ -356,6 +1166,92 @@ fn handle_error(status_code: u32) {
         2 => {
             debug!(target: "GATEWAY SERVICE", "Reply has an Error: Index Not Exist");
         }
+        3 => {
+            debug!(target: "GATEWAY SERVICE", "Reply has an Error: Invalid Transaction");
+        }
         _ => {}
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use group::ff::Field;
+    use pasta_curves::pallas;
+    use rand::rngs::OsRng;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::blockchain::Rocks;
+
+    /// Replies of a test gateway, keyed by command and request payload
+    pub(crate) type TestReplies = HashMap<(u8, Vec<u8>), Vec<u8>>;
+
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    /// Start a gateway answering each request with the reply stored for
+    /// its command and payload, or with an `IndexNotExist` error. Returns
+    /// the endpoints and public key to pass to `GatewayClient::new`.
+    pub(crate) async fn start_test_gateway(replies: TestReplies) -> Result<(Url, Url, PublicKey)> {
+        let secret = SecretKey::random(&mut OsRng);
+        let addr = free_addr();
+
+        let mut service = RepProtocol::new(addr, "TEST GATEWAY".into(), secret);
+        let (reply_s, request_r) = service.start().await?;
+        let (stop_s, stop_r) = async_channel::unbounded::<()>();
+        async_std::task::spawn(async move {
+            let _stop_s = stop_s;
+            service.run(stop_r).await
+        });
+
+        async_std::task::spawn(async move {
+            while let Ok((peer, request)) = request_r.recv().await {
+                let key = (request.get_command(), request.get_payload());
+                let reply = match replies.get(&key) {
+                    Some(payload) => Reply::from(&request, 0, payload.clone()),
+                    None => Reply::from(&request, GatewayError::IndexNotExist as u32, vec![]),
+                };
+                reply_s.send((peer, reply)).await?;
+            }
+            Ok::<(), Error>(())
+        });
+
+        let url = Url::parse(&format!("tcp://{}", addr))?;
+        let pub_url = Url::parse(&format!("tcp://{}", free_addr()))?;
+        Ok((url, pub_url, PublicKey::from_secret(secret)))
+    }
+
+    fn random_nullifier() -> Nullifier {
+        Nullifier::new(SecretKey::random(&mut OsRng), pallas::Base::random(&mut OsRng))
+    }
+
+    #[async_std::test]
+    async fn nullifier_spent_status_test() -> Result<()> {
+        let spent = random_nullifier();
+        let unspent = random_nullifier();
+
+        let mut filter = NullifierFilter::new(0);
+        filter.insert_slab(1, &[spent]);
+
+        let mut replies = TestReplies::new();
+        replies.insert((GatewayCommand::GetLastIndex as u8, vec![]), serialize(&1u64));
+        replies.insert(
+            (GatewayCommand::GetNullifierFilter as u8, serialize(&0u64)),
+            serialize(&filter),
+        );
+        let gateway = start_test_gateway(replies).await?;
+
+        let dir = tempdir()?;
+        let rocks = Rocks::new(dir.path())?;
+        let mut client = GatewayClient::new(vec![gateway], RocksColumn::new(rocks))?;
+        client.start_compact().await?;
+
+        let statuses = client.nullifier_spent_status(&[spent, unspent], 0).await?;
+        assert_eq!(statuses, vec![SpentStatus::MaybeSpent, SpentStatus::NotSpent]);
+
+        Ok(())
+    }
+}
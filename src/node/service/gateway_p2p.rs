@@ -1,178 +1,716 @@
-use async_std::sync::{Arc, Mutex};
-use std::io;
+use async_std::{
+    future::timeout,
+    sync::{Arc, Mutex},
+};
+use futures::future::join_all;
+use std::{io, time::Duration};
 
 use async_executor::Executor;
-use log::debug;
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use rand::Rng;
 
 use crate::{
-    blockchain::{rocks::columns, RocksColumn, Slab, SlabStore},
-    net,
-    net::{P2p, P2pPtr, Settings},
+    blockchain::{rocks::columns, Rocks, RocksColumn, Slab, SlabStore},
+    consensus::{
+        ConsensusEvent, ConsensusState, ConsensusStatePtr, ParticipantSettings, ProtocolConsensus,
+        SlabCertificate,
+    },
+    crypto::keypair::PublicKey,
+    impl_vec, net,
+    net::{Gossip, GossipPtr, GossipSettings, P2p, P2pPtr, Settings},
+    tx::Transaction,
     util::{
-        serial::{deserialize, serialize, Decodable, Encodable},
+        serial::{deserialize, serialize, Decodable, Encodable, VarInt},
         sleep,
     },
     Error, Result,
 };
 
+use super::gateway::GatewaySlabsSubscriber;
+
+/// Seconds to wait for the replies of the connected peers.
+const REQUEST_TIMEOUT: u64 = 4;
+
+/// Interval in seconds between two syncs with the network.
+const SYNC_INTERVAL: u32 = 4;
+
+/// Maximum number of slabs returned for a single range request.
+const MAX_SLAB_RANGE: u64 = 100;
+
+/// Seconds to wait for a gateway peer to connect before the first sync.
+const PEER_WAIT_TIMEOUT: u64 = 30;
+
+/// Service flag of nodes running the P2P gateway.
+pub const SERVICE_GATEWAY: net::ServiceBitflag = net::SERVICE_APP_START << 1;
+
+/// Service flag of the gateways taking part in the consensus.
+pub const SERVICE_CONSENSUS: net::ServiceBitflag = net::SERVICE_APP_START << 2;
+
+#[repr(u32)]
+enum GatewayError {
+    NoError,
+    UpdateIndex,
+    IndexNotExist,
+    RequestFailed,
+}
+
+/// Consensus ordering the slabs of the network.
+pub struct ConsensusSettings {
+    /// Public keys of the participants, in the same order on every node
+    pub participants: Vec<PublicKey>,
+    /// Set when we take part in the consensus
+    pub participant: Option<ParticipantSettings>,
+}
+
+/// Gateway running over the P2P network instead of ZeroMQ. Every node
+/// keeps a full copy of the slabs: new slabs are gossiped to all peers,
+/// and missing slabs are requested from the connected peers.
+///
+/// Slabs are ordered by the Streamlet consensus between a fixed set of
+/// participants: put slabs hand their transaction over to the
+/// participants, and a slab is appended for each finalized block. The
+/// participants sign each slab, and other nodes only store slabs carrying
+/// the signatures of more than 2n/3 participants, so no single peer can
+/// order slabs. Nodes which aren't participants send the transactions of
+/// their put slabs to the participants they are connected to, or else to
+/// their gateway peers, which pass them on to their own participants.
 pub struct Gateway {
     p2p: P2pPtr,
+    gossip: GossipPtr<SlabMessage>,
     slabstore: Arc<SlabStore>,
-    _last_indexes: Arc<Mutex<Vec<u64>>>,
+    certificates: Arc<RocksColumn<columns::SlabCertificates>>,
+    participants: Arc<Vec<PublicKey>>,
+    slabs_sub_s: async_channel::Sender<Slab>,
+    slabs_sub_rv: GatewaySlabsSubscriber,
+    consensus: Option<ConsensusStatePtr>,
+    consensus_events_rv: async_channel::Receiver<ConsensusEvent>,
 }
 
 impl Gateway {
-    pub async fn new(_settings: Settings, rocks: RocksColumn<columns::Slabs>) -> Result<Self> {
-        let slabstore = SlabStore::new(rocks)?;
-        let settings = Settings::default();
+    pub async fn new(
+        settings: Settings,
+        rocks: Arc<Rocks>,
+        consensus: ConsensusSettings,
+    ) -> Result<Arc<Self>> {
+        if consensus.participants.is_empty() {
+            return Err(Error::ConsensusError("No consensus participants configured"))
+        }
 
-        let p2p = P2p::new(settings).await;
-        let last_indexes = Arc::new(Mutex::new(vec![0; 10]));
-        Ok(Self { p2p, slabstore, _last_indexes: last_indexes })
+        let slabstore = SlabStore::new(RocksColumn::<columns::Slabs>::new(rocks.clone()))?;
+        let certificates = Arc::new(RocksColumn::<columns::SlabCertificates>::new(rocks.clone()));
+        let participants = Arc::new(consensus.participants.clone());
+        let (slabs_sub_s, slabs_sub_rv) = async_channel::unbounded::<Slab>();
+        let (consensus_events_s, consensus_events_rv) = async_channel::unbounded();
+
+        let consensus = match consensus.participant {
+            Some(participant) => {
+                let last_index = participant.state.lock().await.restore(&slabstore).await?;
+                debug!(target: "GATEWAY", "Restored state up to slab {}", last_index);
+
+                Some(Arc::new(Mutex::new(ConsensusState::new(
+                    consensus.participants,
+                    participant,
+                    rocks,
+                    slabstore.clone(),
+                    consensus_events_s,
+                )?)))
+            }
+            None => None,
+        };
+
+        let mut services = settings.services | SERVICE_GATEWAY;
+        if consensus.is_some() {
+            services |= SERVICE_CONSENSUS;
+        }
+        let p2p = P2p::new(Settings { services, ..settings }).await;
+
+        // Gossiped slabs are only relayed if they are certified and extend
+        // our slabstore. Participants append their own slabs.
+        let gossip = Gossip::<SlabMessage>::new(
+            p2p.clone(),
+            !net::SESSION_SEED,
+            SERVICE_GATEWAY,
+            GossipSettings::default(),
+        )
+        .await;
+        let slabstore2 = slabstore.clone();
+        let certificates2 = certificates.clone();
+        let participants2 = participants.clone();
+        let slabs_sub_s2 = slabs_sub_s.clone();
+        let is_participant = consensus.is_some();
+        gossip
+            .set_validator(move |msg: Arc<SlabMessage>| {
+                let slabstore = slabstore2.clone();
+                let certificates = certificates2.clone();
+                let participants = participants2.clone();
+                let slabs_sub_s = slabs_sub_s2.clone();
+                async move {
+                    debug!(target: "GATEWAY", "Received slab {}", msg.slab.get_index());
+                    if is_participant {
+                        return Ok(false)
+                    }
+                    store_slab(&slabstore, &certificates, &participants, &slabs_sub_s, &msg).await
+                }
+            })
+            .await;
+
+        let slabstore2 = slabstore.clone();
+        let certificates2 = certificates.clone();
+        let consensus2 = consensus.clone();
+        p2p.protocol_registry()
+            .register_with_services(!net::SESSION_SEED, SERVICE_GATEWAY, move |channel, p2p| {
+                let slabstore = slabstore2.clone();
+                let certificates = certificates2.clone();
+                let consensus = consensus2.clone();
+                async move {
+                    ProtocolGateway::new(channel, slabstore, certificates, consensus, p2p).await
+                }
+            })
+            .await;
+
+        if let Some(consensus) = consensus.clone() {
+            p2p.protocol_registry()
+                .register_with_services(!net::SESSION_SEED, SERVICE_GATEWAY, move |channel, p2p| {
+                    let consensus = consensus.clone();
+                    async move { ProtocolConsensus::new(channel, consensus, p2p).await }
+                })
+                .await;
+        }
+
+        Ok(Arc::new(Self {
+            p2p,
+            gossip,
+            slabstore,
+            certificates,
+            participants,
+            slabs_sub_s,
+            slabs_sub_rv,
+            consensus,
+            consensus_events_rv,
+        }))
     }
 
-    pub async fn start(&self, executor: Arc<Executor<'_>>) -> Result<()> {
+    /// Start the P2P network and keep the local slabstore in sync with
+    /// the peers, or take part in the consensus when we are a participant.
+    /// Blocks until the network is stopped with stop().
+    pub async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
         self.p2p.clone().start(executor.clone()).await?;
 
-        self.p2p.clone().run(executor.clone()).await?;
+        let tasks = match &self.consensus {
+            Some(consensus) => vec![
+                executor.spawn(ConsensusState::proposal_loop(consensus.clone(), self.p2p.clone())),
+                executor.spawn(self.clone().consensus_events_loop()),
+            ],
+            None => vec![executor.spawn(self.clone().sync_loop())],
+        };
+        let result = self.p2p.clone().run(executor).await;
+        for task in tasks {
+            let _ = task.cancel().await;
+        }
+        result
+    }
 
-        Ok(())
+    /// Broadcast our slab votes to the other participants, and pass the
+    /// certified slabs to the subscribers and to the network.
+    async fn consensus_events_loop(self: Arc<Self>) {
+        while let Ok(event) = self.consensus_events_rv.recv().await {
+            let result = match event {
+                ConsensusEvent::SlabVote(vote) => self.p2p.broadcast(vote).await,
+                ConsensusEvent::SlabCertified(slab, certificate) => {
+                    if let Err(e) = self.slabs_sub_s.send(slab.clone()).await {
+                        error!(target: "GATEWAY", "Unable to notify slab subscribers: {}", e);
+                    }
+                    self.gossip.broadcast(SlabMessage { slab, certificate }).await
+                }
+            };
+
+            if let Err(e) = result {
+                warn!(target: "GATEWAY", "Failed to broadcast consensus event: {}", e);
+            }
+        }
     }
 
-    async fn publish(&self, msg: GatewayMessage) -> Result<()> {
-        self.p2p.broadcast(msg).await
+    /// Shut down the P2P network, start() returns once it's stopped.
+    pub async fn stop(&self) {
+        self.p2p.stop().await
     }
 
-    async fn _subscribe_loop(&self, executor: Arc<Executor<'_>>) -> Result<()> {
-        let new_channel_sub = self.p2p.subscribe_channel().await;
+    /// Wait until a gateway peer is connected, for at most
+    /// `PEER_WAIT_TIMEOUT` seconds. Returns false if none connected.
+    pub async fn wait_for_peer(&self) -> bool {
+        let subscription = self.p2p.subscribe_channel().await;
+        let connected = async {
+            for channel in self.p2p.channels().await {
+                if channel.supports(SERVICE_GATEWAY).await {
+                    return true
+                }
+            }
+
+            loop {
+                match subscription.receive().await {
+                    Ok(channel) if channel.supports(SERVICE_GATEWAY).await => return true,
+                    Ok(_) => {}
+                    Err(_) => return false,
+                }
+            }
+        };
+
+        let result = timeout(Duration::from_secs(PEER_WAIT_TIMEOUT), connected).await;
+        subscription.unsubscribe().await;
+        result.unwrap_or(false)
+    }
 
+    async fn sync_loop(self: Arc<Self>) {
         loop {
-            let channel = new_channel_sub.receive().await?;
+            sleep(SYNC_INTERVAL).await;
 
-            let message_subsytem = channel.get_message_subsystem();
+            if let Err(e) = self.sync().await {
+                error!(target: "GATEWAY", "Sync failed: {}", e);
+            }
+        }
+    }
 
-            message_subsytem.add_dispatch::<GatewayMessage>().await;
+    /// Fetch the slabs we are missing from the connected peers. Only
+    /// slabs certified by the consensus participants are stored.
+    pub async fn sync(&self) -> Result<u64> {
+        debug!(target: "GATEWAY", "Start Syncing");
 
-            let msg_sub = channel.subscribe_msg::<GatewayMessage>().await?;
+        let mut local_last_index = self.slabstore.get_last_index()?;
+        let last_index = self.get_last_index().await?;
 
-            let jobsman = net::ProtocolJobsManager::new("GatewayMessage", channel);
+        while local_last_index < last_index {
+            let to = last_index.min(local_last_index + MAX_SLAB_RANGE);
+            let msgs = self.get_slab_range(local_last_index + 1, to).await?;
+            if msgs.is_empty() {
+                break
+            }
 
-            jobsman.clone().start(executor.clone());
+            for msg in msgs {
+                // A gossiped slab might have been stored in the meantime
+                if !self.store_slab(&msg).await? {
+                    break
+                }
+            }
 
-            jobsman
-                .spawn(Self::handle_msg(self.slabstore.clone(), msg_sub), executor.clone())
-                .await;
+            let new_last_index = self.slabstore.get_last_index()?;
+            if new_last_index == local_last_index {
+                break
+            }
+            local_last_index = new_last_index;
         }
+
+        debug!(target: "GATEWAY", "End Syncing");
+        Ok(local_last_index)
     }
 
-    pub async fn handle_msg(
-        slabstore: Arc<SlabStore>,
-        msg_sub: net::MessageSubscription<GatewayMessage>,
-    ) -> Result<()> {
-        loop {
-            let msg = msg_sub.receive().await?;
+    async fn store_slab(&self, msg: &SlabMessage) -> Result<bool> {
+        store_slab(&self.slabstore, &self.certificates, &self.participants, &self.slabs_sub_s, msg)
+            .await
+    }
 
-            match msg.get_command() {
-                GatewayCommand::PutSlab => {
-                    debug!(target: "GATEWAY", "Received putslab msg");
+    /// Send a request to all the connected gateway peers and collect
+    /// the replies that arrived before the request timed out.
+    async fn request_all(
+        &self,
+        command: GatewayCommand,
+        payload: Vec<u8>,
+    ) -> Result<Vec<GatewayReply>> {
+        let request = GatewayRequest::new(command, payload);
+        let request_timeout = Duration::from_secs(REQUEST_TIMEOUT);
+
+        let mut channels = vec![];
+        for channel in self.p2p.channels().await {
+            if channel.supports(SERVICE_GATEWAY).await {
+                channels.push(channel);
+            }
+        }
 
-                    let slab = msg.get_payload();
+        let requests = channels
+            .iter()
+            .map(|channel| channel.request::<_, GatewayReply>(request.clone(), request_timeout));
 
-                    slabstore.put(deserialize(&slab)?)?;
+        let mut replies = vec![];
+        for (channel, result) in channels.iter().zip(join_all(requests).await) {
+            match result {
+                Ok(reply) if !reply.has_error() => replies.push((*reply).clone()),
+                Ok(_) => {}
+                Err(e) => debug!(target: "GATEWAY", "No reply from [{}]: {}", channel.address(), e),
+            }
+        }
 
-                    // TODO publish the new received slab
-                }
-                GatewayCommand::GetSlab => {
-                    debug!(target: "GATEWAY", "Received getslab msg");
+        Ok(replies)
+    }
+
+    /// Highest last index among the connected peers and our own.
+    pub async fn get_last_index(&self) -> Result<u64> {
+        debug!(target: "GATEWAY", "Get last index");
+
+        let mut last_index = self.slabstore.get_last_index()?;
+        for reply in self.request_all(GatewayCommand::GetLastIndex, vec![]).await? {
+            match deserialize::<u64>(&reply.payload) {
+                Ok(index) => last_index = last_index.max(index),
+                Err(e) => warn!(target: "GATEWAY", "Invalid last index reply: {}", e),
+            }
+        }
+
+        Ok(last_index)
+    }
+
+    pub async fn get_slab(&self, index: u64) -> Result<Option<Slab>> {
+        debug!(target: "GATEWAY", "Get slab");
 
-                    let index = msg.get_payload();
-                    let _slab = slabstore.get(index)?;
+        if let Some(slab) = self.slabstore.get(serialize(&index))? {
+            return Ok(Some(deserialize(&slab)?))
+        }
 
-                    // TODO publish the slab
+        for reply in self.request_all(GatewayCommand::GetSlab, serialize(&index)).await? {
+            if let Ok(msg) = deserialize::<SlabMessage>(&reply.payload) {
+                if msg.slab.get_index() == index &&
+                    msg.certificate.verify(&msg.slab, &self.participants)
+                {
+                    return Ok(Some(msg.slab))
                 }
-                GatewayCommand::GetLastIndex => {
-                    debug!(target: "GATEWAY","Received getlastindex msg");
+            }
+        }
 
-                    let _index = slabstore.get_last_index_as_bytes()?;
+        Ok(None)
+    }
 
-                    // TODO publish the inex
+    /// Request the slabs in `[from, to]` from the connected peers. The
+    /// longest reply of contiguous certified slabs is used.
+    pub async fn get_slab_range(&self, from: u64, to: u64) -> Result<Vec<SlabMessage>> {
+        debug!(target: "GATEWAY", "Get slab range {}..={}", from, to);
+
+        let mut payload = serialize(&from);
+        payload.extend(serialize(&to));
+
+        let mut msgs: Vec<SlabMessage> = vec![];
+        for reply in self.request_all(GatewayCommand::GetSlabRange, payload).await? {
+            let reply_msgs: Vec<SlabMessage> = match deserialize(&reply.payload) {
+                Ok(reply_msgs) => reply_msgs,
+                Err(e) => {
+                    warn!(target: "GATEWAY", "Invalid slab range reply: {}", e);
+                    continue
                 }
+            };
+
+            let is_valid = reply_msgs.iter().enumerate().all(|(i, msg)| {
+                msg.slab.get_index() == from + i as u64 &&
+                    msg.certificate.verify(&msg.slab, &self.participants)
+            });
+
+            if !is_valid {
+                warn!(target: "GATEWAY", "Uncertified slab range reply");
+                continue
+            }
+
+            if reply_msgs.len() > msgs.len() {
+                msgs = reply_msgs;
             }
         }
+
+        Ok(msgs)
     }
 
-    pub async fn sync(&self) -> Result<()> {
-        debug!(target: "GATEWAY", "Start Syncing");
+    /// Hand the transaction of a slab over to the consensus. The slab is
+    /// appended once a block including it is finalized and certified.
+    /// Nodes which aren't participants send it to the participants they
+    /// are connected to, or else to their gateway peers.
+    pub async fn put_slab(&self, slab: Slab) -> Result<()> {
+        debug!(target: "GATEWAY", "Put slab");
 
-        loop {
-            let local_last_index = self.slabstore.get_last_index()?;
+        if let Some(consensus) = &self.consensus {
+            return propose_tx(consensus, &self.p2p, &slab).await
+        }
 
-            // start syncing every 4 seconds
-            sleep(4).await;
+        let tx: Transaction = deserialize(&slab.get_payload())?;
+        if send_to_participants(&self.p2p, &tx).await? {
+            return Ok(())
+        }
 
-            self.get_last_index().await?;
-            let last_index = 0;
+        if self.request_all(GatewayCommand::PutSlab, serialize(&slab)).await?.is_empty() {
+            return Err(Error::ServicesError("No peer accepted the slab"))
+        }
 
-            if last_index < local_last_index {
-                return Err(Error::SlabsStore(
-                    "Local slabstore has higher index than gateway's slabstore.
-                 Run \" darkfid -r \" to refresh the database."
-                        .into(),
-                ))
-            }
+        Ok(())
+    }
+
+    /// Receive the slabs added to the slabstore, either fetched by sync or
+    /// gossiped by the peers.
+    pub fn subscribe(&self) -> GatewaySlabsSubscriber {
+        self.slabs_sub_rv.clone()
+    }
+
+    pub fn get_slabstore(&self) -> Arc<SlabStore> {
+        self.slabstore.clone()
+    }
+
+    pub fn p2p(&self) -> P2pPtr {
+        self.p2p.clone()
+    }
+}
+
+/// Store a certified slab extending our slabstore along with its
+/// certificate, then notify the subscribers. Returns false for slabs
+/// without a valid certificate, that we already have, or that don't follow
+/// our last index.
+async fn store_slab(
+    slabstore: &SlabStore,
+    certificates: &RocksColumn<columns::SlabCertificates>,
+    participants: &[PublicKey],
+    slabs_sub_s: &async_channel::Sender<Slab>,
+    msg: &SlabMessage,
+) -> Result<bool> {
+    let index = msg.slab.get_index();
+    if !msg.certificate.verify(&msg.slab, participants) {
+        warn!(target: "GATEWAY", "Rejected slab {} without a valid certificate", index);
+        return Ok(false)
+    }
+
+    if slabstore.put(msg.slab.clone())?.is_none() {
+        return Ok(false)
+    }
+    certificates.put(index, msg.certificate.clone())?;
+
+    slabs_sub_s.send(msg.slab.clone()).await?;
+    Ok(true)
+}
+
+/// Certified slab at the given index, if we have both the slab and its
+/// certificate.
+fn get_certified_slab(
+    slabstore: &SlabStore,
+    certificates: &RocksColumn<columns::SlabCertificates>,
+    index: u64,
+) -> Result<Option<SlabMessage>> {
+    let slab = match slabstore.get(serialize(&index))? {
+        Some(slab) => deserialize(&slab)?,
+        None => return Ok(None),
+    };
+
+    let certificate = match certificates.get(index)? {
+        Some(certificate) => deserialize(&certificate)?,
+        None => return Ok(None),
+    };
+
+    Ok(Some(SlabMessage { slab, certificate }))
+}
+
+/// Send a transaction to the connected consensus participants. Returns
+/// false if we aren't connected to any.
+async fn send_to_participants(p2p: &P2pPtr, tx: &Transaction) -> Result<bool> {
+    let mut sent = false;
+    for channel in p2p.channels().await {
+        if channel.supports(SERVICE_CONSENSUS).await {
+            channel.send(tx.clone()).await?;
+            sent = true;
+        }
+    }
+    Ok(sent)
+}
+
+/// Verify the transaction of a put slab and add it to the consensus
+/// participants' mempools.
+async fn propose_tx(consensus: &ConsensusStatePtr, p2p: &P2pPtr, slab: &Slab) -> Result<()> {
+    let tx: Transaction = deserialize(&slab.get_payload())?;
+    let added = consensus.lock().await.append_tx(tx.clone()).await?;
+    if added {
+        p2p.broadcast(tx).await?;
+    }
+    Ok(())
+}
+
+/// Answers gateway requests from a peer. Replies are received by the
+/// requests themselves, and new slabs are relayed by the slab gossip.
+pub struct ProtocolGateway {
+    channel: net::ChannelPtr,
+    request_sub: net::MessageSubscription<GatewayRequest>,
+    jobsman: net::ProtocolJobsManagerPtr,
+    slabstore: Arc<SlabStore>,
+    certificates: Arc<RocksColumn<columns::SlabCertificates>>,
+    consensus: Option<ConsensusStatePtr>,
+    p2p: P2pPtr,
+}
 
-            if last_index > 0 {
-                for index in (local_last_index + 1)..(last_index + 1) {
-                    self.get_slab(index).await?
+impl ProtocolGateway {
+    async fn new(
+        channel: net::ChannelPtr,
+        slabstore: Arc<SlabStore>,
+        certificates: Arc<RocksColumn<columns::SlabCertificates>>,
+        consensus: Option<ConsensusStatePtr>,
+        p2p: P2pPtr,
+    ) -> net::ProtocolBasePtr {
+        let message_subsytem = channel.get_message_subsystem();
+        message_subsytem.add_dispatch::<GatewayRequest>().await;
+        message_subsytem.add_dispatch::<GatewayReply>().await;
+
+        let request_sub = channel
+            .clone()
+            .subscribe_msg::<GatewayRequest>()
+            .await
+            .expect("Missing GatewayRequest dispatcher!");
+
+        Arc::new(Self {
+            channel: channel.clone(),
+            request_sub,
+            jobsman: net::ProtocolJobsManager::new("ProtocolGateway", channel),
+            slabstore,
+            certificates,
+            consensus,
+            p2p,
+        })
+    }
+
+    async fn handle_receive_request(self: Arc<Self>) -> Result<()> {
+        debug!(target: "GATEWAY", "ProtocolGateway::handle_receive_request() [START]");
+        loop {
+            let request = self.request_sub.receive().await?;
+
+            // A request that can't be served gets an error reply, and the
+            // following requests of the peer are still served.
+            let reply = match self.handle_request(&request).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    warn!(
+                        target: "GATEWAY",
+                        "Failed to handle request from [{}]: {}", self.channel.address(), e
+                    );
+                    GatewayReply::from(&request, GatewayError::RequestFailed as u32, vec![])
+                }
+            };
+
+            self.channel.send(reply).await?;
+        }
+    }
+
+    async fn handle_request(&self, request: &GatewayRequest) -> Result<GatewayReply> {
+        let mut reply = GatewayReply::from(request, GatewayError::NoError as u32, vec![]);
+
+        match request.command {
+            GatewayCommand::PutSlab => {
+                debug!(target: "GATEWAY", "Received putslab msg");
+                let slab: Slab = deserialize(&request.payload)?;
+                if self.add_slab(slab).await? {
+                    reply.payload = request.payload.clone();
+                } else {
+                    reply.error = GatewayError::UpdateIndex as u32;
+                }
+            }
+            GatewayCommand::GetSlab => {
+                debug!(target: "GATEWAY", "Received getslab msg");
+                let index: u64 = deserialize(&request.payload)?;
+                match get_certified_slab(&self.slabstore, &self.certificates, index)? {
+                    Some(msg) => reply.payload = serialize(&msg),
+                    None => reply.error = GatewayError::IndexNotExist as u32,
                 }
             }
+            GatewayCommand::GetLastIndex => {
+                debug!(target: "GATEWAY", "Received getlastindex msg");
+                reply.payload = serialize(&self.last_certified_index().await?);
+            }
+            GatewayCommand::GetSlabRange => {
+                debug!(target: "GATEWAY", "Received getslabrange msg");
+                let mut payload = &request.payload[..];
+                let from: u64 = Decodable::decode(&mut payload)?;
+                let to: u64 = Decodable::decode(&mut payload)?;
+                let to = to.min(from.saturating_add(MAX_SLAB_RANGE - 1));
+
+                let mut msgs: Vec<SlabMessage> = vec![];
+                for index in from..=to {
+                    match get_certified_slab(&self.slabstore, &self.certificates, index)? {
+                        Some(msg) => msgs.push(msg),
+                        None => break,
+                    }
+                }
 
-            debug!(target: "GATEWAY","End Syncing");
+                if msgs.is_empty() {
+                    reply.error = GatewayError::IndexNotExist as u32;
+                } else {
+                    reply.payload = serialize(&msgs);
+                }
+            }
         }
+
+        Ok(reply)
     }
 
-    pub async fn get_slab(&self, index: u64) -> Result<()> {
-        debug!(target: "GATEWAY","Send get slab msg");
-        let msg = GatewayMessage::new(GatewayCommand::GetSlab, serialize(&index));
-        self.publish(msg).await
+    /// Index of the last slab we can serve along with its certificate.
+    /// Participants might have appended slabs which aren't certified yet.
+    async fn last_certified_index(&self) -> Result<u64> {
+        match &self.consensus {
+            Some(consensus) => Ok(consensus.lock().await.last_certified()),
+            None => self.slabstore.get_last_index(),
+        }
     }
 
-    pub async fn put_slab(&self, slab: Slab) -> Result<()> {
-        debug!(target: "GATEWAY","Send put slab msg");
-        let msg = GatewayMessage::new(GatewayCommand::PutSlab, serialize(&slab));
-        self.publish(msg).await
+    /// Hand the transaction of a slab put by a peer over to the consensus,
+    /// or to the participants we are connected to. Slabs aren't forwarded
+    /// to other gateways, so they travel a single hop.
+    async fn add_slab(&self, slab: Slab) -> Result<bool> {
+        let result = match &self.consensus {
+            Some(consensus) => propose_tx(consensus, &self.p2p, &slab).await.map(|_| true),
+            None => match deserialize::<Transaction>(&slab.get_payload()) {
+                Ok(tx) => send_to_participants(&self.p2p, &tx).await,
+                Err(e) => Err(e),
+            },
+        };
+
+        match result {
+            Ok(added) => Ok(added),
+            Err(e) => {
+                warn!(target: "GATEWAY", "Rejected slab: {}", e);
+                Ok(false)
+            }
+        }
     }
+}
 
-    pub async fn get_last_index(&self) -> Result<()> {
-        debug!(target: "GATEWAY","Send get last index msg");
-        let msg = GatewayMessage::new(GatewayCommand::PutSlab, vec![]);
-        self.publish(msg).await
+#[async_trait]
+impl net::ProtocolBase for ProtocolGateway {
+    /// Starts the gateway protocol. Runs the request handler on the
+    /// protocol task manager.
+    async fn start(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
+        debug!(target: "GATEWAY", "ProtocolGateway::start() [START]");
+        self.jobsman.clone().start(executor.clone());
+        self.jobsman.clone().spawn(self.clone().handle_receive_request(), executor).await;
+        debug!(target: "GATEWAY", "ProtocolGateway::start() [END]");
+        Ok(())
     }
 
-    pub fn get_slabstore(&self) -> Arc<SlabStore> {
-        self.slabstore.clone()
+    fn name(&self) -> &'static str {
+        "ProtocolGateway"
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum GatewayCommand {
     PutSlab,
     GetSlab,
     GetLastIndex,
+    GetSlabRange,
 }
 
+/// Request sent to the peers. Replies carry the same id.
 #[derive(Debug, PartialEq, Clone)]
-pub struct GatewayMessage {
+pub struct GatewayRequest {
+    id: u32,
     command: GatewayCommand,
     payload: Vec<u8>,
 }
 
-impl GatewayMessage {
+impl GatewayRequest {
     pub fn new(command: GatewayCommand, payload: Vec<u8>) -> Self {
-        Self { command, payload }
+        let id = rand::thread_rng().gen();
+        Self { id, command, payload }
     }
+
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
     pub fn get_command(&self) -> GatewayCommand {
-        self.command.clone()
+        self.command
     }
 
     pub fn get_payload(&self) -> Vec<u8> {
@@ -180,30 +718,299 @@ impl GatewayMessage {
     }
 }
 
-impl Encodable for GatewayMessage {
+#[derive(Debug, PartialEq, Clone)]
+pub struct GatewayReply {
+    id: u32,
+    error: u32,
+    payload: Vec<u8>,
+}
+
+impl GatewayReply {
+    pub fn from(request: &GatewayRequest, error: u32, payload: Vec<u8>) -> Self {
+        Self { id: request.id, error, payload }
+    }
+
+    pub fn has_error(&self) -> bool {
+        self.error != 0
+    }
+
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn get_payload(&self) -> Vec<u8> {
+        self.payload.clone()
+    }
+}
+
+/// Certified slab, gossiped to the network and returned by slab requests.
+#[derive(Debug, Clone)]
+pub struct SlabMessage {
+    pub slab: Slab,
+    pub certificate: SlabCertificate,
+}
+
+impl Encodable for GatewayRequest {
     fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
         let mut len = 0;
-        len += (self.command.clone() as u8).encode(&mut s)?;
+        len += self.id.encode(&mut s)?;
+        len += (self.command as u8).encode(&mut s)?;
         len += self.payload.encode(&mut s)?;
         Ok(len)
     }
 }
 
-impl Decodable for GatewayMessage {
+impl Decodable for GatewayRequest {
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let id = Decodable::decode(&mut d)?;
         let command_code: u8 = Decodable::decode(&mut d)?;
         let command = match command_code {
             0 => GatewayCommand::PutSlab,
             1 => GatewayCommand::GetSlab,
-            _ => GatewayCommand::GetLastIndex,
+            2 => GatewayCommand::GetLastIndex,
+            3 => GatewayCommand::GetSlabRange,
+            _ => return Err(Error::DecodeError("Unknown gateway command")),
         };
 
-        Ok(Self { command, payload: Decodable::decode(&mut d)? })
+        Ok(Self { id, command, payload: Decodable::decode(&mut d)? })
+    }
+}
+
+impl Encodable for GatewayReply {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.id.encode(&mut s)?;
+        len += self.error.encode(&mut s)?;
+        len += self.payload.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for GatewayReply {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            id: Decodable::decode(&mut d)?,
+            error: Decodable::decode(&mut d)?,
+            payload: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+impl Encodable for SlabMessage {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.slab.encode(&mut s)?;
+        len += self.certificate.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SlabMessage {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self { slab: Decodable::decode(&mut d)?, certificate: Decodable::decode(&mut d)? })
+    }
+}
+
+impl_vec!(SlabMessage);
+
+impl net::Message for GatewayRequest {
+    fn name() -> &'static str {
+        "gatewayreq"
+    }
+
+    fn max_size() -> usize {
+        4 * 1024 * 1024
     }
 }
 
-impl net::Message for GatewayMessage {
+impl net::Message for GatewayReply {
     fn name() -> &'static str {
-        "reply"
+        "gatewayrep"
+    }
+
+    // Replies carry batches of slabs
+    fn max_size() -> usize {
+        32 * 1024 * 1024
+    }
+}
+
+impl net::Request for GatewayRequest {
+    fn request_id(&self) -> u32 {
+        self.id
+    }
+
+    fn set_request_id(&mut self, id: u32) {
+        self.id = id;
+    }
+}
+
+impl net::Response for GatewayReply {
+    fn request_id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl net::Message for SlabMessage {
+    fn name() -> &'static str {
+        "slab"
+    }
+
+    fn max_size() -> usize {
+        4 * 1024 * 1024
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use incrementalmerkletree::bridgetree::BridgeTree;
+    use rand::rngs::OsRng;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::{
+        blockchain::Rocks,
+        crypto::{
+            keypair::Keypair,
+            merkle_node::MerkleNode,
+            proof::{ProvingKey, VerifyingKey},
+            types::DrkTokenId,
+        },
+        net::sim::SimNetwork,
+        node::{slab_payload, state::State},
+        tx::{TransactionBuilder, TransactionBuilderClearInputInfo, TransactionBuilderOutputInfo},
+        zk::circuit::{MintContract, SpendContract},
+    };
+
+    /// Keys of the single consensus participant, and of a cashier whose
+    /// deposits it accepts.
+    struct TestKeys {
+        participant: Keypair,
+        cashier: Keypair,
+        mint_pk: ProvingKey,
+        spend_pk: ProvingKey,
+    }
+
+    impl TestKeys {
+        fn new() -> Self {
+            Self {
+                participant: Keypair::random(&mut OsRng),
+                cashier: Keypair::random(&mut OsRng),
+                mint_pk: ProvingKey::build(11, &MintContract::default()),
+                spend_pk: ProvingKey::build(11, &SpendContract::default()),
+            }
+        }
+
+        /// Put slab holding a deposit from the cashier.
+        fn deposit(&self, value: u64) -> Result<(Slab, Transaction)> {
+            let token_id = DrkTokenId::random(&mut OsRng);
+            let tx = TransactionBuilder {
+                clear_inputs: vec![TransactionBuilderClearInputInfo {
+                    value,
+                    token_id,
+                    signature_secret: self.cashier.secret,
+                }],
+                inputs: vec![],
+                outputs: vec![TransactionBuilderOutputInfo {
+                    value,
+                    token_id,
+                    public: Keypair::random(&mut OsRng).public,
+                }],
+            }
+            .build(&self.mint_pk, &self.spend_pk)?;
+
+            Ok((Slab::new(serialize(&tx)), tx))
+        }
+
+        /// Consensus settings of a node, taking part in the consensus or not.
+        fn consensus(&self, rocks: &Arc<Rocks>, is_participant: bool) -> ConsensusSettings {
+            let participant = is_participant.then(|| ParticipantSettings {
+                secret: self.participant.secret,
+                genesis_time: 0,
+                delta: 1,
+                state: Arc::new(Mutex::new(State {
+                    tree: BridgeTree::<MerkleNode, 32>::new(100),
+                    merkle_roots: RocksColumn::<columns::MerkleRoots>::new(rocks.clone()),
+                    merkle_anchor_window: 10,
+                    last_slab_index: 0,
+                    nullifiers: RocksColumn::<columns::Nullifiers>::new(rocks.clone()),
+                    public_keys: vec![self.cashier.public],
+                    mint_vk: VerifyingKey::build(11, &MintContract::default()),
+                    spend_vk: VerifyingKey::build(11, &SpendContract::default()),
+                })),
+            });
+
+            ConsensusSettings { participants: vec![self.participant.public], participant }
+        }
+    }
+
+    /// Gateway at `SimNetwork::node_addr(index)`, connected to the given
+    /// peers of the simulated network. The database is removed along with
+    /// the returned directory.
+    async fn new_gateway(
+        network: &Arc<SimNetwork>,
+        index: usize,
+        peers: &[usize],
+        keys: &TestKeys,
+        is_participant: bool,
+        executor: Arc<Executor<'_>>,
+    ) -> Result<(Arc<Gateway>, TempDir)> {
+        let dir = tempdir()?;
+        let rocks = Rocks::new(dir.path())?;
+
+        let mut settings = network.node_settings(index);
+        settings.peers = peers.iter().map(|peer| SimNetwork::node_addr(*peer).into()).collect();
+        let consensus = keys.consensus(&rocks, is_participant);
+        let gateway = Gateway::new(settings, rocks, consensus).await?;
+
+        let gateway2 = gateway.clone();
+        executor.spawn(gateway2.start(executor.clone())).detach();
+        Ok((gateway, dir))
+    }
+
+    async fn has_last_index(gateway: &Gateway, index: u64) -> bool {
+        gateway.get_slabstore().get_last_index().map_or(false, |last| last == index)
+    }
+
+    #[test]
+    fn gateway_p2p_test() -> Result<()> {
+        let executor = Arc::new(Executor::new());
+        let ex = executor.clone();
+        let timeout = Duration::from_secs(60);
+        let keys = TestKeys::new();
+
+        smol::block_on(executor.run(async move {
+            let network = SimNetwork::new(0);
+
+            // A is the only consensus participant
+            let (node_a, _dir_a) = new_gateway(&network, 0, &[], &keys, true, ex.clone()).await?;
+            node_a.put_slab(keys.deposit(1)?.0).await?;
+            assert!(network.wait_until(timeout, || has_last_index(&node_a, 1)).await);
+
+            let (node_b, _dir_b) = new_gateway(&network, 1, &[0], &keys, false, ex.clone()).await?;
+            let (node_c, _dir_c) = new_gateway(&network, 2, &[1], &keys, false, ex.clone()).await?;
+
+            // The nodes sync the certified slabs once connected
+            assert!(network.wait_until(timeout, || has_last_index(&node_b, 1)).await);
+            assert!(network.wait_until(timeout, || has_last_index(&node_c, 1)).await);
+
+            // A slab put on C is ordered by A, and the certified slab is
+            // gossiped back to every node
+            let (slab, tx) = keys.deposit(2)?;
+            node_c.put_slab(slab).await?;
+            assert!(network.wait_until(timeout, || has_last_index(&node_b, 2)).await);
+            assert!(network.wait_until(timeout, || has_last_index(&node_c, 2)).await);
+
+            let slab = node_c.get_slab(2).await?.unwrap();
+            assert_eq!(slab_payload::decode(&slab.get_payload())?, vec![tx]);
+
+            // Slabs without a valid certificate are rejected
+            let mut forged = Slab::new(slab.get_payload());
+            forged.set_index(3);
+            let msg = SlabMessage { slab: forged, certificate: SlabCertificate { votes: vec![] } };
+            assert!(!node_b.store_slab(&msg).await?);
+            assert!(has_last_index(&node_b, 2).await);
+
+            Ok(())
+        }))
     }
 }
@@ -1,5 +1,6 @@
 pub mod gateway;
 pub mod gateway_p2p;
 pub mod reqrep;
+pub mod secure;
 
 pub use gateway::{GatewayClient, GatewayService, GatewaySlabsSubscriber};
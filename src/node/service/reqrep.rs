@@ -1,20 +1,97 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use async_executor::Executor;
-use async_std::prelude::*;
+use async_std::future::timeout;
 use bytes::Bytes;
 use futures::FutureExt;
 use log::*;
 use rand::Rng;
-use signal_hook::consts::SIGINT;
-use signal_hook_async_std::Signals;
+use smol::Timer;
 use zeromq::*;
 
 use crate::{
+    crypto::keypair::{PublicKey, SecretKey},
     util::serial::{deserialize, serialize, Decodable, Encodable},
-    Result,
+    Error, Result,
 };
 
+use super::secure::{accept, ClientHandshake, Frame, Session, SignedMessage};
+
+/// Seconds to wait for a reply before the request is retried.
+pub const REQUEST_TIMEOUT: u64 = 10;
+
+/// Number of times a request is retried before giving up.
+pub const REQUEST_RETRIES: u32 = 3;
+
+/// Milliseconds to wait before the first retry. The delay doubles with
+/// every following retry.
+pub const RETRY_BACKOFF: u64 = 500;
+
+/// Maximum number of client sessions kept by a `RepProtocol`. The least
+/// recently used session is dropped to make room for a new one.
+pub const MAX_SESSIONS: usize = 1024;
+
+/// Maximum number of sessions a `RepProtocol` starts per second. Further
+/// handshakes are dropped, so a flood of new sessions can't evict the
+/// sessions in use faster than that. A peer can start one session per
+/// second, its repeated handshakes are dropped without counting against
+/// the limit.
+pub const MAX_NEW_SESSIONS_PER_SECOND: usize = 32;
+
+/// Seconds after which an idle session is dropped. Clients reconnect
+/// before reusing a session idle for half as long.
+pub const SESSION_IDLE_TIMEOUT: u64 = 600;
+
+/// Errors returned by `ReqProtocol::request`.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ReqError {
+    #[error("Request to {0} timed out")]
+    Timeout(SocketAddr),
+    #[error("Connection to {0} failed: {1}")]
+    Connection(SocketAddr, String),
+    /// The last attempt couldn't be sent, the socket failed to reconnect
+    #[error("Unable to reach {0}: {1}")]
+    Unreachable(SocketAddr, String),
+    #[error("Reply has error code {0}")]
+    Reply(u32),
+}
+
+/// Request statistics of a `ReqProtocol`.
+#[derive(Debug, Clone, Default)]
+pub struct ReqMetrics {
+    /// Requests which got a reply
+    pub replies: u64,
+    /// Requests which failed after all retries
+    pub failures: u64,
+    /// Attempts which timed out
+    pub timeouts: u64,
+    /// Number of times the socket was reconnected
+    pub reconnects: u64,
+    /// Round trip time of the last reply
+    pub last_latency: Duration,
+    /// Sum of the round trip times of all replies
+    pub total_latency: Duration,
+}
+
+impl ReqMetrics {
+    pub fn average_latency(&self) -> Duration {
+        if self.replies == 0 {
+            return Duration::ZERO
+        }
+        self.total_latency / self.replies as u32
+    }
+
+    fn record_reply(&mut self, latency: Duration) {
+        self.replies += 1;
+        self.last_latency = latency;
+        self.total_latency += latency;
+    }
+}
+
 pub type PeerId = Vec<u8>;
 
 pub type Channels =
@@ -30,6 +107,14 @@ pub fn addr_to_string(addr: SocketAddr) -> String {
     format!("tcp://{}", addr)
 }
 
+struct PeerSession {
+    session: Session,
+    last_seen: Instant,
+}
+
+/// Serves requests from `ReqProtocol` clients. Every client has to
+/// complete a handshake with the service's static key first, after which
+/// requests and replies are encrypted.
 pub struct RepProtocol {
     addr: SocketAddr,
     socket: zeromq::RouterSocket,
@@ -37,17 +122,31 @@ pub struct RepProtocol {
     send_queue: async_channel::Sender<(PeerId, Request)>,
     channels: Channels,
     service_name: String,
+    secret: SecretKey,
+    sessions: HashMap<PeerId, PeerSession>,
+    /// Start times and peers of the sessions started within the last second
+    session_starts: VecDeque<(Instant, PeerId)>,
 }
 
 impl RepProtocol {
-    pub fn new(addr: SocketAddr, service_name: String) -> RepProtocol {
+    pub fn new(addr: SocketAddr, service_name: String, secret: SecretKey) -> RepProtocol {
         let socket = zeromq::RouterSocket::new();
         let (send_queue, recv_channel) = async_channel::unbounded::<(PeerId, Request)>();
         let (send_channel, recv_queue) = async_channel::unbounded::<(PeerId, Reply)>();
 
         let channels = (send_channel, recv_channel);
 
-        RepProtocol { addr, socket, recv_queue, send_queue, channels, service_name }
+        RepProtocol {
+            addr,
+            socket,
+            recv_queue,
+            send_queue,
+            channels,
+            service_name,
+            secret,
+            sessions: HashMap::new(),
+            session_starts: VecDeque::new(),
+        }
     }
 
     pub async fn start(
@@ -60,146 +159,346 @@ impl RepProtocol {
         Ok(self.channels.clone())
     }
 
-    pub async fn run(&mut self, executor: Arc<Executor<'_>>) -> Result<()> {
+    /// Serve requests until `stop_signal` receives a message or is closed.
+    pub async fn run(&mut self, stop_signal: async_channel::Receiver<()>) -> Result<()> {
         debug!(target: "REP PROTOCOL API", "{} SERVICE: Running", self.service_name);
 
-        let (stop_s, stop_r) = async_channel::unbounded::<()>();
-
-        let signals = Signals::new(&[SIGINT])?;
-        let handle = signals.handle();
-
-        let signals_task = executor.spawn(async move {
-            let mut signals = signals.fuse();
-            while let Some(signal) = signals.next().await {
-                match signal {
-                    SIGINT => {
-                        stop_s.send(()).await?;
-                        break
-                    }
-                    _ => unreachable!(),
-                }
-            }
-            Ok::<(), crate::Error>(())
-        });
-
         loop {
             let event = futures::select! {
                 msg = self.socket.recv().fuse() => NetEvent::Receive(msg?),
                 msg = self.recv_queue.recv().fuse() => NetEvent::Send(msg?),
-                _ = stop_r.recv().fuse() => NetEvent::Stop
+                _ = stop_signal.recv().fuse() => NetEvent::Stop
             };
 
             match event {
                 NetEvent::Receive(msg) => {
                     if let Some(peer) = msg.get(0) {
-                        if let Some(request) = msg.get(1) {
-                            let request: Vec<u8> = request.to_vec();
-                            let request: Request = deserialize(&request)?;
-                            self.send_queue.send((peer.to_vec(), request)).await?;
+                        if let Some(frame) = msg.get(1) {
+                            if let Err(e) = self.handle_frame(peer.to_vec(), frame).await {
+                                warn!(
+                                    target: "REP PROTOCOL API",
+                                    "{} SERVICE: Dropped frame: {}", self.service_name, e
+                                );
+                            }
                         }
                     }
                 }
                 NetEvent::Send((peer, reply)) => {
-                    let peer = Bytes::from(peer);
-                    let mut msg: Vec<Bytes> = vec![peer];
-                    let reply: Vec<u8> = serialize(&reply);
-                    let reply = Bytes::from(reply);
-                    msg.push(reply);
-
-                    let reply = zeromq::ZmqMessage::try_from(msg)
-                        .map_err(|_| crate::Error::TryFromError)?;
-
-                    self.socket.send(reply).await?;
+                    let frame = match self.sessions.get_mut(&peer) {
+                        Some(peer_session) => peer_session.session.seal(&serialize(&reply))?,
+                        None => {
+                            warn!(
+                                target: "REP PROTOCOL API",
+                                "{} SERVICE: No session for reply", self.service_name
+                            );
+                            continue
+                        }
+                    };
+                    self.send_frame(peer, &frame).await?;
                 }
                 NetEvent::Stop => break,
             }
         }
 
-        handle.close();
-        signals_task.await?;
-
         debug!(target: "REP PROTOCOL API","{} SERVICE: Stopped", self.service_name);
         Ok(())
     }
+
+    /// A `Hello` starts a new session for the peer, replacing any previous
+    /// one. Data frames are decrypted and queued as requests.
+    async fn handle_frame(&mut self, peer: PeerId, frame: &[u8]) -> Result<()> {
+        let frame: Frame = deserialize(frame)?;
+
+        if let Frame::Hello(_) = frame {
+            self.admit_session(&peer)?;
+            let (session, welcome) = accept(&self.secret, frame)?;
+            self.insert_session(peer.clone(), session);
+            return self.send_frame(peer, &welcome).await
+        }
+
+        let request = match self.sessions.get_mut(&peer) {
+            Some(peer_session) => {
+                peer_session.last_seen = Instant::now();
+                peer_session.session.open(frame)?
+            }
+            None => return Err(Error::SecureChannelError("No session for peer")),
+        };
+
+        let request: Request = deserialize(&request)?;
+        self.send_queue.send((peer, request)).await?;
+        Ok(())
+    }
+
+    /// Count a handshake against the session rate limits before it is
+    /// accepted. Fails if the peer already started a session within the
+    /// last second, or once `MAX_NEW_SESSIONS_PER_SECOND` sessions were
+    /// started by all peers. Only admitted handshakes are counted.
+    fn admit_session(&mut self, peer: &PeerId) -> Result<()> {
+        let now = Instant::now();
+        while let Some((start, _)) = self.session_starts.front() {
+            if now.duration_since(*start) < Duration::from_secs(1) {
+                break
+            }
+            self.session_starts.pop_front();
+        }
+
+        if self.session_starts.iter().any(|(_, p)| p == peer) {
+            return Err(Error::SecureChannelError("Peer restarted its session too soon"))
+        }
+        if self.session_starts.len() >= MAX_NEW_SESSIONS_PER_SECOND {
+            return Err(Error::SecureChannelError("Too many new sessions"))
+        }
+        self.session_starts.push_back((now, peer.clone()));
+        Ok(())
+    }
+
+    /// Drop the idle sessions, and the least recently used one if the
+    /// map is still full, before adding a new session.
+    fn insert_session(&mut self, peer: PeerId, session: Session) {
+        let now = Instant::now();
+        let idle_timeout = Duration::from_secs(SESSION_IDLE_TIMEOUT);
+        self.sessions.retain(|_, s| now.duration_since(s.last_seen) < idle_timeout);
+
+        if self.sessions.len() >= MAX_SESSIONS && !self.sessions.contains_key(&peer) {
+            let oldest =
+                self.sessions.iter().min_by_key(|(_, s)| s.last_seen).map(|(peer, _)| peer.clone());
+            if let Some(oldest) = oldest {
+                self.sessions.remove(&oldest);
+            }
+        }
+
+        self.sessions.insert(peer, PeerSession { session, last_seen: now });
+    }
+
+    async fn send_frame(&mut self, peer: PeerId, frame: &Frame) -> Result<()> {
+        let msg: Vec<Bytes> = vec![Bytes::from(peer), Bytes::from(serialize(frame))];
+        let msg = zeromq::ZmqMessage::try_from(msg).map_err(|_| crate::Error::TryFromError)?;
+        self.socket.send(msg).await?;
+        Ok(())
+    }
 }
 
+/// Sends requests to a `RepProtocol`. The service is authenticated with
+/// its pinned public key during the handshake, and all requests and
+/// replies are encrypted. Requests time out after `REQUEST_TIMEOUT`
+/// seconds. Idempotent requests are retried with an exponential backoff,
+/// and the socket is reconnected before the next attempt after a failure.
 pub struct ReqProtocol {
     addr: SocketAddr,
     socket: zeromq::DealerSocket,
     service_name: String,
+    server_public: PublicKey,
+    session: Option<Session>,
+    request_timeout: Duration,
+    /// Set when the last attempt failed, the socket is reconnected first
+    broken: bool,
+    /// Last time the session was used, idle sessions expire on the service
+    last_used: Instant,
+    metrics: ReqMetrics,
 }
 
 impl ReqProtocol {
-    pub fn new(addr: SocketAddr, service_name: String) -> ReqProtocol {
+    pub fn new(addr: SocketAddr, service_name: String, server_public: PublicKey) -> ReqProtocol {
         let socket = zeromq::DealerSocket::new();
-        ReqProtocol { addr, socket, service_name }
+        ReqProtocol {
+            addr,
+            socket,
+            service_name,
+            server_public,
+            session: None,
+            request_timeout: Duration::from_secs(REQUEST_TIMEOUT),
+            broken: false,
+            last_used: Instant::now(),
+            metrics: ReqMetrics::default(),
+        }
+    }
+
+    pub fn set_request_timeout(&mut self, request_timeout: Duration) {
+        self.request_timeout = request_timeout;
     }
 
     pub async fn start(&mut self) -> Result<()> {
         let addr = addr_to_string(self.addr);
         self.socket.connect(addr.as_str()).await?;
         debug!(target: "REQ PROTOCOL API","{} SERVICE: Connected To {}", self.service_name, self.addr);
+
+        self.session = None;
+        let session = timeout(self.request_timeout, self.handshake())
+            .await
+            .map_err(|_| ReqError::Timeout(self.addr))??;
+        self.session = Some(session);
+        self.broken = false;
+        self.last_used = Instant::now();
+
+        debug!(target: "REQ PROTOCOL API","{} SERVICE: Session established", self.service_name);
         Ok(())
     }
 
-    pub async fn request(
-        &mut self,
-        command: u8,
-        data: Vec<u8>,
-        handle_error: Arc<dyn Fn(u32) + Send + Sync>,
-    ) -> Result<Option<Vec<u8>>> {
+    async fn handshake(&mut self) -> Result<Session> {
+        let (handshake, hello) = ClientHandshake::new(self.server_public);
+        self.socket.send(Bytes::from(serialize(&hello)).into()).await?;
+
+        let welcome = self.recv_frame().await?;
+        handshake.finish(welcome)
+    }
+
+    async fn recv_frame(&mut self) -> Result<Frame> {
+        let msg: zeromq::ZmqMessage = self.socket.recv().await?;
+        match msg.get(0) {
+            Some(frame) => deserialize(&frame.to_vec()),
+            None => Err(Error::ZmqError("Couldn't parse ZmqMessage".to_string())),
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        self.metrics.reconnects += 1;
+        self.socket = zeromq::DealerSocket::new();
+        self.start().await
+    }
+
+    pub fn metrics(&self) -> &ReqMetrics {
+        &self.metrics
+    }
+
+    /// Send an idempotent request and wait for its reply, retrying up to
+    /// `REQUEST_RETRIES` times. A reply carrying an error code is returned
+    /// as `ReqError::Reply`.
+    pub async fn request(&mut self, command: u8, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.send_request(command, data, REQUEST_RETRIES).await
+    }
+
+    /// Send a request which must not be repeated, eg. one with side effects
+    /// on the service. A request that timed out might still have been
+    /// processed, so it is never retried.
+    pub async fn request_once(&mut self, command: u8, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.send_request(command, data, 0).await
+    }
+
+    async fn send_request(&mut self, command: u8, data: Vec<u8>, retries: u32) -> Result<Vec<u8>> {
         let request = Request::new(command, data);
-        let req = serialize(&request);
+
+        let mut last_error = ReqError::Timeout(self.addr);
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 0..(retries + 1) {
+            if attempt > 0 {
+                Timer::after(Duration::from_millis(backoff)).await;
+                backoff *= 2;
+            }
+
+            let idle = self.last_used.elapsed() > Duration::from_secs(SESSION_IDLE_TIMEOUT / 2);
+            if self.broken || idle {
+                if let Err(e) = self.reconnect().await {
+                    warn!(
+                        target: "REQ PROTOCOL API",
+                        "{} SERVICE: Unable to reconnect to {}: {}",
+                        self.service_name, self.addr, e
+                    );
+                    last_error = ReqError::Unreachable(self.addr, e.to_string());
+                    continue
+                }
+            }
+
+            let start = Instant::now();
+            let result = timeout(self.request_timeout, self.send_and_recv(&request)).await;
+            self.broken = !matches!(result, Ok(Ok(_)));
+            self.last_used = Instant::now();
+
+            match result {
+                Ok(Ok(reply)) => {
+                    let latency = start.elapsed();
+                    self.metrics.record_reply(latency);
+
+                    debug!(
+                        target: "REQ PROTOCOL API",
+                        "{} SERVICE: Received Reply {{ error: {}, latency: {:?} }}",
+                        self.service_name,
+                        reply.has_error(),
+                        latency
+                    );
+
+                    if reply.has_error() {
+                        return Err(ReqError::Reply(reply.get_error()).into())
+                    }
+
+                    return Ok(reply.get_payload())
+                }
+                Ok(Err(e)) => {
+                    warn!(
+                        target: "REQ PROTOCOL API",
+                        "{} SERVICE: Request {{ command: {} }} to {} failed: {}",
+                        self.service_name, command, self.addr, e
+                    );
+                    if attempt == retries {
+                        self.metrics.failures += 1;
+                        return Err(ReqError::Connection(self.addr, e.to_string()).into())
+                    }
+                }
+                Err(_) => {
+                    last_error = ReqError::Timeout(self.addr);
+                    self.metrics.timeouts += 1;
+                    warn!(
+                        target: "REQ PROTOCOL API",
+                        "{} SERVICE: Request {{ command: {} }} to {} timed out",
+                        self.service_name, command, self.addr
+                    );
+                }
+            }
+        }
+
+        self.metrics.failures += 1;
+        Err(last_error.into())
+    }
+
+    async fn send_and_recv(&mut self, request: &Request) -> Result<Reply> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Err(Error::SecureChannelError("No session established")),
+        };
+
+        let req = serialize(&session.seal(&serialize(request))?);
         let req = bytes::Bytes::from(req);
         let req: zeromq::ZmqMessage = req.into();
 
         self.socket.send(req).await?;
         debug!(
-        target: "REQ PROTOCOL API",
-                "{} SERVICE: Sent Request {{ command: {} }}",
-                self.service_name, command
-            );
+            target: "REQ PROTOCOL API",
+            "{} SERVICE: Sent Request {{ command: {} }}",
+            self.service_name,
+            request.get_command()
+        );
 
-        let rep: zeromq::ZmqMessage = self.socket.recv().await?;
-        if let Some(reply) = rep.get(0) {
-            let reply: Vec<u8> = reply.to_vec();
+        loop {
+            let frame = self.recv_frame().await?;
+            let reply = match self.session.as_mut() {
+                Some(session) => session.open(frame)?,
+                None => return Err(Error::SecureChannelError("No session established")),
+            };
 
             let reply: Reply = deserialize(&reply)?;
 
-            debug!(
-            target: "REQ PROTOCOL API",
-                    "{} SERVICE: Received Reply {{ error: {} }}",
-                    self.service_name,
-                    reply.has_error()
-                );
-
-            if reply.has_error() {
-                handle_error(reply.get_error());
-                return Ok(None)
-            }
-
+            // Late replies to requests which already timed out are skipped
             if reply.get_id() != request.get_id() {
-                warn!("Reply id is not equal to Request id");
-                return Ok(None)
+                warn!(target: "REQ PROTOCOL API", "Reply id is not equal to Request id");
+                continue
             }
 
-            Ok(Some(reply.get_payload()))
-        } else {
-            Err(crate::Error::ZmqError("Couldn't parse ZmqMessage".to_string()))
+            return Ok(reply)
         }
     }
 }
 
+/// Publishes messages signed with the service's static key.
 pub struct Publisher {
     addr: SocketAddr,
     socket: zeromq::PubSocket,
     service_name: String,
+    secret: SecretKey,
 }
 
 impl Publisher {
-    pub fn new(addr: SocketAddr, service_name: String) -> Publisher {
+    pub fn new(addr: SocketAddr, service_name: String, secret: SecretKey) -> Publisher {
         let socket = zeromq::PubSocket::new();
-        Publisher { addr, socket, service_name }
+        Publisher { addr, socket, service_name, secret }
     }
 
     pub async fn start(&mut self, recv_queue: async_channel::Receiver<Vec<u8>>) -> Result<()> {
@@ -217,22 +516,26 @@ impl Publisher {
     }
 
     async fn publish(&mut self, data: Vec<u8>) -> Result<()> {
-        let data = Bytes::from(data);
+        let message = SignedMessage::new(&self.secret, data);
+        let data = Bytes::from(serialize(&message));
         self.socket.send(data.into()).await?;
         Ok(())
     }
 }
 
+/// Receives messages from a `Publisher` and verifies them against the
+/// publisher's pinned public key.
 pub struct Subscriber {
     addr: SocketAddr,
     socket: zeromq::SubSocket,
     service_name: String,
+    publisher_public: PublicKey,
 }
 
 impl Subscriber {
-    pub fn new(addr: SocketAddr, service_name: String) -> Subscriber {
+    pub fn new(addr: SocketAddr, service_name: String, publisher_public: PublicKey) -> Subscriber {
         let socket = zeromq::SubSocket::new();
-        Subscriber { addr, socket, service_name }
+        Subscriber { addr, socket, service_name, publisher_public }
     }
 
     pub async fn start(&mut self) -> Result<()> {
@@ -252,8 +555,11 @@ impl Subscriber {
         let data = self.socket.recv().await?;
         match data.get(0) {
             Some(d) => {
-                let data = d.to_vec();
-                let data: T = deserialize(&data)?;
+                let message: SignedMessage = deserialize(&d.to_vec())?;
+                if !message.verify(&self.publisher_public) {
+                    return Err(Error::SecureChannelError("Invalid publisher signature"))
+                }
+                let data: T = deserialize(&message.payload)?;
                 Ok(data)
             }
             None => Err(crate::Error::ZmqError("Couldn't parse ZmqMessage".to_string())),
@@ -370,8 +676,36 @@ impl Decodable for Reply {
 
 #[cfg(test)]
 mod tests {
-    use super::{Reply, Request, Result};
-    use crate::util::serial::{deserialize, serialize};
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use rand::rngs::OsRng;
+
+    use super::{
+        RepProtocol, Reply, ReqError, ReqMetrics, ReqProtocol, Request, Result,
+        MAX_NEW_SESSIONS_PER_SECOND, MAX_SESSIONS, SESSION_IDLE_TIMEOUT,
+    };
+    use crate::{
+        crypto::keypair::{PublicKey, SecretKey},
+        node::service::secure::{accept, ClientHandshake},
+        util::serial::{deserialize, serialize},
+        Error,
+    };
+
+    #[test]
+    fn req_metrics_average_latency_test() {
+        let mut metrics = ReqMetrics::default();
+        assert_eq!(metrics.average_latency(), Duration::ZERO);
+
+        metrics.record_reply(Duration::from_millis(10));
+        metrics.record_reply(Duration::from_millis(30));
+        assert_eq!(metrics.replies, 2);
+        assert_eq!(metrics.last_latency, Duration::from_millis(30));
+        assert_eq!(metrics.average_latency(), Duration::from_millis(20));
+    }
 
     #[test]
     fn serialize_and_deserialize_request_test() {
@@ -391,4 +725,148 @@ mod tests {
         let deserialized_reply = deserialize(&serialized_reply).ok();
         assert_eq!(deserialized_reply, Some(reply));
     }
+
+    #[test]
+    fn session_limit_test() -> Result<()> {
+        let secret = SecretKey::random(&mut OsRng);
+        let public = PublicKey::from_secret(secret);
+        let mut service = RepProtocol::new(free_addr(), "TEST".into(), secret);
+
+        for i in 0..(MAX_SESSIONS as u32 + 1) {
+            let (session, _) = accept(&secret, ClientHandshake::new(public).1)?;
+            service.insert_session(i.to_le_bytes().to_vec(), session);
+        }
+
+        // The least recently used session made room for the last one
+        assert_eq!(service.sessions.len(), MAX_SESSIONS);
+        assert!(!service.sessions.contains_key(&0u32.to_le_bytes().to_vec()));
+        assert!(service.sessions.contains_key(&(MAX_SESSIONS as u32).to_le_bytes().to_vec()));
+
+        // Idle sessions are dropped when a new one starts
+        let idle = Instant::now() - Duration::from_secs(SESSION_IDLE_TIMEOUT + 1);
+        for peer_session in service.sessions.values_mut() {
+            peer_session.last_seen = idle;
+        }
+        let (session, _) = accept(&secret, ClientHandshake::new(public).1)?;
+        service.insert_session(vec![], session);
+        assert_eq!(service.sessions.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_rate_limit_test() -> Result<()> {
+        let secret = SecretKey::random(&mut OsRng);
+        let public = PublicKey::from_secret(secret);
+        let mut service = RepProtocol::new(free_addr(), "TEST".into(), secret);
+
+        for i in 0..MAX_SESSIONS as u32 {
+            let (session, _) = accept(&secret, ClientHandshake::new(public).1)?;
+            service.insert_session(i.to_le_bytes().to_vec(), session);
+        }
+
+        // A flood of handshakes only evicts sessions up to the rate limit
+        let mut started = 0;
+        for i in 0..(2 * MAX_NEW_SESSIONS_PER_SECOND as u32) {
+            let peer = (MAX_SESSIONS as u32 + i).to_le_bytes().to_vec();
+            if service.admit_session(&peer).is_ok() {
+                let (session, _) = accept(&secret, ClientHandshake::new(public).1)?;
+                service.insert_session(peer, session);
+                started += 1;
+            }
+        }
+
+        assert_eq!(started, MAX_NEW_SESSIONS_PER_SECOND);
+        assert_eq!(service.sessions.len(), MAX_SESSIONS);
+        let kept = (MAX_NEW_SESSIONS_PER_SECOND as u32).to_le_bytes().to_vec();
+        assert!(service.sessions.contains_key(&kept));
+
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_hello_test() -> Result<()> {
+        let secret = SecretKey::random(&mut OsRng);
+        let mut service = RepProtocol::new(free_addr(), "TEST".into(), secret);
+
+        // Repeated handshakes of a peer are dropped without using up the limit
+        let peer = 0u32.to_le_bytes().to_vec();
+        service.admit_session(&peer)?;
+        for _ in 0..(2 * MAX_NEW_SESSIONS_PER_SECOND) {
+            assert!(service.admit_session(&peer).is_err());
+        }
+        assert_eq!(service.session_starts.len(), 1);
+
+        for i in 1..MAX_NEW_SESSIONS_PER_SECOND as u32 {
+            service.admit_session(&i.to_le_bytes().to_vec())?;
+        }
+        let other = (MAX_NEW_SESSIONS_PER_SECOND as u32).to_le_bytes().to_vec();
+        assert!(service.admit_session(&other).is_err());
+
+        // The peer can start a new session once its last one is a second old
+        service.session_starts.clear();
+        let started = Instant::now() - Duration::from_secs(1);
+        service.session_starts.push_back((started, peer.clone()));
+        service.admit_session(&peer)?;
+
+        Ok(())
+    }
+
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[async_std::test]
+    async fn request_timeout_and_retry_test() -> Result<()> {
+        let secret = SecretKey::random(&mut OsRng);
+        let addr = free_addr();
+
+        let mut service = RepProtocol::new(addr, "TEST".into(), secret);
+        let (reply_s, request_r) = service.start().await?;
+        let (stop_s, stop_r) = async_channel::unbounded::<()>();
+        let service_task = async_std::task::spawn(async move { service.run(stop_r).await });
+
+        // The first request of every command is left without a reply
+        let (commands_s, commands_r) = async_channel::unbounded::<u8>();
+        let responder_task = async_std::task::spawn(async move {
+            let mut received: HashMap<u8, u8> = HashMap::new();
+            while let Ok((peer, request)) = request_r.recv().await {
+                let count = received.entry(request.get_command()).or_default();
+                *count += 1;
+                commands_s.send(request.get_command()).await?;
+                if *count > 1 {
+                    reply_s.send((peer, Reply::from(&request, 0, vec![*count]))).await?;
+                }
+            }
+            Ok::<(), Error>(())
+        });
+
+        let mut client = ReqProtocol::new(addr, "TEST".into(), PublicKey::from_secret(secret));
+        client.set_request_timeout(Duration::from_millis(500));
+        client.start().await?;
+
+        // Idempotent requests are retried on a new connection
+        assert_eq!(client.request(0, vec![]).await?, vec![2]);
+        assert_eq!(client.metrics().timeouts, 1);
+        assert_eq!(client.metrics().reconnects, 1);
+        assert_eq!(client.metrics().replies, 1);
+
+        // Other requests are sent once
+        match client.request_once(1, vec![]).await {
+            Err(Error::ReqError(ReqError::Timeout(timeout_addr))) => assert_eq!(timeout_addr, addr),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert_eq!(client.metrics().failures, 1);
+        let commands: Vec<u8> = std::iter::from_fn(|| commands_r.try_recv().ok()).collect();
+        assert_eq!(commands, vec![0, 0, 1]);
+
+        // The next request reconnects first
+        assert_eq!(client.request_once(1, vec![]).await?, vec![2]);
+        assert_eq!(client.metrics().reconnects, 2);
+
+        stop_s.send(()).await?;
+        service_task.await?;
+        responder_task.cancel().await;
+        Ok(())
+    }
 }
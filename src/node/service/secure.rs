@@ -0,0 +1,278 @@
+use std::io;
+
+use blake2b_simd::Params as Blake2bParams;
+use crypto_api_chachapoly::ChachaPolyIetf;
+use rand::rngs::OsRng;
+
+use crate::{
+    crypto::{
+        diffie_hellman::sapling_ka_agree,
+        keypair::{PublicKey, SecretKey},
+        schnorr::{SchnorrPublic, SchnorrSecret, Signature},
+    },
+    util::serial::{Decodable, Encodable},
+    Error, Result,
+};
+
+pub const SESSION_KDF_PERSONALIZATION: &[u8; 16] = b"DarkFi_GwSession";
+
+const AEAD_TAG_SIZE: usize = 16;
+
+/// Frames exchanged between a `ReqProtocol` and a `RepProtocol`.
+///
+/// The handshake follows the Noise NK pattern: the client knows the static
+/// key of the gateway beforehand and sends an ephemeral key in `Hello`.
+/// The gateway answers with its own ephemeral key in `Welcome`, together
+/// with an empty ciphertext which proves it holds the static secret key.
+/// All requests and replies are then sent as `Data` frames.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Hello(PublicKey),
+    Welcome(PublicKey, Vec<u8>),
+    Data(u64, Vec<u8>),
+}
+
+impl Encodable for Frame {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        match self {
+            Frame::Hello(ephem_public) => {
+                len += 0u8.encode(&mut s)?;
+                len += ephem_public.encode(&mut s)?;
+            }
+            Frame::Welcome(ephem_public, confirm) => {
+                len += 1u8.encode(&mut s)?;
+                len += ephem_public.encode(&mut s)?;
+                len += confirm.encode(&mut s)?;
+            }
+            Frame::Data(counter, ciphertext) => {
+                len += 2u8.encode(&mut s)?;
+                len += counter.encode(&mut s)?;
+                len += ciphertext.encode(&mut s)?;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Frame {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let tag: u8 = Decodable::decode(&mut d)?;
+        match tag {
+            0 => Ok(Frame::Hello(Decodable::decode(&mut d)?)),
+            1 => Ok(Frame::Welcome(Decodable::decode(&mut d)?, Decodable::decode(&mut d)?)),
+            2 => Ok(Frame::Data(Decodable::decode(&mut d)?, Decodable::decode(&mut d)?)),
+            _ => Err(Error::SecureChannelError("Unknown frame")),
+        }
+    }
+}
+
+/// Client side of the handshake, waiting for the gateway's `Welcome`.
+pub struct ClientHandshake {
+    ephem_secret: SecretKey,
+    ephem_public: PublicKey,
+    server_public: PublicKey,
+}
+
+impl ClientHandshake {
+    /// Start a handshake with the gateway owning `server_public`.
+    /// Returns the `Hello` frame to send.
+    pub fn new(server_public: PublicKey) -> (Self, Frame) {
+        let ephem_secret = SecretKey::random(&mut OsRng);
+        let ephem_public = PublicKey::from_secret(ephem_secret);
+        (Self { ephem_secret, ephem_public, server_public }, Frame::Hello(ephem_public))
+    }
+
+    pub fn finish(self, frame: Frame) -> Result<Session> {
+        let (server_ephem, confirm) = match frame {
+            Frame::Welcome(server_ephem, confirm) => (server_ephem, confirm),
+            _ => return Err(Error::SecureChannelError("Expected a welcome frame")),
+        };
+
+        let static_dh = sapling_ka_agree(&self.ephem_secret, &self.server_public);
+        let ephem_dh = sapling_ka_agree(&self.ephem_secret, &server_ephem);
+        let (send_key, recv_key) =
+            derive_keys(&static_dh, &ephem_dh, &self.ephem_public, &server_ephem);
+
+        let mut session = Session::new(send_key, recv_key);
+        session.open(Frame::Data(0, confirm))?;
+        Ok(session)
+    }
+}
+
+/// Answer a client's `Hello` with the gateway's static `secret`.
+/// Returns the established session and the `Welcome` frame to send.
+pub fn accept(secret: &SecretKey, frame: Frame) -> Result<(Session, Frame)> {
+    let client_ephem = match frame {
+        Frame::Hello(client_ephem) => client_ephem,
+        _ => return Err(Error::SecureChannelError("Expected a hello frame")),
+    };
+
+    let ephem_secret = SecretKey::random(&mut OsRng);
+    let ephem_public = PublicKey::from_secret(ephem_secret);
+
+    let static_dh = sapling_ka_agree(secret, &client_ephem);
+    let ephem_dh = sapling_ka_agree(&ephem_secret, &client_ephem);
+    let (recv_key, send_key) = derive_keys(&static_dh, &ephem_dh, &client_ephem, &ephem_public);
+
+    let mut session = Session::new(send_key, recv_key);
+    let confirm = match session.seal(&[])? {
+        Frame::Data(_, confirm) => confirm,
+        _ => unreachable!(),
+    };
+
+    Ok((session, Frame::Welcome(ephem_public, confirm)))
+}
+
+/// Returns the client to gateway and gateway to client keys.
+fn derive_keys(
+    static_dh: &PublicKey,
+    ephem_dh: &PublicKey,
+    client_ephem: &PublicKey,
+    server_ephem: &PublicKey,
+) -> ([u8; 32], [u8; 32]) {
+    let hash = Blake2bParams::new()
+        .hash_length(64)
+        .personal(SESSION_KDF_PERSONALIZATION)
+        .to_state()
+        .update(&static_dh.to_bytes())
+        .update(&ephem_dh.to_bytes())
+        .update(&client_ephem.to_bytes())
+        .update(&server_ephem.to_bytes())
+        .finalize();
+
+    let mut client_key = [0u8; 32];
+    let mut server_key = [0u8; 32];
+    client_key.copy_from_slice(&hash.as_bytes()[..32]);
+    server_key.copy_from_slice(&hash.as_bytes()[32..]);
+    (client_key, server_key)
+}
+
+/// Encrypts and decrypts the frames of an established session. Every
+/// frame carries a counter used as the nonce; frames with a counter that
+/// was already seen are rejected as replays.
+pub struct Session {
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    send_counter: u64,
+    recv_counter: u64,
+}
+
+impl Session {
+    fn new(send_key: [u8; 32], recv_key: [u8; 32]) -> Self {
+        Self { send_key, recv_key, send_counter: 0, recv_counter: 0 }
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Frame> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let mut ciphertext = vec![0u8; plaintext.len() + AEAD_TAG_SIZE];
+        ChachaPolyIetf::aead_cipher()
+            .seal_to(&mut ciphertext, plaintext, &[], &self.send_key, &nonce(counter))
+            .map_err(|_| Error::SecureChannelError("Encryption failed"))?;
+
+        Ok(Frame::Data(counter, ciphertext))
+    }
+
+    pub fn open(&mut self, frame: Frame) -> Result<Vec<u8>> {
+        let (counter, ciphertext) = match frame {
+            Frame::Data(counter, ciphertext) => (counter, ciphertext),
+            _ => return Err(Error::SecureChannelError("Expected a data frame")),
+        };
+
+        if counter < self.recv_counter {
+            return Err(Error::SecureChannelError("Replayed frame"))
+        }
+
+        if ciphertext.len() < AEAD_TAG_SIZE {
+            return Err(Error::SecureChannelError("Frame is too short"))
+        }
+
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let len = ChachaPolyIetf::aead_cipher()
+            .open_to(&mut plaintext, &ciphertext, &[], &self.recv_key, &nonce(counter))
+            .map_err(|_| Error::SecureChannelError("Decryption failed"))?;
+        plaintext.truncate(len);
+
+        self.recv_counter = counter + 1;
+        Ok(plaintext)
+    }
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// A published message, signed by the gateway's static key. Published
+/// messages aren't encrypted: the slabs are public anyway, and any client
+/// could fetch a shared key. The signature is what authenticates the
+/// gateway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedMessage {
+    pub payload: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl SignedMessage {
+    pub fn new(secret: &SecretKey, payload: Vec<u8>) -> Self {
+        let signature = secret.sign(&payload);
+        Self { payload, signature }
+    }
+
+    pub fn verify(&self, public: &PublicKey) -> bool {
+        public.verify(&self.payload, &self.signature)
+    }
+}
+
+impl Encodable for SignedMessage {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.payload.encode(&mut s)?;
+        len += self.signature.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SignedMessage {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self { payload: Decodable::decode(&mut d)?, signature: Decodable::decode(&mut d)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::{accept, ClientHandshake, SignedMessage};
+    use crate::crypto::keypair::{PublicKey, SecretKey};
+
+    #[test]
+    fn secure_session_test() {
+        let server_secret = SecretKey::random(&mut OsRng);
+        let server_public = PublicKey::from_secret(server_secret);
+
+        let (handshake, hello) = ClientHandshake::new(server_public);
+        let (mut server, welcome) = accept(&server_secret, hello).unwrap();
+        let mut client = handshake.finish(welcome).unwrap();
+
+        let frame = client.seal(b"request").unwrap();
+        assert_eq!(server.open(frame.clone()).unwrap(), b"request".to_vec());
+        // The same frame can't be replayed
+        assert!(server.open(frame).is_err());
+
+        let frame = server.seal(b"reply").unwrap();
+        assert_eq!(client.open(frame).unwrap(), b"reply".to_vec());
+
+        // A gateway without the pinned secret key can't complete the handshake
+        let (handshake, hello) = ClientHandshake::new(server_public);
+        let (_, welcome) = accept(&SecretKey::random(&mut OsRng), hello).unwrap();
+        assert!(handshake.finish(welcome).is_err());
+
+        let message = SignedMessage::new(&server_secret, vec![1, 2, 3]);
+        assert!(message.verify(&server_public));
+        assert!(!message.verify(&PublicKey::random(&mut OsRng)));
+    }
+}
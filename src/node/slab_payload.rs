@@ -0,0 +1,70 @@
+use std::io::Cursor;
+
+use crate::{
+    tx::Transaction,
+    util::serial::{deserialize, Decodable, Encodable, VarInt},
+    Error, Result,
+};
+
+/// Version of the slab payload format.
+///
+/// Slabs written before transactions were bundled carry a single
+/// serialized transaction, which starts with the VarInt number of its
+/// clear inputs. That number can't be `u64::MAX`, so versioned payloads
+/// start with the VarInt `u64::MAX` as a marker, followed by the version.
+pub const SLAB_PAYLOAD_VERSION: u8 = 1;
+
+const SLAB_PAYLOAD_MARKER: [u8; 9] = [0xff; 9];
+
+/// Encode the transactions of a slab.
+pub fn encode(txs: &[Transaction]) -> Result<Vec<u8>> {
+    let mut payload = SLAB_PAYLOAD_MARKER.to_vec();
+    SLAB_PAYLOAD_VERSION.encode(&mut payload)?;
+    VarInt(txs.len() as u64).encode(&mut payload)?;
+    for tx in txs {
+        tx.encode(&mut payload)?;
+    }
+    Ok(payload)
+}
+
+/// Decode the transactions of a slab, including legacy slabs holding a
+/// single transaction.
+pub fn decode(payload: &[u8]) -> Result<Vec<Transaction>> {
+    if !payload.starts_with(&SLAB_PAYLOAD_MARKER) {
+        return Ok(vec![deserialize(payload)?])
+    }
+
+    let mut cursor = Cursor::new(&payload[SLAB_PAYLOAD_MARKER.len()..]);
+    match u8::decode(&mut cursor)? {
+        1 => {
+            let txs: Vec<Transaction> = Decodable::decode(&mut cursor)?;
+            if cursor.position() as usize != cursor.get_ref().len() {
+                return Err(Error::ParseFailed("Trailing data in slab payload"))
+            }
+            Ok(txs)
+        }
+        _ => Err(Error::ParseFailed("Unknown slab payload version")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slab_payload_version() -> Result<()> {
+        let payload = encode(&[])?;
+        assert_eq!(payload[SLAB_PAYLOAD_MARKER.len()], SLAB_PAYLOAD_VERSION);
+        assert!(decode(&payload)?.is_empty());
+
+        let mut unknown = payload.clone();
+        unknown[SLAB_PAYLOAD_MARKER.len()] = SLAB_PAYLOAD_VERSION + 1;
+        assert!(decode(&unknown).is_err());
+
+        let mut trailing = payload;
+        trailing.push(0);
+        assert!(decode(&trailing).is_err());
+
+        Ok(())
+    }
+}
@@ -1,8 +1,12 @@
+use async_std::sync::{Arc, Mutex};
 use incrementalmerkletree::{bridgetree::BridgeTree, Frontier, Tree};
-use log::{debug, error};
+use log::{debug, error, warn};
 
 use crate::{
-    blockchain::{rocks::columns, RocksColumn},
+    blockchain::{
+        rocks::{columns, IteratorMode},
+        RocksColumn, Slab, SlabStore,
+    },
     crypto::{
         coin::Coin,
         keypair::{PublicKey, SecretKey},
@@ -14,19 +18,31 @@ use crate::{
     },
     error,
     tx::Transaction,
+    util::{
+        serial::{deserialize, serialize},
+        sleep,
+    },
     Result,
 };
 
-use super::wallet::walletdb::WalletPtr;
+use super::{compact_slab::CompactSlab, wallet::walletdb::WalletPtr};
+
+/// Interval in seconds between two runs of the expired Merkle roots cleanup.
+const MERKLE_PRUNE_INTERVAL: u32 = 60;
+
+/// Default number of slabs a merkle root stays valid as an anchor.
+pub const MERKLE_ANCHOR_WINDOW: u64 = 100;
 
 pub trait ProgramState {
     fn is_valid_cashier_public_key(&self, public: &PublicKey) -> bool;
     fn is_valid_merkle(&self, merkle: &MerkleNode) -> bool;
+    fn is_stale_merkle(&self, merkle: &MerkleNode) -> bool;
     fn nullifier_exists(&self, nullifier: &Nullifier) -> bool;
     fn mint_vk(&self) -> &VerifyingKey;
     fn spend_vk(&self) -> &VerifyingKey;
 }
 
+#[derive(Clone)]
 pub struct StateUpdate {
     pub nullifiers: Vec<Nullifier>,
     pub coins: Vec<Coin>,
@@ -41,6 +57,8 @@ pub enum VerifyFailed {
     InvalidCashierKey(usize),
     #[error("Invalid merkle root for input {0}")]
     InvalidMerkle(usize),
+    #[error("Merkle root for input {0} is outside of the anchor window")]
+    StaleMerkle(usize),
     #[error("Duplicate nullifier for input {0}")]
     DuplicateNullifier(usize),
     #[error("Spend proof for input {0}")]
@@ -89,6 +107,12 @@ pub fn state_transition<S: ProgramState>(state: &S, tx: Transaction) -> VerifyRe
             return Err(VerifyFailed::InvalidMerkle(i))
         }
 
+        // Anchors older than the configured window are rejected, so
+        // inputs can't reference arbitrarily old states.
+        if state.is_stale_merkle(merkle) {
+            return Err(VerifyFailed::StaleMerkle(i))
+        }
+
         // The nullifiers should not already exist
         // It is double spend protection.
         let nullifier = &input.revealed.nullifier;
@@ -122,9 +146,13 @@ pub fn state_transition<S: ProgramState>(state: &S, tx: Transaction) -> VerifyRe
 pub struct State {
     /// The entire Merkle tree state
     pub tree: BridgeTree<MerkleNode, 32>,
-    /// List of all previous and the current merkle roots.
+    /// Recent merkle roots, mapped to the slab index they appeared in.
     /// This is the hashed value of all the children.
     pub merkle_roots: RocksColumn<columns::MerkleRoots>,
+    /// Number of slabs a merkle root stays valid as an anchor
+    pub merkle_anchor_window: u64,
+    /// Index of the last slab applied to the state
+    pub last_slab_index: u64,
     /// Nullifiers prevent double-spending
     pub nullifiers: RocksColumn<columns::Nullifiers>,
     /// List of Cashier public keys
@@ -139,9 +167,10 @@ impl State {
     pub async fn apply(
         &mut self,
         update: StateUpdate,
+        slab_index: u64,
         secret_keys: Vec<SecretKey>,
         notify: Option<async_channel::Sender<(PublicKey, u64)>>,
-        wallet: WalletPtr,
+        wallet: Option<WalletPtr>,
     ) -> Result<()> {
         // Extend our list of nullifiers with the ones from the update.
         debug!("Extend nullifiers");
@@ -155,8 +184,8 @@ impl State {
             let node = MerkleNode(coin.0);
             self.tree.append(&node);
 
-            // Keep track of all Merkle roots that have existed
-            self.merkle_roots.put(self.tree.root(), vec![] as Vec<u8>)?;
+            // Keep track of the Merkle roots and when they appeared
+            self.merkle_roots.put(self.tree.root(), slab_index)?;
 
             for secret in secret_keys.iter() {
                 if let Some(note) = State::try_decrypt_note(enc_note, *secret) {
@@ -165,7 +194,9 @@ impl State {
 
                     let own_coin = OwnCoin { coin, note, secret: *secret, nullifier };
 
-                    wallet.put_own_coins(own_coin).await?;
+                    if let Some(wallet) = &wallet {
+                        wallet.put_own_coins(own_coin).await?;
+                    }
 
                     let pubkey = PublicKey::from_secret(*secret);
 
@@ -176,14 +207,127 @@ impl State {
                     }
                 }
             }
-            // Save updated merkle tree into wallet.
-            wallet.put_tree(&self.tree).await?;
+        }
+
+        if slab_index > self.last_slab_index {
+            self.last_slab_index = slab_index;
         }
 
         debug!("apply() exiting successfully");
         Ok(())
     }
 
+    /// Apply the updates of a whole slab, then save the tree to the wallet.
+    /// Slabs at or below the last applied index are skipped, so syncing
+    /// again after a restart doesn't append their coins to the tree twice.
+    /// Returns false if the slab was skipped.
+    pub async fn apply_slab(
+        &mut self,
+        updates: Vec<StateUpdate>,
+        slab_index: u64,
+        secret_keys: Vec<SecretKey>,
+        notify: Option<async_channel::Sender<(PublicKey, u64)>>,
+        wallet: WalletPtr,
+    ) -> Result<bool> {
+        if self.is_applied(slab_index) {
+            debug!("Slab {} already applied, skipping", slab_index);
+            return Ok(false)
+        }
+
+        for update in updates {
+            self.apply(
+                update,
+                slab_index,
+                secret_keys.clone(),
+                notify.clone(),
+                Some(wallet.clone()),
+            )
+            .await?;
+        }
+
+        self.save(&wallet).await?;
+        Ok(true)
+    }
+
+    /// Check if the slab was already applied to the state.
+    pub fn is_applied(&self, slab_index: u64) -> bool {
+        slab_index <= self.last_slab_index
+    }
+
+    /// Rebuild the tree and the last slab index from the stored slabs.
+    /// Returns the index of the last applied slab.
+    pub async fn restore(&mut self, slabstore: &SlabStore) -> Result<u64> {
+        let last_index = slabstore.get_last_index()?;
+
+        for index in (self.last_slab_index + 1)..(last_index + 1) {
+            let slab: Slab = match slabstore.get(serialize(&index))? {
+                Some(slab) => deserialize(&slab)?,
+                None => break,
+            };
+
+            let update = CompactSlab::from_slab(&slab)?.into_state_update();
+            self.apply(update, index, vec![], None, None).await?;
+        }
+
+        Ok(self.last_slab_index)
+    }
+
+    /// Save the merkle tree and the index of the last applied slab into
+    /// the wallet. Called once a whole slab is applied, so a restart never
+    /// resumes from the middle of a slab.
+    pub async fn save(&self, wallet: &WalletPtr) -> Result<()> {
+        wallet.put_tree(&self.tree, self.last_slab_index).await
+    }
+
+    /// Check if a root that appeared in the given slab index fell out of
+    /// the anchor window. The window covers the last `merkle_anchor_window`
+    /// slabs, including the last applied one.
+    fn is_expired_index(&self, slab_index: u64) -> bool {
+        self.last_slab_index.saturating_sub(slab_index) >= self.merkle_anchor_window
+    }
+
+    /// Delete the merkle roots that are outside of the anchor window.
+    /// Returns the number of deleted roots.
+    ///
+    /// Roots stored before the slab index was recorded have an empty
+    /// value. Their index is unknown, so they're given the current index
+    /// and expire once the window has passed.
+    pub fn prune_merkle_roots(&self) -> Result<usize> {
+        let mut expired = vec![];
+        let mut unknown = vec![];
+
+        for (key, value) in self.merkle_roots.iterator(IteratorMode::Start)? {
+            let root: MerkleNode = deserialize(&key)?;
+            match deserialize::<u64>(&value) {
+                Ok(slab_index) if self.is_expired_index(slab_index) => expired.push(root),
+                Ok(_) => {}
+                Err(_) => unknown.push(root),
+            }
+        }
+
+        for root in expired.iter() {
+            self.merkle_roots.delete(*root)?;
+        }
+
+        for root in unknown.iter() {
+            self.merkle_roots.put(*root, self.last_slab_index)?;
+        }
+
+        Ok(expired.len())
+    }
+
+    /// Periodically delete the expired merkle roots from the database.
+    pub async fn prune_merkle_roots_loop(state: Arc<Mutex<State>>) {
+        loop {
+            sleep(MERKLE_PRUNE_INTERVAL).await;
+
+            match state.lock().await.prune_merkle_roots() {
+                Ok(pruned) => debug!("Pruned {} expired merkle roots", pruned),
+                Err(e) => warn!("Unable to prune merkle roots: {}", e),
+            }
+        }
+    }
+
     fn try_decrypt_note(ciphertext: &EncryptedNote, secret: SecretKey) -> Option<Note> {
         match ciphertext.decrypt(&secret) {
             Ok(note) => Some(note),
@@ -206,6 +350,19 @@ impl ProgramState for State {
         false
     }
 
+    fn is_stale_merkle(&self, merkle_root: &MerkleNode) -> bool {
+        debug!("Check if merkle is outside of the anchor window");
+        match self.merkle_roots.get(*merkle_root) {
+            Ok(Some(value)) => match deserialize::<u64>(&value) {
+                Ok(slab_index) => self.is_expired_index(slab_index),
+                // Stored before the slab index was recorded, so the index
+                // is unknown until the next pruning run assigns one
+                Err(_) => false,
+            },
+            _ => true,
+        }
+    }
+
     fn nullifier_exists(&self, nullifier: &Nullifier) -> bool {
         debug!("Check if nullifier exists");
         if let Ok(nl) = self.nullifiers.key_exist(nullifier.to_bytes()) {
@@ -222,3 +379,170 @@ impl ProgramState for State {
         &self.spend_vk
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use group::ff::Field;
+    use pasta_curves::pallas;
+    use rand::rngs::OsRng;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::{
+        blockchain::Rocks,
+        crypto::{
+            keypair::Keypair,
+            types::{DrkCoinBlind, DrkSerial, DrkTokenId, DrkValueBlind},
+        },
+        node::wallet::walletdb::WalletDb,
+        zk::circuit::{MintContract, SpendContract},
+    };
+
+    /// State backed by a database in a temporary directory, removed when
+    /// the returned `TempDir` is dropped.
+    fn test_state(merkle_anchor_window: u64) -> Result<(State, TempDir)> {
+        let dir = tempdir()?;
+        let rocks = Rocks::new(dir.path())?;
+
+        let state = State {
+            tree: BridgeTree::<MerkleNode, 32>::new(100),
+            merkle_roots: RocksColumn::<columns::MerkleRoots>::new(rocks.clone()),
+            merkle_anchor_window,
+            last_slab_index: 0,
+            nullifiers: RocksColumn::<columns::Nullifiers>::new(rocks),
+            public_keys: vec![],
+            mint_vk: VerifyingKey::build(11, &MintContract::default()),
+            spend_vk: VerifyingKey::build(11, &SpendContract::default()),
+        };
+        Ok((state, dir))
+    }
+
+    /// Update adding a single coin for someone else.
+    fn coin_update() -> StateUpdate {
+        let note = Note {
+            serial: DrkSerial::random(&mut OsRng),
+            value: 42,
+            token_id: DrkTokenId::random(&mut OsRng),
+            coin_blind: DrkCoinBlind::random(&mut OsRng),
+            value_blind: DrkValueBlind::random(&mut OsRng),
+        };
+        let enc_note = note.encrypt(&Keypair::random(&mut OsRng).public).unwrap();
+
+        StateUpdate {
+            nullifiers: vec![],
+            coins: vec![Coin(pallas::Base::random(&mut OsRng))],
+            enc_notes: vec![enc_note],
+        }
+    }
+
+    /// Apply a coin in each of the slabs 1 to 5 and return the roots.
+    async fn apply_slabs(state: &mut State) -> Result<Vec<MerkleNode>> {
+        let mut roots = vec![];
+        for index in 1..=5 {
+            state.apply(coin_update(), index, vec![], None, None).await?;
+            roots.push(state.tree.root());
+        }
+        Ok(roots)
+    }
+
+    #[test]
+    fn anchor_window_boundary() -> Result<()> {
+        let (mut state, _dir) = test_state(2)?;
+        state.last_slab_index = 5;
+
+        // A window of 2 slabs covers the slabs 4 and 5
+        assert!(!state.is_expired_index(5));
+        assert!(!state.is_expired_index(4));
+        assert!(state.is_expired_index(3));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn merkle_anchor_window() -> Result<()> {
+        let (mut state, _dir) = test_state(2)?;
+        let roots = apply_slabs(&mut state).await?;
+        assert_eq!(state.last_slab_index, 5);
+
+        // Only the roots of the last 2 slabs are within the window
+        for root in &roots[..3] {
+            assert!(state.is_valid_merkle(root));
+            assert!(state.is_stale_merkle(root));
+        }
+        for root in &roots[3..] {
+            assert!(state.is_valid_merkle(root));
+            assert!(!state.is_stale_merkle(root));
+        }
+
+        // Roots stored before the slab index was recorded aren't stale
+        let legacy = MerkleNode(pallas::Base::random(&mut OsRng));
+        state.merkle_roots.put(legacy, vec![] as Vec<u8>)?;
+        assert!(!state.is_stale_merkle(&legacy));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn prune_merkle_roots() -> Result<()> {
+        let (mut state, _dir) = test_state(2)?;
+        let roots = apply_slabs(&mut state).await?;
+        let legacy = MerkleNode(pallas::Base::random(&mut OsRng));
+        state.merkle_roots.put(legacy, vec![] as Vec<u8>)?;
+
+        assert_eq!(state.prune_merkle_roots()?, 3);
+        for root in &roots[..3] {
+            assert!(!state.is_valid_merkle(root));
+        }
+        for root in &roots[3..] {
+            assert!(state.is_valid_merkle(root));
+        }
+
+        // The legacy root was given the current index, and expires with it
+        assert!(state.is_valid_merkle(&legacy));
+        state.last_slab_index = 8;
+        assert!(state.is_stale_merkle(&legacy));
+        assert_eq!(state.prune_merkle_roots()?, 3);
+        assert!(!state.is_valid_merkle(&legacy));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn apply_slab_after_restart() -> Result<()> {
+        let wallet = WalletDb::new("sqlite::memory:", "darkfi").await?;
+        wallet.init_db().await?;
+        wallet.tree_gen().await?;
+
+        let slabs: Vec<(u64, Vec<StateUpdate>)> =
+            (1..=3).map(|index| (index, vec![coin_update(), coin_update()])).collect();
+
+        let (mut state, _dir) = test_state(10)?;
+        state.tree = wallet.get_tree().await?;
+        for (index, updates) in slabs.iter() {
+            assert!(state.apply_slab(updates.clone(), *index, vec![], None, wallet.clone()).await?);
+        }
+        let root = state.tree.root();
+
+        // Restart from the wallet and sync the same slabs again
+        let (mut state, _dir) = test_state(10)?;
+        state.tree = wallet.get_tree().await?;
+        state.last_slab_index = wallet.get_last_slab_index().await?.unwrap();
+        assert_eq!(state.last_slab_index, 3);
+        assert_eq!(state.tree.root(), root);
+
+        for (index, updates) in slabs.iter() {
+            assert!(
+                !state.apply_slab(updates.clone(), *index, vec![], None, wallet.clone()).await?
+            );
+        }
+        assert_eq!(state.tree.root(), root);
+        assert_eq!(wallet.get_tree().await?.root(), root);
+
+        // New slabs are still applied
+        assert!(state.apply_slab(vec![coin_update()], 4, vec![], None, wallet.clone()).await?);
+        assert_ne!(state.tree.root(), root);
+        assert_eq!(wallet.get_last_slab_index().await?, Some(4));
+
+        Ok(())
+    }
+}
@@ -180,7 +180,7 @@ impl WalletDb {
             }
             Err(_) => {
                 let tree = BridgeTree::<MerkleNode, 32>::new(100);
-                self.put_tree(&tree).await?;
+                self.put_tree(&tree, 0).await?;
                 Ok(tree)
             }
         }
@@ -195,24 +195,52 @@ impl WalletDb {
         Ok(tree)
     }
 
-    pub async fn put_tree(&self, tree: &BridgeTree<MerkleNode, 32>) -> Result<()> {
+    /// Write the merkle tree along with the index of the last slab applied
+    /// to it, so a restarted node knows where to resume syncing.
+    pub async fn put_tree(
+        &self,
+        tree: &BridgeTree<MerkleNode, 32>,
+        last_slab_index: u64,
+    ) -> Result<()> {
         debug!("Attempting to write merkle tree");
-        let mut conn = self.conn.acquire().await?;
+        let mut tx = self.conn.begin().await?;
 
         let tree_bytes = bincode::serialize(tree)?;
 
-        debug!("Deleting old row");
-        sqlx::query("DELETE FROM tree;").execute(&mut conn).await?;
+        debug!("Deleting old rows");
+        sqlx::query("DELETE FROM tree;").execute(&mut tx).await?;
+        sqlx::query("DELETE FROM tree_index;").execute(&mut tx).await?;
 
         debug!("Inserting new tree");
         sqlx::query("INSERT INTO tree (tree) VALUES (?1);")
             .bind(tree_bytes)
-            .execute(&mut conn)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("INSERT INTO tree_index (last_slab_index) VALUES (?1);")
+            .bind(last_slab_index as i64)
+            .execute(&mut tx)
             .await?;
 
+        tx.commit().await?;
         Ok(())
     }
 
+    /// Replace the merkle tree with an empty one, so the slabs are applied
+    /// again from the start.
+    pub async fn reset_tree(&self) -> Result<()> {
+        self.put_tree(&BridgeTree::<MerkleNode, 32>::new(100), 0).await
+    }
+
+    /// Index of the last slab applied to the stored merkle tree. Wallets
+    /// written before the index was stored return `None`.
+    pub async fn get_last_slab_index(&self) -> Result<Option<u64>> {
+        debug!("Getting last slab index");
+        let mut conn = self.conn.acquire().await?;
+
+        let row = sqlx::query("SELECT * FROM tree_index").fetch_optional(&mut conn).await?;
+        Ok(row.map(|row| row.get::<i64, _>("last_slab_index") as u64))
+    }
+
     pub async fn get_own_coins(&self) -> Result<OwnCoins> {
         debug!("Finding own coins");
         let is_spent = 0;
@@ -446,7 +474,8 @@ mod tests {
         let root1 = tree1.root();
 
         // put_tree()
-        wallet.put_tree(&tree1).await?;
+        wallet.put_tree(&tree1, 4).await?;
+        assert_eq!(wallet.get_last_slab_index().await?, Some(4));
 
         // get_token_id()
         let id = wallet.get_token_id().await?;
@@ -493,10 +522,11 @@ mod tests {
         assert_eq!(root1, root2);
 
         // Let's try it once more to test sql replacing.
-        wallet.put_tree(&tree2).await?;
+        wallet.put_tree(&tree2, 5).await?;
         let tree3 = wallet.get_tree().await?;
         let root3 = tree3.root();
         assert_eq!(root2, root3);
+        assert_eq!(wallet.get_last_slab_index().await?, Some(5));
 
         Ok(())
     }
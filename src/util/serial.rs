@@ -11,6 +11,17 @@ use num_bigint::BigUint;
 use super::endian;
 use crate::{Error, Result};
 
+/// Upper bound on the memory reserved up front when decoding a vector.
+/// The length prefix comes from the peer, so larger vectors grow as their
+/// elements are actually read.
+pub const MAX_VEC_PREALLOCATION: usize = 4096;
+
+/// Capacity to reserve for a decoded vector of `len` elements of `T`.
+pub fn vec_preallocation<T>(len: u64) -> usize {
+    let max = MAX_VEC_PREALLOCATION / mem::size_of::<T>().max(1);
+    (len as usize).min(max)
+}
+
 /// Encode an object into a vector
 pub fn serialize<T: Encodable + ?Sized>(data: &T) -> Vec<u8> {
     let mut encoder = Vec::new();
@@ -416,7 +427,7 @@ impl<T: Encodable> Encodable for Vec<Option<T>> {
 impl<T: Decodable> Decodable for Vec<Option<T>> {
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
         let len = VarInt::decode(&mut d)?.0;
-        let mut ret = Vec::with_capacity(len as usize);
+        let mut ret = Vec::with_capacity(vec_preallocation::<Option<T>>(len));
         for _ in 0..len {
             ret.push(Decodable::decode(&mut d)?);
         }
@@ -443,7 +454,8 @@ macro_rules! impl_vec {
             #[inline]
             fn decode<D: io::Read>(mut d: D) -> Result<Self> {
                 let len = VarInt::decode(&mut d)?.0;
-                let mut ret = Vec::with_capacity(len as usize);
+                let mut ret =
+                    Vec::with_capacity($crate::util::serial::vec_preallocation::<$type>(len));
                 for _ in 0..len {
                     ret.push(Decodable::decode(&mut d)?);
                 }
@@ -525,9 +537,12 @@ impl Encodable for Vec<u8> {
 impl Decodable for Vec<u8> {
     #[inline]
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
-        let len = VarInt::decode(&mut d)?.0 as usize;
-        let mut ret = vec![0u8; len];
-        d.read_slice(&mut ret)?;
+        let len = VarInt::decode(&mut d)?.0;
+        let mut ret = Vec::with_capacity(vec_preallocation::<u8>(len));
+        (&mut d).take(len).read_to_end(&mut ret)?;
+        if ret.len() as u64 != len {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof))
+        }
         Ok(ret)
     }
 }
@@ -758,6 +773,21 @@ mod tests {
         assert!(deserialize::<Vec<u8>>(&vec_253).is_ok());
     }
 
+    #[test]
+    fn deserialize_huge_length_test() {
+        // A few bytes claiming a length of u64::MAX must fail on the missing
+        // data rather than reserving memory for it.
+        let mut data = vec![0xffu8; 9];
+        data.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(
+            discriminant(&deserialize::<Vec<u8>>(&data).unwrap_err()),
+            discriminant(&Error::Io(io::ErrorKind::UnexpectedEof))
+        );
+        assert!(deserialize::<String>(&data).is_err());
+        assert!(deserialize::<Vec<Option<u64>>>(&data).is_err());
+        assert!(deserialize::<Vec<[u8; 32]>>(&data).is_err());
+    }
+
     #[test]
     fn serialize_vector_test() {
         assert_eq!(serialize(&vec![1u8, 2, 3]), vec![3u8, 1, 2, 3]);